@@ -2,20 +2,311 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
-use std::{env, fs::File, io::Write, path::PathBuf};
+use std::{env, fmt::Write as _, fs, path::PathBuf};
 
 fn main() {
     // Write linker script to out directory, and add that to the search path. We can't actually make
     // the linker use it, only a binary can do that.
-    let image_ld = include_bytes!("image.ld");
-    File::create(PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("image.ld"))
-        .unwrap()
-        .write_all(image_ld)
-        .unwrap();
+    let mut image_ld = String::new();
+    println!("cargo::rerun-if-env-changed=AARCH64_RT_LAYOUT");
+    let mut layout = if let Some(layout_path) = env::var_os("AARCH64_RT_LAYOUT") {
+        let layout_path = PathBuf::from(layout_path);
+        println!("cargo::rerun-if-changed={}", layout_path.display());
+        let layout = fs::read_to_string(&layout_path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", layout_path.display()));
+        Layout::parse(&layout)
+    } else {
+        Layout::default()
+    };
+    if let Some(origin) = env_number("AARCH64_RT_RAM_ORIGIN") {
+        layout.origin = Some(origin);
+    }
+    if let Some(length) = env_number("AARCH64_RT_RAM_LENGTH") {
+        layout.length = Some(length);
+    }
+    if env::var_os("CARGO_FEATURE_RASPBERRY_PI").is_some() {
+        // The firmware loads the image at 0x80000. Leave the rest of the first 256 MiB for the
+        // image, which is available on all models.
+        layout.origin.get_or_insert(0x8_0000);
+        layout.length.get_or_insert(0x1000_0000 - 0x8_0000);
+    }
+    let rom = env::var_os("CARGO_FEATURE_ROM").is_some();
+    if layout.origin.is_some() || layout.length.is_some() || !layout.regions.is_empty() {
+        image_ld += &layout.memory_block(if rom { "ram" } else { "image" });
+    }
+    if rom {
+        image_ld += include_str!("image_rom.ld");
+    } else {
+        image_ld += include_str!("image.ld");
+    }
+    image_ld += &layout.asserts();
+    fs::write(
+        PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("image.ld"),
+        image_ld,
+    )
+    .unwrap();
+
+    let boot_stack_pages = env_number("AARCH64_RT_STACK_PAGES")
+        .or(layout.boot_stack_pages)
+        .unwrap_or(40);
+    let secondary_stack_pages = env_number("AARCH64_RT_SECONDARY_STACK_PAGES")
+        .or(layout.secondary_stack_pages)
+        .unwrap_or(8);
+    let image_origin = match layout.origin {
+        Some(origin) => format!("Some({origin:#x})"),
+        None => "None".to_owned(),
+    };
+    fs::write(
+        PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("config.rs"),
+        format!(
+            "/// The number of pages reserved for the boot stack by [`entry!`] if no size is given.\n\
+             pub const DEFAULT_BOOT_STACK_PAGES: usize = {boot_stack_pages};\n\
+             /// The number of pages reserved for each secondary core stack by [`per_core_stacks!`] \
+             if no size is given.\n\
+             pub const DEFAULT_SECONDARY_STACK_PAGES: usize = {secondary_stack_pages};\n\
+             /// The address which the image is linked at, if the memory layout gives it.\n\
+             #[doc(hidden)]\n\
+             pub const __IMAGE_ORIGIN: Option<u64> = {image_origin};\n"
+        ),
+    )
+    .unwrap();
 
-    println!("cargo::rustc-link-search={}", env::var("OUT_DIR").unwrap());
+    let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo::rustc-link-search={out_dir}");
     println!("cargo::rerun-if-changed=image.ld");
+    println!("cargo::rerun-if-changed=image_rom.ld");
 
-    println!("cargo::rustc-link-arg-examples=-Timage.ld");
+    // Use the full path, as otherwise the linker would find the unmodified image.ld in the package
+    // root first.
+    println!("cargo::rustc-link-arg-examples=-T{out_dir}/image.ld");
     println!("cargo::rustc-link-arg-examples=-Texamples/qemu.ld");
 }
+
+/// Reads the given environment variable as a number, if it is set.
+fn env_number(name: &str) -> Option<u64> {
+    println!("cargo::rerun-if-env-changed={name}");
+    let value = env::var(name).ok()?;
+    Some(parse_number(value.trim()).unwrap_or_else(|e| panic!("Invalid {name}: {e}")))
+}
+
+/// A memory layout read from the file given by `AARCH64_RT_LAYOUT`.
+///
+/// The file consists of `key = value` lines, with `#` starting a comment. `origin` and `length`
+/// give the region the image is linked into, and each `region.<name> = <origin>, <length>` line
+/// adds an extra memory region with the given name. Numbers may be decimal or `0x`-prefixed hex,
+/// and lengths may have a `K`, `M` or `G` suffix.
+///
+/// The optional `max_image_size`, `min_boot_stack_size`, `min_free` and `entry_align` keys add
+/// linker script assertions that the loaded image is at most the given size, that the boot stack is
+/// at least the given size, that at least the given amount of RAM is left after the image, and that
+/// the entry point is aligned to the given power of two, respectively. `min_free` requires `origin`
+/// and `length` to be given too.
+///
+/// `boot_stack_pages` and `secondary_stack_pages` set the default number of 4 KiB pages for the
+/// boot stack and secondary core stacks, unless overridden by `AARCH64_RT_STACK_PAGES` or
+/// `AARCH64_RT_SECONDARY_STACK_PAGES` respectively.
+#[derive(Default)]
+struct Layout {
+    origin: Option<u64>,
+    length: Option<u64>,
+    regions: Vec<(String, u64, u64)>,
+    max_image_size: Option<u64>,
+    min_boot_stack_size: Option<u64>,
+    /// The minimum free RAM, and the line of the layout file which gave it.
+    min_free: Option<(u64, usize)>,
+    entry_align: Option<u64>,
+    boot_stack_pages: Option<u64>,
+    secondary_stack_pages: Option<u64>,
+}
+
+impl Layout {
+    fn parse(layout: &str) -> Self {
+        let mut result = Self::default();
+        for (line_number, line) in layout.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| -> ! { layout_error(line_number, message) };
+            let Some((key, value)) = line.split_once('=') else {
+                error("expected `key = value`");
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "origin" => result.origin = Some(parse_number(value).unwrap_or_else(|e| error(&e))),
+                "length" => result.length = Some(parse_number(value).unwrap_or_else(|e| error(&e))),
+                "max_image_size" => {
+                    result.max_image_size = Some(parse_number(value).unwrap_or_else(|e| error(&e)));
+                }
+                "min_boot_stack_size" => {
+                    result.min_boot_stack_size =
+                        Some(parse_number(value).unwrap_or_else(|e| error(&e)));
+                }
+                "min_free" => {
+                    result.min_free = Some((
+                        parse_number(value).unwrap_or_else(|e| error(&e)),
+                        line_number,
+                    ));
+                }
+                "boot_stack_pages" => {
+                    result.boot_stack_pages =
+                        Some(parse_number(value).unwrap_or_else(|e| error(&e)));
+                }
+                "secondary_stack_pages" => {
+                    result.secondary_stack_pages =
+                        Some(parse_number(value).unwrap_or_else(|e| error(&e)));
+                }
+                "entry_align" => {
+                    let align = parse_number(value).unwrap_or_else(|e| error(&e));
+                    if !align.is_power_of_two() {
+                        error("`entry_align` must be a power of two");
+                    }
+                    result.entry_align = Some(align);
+                }
+                _ => {
+                    let Some(name) = key.strip_prefix("region.") else {
+                        error(&format!("unknown key `{key}`"));
+                    };
+                    let Some((origin, length)) = value.split_once(',') else {
+                        error("expected `<origin>, <length>`");
+                    };
+                    result.regions.push((
+                        name.to_owned(),
+                        parse_number(origin.trim()).unwrap_or_else(|e| error(&e)),
+                        parse_number(length.trim()).unwrap_or_else(|e| error(&e)),
+                    ));
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns a linker script `MEMORY` block for the layout, followed by the end of the given RAM
+    /// region for the early allocator.
+    fn memory_block(&self, ram: &str) -> String {
+        let (Some(origin), Some(length)) = (self.origin, self.length) else {
+            panic!(
+                "Layout must specify both `origin` and `length`, either in the layout file or with \
+                 AARCH64_RT_RAM_ORIGIN and AARCH64_RT_RAM_LENGTH"
+            );
+        };
+        let mut memory = String::from("MEMORY\n{\n");
+        writeln!(
+            memory,
+            "\timage : ORIGIN = {origin:#x}, LENGTH = {length:#x}"
+        )
+        .unwrap();
+        for (name, origin, length) in &self.regions {
+            writeln!(
+                memory,
+                "\t{name} : ORIGIN = {origin:#x}, LENGTH = {length:#x}"
+            )
+            .unwrap();
+        }
+        memory += "}\n\n";
+        writeln!(memory, "early_alloc_end = ORIGIN({ram}) + LENGTH({ram});\n").unwrap();
+        memory
+    }
+
+    /// Returns linker script assertions for the limits given in the layout.
+    ///
+    /// Panics if any of the memory regions overlap.
+    fn asserts(&self) -> String {
+        let mut asserts = String::from("\n");
+        if let Some(max_image_size) = self.max_image_size {
+            writeln!(
+                asserts,
+                "ASSERT(bin_end - text_begin <= {max_image_size:#x}, \
+                 \"Image is larger than max_image_size\");"
+            )
+            .unwrap();
+        }
+        if let Some(min_boot_stack_size) = self.min_boot_stack_size {
+            writeln!(
+                asserts,
+                "ASSERT(boot_stack_end - boot_stack_begin >= {min_boot_stack_size:#x}, \
+                 \"Boot stack is smaller than min_boot_stack_size\");"
+            )
+            .unwrap();
+        }
+        if let Some((min_free, line_number)) = self.min_free {
+            // `early_alloc_end` is only defined before this point if the layout gives the RAM
+            // region.
+            if self.origin.is_none() || self.length.is_none() {
+                layout_error(
+                    line_number,
+                    "`min_free` requires `origin` and `length` to be given",
+                );
+            }
+            writeln!(
+                asserts,
+                "ASSERT(early_alloc_end - early_alloc_begin >= {min_free:#x}, \
+                 \"Less than min_free bytes of RAM are left after the image\");"
+            )
+            .unwrap();
+        }
+        if let Some(entry_align) = self.entry_align {
+            writeln!(
+                asserts,
+                "ASSERT(entry % {entry_align:#x} == 0, \"Entry point isn't aligned to entry_align\");"
+            )
+            .unwrap();
+        }
+        let image = self
+            .origin
+            .zip(self.length)
+            .map(|(origin, length)| ("image", origin, length));
+        let regions = image
+            .into_iter()
+            .chain(
+                self.regions
+                    .iter()
+                    .map(|(name, origin, length)| (name.as_str(), *origin, *length)),
+            )
+            .collect::<Vec<_>>();
+        for (i, &(a, a_origin, a_length)) in regions.iter().enumerate() {
+            for &(b, b_origin, b_length) in &regions[i + 1..] {
+                if regions_overlap(a_origin, a_length, b_origin, b_length) {
+                    panic!("Memory regions {a} and {b} overlap");
+                }
+            }
+        }
+        asserts
+    }
+}
+
+/// Returns whether the regions with the given origins and lengths overlap.
+///
+/// This compares offsets rather than end addresses, so it doesn't overflow for regions which end at
+/// the top of the address space.
+fn regions_overlap(a_origin: u64, a_length: u64, b_origin: u64, b_length: u64) -> bool {
+    if a_origin <= b_origin {
+        b_origin - a_origin < a_length
+    } else {
+        a_origin - b_origin < b_length
+    }
+}
+
+/// Panics with the given message about the layout file line with the given zero-based index.
+fn layout_error(line_number: usize, message: &str) -> ! {
+    panic!("Invalid layout line {}: {message}", line_number + 1)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number, with an optional `K`, `M` or `G` suffix.
+fn parse_number(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.as_bytes().last() {
+        Some(b'K') => (&value[..value.len() - 1], 1 << 10),
+        Some(b'M') => (&value[..value.len() - 1], 1 << 20),
+        Some(b'G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let number = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(&hex.replace('_', ""), 16)
+    } else {
+        digits.replace('_', "").parse()
+    }
+    .map_err(|e| format!("invalid number `{value}`: {e}"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("number `{value}` is too large"))
+}
@@ -7,62 +7,50 @@
 #![no_std]
 #![no_main]
 
-use aarch64_paging::{
-    descriptor::Attributes,
-    mair::{Mair, MairAttribute, NormalMemory},
-};
 use aarch64_rt::{
-    ExceptionHandlers, InitialPagetable, entry, exception_handlers, initial_pagetable,
+    BlockDescriptor, ExceptionHandlers, InitialPagetable, entry, exception_handlers,
+    initial_pagetable,
+    mair::{MairRegistry, MemoryType},
+    mapping::Attributes,
+    platform::qemu_virt,
+    power::{reboot, shutdown},
 };
 use arm_pl011_uart::{PL011Registers, Uart, UniqueMmioPointer};
 use core::{fmt::Write, panic::PanicInfo, ptr::NonNull};
-use smccc::{
-    Hvc,
-    psci::{system_off, system_reset},
-};
 
 /// Base address of the first PL011 UART.
-const PL011_BASE_ADDRESS: *mut PL011Registers = 0x900_0000 as _;
+const PL011_BASE_ADDRESS: *mut PL011Registers = qemu_virt::PL011_BASE_ADDRESS as _;
+
+/// Indirect memory attributes to use, with an index assigned to each memory type.
+const MAIR: MairRegistry = MairRegistry::new()
+    .with(MemoryType::DEVICE_NGNRE)
+    .with(MemoryType::NORMAL_WRITE_BACK);
 
 /// Attributes to use for device memory in the initial identity map.
-const DEVICE_ATTRIBUTES: Attributes = Attributes::VALID
-    .union(Attributes::ATTRIBUTE_INDEX_0)
+const DEVICE_ATTRIBUTES: Attributes = MAIR
+    .attributes(MemoryType::DEVICE_NGNRE)
     .union(Attributes::ACCESSED)
     .union(Attributes::UXN);
 
 /// Attributes to use for normal memory in the initial identity map.
-const MEMORY_ATTRIBUTES: Attributes = Attributes::VALID
-    .union(Attributes::ATTRIBUTE_INDEX_1)
+const MEMORY_ATTRIBUTES: Attributes = MAIR
+    .attributes(MemoryType::NORMAL_WRITE_BACK)
     .union(Attributes::INNER_SHAREABLE)
     .union(Attributes::ACCESSED)
     .union(Attributes::NON_GLOBAL);
 
-/// Indirect memory attributes to use.
-///
-/// These are used for `ATTRIBUTE_INDEX_0` and `ATTRIBUTE_INDEX_1` in `DEVICE_ATTRIBUTES` and
-/// `MEMORY_ATTRIBUTES` respectively.
-const MAIR: Mair = Mair::EMPTY
-    .with_attribute(0, MairAttribute::DEVICE_NGNRE)
-    .with_attribute(
-        1,
-        MairAttribute::normal(
-            NormalMemory::WriteBackNonTransientReadWriteAllocate,
-            NormalMemory::WriteBackNonTransientReadWriteAllocate,
-        ),
-    );
-
 initial_pagetable!(
     {
-        let mut idmap = [0; 512];
+        let mut idmap = [BlockDescriptor::INVALID; 512];
         // 1 GiB of device memory.
-        idmap[0] = DEVICE_ATTRIBUTES.bits();
+        idmap[0] = BlockDescriptor::new(0, DEVICE_ATTRIBUTES);
         // 1 GiB of normal memory.
-        idmap[1] = MEMORY_ATTRIBUTES.bits() | 0x40000000;
+        idmap[1] = BlockDescriptor::new(0x4000_0000, MEMORY_ATTRIBUTES);
         // Another 1 GiB of device memory starting at 256 GiB.
-        idmap[256] = DEVICE_ATTRIBUTES.bits() | 0x4000000000;
+        idmap[256] = BlockDescriptor::new(0x40_0000_0000, DEVICE_ATTRIBUTES);
         InitialPagetable(idmap)
     },
-    MAIR.0
+    MAIR.mair()
 );
 
 exception_handlers!(Exceptions);
@@ -81,15 +69,12 @@ fn main(arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> ! {
     )
     .unwrap();
 
-    system_off::<Hvc>().unwrap();
-    panic!("system_off returned");
+    shutdown();
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    system_reset::<Hvc>().unwrap();
-    #[allow(clippy::empty_loop)]
-    loop {}
+    reboot();
 }
 
 struct Exceptions;
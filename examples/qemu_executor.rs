@@ -0,0 +1,100 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Example of a minimal single-core async executor on QEMU's virt board, which parks the core
+//! while no future is ready to make progress.
+
+#![no_std]
+#![no_main]
+
+use aarch64_rt::{
+    ExceptionHandlers, entry, exception_handlers, initial_pagetable,
+    park::Parker,
+    platform::qemu_virt,
+    power::{reboot, shutdown},
+};
+use arm_pl011_uart::{PL011Registers, Uart, UniqueMmioPointer};
+use core::{
+    fmt::Write,
+    panic::PanicInfo,
+    pin::{Pin, pin},
+    ptr::NonNull,
+    task::{Context, Poll},
+};
+
+/// Base address of the first PL011 UART.
+const PL011_BASE_ADDRESS: *mut PL011Registers = qemu_virt::PL011_BASE_ADDRESS as _;
+
+/// Parks the core while the executor has nothing to do.
+static PARKER: Parker = Parker::new();
+
+initial_pagetable!(qemu_virt::INITIAL_PAGETABLE);
+
+exception_handlers!(Exceptions);
+
+entry!(main);
+fn main(_arg0: u64, _arg1: u64, _arg2: u64, _arg3: u64) -> ! {
+    // SAFETY: The PL011 base address is mapped by the initial identity mapping, and this is the
+    // only place we create something referring to it.
+    let mut uart =
+        Uart::new(unsafe { UniqueMmioPointer::new(NonNull::new(PL011_BASE_ADDRESS).unwrap()) });
+
+    let total = block_on(async {
+        let mut total = 0;
+        for i in 0..5 {
+            yield_now().await;
+            total += i;
+        }
+        total
+    });
+    writeln!(uart, "total = {total}").unwrap();
+
+    shutdown();
+}
+
+/// Runs the given future to completion, parking the core whenever it is pending.
+///
+/// Wakers may be called from interrupt handlers or other cores to unpark it.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = PARKER.waker();
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        PARKER.park();
+    }
+}
+
+/// Returns a future which is pending the first time it is polled, after waking itself so that the
+/// executor polls it again.
+fn yield_now() -> impl Future<Output = ()> {
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    YieldNow(false)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    reboot();
+}
+
+struct Exceptions;
+
+impl ExceptionHandlers for Exceptions {}
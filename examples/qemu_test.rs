@@ -0,0 +1,239 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Integration test to run on QEMU's virt board at EL1, EL2 or EL3.
+//!
+//! This checks the boot arguments which reached `main`, applies the image's relocations, takes and
+//! returns from a synchronous exception, and starts a secondary core with PSCI, checking that values
+//! are passed through correctly at each step. It prints `PASS` and exits via semihosting with
+//! status 0 if everything worked, or prints `FAIL` and the panic message and exits with status 1
+//! otherwise. `scripts/qemu_tests.sh` builds and runs it at each exception level, with semihosting
+//! enabled.
+
+#![no_std]
+#![no_main]
+
+use aarch64_rt::{
+    ExceptionHandlers, RegisterStateRef, Stack, entry, exception_handlers, initial_pagetable,
+    platform::qemu_virt,
+    power::{power_off_core, shutdown},
+    psci::AutoConduit,
+    relocate::{has_relocations, relocate_image},
+    start_core,
+};
+use arm_pl011_uart::{PL011Registers, Uart, UniqueMmioPointer};
+use core::{
+    arch::asm,
+    fmt::Write,
+    hint::spin_loop,
+    panic::PanicInfo,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Base address of the first PL011 UART.
+const PL011_BASE_ADDRESS: *mut PL011Registers = qemu_virt::PL011_BASE_ADDRESS as _;
+
+/// The address which `qemu.ld` links the image at, and QEMU loads it at.
+const IMAGE_BASE: usize = 0x4008_0000;
+
+/// Where QEMU puts the device tree for an image which it doesn't boot as a Linux kernel.
+const DTB_ADDRESS: u64 = qemu_virt::RAM_BASE as u64;
+/// The magic number at the start of a flattened device tree, which is stored big-endian.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// The semihosting operation number to exit the application.
+const SYS_EXIT: u32 = 0x18;
+/// The semihosting exit reason for a normal application exit.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// The MPIDR of the secondary core to start.
+const SECONDARY_MPIDR: u64 = 1;
+
+/// The immediate of the `brk` instruction used to test exception handling.
+const BRK_IMMEDIATE: u64 = 0x1234;
+/// The value passed to the exception handler in x0.
+const EXCEPTION_REQUEST: u64 = 0x5eed_0001;
+/// The value which the exception handler returns in x1.
+const EXCEPTION_RESPONSE: u64 = 0x5eed_0002;
+/// The value passed to the secondary core's entry closure.
+const SECONDARY_ARGUMENT: u64 = 0x5eed_0003;
+
+/// The number of `brk` exceptions handled.
+static BREAKPOINTS: AtomicUsize = AtomicUsize::new(0);
+/// The value which the secondary core received, or 0 if it hasn't run yet.
+static SECONDARY_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+static mut SECONDARY_STACK: Stack<4> = Stack::new();
+
+/// A function pointer in a static, which needs a relocation in a position-independent image.
+static RELOCATED_FUNCTION: fn() -> u64 = relocated_function;
+
+initial_pagetable!(qemu_virt::INITIAL_PAGETABLE);
+
+exception_handlers!(Exceptions);
+
+entry!(main);
+fn main(arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> ! {
+    // SAFETY: QEMU loads the image at the address it is linked at, and nothing has used a
+    // relocated address yet.
+    unsafe { relocate_image(IMAGE_BASE, IMAGE_BASE) };
+
+    let mut uart = uart();
+    writeln!(uart, "main({arg0:#x}, {arg1:#x}, {arg2:#x}, {arg3:#x})").unwrap();
+    writeln!(uart, "current EL: {}", current_el()).unwrap();
+    writeln!(uart, "relocations: {}", has_relocations()).unwrap();
+
+    test_boot_args([arg0, arg1, arg2, arg3]);
+    writeln!(uart, "boot arguments OK").unwrap();
+
+    assert_eq!(RELOCATED_FUNCTION(), 42);
+    writeln!(uart, "relocation OK").unwrap();
+
+    test_exception();
+    writeln!(uart, "exception OK").unwrap();
+
+    test_secondary_core();
+    writeln!(uart, "secondary core OK").unwrap();
+
+    writeln!(uart, "PASS").unwrap();
+    semihosting_exit(0);
+    shutdown();
+}
+
+fn relocated_function() -> u64 {
+    42
+}
+
+/// Checks that the registers which QEMU set on entry reached `main` unchanged.
+///
+/// QEMU puts the device tree at the start of RAM, and passes its address in x0 only to images which
+/// it boots as Linux kernels, so for this ELF image x0 may be either that or 0. It leaves x1-x3
+/// zeroed.
+fn test_boot_args(args: [u64; 4]) {
+    // SAFETY: The start of RAM is mapped by the initial pagetable, and QEMU put the device tree
+    // there, which nothing has overwritten.
+    let magic = unsafe { (DTB_ADDRESS as *const u32).read_volatile() };
+    assert_eq!(
+        u32::from_be(magic),
+        FDT_MAGIC,
+        "No device tree at {DTB_ADDRESS:#x}"
+    );
+    assert!(
+        args[0] == DTB_ADDRESS || args[0] == 0,
+        "Unexpected x0 {:#x}",
+        args[0]
+    );
+    assert_eq!(args[1..], [0; 3]);
+}
+
+/// Takes a breakpoint exception, and checks that the handler saw and could change the registers.
+fn test_exception() {
+    let response: u64;
+    // SAFETY: The exception handler skips the `brk` instruction and only changes x1.
+    unsafe {
+        asm!(
+            "brk #{immediate}",
+            immediate = const BRK_IMMEDIATE,
+            in("x0") EXCEPTION_REQUEST,
+            inout("x1") 0u64 => response,
+        );
+    }
+    assert_eq!(BREAKPOINTS.load(Ordering::Acquire), 1);
+    assert_eq!(response, EXCEPTION_RESPONSE);
+}
+
+/// Starts the secondary core, and waits for it to report the argument it was passed.
+fn test_secondary_core() {
+    let argument = SECONDARY_ARGUMENT;
+    // SAFETY: The stack is only used by the secondary core, which is only started once.
+    unsafe {
+        start_core::<AutoConduit, _, _>(SECONDARY_MPIDR, &raw mut SECONDARY_STACK, move || {
+            SECONDARY_RECEIVED.store(argument, Ordering::Release);
+            power_off_core();
+        })
+        .expect("Failed to start secondary core");
+    }
+    while SECONDARY_RECEIVED.load(Ordering::Acquire) == 0 {
+        spin_loop();
+    }
+    assert_eq!(
+        SECONDARY_RECEIVED.load(Ordering::Acquire),
+        SECONDARY_ARGUMENT
+    );
+}
+
+/// Returns the current exception level.
+fn current_el() -> u64 {
+    let current_el: u64;
+    // SAFETY: Reading CurrentEL is always safe.
+    unsafe {
+        asm!("mrs {}, CurrentEL", out(reg) current_el, options(nomem, nostack, preserves_flags));
+    }
+    (current_el >> 2) & 0b11
+}
+
+/// Returns the ESR for the current exception level.
+fn read_esr() -> u64 {
+    let esr: u64;
+    // SAFETY: Reading the ESR for the current exception level is always safe.
+    unsafe {
+        match current_el() {
+            1 => asm!("mrs {}, esr_el1", out(reg) esr, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mrs {}, esr_el2", out(reg) esr, options(nomem, nostack, preserves_flags)),
+            _ => asm!("mrs {}, esr_el3", out(reg) esr, options(nomem, nostack, preserves_flags)),
+        }
+    }
+    esr
+}
+
+fn uart() -> Uart<'static> {
+    // SAFETY: The PL011 base address is mapped by the initial identity mapping. The test only
+    // writes to it from one core at a time.
+    Uart::new(unsafe { UniqueMmioPointer::new(NonNull::new(PL011_BASE_ADDRESS).unwrap()) })
+}
+
+/// Exits QEMU via semihosting with the given status.
+fn semihosting_exit(status: u64) {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status];
+    // SAFETY: The semihosting exit call only reads the parameter block we pass, and the test script
+    // always enables semihosting.
+    unsafe {
+        asm!(
+            "hlt #0xf000",
+            inout("w0") SYS_EXIT => _,
+            in("x1") &block,
+            options(nostack, readonly),
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let _ = writeln!(uart(), "FAIL: {info}");
+    semihosting_exit(1);
+    shutdown();
+}
+
+struct Exceptions;
+
+impl ExceptionHandlers for Exceptions {
+    extern "C" fn sync_current(mut register_state: RegisterStateRef) {
+        let esr = read_esr();
+        // Exception class for a BRK instruction, with its immediate in the ISS.
+        assert_eq!(
+            (esr >> 26) & 0x3f,
+            0x3c,
+            "Unexpected exception with ESR {esr:#x}"
+        );
+        assert_eq!(esr & 0xffff, BRK_IMMEDIATE);
+        assert_eq!(register_state.registers[0], EXCEPTION_REQUEST);
+        BREAKPOINTS.fetch_add(1, Ordering::AcqRel);
+        // SAFETY: The test expects x1 to change, and we skip the `brk` instruction so it isn't
+        // taken again.
+        let register_state = unsafe { register_state.get_mut() };
+        register_state.registers[1] = EXCEPTION_RESPONSE;
+        register_state.elr += 4;
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Attribute macros for the `aarch64-rt` crate.
+//!
+//! These are re-exported by `aarch64-rt` with its `macros` feature, so shouldn't be depended on
+//! directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    Error, Expr, ItemFn, ReturnType, Token, parse::Parser, parse_macro_input,
+    punctuated::Punctuated,
+};
+
+/// Registers the function as the handler for the interrupt with the given INTID, to be called by
+/// `aarch64_rt::gic::dispatch_irq`.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::irq;
+///
+/// #[irq(intid = 30)]
+/// fn timer() {
+///     // ...
+/// }
+/// ```
+///
+/// The function must take no arguments and return `()`. It may still be called directly.
+#[proc_macro_attribute]
+pub fn irq(args: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    match irq_impl(args.into(), item) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.into_compile_error().into(),
+    }
+}
+
+fn irq_impl(args: proc_macro2::TokenStream, item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let args = Punctuated::<syn::MetaNameValue, Token![,]>::parse_terminated.parse2(args)?;
+    let mut intid: Option<Expr> = None;
+    for arg in args {
+        if arg.path.is_ident("intid") {
+            if intid.is_some() {
+                return Err(Error::new_spanned(arg, "duplicate `intid`"));
+            }
+            intid = Some(arg.value);
+        } else {
+            return Err(Error::new_spanned(
+                arg.path,
+                "unknown argument, expected `intid`",
+            ));
+        }
+    }
+    let Some(intid) = intid else {
+        return Err(Error::new(
+            Span::call_site(),
+            "missing INTID, e.g. `#[irq(intid = 30)]`",
+        ));
+    };
+
+    let signature = &item.sig;
+    if !signature.inputs.is_empty()
+        || !matches!(signature.output, ReturnType::Default)
+        || signature.asyncness.is_some()
+        || signature.unsafety.is_some()
+        || !signature.generics.params.is_empty()
+    {
+        return Err(Error::new_spanned(
+            signature,
+            "IRQ handlers must have the signature `fn()`",
+        ));
+    }
+    let name = &signature.ident;
+
+    Ok(quote! {
+        #item
+        ::aarch64_rt::irq_handler!(#intid, #name);
+    })
+}
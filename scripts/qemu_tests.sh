@@ -0,0 +1,68 @@
+#!/bin/bash
+# Copyright 2025 The aarch64-rt Authors.
+# This project is dual-licensed under Apache 2.0 and MIT terms.
+# See LICENSE-APACHE and LICENSE-MIT for details.
+
+# Builds the `qemu_test` example for each exception level and runs it on QEMU's virt board, checking
+# that it reaches `PASS` and exits via semihosting with status 0. The test exits with status 1 if it
+# panics.
+#
+# QEMU starts an ELF image at the highest exception level the board implements, so EL2 and EL3 are
+# selected with the `virtualization` and `secure` machine options. Both of these also make QEMU's
+# PSCI implementation use SMC, which the runtime uses by default above EL1.
+
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+
+QEMU=${QEMU:-qemu-system-aarch64}
+TIMEOUT=${TIMEOUT:-60}
+PIE_RUSTFLAGS="-C relocation-model=pie -C link-arg=-pie -C link-arg=-znotext"
+
+failures=0
+
+# Runs a single test case.
+#
+# Arguments: name, expected exception level, QEMU machine options, cargo features, RUSTFLAGS.
+run_test() {
+	local name=$1 el=$2 machine=$3 features=$4 rustflags=$5
+	local target_dir=target/qemu-tests/$name
+	local image=$target_dir/aarch64-unknown-none/debug/examples/qemu_test
+
+	echo "=== $name"
+	RUSTFLAGS=$rustflags cargo build --quiet --example qemu_test --target-dir "$target_dir" \
+		${features:+--features "$features"}
+
+	local output status=0
+	output=$(timeout "$TIMEOUT" "$QEMU" -machine "$machine" -cpu max -smp 2 -m 1G \
+		-nographic -no-reboot -semihosting -kernel "$image" 2>&1) || status=$?
+	echo "$output"
+
+	if [[ $status -eq 124 ]]; then
+		echo "--- $name FAILED: timed out"
+		failures=$((failures + 1))
+	elif [[ $status -ne 0 ]]; then
+		echo "--- $name FAILED: QEMU exited with status $status"
+		failures=$((failures + 1))
+	elif ! grep -q "^current EL: $el" <<<"$output"; then
+		echo "--- $name FAILED: not running at EL$el"
+		failures=$((failures + 1))
+	elif ! grep -q "^PASS" <<<"$output"; then
+		echo "--- $name FAILED: didn't pass"
+		failures=$((failures + 1))
+	else
+		echo "--- $name passed"
+	fi
+}
+
+run_test el1 1 virt el1 ""
+run_test el2 2 virt,virtualization=on el2 ""
+run_test el3 3 virt,secure=on,virtualization=on el3 ""
+run_test any-el 2 virt,virtualization=on "" ""
+run_test el1-pie 1 virt el1 "$PIE_RUSTFLAGS"
+
+if [[ $failures -ne 0 ]]; then
+	echo "$failures test(s) failed"
+	exit 1
+fi
+echo "All tests passed"
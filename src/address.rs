@@ -0,0 +1,119 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Conversion between physical and virtual addresses.
+//!
+//! The runtime assumes that physical memory is mapped linearly, with every virtual address being
+//! its physical address plus a constant offset. This offset is recorded by the entry point from the
+//! difference between the address the image was linked at and the address it is running from
+//! before Rust code is run, so that an image linked in the high half of the address space and
+//! mapped there with [`initial_pagetable!`](crate::initial_pagetable) can convert between the two.
+//! For an identity-mapped image the offset is zero, and [`relocate_image`] also resets it to zero,
+//! as a relocated image runs at the address it was loaded at.
+//!
+//! [`relocate_image`]: crate::relocate::relocate_image
+
+use core::{
+    arch::asm,
+    fmt::{self, Debug, Formatter},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The offset to add to a physical address to get the corresponding virtual address.
+static VIRT_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// A physical address.
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(transparent)]
+pub struct PhysAddr(pub usize);
+
+impl Debug for PhysAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "PhysAddr({:#x})", self.0)
+    }
+}
+
+/// A virtual address.
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(transparent)]
+pub struct VirtAddr(pub usize);
+
+impl Debug for VirtAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "VirtAddr({:#x})", self.0)
+    }
+}
+
+impl VirtAddr {
+    /// Returns the virtual address of the given pointer.
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        Self(ptr as usize)
+    }
+
+    /// Returns the virtual address as a pointer.
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    /// Returns the virtual address as a mutable pointer.
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+/// Returns the offset which is added to a physical address to get the corresponding virtual
+/// address.
+pub fn virt_offset() -> usize {
+    VIRT_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Sets the offset which is added to a physical address to get the corresponding virtual address.
+///
+/// This is only needed if the application changes the mapping of physical memory after boot.
+///
+/// # Safety
+///
+/// Physical memory must be mapped linearly with the given offset, as code (such as device
+/// drivers) may access memory at the virtual addresses returned by [`phys_to_virt`].
+pub unsafe fn set_virt_offset(offset: usize) {
+    VIRT_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Returns the physical address corresponding to the given virtual address.
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    PhysAddr(addr.0.wrapping_sub(virt_offset()))
+}
+
+/// Returns the virtual address corresponding to the given physical address.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr(addr.0.wrapping_add(virt_offset()))
+}
+
+/// Records the offset between the address the image was linked at and the address it is running
+/// from.
+///
+/// This must be called while running from the address the image was loaded at, i.e. before jumping
+/// to the linked address if it is different.
+pub(crate) fn record_virt_offset() {
+    let load_address: usize;
+    let link_address: usize;
+    // SAFETY: This only computes addresses, and doesn't access any memory other than the literal
+    // embedded in the code.
+    unsafe {
+        asm!(
+            "adrp {load}, text_begin",
+            "add {load}, {load}, :lo12:text_begin",
+            "ldr {link}, 0f",
+            "b 1f",
+            ".balign 8",
+            "0:",
+            ".quad text_begin",
+            "1:",
+            load = out(reg) load_address,
+            link = out(reg) link_address,
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    VIRT_OFFSET.store(link_address.wrapping_sub(load_address), Ordering::Relaxed);
+}
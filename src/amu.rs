@@ -0,0 +1,70 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Access to the architected counters of the Activity Monitors Unit (AMU).
+//!
+//! The counters must be enabled on each core with [`enable`] before they count. With the `amu`
+//! feature this is done automatically for the boot core and for secondary cores started by this
+//! crate.
+
+use crate::{
+    init_steps::IdField,
+    sysreg::{current_el, read_sysreg, write_sysreg},
+};
+
+/// CPTR_EL2.TAM and CPTR_EL3.TAM: traps accesses to the AMU registers.
+const CPTR_TAM: u64 = 1 << 30;
+/// The architected counters in counter group 0.
+const ARCHITECTED_COUNTERS: u64 = 0b1111;
+
+/// Returns whether the Activity Monitors Unit is implemented.
+pub fn amu_implemented() -> bool {
+    IdField::AMU.is_implemented()
+}
+
+/// Enables the architected AMU counters on the current core, if the AMU is implemented.
+///
+/// When running at EL2 or EL3 this also stops accesses to the AMU registers from being trapped to
+/// the current exception level. When running at EL1, any higher exception levels must already
+/// allow access.
+pub fn enable() {
+    if !amu_implemented() {
+        return;
+    }
+    // SAFETY: Allowing access to the AMU and enabling its counters doesn't affect memory safety.
+    unsafe {
+        match current_el() {
+            3 => write_sysreg!("cptr_el3", read_sysreg!("cptr_el3") & !CPTR_TAM),
+            2 => write_sysreg!("cptr_el2", read_sysreg!("cptr_el2") & !CPTR_TAM),
+            _ => {}
+        }
+        core::arch::asm!("isb", options(nomem, nostack, preserves_flags));
+        // AMCNTENSET0_EL0
+        write_sysreg!("s3_3_c13_c2_5", ARCHITECTED_COUNTERS);
+    }
+}
+
+/// Returns the number of processor cycles counted, at the frequency of the core's clock.
+pub fn core_cycles() -> u64 {
+    // SAFETY: Reading AMEVCNTR00_EL0 doesn't affect memory safety.
+    unsafe { read_sysreg!("s3_3_c13_c4_0") }
+}
+
+/// Returns the number of cycles counted at the constant frequency of the system counter.
+pub fn constant_frequency_cycles() -> u64 {
+    // SAFETY: Reading AMEVCNTR01_EL0 doesn't affect memory safety.
+    unsafe { read_sysreg!("s3_3_c13_c4_1") }
+}
+
+/// Returns the number of instructions retired.
+pub fn instructions_retired() -> u64 {
+    // SAFETY: Reading AMEVCNTR02_EL0 doesn't affect memory safety.
+    unsafe { read_sysreg!("s3_3_c13_c4_2") }
+}
+
+/// Returns the number of cycles in which the core was stalled waiting for a memory access.
+pub fn memory_stall_cycles() -> u64 {
+    // SAFETY: Reading AMEVCNTR03_EL0 doesn't affect memory safety.
+    unsafe { read_sysreg!("s3_3_c13_c4_3") }
+}
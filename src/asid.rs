@@ -0,0 +1,288 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Address space identifier (ASID) allocation for switching between EL0 address spaces at EL1.
+//!
+//! Each address space has an [`Asid`], which is assigned an ASID by an [`AsidAllocator`] the first
+//! time [`AsidAllocator::switch_ttbr0`] switches to it. ASIDs are tagged with a generation, and
+//! once all are in use a new generation is started: every ASID is released except those which
+//! cores are currently running with, and each core invalidates its TLB before it next switches.
+//! This avoids invalidating the TLB on every switch, while never letting two address spaces share
+//! TLB entries.
+//!
+//! ```rust,ignore
+//! use aarch64_rt::asid::{Asid, AsidAllocator};
+//!
+//! static ASIDS: AsidAllocator = AsidAllocator::new();
+//!
+//! struct Task {
+//!     asid: Asid,
+//!     table: PhysAddr,
+//! }
+//!
+//! fn switch_to(task: &Task) {
+//!     // SAFETY: The task's tables map the kernel in the same way as every other task's.
+//!     unsafe {
+//!         ASIDS.switch_ttbr0(task.table, &task.asid);
+//!     }
+//! }
+//! ```
+//!
+//! Each core which uses an allocator is given its own slot in it the first time it does so, so at
+//! most 256 cores are supported.
+
+use crate::{
+    address::PhysAddr,
+    sysreg::{MAX_CORES, core_index, read_sysreg, write_sysreg},
+};
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// The number of bits of the combined ID used for the ASID. The generation is in the bits above.
+const ASID_SHIFT: u32 = 16;
+/// The ASID part of a combined ID.
+const ASID_MASK: u64 = (1 << ASID_SHIFT) - 1;
+/// The maximum number of ASIDs, with 16-bit ASIDs.
+const MAX_ASIDS: usize = 1 << ASID_SHIFT;
+/// TCR_EL1.AS: 16-bit ASIDs are used.
+const TCR_AS: u64 = 1 << 36;
+/// The position of the ASID in TTBR0_EL1.
+const TTBR_ASID_SHIFT: u32 = 48;
+
+/// The ASID assigned to an address space, if any, along with its generation.
+#[derive(Debug, Default)]
+pub struct Asid {
+    id: AtomicU64,
+}
+
+impl Asid {
+    /// Creates a new address space identifier, which hasn't been assigned an ASID yet.
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the ASID currently assigned to the address space, if any.
+    ///
+    /// This may change when the address space is next switched to, if a new generation has started
+    /// since.
+    pub fn get(&self) -> Option<u16> {
+        let id = self.id.load(Ordering::Relaxed);
+        (id != 0).then_some((id & ASID_MASK) as u16)
+    }
+}
+
+/// State of an `AsidAllocator` which is only accessed with its lock held.
+struct LockedState {
+    /// Which ASIDs are in use in the current generation.
+    used: [u64; MAX_ASIDS / 64],
+    /// The next ASID to try allocating.
+    next: usize,
+    /// The ID each core was running with when the last new generation was started, or has since
+    /// been reassigned to in the new generation.
+    reserved: [u64; MAX_CORES],
+}
+
+/// Allocates ASIDs to address spaces, and switches TTBR0_EL1 between them.
+///
+/// ASID 0 is never allocated, so may be used for a kernel-only address space.
+pub struct AsidAllocator {
+    lock: AtomicBool,
+    generation: AtomicU64,
+    /// The ID each core is currently running with, or 0 if a new generation has been started since
+    /// it last switched.
+    active: [AtomicU64; MAX_CORES],
+    /// Whether each core needs to invalidate its TLB before it next switches.
+    flush_pending: [AtomicBool; MAX_CORES],
+    state: UnsafeCell<LockedState>,
+}
+
+// SAFETY: `state` is only accessed with `lock` held.
+unsafe impl Sync for AsidAllocator {}
+
+impl AsidAllocator {
+    /// Creates a new allocator with no ASIDs in use.
+    pub const fn new() -> Self {
+        Self {
+            lock: AtomicBool::new(false),
+            generation: AtomicU64::new(1 << ASID_SHIFT),
+            active: [const { AtomicU64::new(0) }; MAX_CORES],
+            flush_pending: [const { AtomicBool::new(false) }; MAX_CORES],
+            state: UnsafeCell::new(LockedState {
+                used: [0; MAX_ASIDS / 64],
+                next: 1,
+                reserved: [0; MAX_CORES],
+            }),
+        }
+    }
+
+    /// Switches TTBR0_EL1 to the given root translation table for the given address space,
+    /// assigning it an ASID if it doesn't already have one in the current generation.
+    ///
+    /// This must be called at EL1. The TLB is only invalidated when needed after a new generation
+    /// has started.
+    ///
+    /// # Safety
+    ///
+    /// `table` must be the physical address of a valid root translation table for the
+    /// configuration in TCR_EL1, which must not unmap any memory which the program is using. The
+    /// same `Asid` must always be used with the same tables, and must not be used with any other
+    /// `AsidAllocator`.
+    pub unsafe fn switch_ttbr0(&self, table: PhysAddr, asid: &Asid) {
+        let core = core_index();
+        let mut id = asid.id.load(Ordering::Relaxed);
+        let old_active = self.active[core].load(Ordering::Relaxed);
+        // The fast path: if the address space already has an ASID in the current generation, and
+        // no new generation has started since this core last switched, just record it as active.
+        // If a new generation is started concurrently then it will have cleared `active`, so the
+        // compare and exchange will fail.
+        if old_active == 0
+            || !self.is_current_generation(id)
+            || self.active[core]
+                .compare_exchange(old_active, id, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.lock();
+            // SAFETY: We hold the lock.
+            let state = unsafe { &mut *self.state.get() };
+            id = asid.id.load(Ordering::Relaxed);
+            if !self.is_current_generation(id) {
+                id = self.new_id(state, id);
+                asid.id.store(id, Ordering::Relaxed);
+            }
+            if self.flush_pending[core].swap(false, Ordering::Relaxed) {
+                // SAFETY: Invalidating TLB entries doesn't affect memory safety.
+                unsafe {
+                    asm!("tlbi vmalle1", "dsb nsh", options(nostack, preserves_flags));
+                }
+            }
+            self.active[core].store(id, Ordering::Relaxed);
+            self.unlock();
+        }
+        // SAFETY: Our caller promised that the table is valid and maps everything the program is
+        // using, and the ASID isn't in use by any other address space.
+        unsafe {
+            write_sysreg!(
+                "ttbr0_el1",
+                table.0 as u64 | (id & ASID_MASK) << TTBR_ASID_SHIFT
+            );
+            asm!("isb", options(nostack, preserves_flags));
+        }
+    }
+
+    fn is_current_generation(&self, id: u64) -> bool {
+        id != 0 && id >> ASID_SHIFT == self.generation.load(Ordering::Relaxed) >> ASID_SHIFT
+    }
+
+    /// Returns a new ID in the current generation for an address space which previously had the
+    /// given ID, starting a new generation if necessary.
+    fn new_id(&self, state: &mut LockedState, old_id: u64) -> u64 {
+        let generation = self.generation.load(Ordering::Relaxed);
+        if old_id != 0 {
+            let asid = old_id & ASID_MASK;
+            let new_id = generation | asid;
+            // If a core was running with the old ID when the new generation started then its ASID
+            // was kept for it.
+            let mut reserved = false;
+            for entry in &mut state.reserved {
+                if *entry == old_id {
+                    *entry = new_id;
+                    reserved = true;
+                }
+            }
+            if reserved || !state.take(asid as usize) {
+                return new_id;
+            }
+        }
+        let asid = match state.allocate(asid_count()) {
+            Some(asid) => asid,
+            None => {
+                self.new_generation(state);
+                state
+                    .allocate(asid_count())
+                    .expect("No ASIDs free after starting new generation")
+            }
+        };
+        self.generation.load(Ordering::Relaxed) | asid as u64
+    }
+
+    /// Starts a new generation, releasing every ASID except those which cores are currently
+    /// running with.
+    fn new_generation(&self, state: &mut LockedState) {
+        state.used.fill(0);
+        state.next = 1;
+        for (core, active) in self.active.iter().enumerate() {
+            let mut id = active.swap(0, Ordering::Relaxed);
+            // If the core hasn't switched since the last new generation, keep what it had then.
+            if id == 0 {
+                id = state.reserved[core];
+            }
+            if id != 0 {
+                state.take(id as usize & ASID_MASK as usize);
+            }
+            state.reserved[core] = id;
+        }
+        for flush_pending in &self.flush_pending {
+            flush_pending.store(true, Ordering::Relaxed);
+        }
+        self.generation
+            .fetch_add(1 << ASID_SHIFT, Ordering::Relaxed);
+    }
+
+    fn lock(&self) {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+impl Default for AsidAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockedState {
+    /// Marks the given ASID as used. Returns whether it was already in use.
+    fn take(&mut self, asid: usize) -> bool {
+        let bit = 1 << (asid % 64);
+        let already_used = self.used[asid / 64] & bit != 0;
+        self.used[asid / 64] |= bit;
+        already_used
+    }
+
+    /// Allocates an unused ASID below `count`, if there is one.
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        let asid = (self.next..count)
+            .chain(1..self.next.min(count))
+            .find(|&asid| self.used[asid / 64] & (1 << (asid % 64)) == 0)?;
+        self.take(asid);
+        self.next = asid + 1;
+        Some(asid)
+    }
+}
+
+/// Returns the number of ASIDs supported with the current configuration.
+fn asid_count() -> usize {
+    // SAFETY: Reading ID_AA64MMFR0_EL1 and TCR_EL1 is always safe.
+    let (mmfr0, tcr) = unsafe { (read_sysreg!("id_aa64mmfr0_el1"), read_sysreg!("tcr_el1")) };
+    if (mmfr0 >> 4) & 0xf == 0b0010 && tcr & TCR_AS != 0 {
+        1 << 16
+    } else {
+        1 << 8
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Reporting of failures early in boot, before any console is available.
+//!
+//! When the runtime can't continue booting, such as when it is entered at the wrong exception
+//! level, takes an exception before the application's exception vector is set, or finds a
+//! relocation it can't apply, it records a [`BootFailure`] and stops the core in a `wfe` loop rather
+//! than running into undefined behaviour. The failure is recorded in two ways:
+//!
+//! - The [`BootError`] code is left in `x9` and a detail value in `x10`, for a debugger attached to
+//!   the core.
+//! - The two 64-bit words at the `__aarch64_rt_boot_failure` symbol, in the `.noinit` section, are
+//!   set to `0xb007fa11 << 32 | code` and the detail value. Their cache lines are cleaned to the
+//!   point of coherency, so they can be read from memory by a debugger or another agent. As the
+//!   section isn't zeroed, they survive a warm reset if the RAM is preserved, so the next boot can
+//!   report them with [`last_boot_failure`].
+//!
+//! [`BootFailure::decode`] turns the two words back into a [`BootFailure`].
+//!
+//! An early exception vector which reports [`BootError::EarlyException`] is installed by the entry
+//! point before the MMU is enabled, so a bad initial pagetable is reported rather than looping on
+//! an invalid vector. It is replaced by the runtime's own vector if the `exceptions` feature is
+//! enabled.
+
+use core::{
+    arch::{asm, global_asm, naked_asm},
+    fmt::{self, Display, Formatter},
+    mem::MaybeUninit,
+};
+
+/// The magic value in the upper 32 bits of the first word of a boot failure record.
+pub const BOOT_FAILURE_MAGIC: u32 = 0xb007_fa11;
+
+/// The reason the runtime stopped booting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum BootError {
+    /// The image was entered at an exception level other than the one it was built for. The
+    /// detail is the current exception level.
+    UnexpectedEl = 1,
+    /// The core doesn't support the 4 KiB translation granule which the initial pagetable uses.
+    /// The detail is the value of ID_AA64MMFR0_EL1.
+    GranuleUnsupported = 2,
+    /// An exception was taken before the exception vector was set, e.g. because the initial
+    /// pagetable doesn't map something which the entry point used. The detail is the value of
+    /// ESR_ELx.
+    EarlyException = 3,
+    /// The image has a dynamic relocation of a type which can't be applied. The detail is the
+    /// relocation type.
+    UnsupportedRelocation = 4,
+}
+
+impl BootError {
+    /// Returns the error with the given code, if any.
+    pub const fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(Self::UnexpectedEl),
+            2 => Some(Self::GranuleUnsupported),
+            3 => Some(Self::EarlyException),
+            4 => Some(Self::UnsupportedRelocation),
+            _ => None,
+        }
+    }
+
+    /// Returns the numeric code of the error.
+    pub const fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Display for BootError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedEl => write!(f, "Entered at unexpected exception level"),
+            Self::GranuleUnsupported => write!(f, "4 KiB translation granule not supported"),
+            Self::EarlyException => write!(f, "Exception before exception vector was set"),
+            Self::UnsupportedRelocation => write!(f, "Unsupported relocation type"),
+        }
+    }
+}
+
+/// A failure to boot, as recorded in the boot failure record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BootFailure {
+    /// The reason for the failure.
+    pub error: BootError,
+    /// A value giving more detail about the failure, as described for each [`BootError`].
+    pub detail: u64,
+}
+
+impl BootFailure {
+    /// Decodes the two words of a boot failure record, as read from the `__aarch64_rt_boot_failure`
+    /// symbol.
+    ///
+    /// Returns `None` if the first word doesn't have the magic value, or has an unknown error code.
+    pub const fn decode(record: [u64; 2]) -> Option<Self> {
+        if (record[0] >> 32) as u32 != BOOT_FAILURE_MAGIC {
+            return None;
+        }
+        match BootError::from_code(record[0] as u32) {
+            Some(error) => Some(Self {
+                error,
+                detail: record[1],
+            }),
+            None => None,
+        }
+    }
+}
+
+impl Display for BootFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({:#x})", self.error, self.detail)
+    }
+}
+
+/// The boot failure record, which isn't zeroed by the entry point.
+#[unsafe(export_name = "__aarch64_rt_boot_failure")]
+#[unsafe(link_section = ".noinit.boot_failure")]
+static mut BOOT_FAILURE: MaybeUninit<[u64; 2]> = MaybeUninit::uninit();
+
+/// Returns the boot failure recorded by a previous boot, if there is one and the record survived
+/// the reset.
+///
+/// The record isn't cleared automatically, so this will keep returning the same failure until
+/// [`clear_boot_failure`] is called.
+pub fn last_boot_failure() -> Option<BootFailure> {
+    // SAFETY: The record is only written by `boot_failed`, which never returns, so nothing else can
+    // be writing it. Any bit pattern is a valid `[u64; 2]`.
+    let record = unsafe { (&raw const BOOT_FAILURE).cast::<[u64; 2]>().read_volatile() };
+    BootFailure::decode(record)
+}
+
+/// Clears the boot failure record, so that [`last_boot_failure`] doesn't report the same failure on
+/// future boots.
+pub fn clear_boot_failure() {
+    // SAFETY: The record is only written by `boot_failed`, which never returns, so nothing else can
+    // be accessing it.
+    unsafe {
+        (&raw mut BOOT_FAILURE)
+            .cast::<[u64; 2]>()
+            .write_volatile([0; 2]);
+    }
+}
+
+/// Records the given boot failure and stops the current core in a `wfe` loop.
+///
+/// This doesn't need a stack or a console, so may be used in place of a panic in code which runs
+/// before the image is ready to handle one.
+pub fn boot_failed(error: BootError, detail: u64) -> ! {
+    // SAFETY: `__aarch64_rt_boot_failed` only writes the boot failure record, and never returns.
+    unsafe {
+        asm!(
+            "b {boot_failed}",
+            boot_failed = sym __aarch64_rt_boot_failed,
+            in("x9") error.code() as u64,
+            in("x10") detail,
+            options(noreturn),
+        )
+    }
+}
+
+/// Records a boot failure with the code in x9 and the detail in x10, and stops the current core in
+/// a `wfe` loop.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It may be branched to
+/// from assembly code without a stack, and never returns.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn __aarch64_rt_boot_failed() -> ! {
+    naked_asm!(
+        "adrp x11, {record}",
+        "add x11, x11, :lo12:{record}",
+        "movz x12, #({magic} >> 16), lsl #48",
+        "movk x12, #({magic} & 0xffff), lsl #32",
+        "orr x12, x12, x9",
+        "stp x12, x10, [x11]",
+        "dc civac, x11",
+        "dsb sy",
+        "0:",
+        "wfe",
+        "b 0b",
+        record = sym BOOT_FAILURE,
+        magic = const BOOT_FAILURE_MAGIC,
+    )
+}
+
+/// Points the vector base address register for the current exception level to the early exception
+/// vector, which reports [`BootError::EarlyException`] for any exception.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before any Rust code is run.
+///
+/// Clobbers x9 and x10.
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn set_early_exception_vector() {
+    naked_asm!(
+        "adrp x9, __aarch64_rt_early_vector_table",
+        "add x9, x9, :lo12:__aarch64_rt_early_vector_table",
+        "mrs x10, CurrentEL",
+        "ubfx x10, x10, #2, #2",
+        "cmp x10, #2",
+        "b.eq 2f",
+        "b.hi 3f",
+        "msr vbar_el1, x9",
+        "b 4f",
+        "2:",
+        "msr vbar_el2, x9",
+        "b 4f",
+        "3:",
+        "msr vbar_el3, x9",
+        "4:",
+        "isb",
+        "ret",
+    )
+}
+
+// The early exception vector table, with every entry reporting an early exception along with the
+// syndrome for the current exception level.
+global_asm!(
+    ".section .init.__aarch64_rt_early_vector_table, \"ax\"",
+    ".balign 0x800",
+    "__aarch64_rt_early_vector_table:",
+    ".rept 16",
+    ".balign 0x80",
+    "b __aarch64_rt_early_exception",
+    ".endr",
+    "__aarch64_rt_early_exception:",
+    "mrs x10, CurrentEL",
+    "ubfx x10, x10, #2, #2",
+    "cmp x10, #2",
+    "b.eq 2f",
+    "b.hi 3f",
+    "mrs x10, esr_el1",
+    "b 4f",
+    "2:",
+    "mrs x10, esr_el2",
+    "b 4f",
+    "3:",
+    "mrs x10, esr_el3",
+    "4:",
+    "mov x9, #{early_exception}",
+    "b __aarch64_rt_boot_failed",
+    early_exception = const BootError::EarlyException as u32,
+);
@@ -0,0 +1,165 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A buffer for console output written before the console is ready.
+//!
+//! If the registered [`Platform`](crate::platform::Platform) reports that its console isn't ready
+//! yet, with [`console_ready`](crate::platform::Platform::console_ready), anything written to
+//! [`Console`](crate::platform::Console) is kept in a static ring buffer of [`BOOTLOG_SIZE`] bytes
+//! instead. The buffer is flushed to the console by the next write once the console is ready, or
+//! explicitly with [`flush`], e.g. straight after initialising the UART:
+//!
+//! ```rust,ignore
+//! // Anything written to `Console` before this is kept in the boot log.
+//! init_uart();
+//! UART_READY.store(true, Ordering::Release);
+//! aarch64_rt::bootlog::flush();
+//! ```
+//!
+//! If more than [`BOOTLOG_SIZE`] bytes are written before the console is ready then the oldest are
+//! dropped, and a note of how many were dropped is written before the rest.
+
+use crate::{
+    platform::platform,
+    sysreg::{read_sysreg, write_sysreg},
+};
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    fmt::Write,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The size of the boot log buffer in bytes.
+pub const BOOTLOG_SIZE: usize = 4096;
+
+/// A ring buffer of bytes written before the console was ready.
+struct BootLog {
+    /// Whether some core is accessing `ring`.
+    locked: AtomicBool,
+    /// Whether there is anything in the buffer which hasn't been flushed.
+    pending: AtomicBool,
+    ring: UnsafeCell<Ring>,
+}
+
+// SAFETY: `ring` is only accessed while `locked` is held.
+unsafe impl Sync for BootLog {}
+
+struct Ring {
+    buffer: [u8; BOOTLOG_SIZE],
+    /// The total number of bytes written since the buffer was last flushed, including any which
+    /// have since been overwritten.
+    written: usize,
+}
+
+static BOOTLOG: BootLog = BootLog {
+    locked: AtomicBool::new(false),
+    pending: AtomicBool::new(false),
+    ring: UnsafeCell::new(Ring {
+        buffer: [0; BOOTLOG_SIZE],
+        written: 0,
+    }),
+};
+
+impl BootLog {
+    /// Calls `f` with exclusive access to the ring buffer.
+    ///
+    /// IRQs and FIQs are masked while the lock is held, so that an interrupt handler which writes
+    /// to the console can't spin forever waiting for the code it interrupted to release it.
+    fn with_ring<R>(&self, f: impl FnOnce(&mut Ring) -> R) -> R {
+        // SAFETY: Masking interrupts doesn't affect memory safety.
+        let daif = unsafe {
+            let daif = read_sysreg!("daif");
+            asm!("msr daifset, #3", options(nomem, nostack, preserves_flags));
+            daif
+        };
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // SAFETY: We hold the lock, so nothing else is accessing the ring buffer.
+        let result = f(unsafe { &mut *self.ring.get() });
+        self.locked.store(false, Ordering::Release);
+        // SAFETY: Restoring the previous interrupt masks doesn't affect memory safety.
+        unsafe {
+            write_sysreg!("daif", daif);
+        }
+        result
+    }
+}
+
+/// Appends the given string to the boot log.
+pub(crate) fn capture(s: &str) {
+    BOOTLOG.with_ring(|ring| {
+        for &byte in s.as_bytes() {
+            ring.buffer[ring.written % BOOTLOG_SIZE] = byte;
+            ring.written += 1;
+        }
+    });
+    BOOTLOG.pending.store(true, Ordering::Release);
+}
+
+/// Writes everything in the boot log to the platform's console, and empties it.
+///
+/// This writes to the console regardless of whether it reports that it is ready, so must only be
+/// called once it is.
+pub fn flush() {
+    if !BOOTLOG.pending.swap(false, Ordering::Acquire) {
+        return;
+    }
+    BOOTLOG.with_ring(|ring| {
+        let platform = platform();
+        let len = ring.written.min(BOOTLOG_SIZE);
+        let start = ring.written - len;
+        if start > 0 {
+            let mut note = NoteBuffer::new();
+            let _ = writeln!(note, "[{start} bytes of boot log dropped]");
+            platform.console_write(note.as_str());
+        }
+        // Put the oldest byte first.
+        ring.buffer.rotate_left(start % BOOTLOG_SIZE);
+        // The oldest bytes may have been dropped part way through a character, so skip any which
+        // aren't valid UTF-8.
+        for chunk in ring.buffer[..len].utf8_chunks() {
+            platform.console_write(chunk.valid());
+        }
+        ring.written = 0;
+    });
+}
+
+/// A small buffer to format the dropped bytes note into.
+struct NoteBuffer {
+    bytes: [u8; 64],
+    len: usize,
+}
+
+impl NoteBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // The buffer is only written by `write_str`, which only appends whole strings.
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Write for NoteBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.bytes
+            .get_mut(self.len..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,542 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Cache maintenance operations.
+
+use crate::sysreg::{SCTLR_C, read_sysreg, write_sysreg};
+use core::arch::{asm, naked_asm};
+
+/// Data cache clean to the point of unification is not required for instruction to data coherence,
+/// in CTR_EL0.
+const CTR_IDC: u64 = 1 << 28;
+/// Instruction cache invalidation to the point of unification is not required for data to
+/// instruction coherence, in CTR_EL0.
+const CTR_DIC: u64 = 1 << 29;
+
+/// The maximum number of cache levels described by CLIDR_EL1.
+pub const MAX_CACHE_LEVELS: usize = 7;
+
+/// InD bit in CSSELR_EL1, selecting the instruction cache rather than the data or unified cache.
+const CSSELR_IND: u64 = 1 << 0;
+/// CCIDX field of ID_AA64MMFR2_EL1, non-zero if CCSIDR_EL1 uses the 64-bit format.
+const ID_AA64MMFR2_CCIDX_SHIFT: u64 = 20;
+
+fn read_ctr() -> u64 {
+    // SAFETY: Reading CTR_EL0 is always safe.
+    unsafe { read_sysreg!("ctr_el0") }
+}
+
+/// Returns the size in bytes of the smallest data cache line in the system.
+fn dcache_line_size() -> usize {
+    // DminLine is the log2 of the number of words in the smallest data cache line.
+    4 << ((read_ctr() >> 16) & 0xf)
+}
+
+/// Returns the cache writeback granule in bytes: the largest block of memory which may be written
+/// back as a unit by any data cache in the system.
+///
+/// Buffers which a device writes to without going through the caches should be aligned to this,
+/// and a multiple of it in length, so that writing back a cache line for some other data can't
+/// overwrite what the device has written.
+pub fn writeback_granule() -> usize {
+    // CWG is the log2 of the number of words in the granule, or 0 if it isn't known, in which case
+    // the architectural maximum of 2 KiB must be assumed.
+    match (read_ctr() >> 24) & 0xf {
+        0 => 2048,
+        cwg => 4 << cwg,
+    }
+}
+
+/// Returns the size in bytes of the smallest instruction cache line in the system.
+fn icache_line_size() -> usize {
+    // IminLine is the log2 of the number of words in the smallest instruction cache line.
+    4 << (read_ctr() & 0xf)
+}
+
+/// Calls the given function with the address of each data cache line covering the given range,
+/// then waits for any cache maintenance it did to complete.
+fn for_each_dcache_line(start: *const u8, len: usize, f: impl Fn(usize)) {
+    let line_size = dcache_line_size();
+    let end = start as usize + len;
+    let mut line = start as usize & !(line_size - 1);
+    while line < end {
+        f(line);
+        line += line_size;
+    }
+    // SAFETY: A synchronisation barrier is always safe.
+    unsafe {
+        asm!("dsb sy", options(nostack, preserves_flags));
+    }
+}
+
+/// Cleans the data cache lines covering the given range to the point of coherency, and waits for
+/// that to complete.
+///
+/// This makes data written by the current core visible to observers which access memory without
+/// going through the cache, such as a core which is running with its MMU and caches off.
+pub fn clean_dcache_range(start: *const u8, len: usize) {
+    for_each_dcache_line(start, len, |line| {
+        // SAFETY: Cleaning a cache line doesn't affect memory safety.
+        unsafe {
+            asm!("dc cvac, {line}", line = in(reg) line, options(nostack, preserves_flags));
+        }
+    });
+}
+
+/// Cleans and invalidates the data cache lines covering the given range to the point of coherency,
+/// and waits for that to complete.
+///
+/// As well as cleaning, this ensures that the next read of the range by the current core will fetch
+/// it from memory, so that it sees data written by observers which don't go through the cache.
+pub fn clean_invalidate_dcache_range(start: *const u8, len: usize) {
+    for_each_dcache_line(start, len, |line| {
+        // SAFETY: Cleaning and invalidating a cache line doesn't affect memory safety, as any dirty
+        // data is written back first.
+        unsafe {
+            asm!("dc civac, {line}", line = in(reg) line, options(nostack, preserves_flags));
+        }
+    });
+}
+
+/// Invalidates the data cache lines covering the given range to the point of coherency, without
+/// cleaning them, and waits for that to complete.
+///
+/// This discards any stale copy of data which was written to memory with the caches off.
+///
+/// # Safety
+///
+/// Any dirty data in the cache lines covering the range is lost, including data outside the range
+/// if it isn't aligned to cache lines.
+#[cfg(feature = "initial-pagetable")]
+pub(crate) unsafe fn invalidate_dcache_range(start: *const u8, len: usize) {
+    for_each_dcache_line(start, len, |line| {
+        // SAFETY: The caller guarantees that it is fine to discard any dirty data in the line.
+        unsafe {
+            asm!("dc ivac, {line}", line = in(reg) line, options(nostack, preserves_flags));
+        }
+    });
+}
+
+/// Makes instructions written to the given range by the current core visible to instruction fetches
+/// by all cores in the inner shareable domain.
+///
+/// This must be called after writing code to memory (e.g. when loading a module or patching
+/// instructions) and before executing it. Other cores which may have already fetched from the range
+/// must also execute an `isb` before executing it.
+pub fn sync_icache(start: *const u8, len: usize) {
+    let ctr = read_ctr();
+    let end = start as usize + len;
+
+    if ctr & CTR_IDC == 0 {
+        let line_size = dcache_line_size();
+        let mut line = start as usize & !(line_size - 1);
+        while line < end {
+            // SAFETY: Cleaning a cache line doesn't affect memory safety.
+            unsafe {
+                asm!("dc cvau, {line}", line = in(reg) line, options(nostack, preserves_flags));
+            }
+            line += line_size;
+        }
+    }
+    // SAFETY: A synchronisation barrier is always safe.
+    unsafe {
+        asm!("dsb ish", options(nostack, preserves_flags));
+    }
+
+    if ctr & CTR_DIC == 0 {
+        let line_size = icache_line_size();
+        let mut line = start as usize & !(line_size - 1);
+        while line < end {
+            // SAFETY: Invalidating an instruction cache line doesn't affect memory safety.
+            unsafe {
+                asm!("ic ivau, {line}", line = in(reg) line, options(nostack, preserves_flags));
+            }
+            line += line_size;
+        }
+        // SAFETY: A synchronisation barrier is always safe.
+        unsafe {
+            asm!("dsb ish", options(nostack, preserves_flags));
+        }
+    }
+    // SAFETY: An instruction synchronisation barrier is always safe.
+    unsafe {
+        asm!("isb", options(nostack, preserves_flags));
+    }
+}
+
+/// Writes the given instructions to memory starting at `dst`, and makes them visible to instruction
+/// fetches.
+///
+/// If `sync_other_cores` is true then this then runs an ISB on every other core which has enabled
+/// remote calls with [`smp::enable_remote_calls`](crate::smp::enable_remote_calls), and waits for
+/// them all to acknowledge it, so that they will all fetch the new instructions once this returns.
+/// Those cores must have IRQs unmasked, or this will wait until they unmask them. Cores which
+/// haven't enabled remote calls aren't synchronised.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `insns.len()` instructions, and mapped as writable. No other
+/// core may be executing the instructions being replaced while they are being written, unless the
+/// replacement is one of the cases permitted by the Arm architecture for concurrent modification
+/// (e.g. replacing a single `B`, `BL`, `NOP`, `BRK` or `HVC`/`SMC`/`SVC` instruction with another).
+pub unsafe fn patch_instructions(dst: *mut u32, insns: &[u32], sync_other_cores: bool) {
+    for (i, insn) in insns.iter().enumerate() {
+        // SAFETY: Our caller promised that `dst` is valid for writes of `insns.len()` instructions.
+        unsafe {
+            dst.add(i).write_volatile(*insn);
+        }
+    }
+    sync_icache(dst.cast(), size_of_val(insns));
+    if sync_other_cores {
+        crate::smp::run_on_all_cores(|| {
+            // SAFETY: An instruction synchronisation barrier is always safe.
+            unsafe {
+                asm!("isb", options(nostack, preserves_flags));
+            }
+        });
+    }
+}
+
+/// The kind of caches implemented at a cache level, from CLIDR_EL1.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CacheType {
+    /// No cache.
+    #[default]
+    None,
+    /// An instruction cache only.
+    Instruction,
+    /// A data cache only.
+    Data,
+    /// Separate instruction and data caches.
+    Separate,
+    /// A unified cache for both instructions and data.
+    Unified,
+}
+
+impl CacheType {
+    /// Returns whether there is a data or unified cache at this level.
+    pub fn has_data(self) -> bool {
+        matches!(self, Self::Data | Self::Separate | Self::Unified)
+    }
+
+    /// Returns whether there is a separate instruction cache at this level.
+    pub fn has_instruction(self) -> bool {
+        matches!(self, Self::Instruction | Self::Separate)
+    }
+}
+
+/// The geometry of a single cache, from CCSIDR_EL1.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheGeometry {
+    /// The size of a cache line in bytes.
+    pub line_size: usize,
+    /// The associativity, i.e. the number of ways.
+    pub ways: u32,
+    /// The number of sets.
+    pub sets: u32,
+}
+
+impl CacheGeometry {
+    /// Returns the total size of the cache in bytes.
+    pub fn size(&self) -> usize {
+        self.line_size * self.ways as usize * self.sets as usize
+    }
+}
+
+/// A level of the cache hierarchy of the current core.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheLevel {
+    /// The cache level, starting from 1.
+    pub level: u8,
+    /// The kind of caches at this level.
+    pub cache_type: CacheType,
+    /// The geometry of the data or unified cache at this level, if any.
+    pub data: Option<CacheGeometry>,
+    /// The geometry of the separate instruction cache at this level, if any.
+    pub instruction: Option<CacheGeometry>,
+}
+
+/// The cache hierarchy of the current core, as described by CLIDR_EL1 and CCSIDR_EL1.
+///
+/// This describes the caches visible to the core, which are used by set/way cache maintenance. The
+/// geometry reported by CCSIDR_EL1 isn't guaranteed to match the physical caches, so shouldn't be
+/// relied on for anything other than set/way maintenance and as a hint for data placement.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheTopology {
+    levels: [CacheLevel; MAX_CACHE_LEVELS],
+    count: usize,
+    /// The level of coherence: the first level which doesn't need to be cleaned or invalidated to
+    /// make data coherent with memory, from CLIDR_EL1.LoC.
+    pub level_of_coherence: u8,
+    /// The level of unification for the inner shareable domain, from CLIDR_EL1.LoUIS.
+    pub level_of_unification_inner_shareable: u8,
+    /// The level of unification for the current core, from CLIDR_EL1.LoUU.
+    pub level_of_unification_uniprocessor: u8,
+}
+
+impl CacheTopology {
+    /// Reads the cache hierarchy of the current core.
+    pub fn current() -> Self {
+        // SAFETY: Reading CLIDR_EL1 is always safe.
+        let clidr = unsafe { read_sysreg!("clidr_el1") };
+        let mut topology = Self {
+            level_of_unification_inner_shareable: ((clidr >> 21) & 0x7) as u8,
+            level_of_coherence: ((clidr >> 24) & 0x7) as u8,
+            level_of_unification_uniprocessor: ((clidr >> 27) & 0x7) as u8,
+            ..Self::default()
+        };
+        for index in 0..MAX_CACHE_LEVELS {
+            let cache_type = match (clidr >> (index * 3)) & 0x7 {
+                0b001 => CacheType::Instruction,
+                0b010 => CacheType::Data,
+                0b011 => CacheType::Separate,
+                0b100 => CacheType::Unified,
+                _ => break,
+            };
+            let level = index as u8 + 1;
+            topology.levels[index] = CacheLevel {
+                level,
+                cache_type,
+                data: cache_type.has_data().then(|| read_geometry(level, false)),
+                instruction: cache_type
+                    .has_instruction()
+                    .then(|| read_geometry(level, true)),
+            };
+            topology.count = index + 1;
+        }
+        topology
+    }
+
+    /// Returns the implemented cache levels, starting from level 1.
+    pub fn levels(&self) -> &[CacheLevel] {
+        &self.levels[..self.count]
+    }
+
+    /// Returns the given cache level, starting from 1, if it is implemented.
+    pub fn level(&self, level: u8) -> Option<&CacheLevel> {
+        self.levels().get(usize::from(level).checked_sub(1)?)
+    }
+}
+
+/// Reads the geometry of the instruction or data cache at the given level, starting from 1.
+fn read_geometry(level: u8, instruction: bool) -> CacheGeometry {
+    let csselr = u64::from(level - 1) << 1 | if instruction { CSSELR_IND } else { 0 };
+    // SAFETY: Masking IRQs while selecting and reading the cache doesn't affect memory safety, and
+    // stops an interrupt handler from changing CSSELR_EL1 in between. CSSELR_EL1 isn't used for
+    // anything else, so its previous value needn't be restored. Reading ID_AA64MMFR2_EL1 is always
+    // safe.
+    let (ccsidr, mmfr2) = unsafe {
+        let daif = read_sysreg!("daif");
+        asm!("msr daifset, #2", options(nomem, nostack, preserves_flags));
+        write_sysreg!("csselr_el1", csselr);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+        let ccsidr = read_sysreg!("ccsidr_el1");
+        write_sysreg!("daif", daif);
+        (ccsidr, read_sysreg!("id_aa64mmfr2_el1"))
+    };
+    // LineSize is the log2 of the number of bytes in a line, minus 4.
+    let line_size = 16 << (ccsidr & 0x7);
+    // Associativity and NumSets are one less than the actual values.
+    let (ways, sets) = if (mmfr2 >> ID_AA64MMFR2_CCIDX_SHIFT) & 0xf != 0 {
+        ((ccsidr >> 3) & 0x1f_ffff, (ccsidr >> 32) & 0xff_ffff)
+    } else {
+        ((ccsidr >> 3) & 0x3ff, (ccsidr >> 13) & 0x7fff)
+    };
+    CacheGeometry {
+        line_size,
+        ways: ways as u32 + 1,
+        sets: sets as u32 + 1,
+    }
+}
+
+/// The caches to clean and invalidate by set/way before powering down.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PowerDownScope {
+    /// Only the current core is being powered down, so only the cache levels private to it, up to
+    /// the level of unification for the inner shareable domain, are flushed. Shared caches stay
+    /// coherent with the other cores.
+    Core,
+    /// The whole cluster or system is being powered down, so all cache levels up to the level of
+    /// coherence are flushed.
+    Cluster,
+}
+
+impl PowerDownScope {
+    /// Returns the number of cache levels to flush, starting from level 1.
+    fn levels(self) -> u64 {
+        let topology = CacheTopology::current();
+        match self {
+            Self::Core => topology.level_of_unification_inner_shareable.into(),
+            Self::Cluster => topology.level_of_coherence.into(),
+        }
+    }
+}
+
+/// Cleans and invalidates the data and unified caches of the current core by set/way, for the
+/// levels given by `scope`, and waits for that to complete.
+///
+/// Set/way maintenance only affects the caches of the current core and isn't broadcast to other
+/// cores, so it can't be used to make data coherent with them or with devices; use
+/// [`clean_invalidate_dcache_range`] for that. It is only useful for emptying the caches before
+/// they lose power, and then only once the data cache has been disabled, as otherwise lines may be
+/// allocated again by speculative accesses as soon as they are cleaned. See
+/// [`prepare_dcache_for_power_down`], which does that too.
+///
+/// IRQs and FIQs are masked while this runs, as an interrupt handler could otherwise change the
+/// cache selected in CSSELR_EL1.
+pub fn clean_invalidate_dcache_set_way(scope: PowerDownScope) {
+    // SAFETY: Cleaning and invalidating by set/way writes back any dirty data before invalidating
+    // it, so doesn't lose any writes. `clean_invalidate_dcache_levels` follows the standard calling
+    // convention.
+    unsafe {
+        clean_invalidate_dcache_levels(scope.levels());
+    }
+}
+
+/// Prepares the current core's data caches to lose power, by disabling the data cache for the
+/// current exception level, cleaning and invalidating it by set/way for the levels given by
+/// `scope`, and then taking the core out of coherency.
+///
+/// The data cache is disabled and cleaned in assembly without any memory accesses in between, so
+/// that no dirty lines, such as those of the stack, can be missed. Taking the core out of
+/// coherency means clearing CPUECTLR_EL1.SMPEN at EL3 on cores which need it, as identified by
+/// [`Midr::needs_smpen`](crate::cpu::Midr::needs_smpen). On other cores this is done by the
+/// hardware or firmware as part of the power-down.
+///
+/// This is needed by firmware at EL3 serving PSCI `CPU_OFF` or a power-down `CPU_SUSPEND`, just
+/// before the core is powered down. Code at lower exception levels calling PSCI doesn't need it,
+/// as the firmware does the same before powering down the core.
+///
+/// # Safety
+///
+/// After this returns the current core accesses memory as non-cacheable, and isn't coherent with
+/// other cores, so it mustn't share any data with them other than through memory which they also
+/// access with caches off, or after they have cleaned it. Exclusive accesses to normal memory, and
+/// so atomics, may not work. The core should be powered down soon afterwards, without returning
+/// far.
+pub unsafe fn prepare_dcache_for_power_down(scope: PowerDownScope) {
+    let levels = scope.levels();
+    // SAFETY: Our caller promised to cope with the data cache being disabled and the core leaving
+    // coherency. `disable_dcache_and_clean` follows the standard calling convention.
+    unsafe {
+        disable_dcache_and_clean(levels);
+    }
+}
+
+/// Cleans and invalidates the data and unified caches of the current core by set/way, for levels 1
+/// to `levels`, with IRQs and FIQs masked.
+///
+/// Doesn't access memory, so can be used after the data cache has been disabled.
+///
+/// # Safety
+///
+/// This follows the standard calling convention, but only clobbers x0-x13 so may also be called
+/// from assembly which relies on that.
+#[unsafe(naked)]
+unsafe extern "C" fn clean_invalidate_dcache_levels(levels: u64) {
+    naked_asm!(
+        "mrs x13, daif",
+        "msr daifset, #3",
+        "dsb sy",
+        "mrs x1, clidr_el1",
+        "mrs x4, id_aa64mmfr2_el1",
+        "ubfx x4, x4, #{ccidx_shift}, #4",
+        // x2 is the level field for CSSELR_EL1 and DC CISW, which is twice the level index.
+        "mov x2, #0",
+        "lsl x0, x0, #1",
+        "cbz x0, 5f",
+        "1:",
+        // Skip levels with no data or unified cache.
+        "add x3, x2, x2, lsr #1",
+        "lsr x3, x1, x3",
+        "and x3, x3, #7",
+        "cmp x3, #2",
+        "b.lt 4f",
+        "msr csselr_el1, x2",
+        "isb",
+        "mrs x3, ccsidr_el1",
+        // x5 is the log2 of the line size in bytes.
+        "and x5, x3, #7",
+        "add x5, x5, #4",
+        // x6 is the number of ways minus one, and x7 the number of sets minus one.
+        "cbnz x4, 2f",
+        "ubfx x6, x3, #3, #10",
+        "ubfx x7, x3, #13, #15",
+        "b 3f",
+        "2:",
+        "ubfx x6, x3, #3, #21",
+        "ubfx x7, x3, #32, #24",
+        "3:",
+        // The way goes in the top bits of the 32-bit set/way value.
+        "clz w8, w6",
+        "mov x9, x6",
+        "6:",
+        "mov x10, x7",
+        "7:",
+        "lsl x11, x9, x8",
+        "lsl x12, x10, x5",
+        "orr x11, x11, x12",
+        "orr x11, x11, x2",
+        "dc cisw, x11",
+        "subs x10, x10, #1",
+        "b.ge 7b",
+        "subs x9, x9, #1",
+        "b.ge 6b",
+        // Finish this level before moving on, so that lines evicted to the next level are cleaned
+        // there.
+        "dsb sy",
+        "4:",
+        "add x2, x2, #2",
+        "cmp x2, x0",
+        "b.lt 1b",
+        "5:",
+        "msr csselr_el1, xzr",
+        "dsb sy",
+        "isb",
+        "msr daif, x13",
+        "ret",
+        ccidx_shift = const ID_AA64MMFR2_CCIDX_SHIFT,
+    )
+}
+
+/// Disables the data cache for the current exception level, cleans and invalidates levels 1 to
+/// `levels` by set/way, and clears SMPEN if needed.
+///
+/// # Safety
+///
+/// See [`prepare_dcache_for_power_down`].
+#[unsafe(naked)]
+unsafe extern "C" fn disable_dcache_and_clean(levels: u64) {
+    naked_asm!(
+        "mov x15, x30",
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #3",
+        "b.eq 3f",
+        "cmp x9, #2",
+        "b.eq 2f",
+        "mrs x9, sctlr_el1",
+        "bic x9, x9, #{sctlr_c}",
+        "msr sctlr_el1, x9",
+        "b 4f",
+        "2:",
+        "mrs x9, sctlr_el2",
+        "bic x9, x9, #{sctlr_c}",
+        "msr sctlr_el2, x9",
+        "b 4f",
+        "3:",
+        "mrs x9, sctlr_el3",
+        "bic x9, x9, #{sctlr_c}",
+        "msr sctlr_el3, x9",
+        "4:",
+        "isb",
+        "bl {clean_invalidate_dcache_levels}",
+        "bl {disable_smpen}",
+        "mov x30, x15",
+        "ret",
+        sctlr_c = const SCTLR_C,
+        clean_invalidate_dcache_levels = sym clean_invalidate_dcache_levels,
+        disable_smpen = sym crate::cpu::disable_smpen,
+    )
+}
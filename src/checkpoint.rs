@@ -0,0 +1,401 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Snapshots of the CPU state of the current core, to restore later.
+//!
+//! [`CpuState::capture`] saves the general-purpose registers, the stack pointers, the PSTATE and
+//! the key system registers of the current exception level. [`CpuState::restore`] puts them all
+//! back and resumes as if `capture` had returned again, like `setjmp` and `longjmp` in C. This is
+//! useful for checkpointing while debugging, experimenting with migrating execution between cores,
+//! and implementing suspend paths which lose the core's state:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::checkpoint::CpuState;
+//!
+//! let mut checkpoint = CpuState::new();
+//! // SAFETY: This function doesn't return until the checkpoint is restored, and doesn't change any
+//! // local variables after capturing it.
+//! if unsafe { checkpoint.capture() } {
+//!     // Resumed by `restore`, with the registers as they were when `capture` first returned.
+//!     return;
+//! }
+//! // ...
+//! // SAFETY: This function hasn't returned, and hasn't changed any local variables since the
+//! // checkpoint was captured.
+//! unsafe { checkpoint.restore() };
+//! ```
+//!
+//! The general-purpose registers, ELR and SPSR are kept in a [`RegisterState`], the same structure
+//! which exception handlers are given, as if an exception had been taken where `capture` returns.
+//! The floating-point and SIMD registers aren't included.
+
+use crate::{
+    RegisterState,
+    sysreg::{current_el, read_sysreg, write_sysreg},
+};
+use core::{
+    arch::{asm, naked_asm},
+    mem::offset_of,
+};
+
+/// The shift of the PAN field of ID_AA64MMFR1_EL1, which is non-zero if FEAT_PAN is implemented.
+const ID_AA64MMFR1_PAN_SHIFT: u32 = 20;
+/// The shift of the SSBS field of ID_AA64PFR1_EL1, which is non-zero if FEAT_SSBS is implemented.
+const ID_AA64PFR1_SSBS_SHIFT: u32 = 4;
+
+/// The key system registers of the current exception level, other than the stack pointers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct SystemRegisters {
+    /// SCTLR_ELx.
+    pub sctlr: u64,
+    /// TCR_ELx.
+    pub tcr: u64,
+    /// MAIR_ELx.
+    pub mair: u64,
+    /// TTBR0_ELx.
+    pub ttbr0: u64,
+    /// TTBR1_EL1 at EL1, or 0 at EL2 and EL3 where it isn't used.
+    pub ttbr1: u64,
+    /// VBAR_ELx.
+    pub vbar: u64,
+    /// TPIDR_ELx.
+    pub tpidr: u64,
+    /// CPACR_EL1 at EL1, or CPTR_ELx at EL2 and EL3.
+    pub cptr: u64,
+}
+
+impl SystemRegisters {
+    /// Returns the current values of the system registers for the current exception level.
+    pub fn current() -> Self {
+        // SAFETY: Reading the system registers for the current exception level is always safe.
+        unsafe {
+            match current_el() {
+                1 => Self {
+                    sctlr: read_sysreg!("sctlr_el1"),
+                    tcr: read_sysreg!("tcr_el1"),
+                    mair: read_sysreg!("mair_el1"),
+                    ttbr0: read_sysreg!("ttbr0_el1"),
+                    ttbr1: read_sysreg!("ttbr1_el1"),
+                    vbar: read_sysreg!("vbar_el1"),
+                    tpidr: read_sysreg!("tpidr_el1"),
+                    cptr: read_sysreg!("cpacr_el1"),
+                },
+                2 => Self {
+                    sctlr: read_sysreg!("sctlr_el2"),
+                    tcr: read_sysreg!("tcr_el2"),
+                    mair: read_sysreg!("mair_el2"),
+                    ttbr0: read_sysreg!("ttbr0_el2"),
+                    ttbr1: 0,
+                    vbar: read_sysreg!("vbar_el2"),
+                    tpidr: read_sysreg!("tpidr_el2"),
+                    cptr: read_sysreg!("cptr_el2"),
+                },
+                _ => Self {
+                    sctlr: read_sysreg!("sctlr_el3"),
+                    tcr: read_sysreg!("tcr_el3"),
+                    mair: read_sysreg!("mair_el3"),
+                    ttbr0: read_sysreg!("ttbr0_el3"),
+                    ttbr1: 0,
+                    vbar: read_sysreg!("vbar_el3"),
+                    tpidr: read_sysreg!("tpidr_el3"),
+                    cptr: read_sysreg!("cptr_el3"),
+                },
+            }
+        }
+    }
+
+    /// Writes the system registers for the current exception level, invalidating the TLB before
+    /// SCTLR_ELx is written in case the translation configuration changed.
+    ///
+    /// # Safety
+    ///
+    /// The new configuration must map the code which is running and the memory it uses at the same
+    /// addresses as the current configuration, and the vector table must be valid.
+    unsafe fn write(&self) {
+        // SAFETY: Our caller guarantees that the new configuration is compatible with the code
+        // which is running.
+        unsafe {
+            match current_el() {
+                1 => {
+                    write_sysreg!("mair_el1", self.mair);
+                    write_sysreg!("tcr_el1", self.tcr);
+                    write_sysreg!("ttbr0_el1", self.ttbr0);
+                    write_sysreg!("ttbr1_el1", self.ttbr1);
+                    write_sysreg!("vbar_el1", self.vbar);
+                    write_sysreg!("tpidr_el1", self.tpidr);
+                    write_sysreg!("cpacr_el1", self.cptr);
+                    asm!(
+                        "isb",
+                        "tlbi vmalle1",
+                        "dsb nsh",
+                        "isb",
+                        options(nostack, preserves_flags)
+                    );
+                    write_sysreg!("sctlr_el1", self.sctlr);
+                }
+                2 => {
+                    write_sysreg!("mair_el2", self.mair);
+                    write_sysreg!("tcr_el2", self.tcr);
+                    write_sysreg!("ttbr0_el2", self.ttbr0);
+                    write_sysreg!("vbar_el2", self.vbar);
+                    write_sysreg!("tpidr_el2", self.tpidr);
+                    write_sysreg!("cptr_el2", self.cptr);
+                    asm!(
+                        "isb",
+                        "tlbi alle2",
+                        "dsb nsh",
+                        "isb",
+                        options(nostack, preserves_flags)
+                    );
+                    write_sysreg!("sctlr_el2", self.sctlr);
+                }
+                _ => {
+                    write_sysreg!("mair_el3", self.mair);
+                    write_sysreg!("tcr_el3", self.tcr);
+                    write_sysreg!("ttbr0_el3", self.ttbr0);
+                    write_sysreg!("vbar_el3", self.vbar);
+                    write_sysreg!("tpidr_el3", self.tpidr);
+                    write_sysreg!("cptr_el3", self.cptr);
+                    asm!(
+                        "isb",
+                        "tlbi alle3",
+                        "dsb nsh",
+                        "isb",
+                        options(nostack, preserves_flags)
+                    );
+                    write_sysreg!("sctlr_el3", self.sctlr);
+                }
+            }
+            asm!("isb", options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// A snapshot of the CPU state of a core, captured by [`CpuState::capture`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct CpuState {
+    /// The general-purpose registers, and the ELR and SPSR to resume at, as in an exception frame.
+    ///
+    /// The ELR is the address which `capture` returned to, and the SPSR has the NZCV flags, the
+    /// DAIF interrupt masks, the exception level, the stack pointer selection, and PAN and SSBS if
+    /// implemented, which it returned with. Other PSTATE fields are cleared on restore.
+    pub registers: RegisterState,
+    /// The stack pointer which `capture` returned with.
+    pub stack_pointer: u64,
+    /// SP_EL0. This is the same as the stack pointer if it was selected.
+    ///
+    /// SP_ELx can't be read while SP_EL0 is selected, so isn't captured or restored in that case.
+    pub sp_el0: u64,
+    /// The key system registers of the current exception level.
+    pub system_registers: SystemRegisters,
+}
+
+impl CpuState {
+    /// Returns an empty CPU state, to be filled in by [`capture`](Self::capture).
+    pub const fn new() -> Self {
+        Self {
+            registers: RegisterState::empty(),
+            stack_pointer: 0,
+            sp_el0: 0,
+            system_registers: SystemRegisters {
+                sctlr: 0,
+                tcr: 0,
+                mair: 0,
+                ttbr0: 0,
+                ttbr1: 0,
+                vbar: 0,
+                tpidr: 0,
+                cptr: 0,
+            },
+        }
+    }
+
+    /// Captures the current CPU state into `self`.
+    ///
+    /// Returns false when the state is captured, or true when it is resumed by
+    /// [`restore`](Self::restore).
+    ///
+    /// # Safety
+    ///
+    /// This returns a second time if the state is restored, which the compiler doesn't expect. If
+    /// the state may be restored, the function which calls `capture` must not return until then,
+    /// and must not change any of its local variables after `capture` first returns, unless all
+    /// the memory it is using is restored too.
+    #[unsafe(naked)]
+    pub unsafe extern "C" fn capture(&mut self) -> bool {
+        naked_asm!(
+            "stp x0, x1, [x0, #0]",
+            "stp x2, x3, [x0, #16]",
+            "stp x4, x5, [x0, #32]",
+            "stp x6, x7, [x0, #48]",
+            "stp x8, x9, [x0, #64]",
+            "stp x10, x11, [x0, #80]",
+            "stp x12, x13, [x0, #96]",
+            "stp x14, x15, [x0, #112]",
+            "stp x16, x17, [x0, #128]",
+            // x18 is followed by the padding in the register state.
+            "stp x18, xzr, [x0, #144]",
+            "stp x29, x30, [x0, #{fp_offset}]",
+            "stp x19, x20, [x0, #{callee_saved_offset}]",
+            "stp x21, x22, [x0, #{callee_saved_offset} + 16]",
+            "stp x23, x24, [x0, #{callee_saved_offset} + 32]",
+            "stp x25, x26, [x0, #{callee_saved_offset} + 48]",
+            "stp x27, x28, [x0, #{callee_saved_offset} + 64]",
+            // Build the SPSR to resume with from the current PSTATE, and resume at the return
+            // address.
+            "mrs x9, nzcv",
+            "mrs x10, daif",
+            "orr x9, x9, x10",
+            "mrs x10, CurrentEL",
+            "orr x9, x9, x10",
+            // PAN and SSBS are at the same bits in their registers as in the SPSR, but can only be
+            // read if implemented.
+            "mrs x11, id_aa64mmfr1_el1",
+            "ubfx x11, x11, #{pan_shift}, #4",
+            "cbz x11, 0f",
+            // PAN
+            "mrs x11, s3_0_c4_c2_3",
+            "orr x9, x9, x11",
+            "0:",
+            "mrs x11, id_aa64pfr1_el1",
+            "ubfx x11, x11, #{ssbs_shift}, #4",
+            "cbz x11, 0f",
+            // SSBS
+            "mrs x11, s3_3_c4_c2_6",
+            "orr x9, x9, x11",
+            "0:",
+            "mrs x10, spsel",
+            "orr x9, x9, x10",
+            "stp x30, x9, [x0, #{elr_offset}]",
+            // SP_EL0 can only be read while it isn't selected, and is the stack pointer otherwise.
+            "mov x11, sp",
+            "mov x12, x11",
+            "cbz x10, 0f",
+            "mrs x12, sp_el0",
+            "0:",
+            "stp x11, x12, [x0, #{stack_pointer_offset}]",
+            // Returns false to our caller.
+            "b {capture_system_registers}",
+            fp_offset = const offset_of!(CpuState, registers.fp),
+            elr_offset = const offset_of!(CpuState, registers.elr),
+            callee_saved_offset = const offset_of!(CpuState, registers.callee_saved),
+            stack_pointer_offset = const offset_of!(CpuState, stack_pointer),
+            pan_shift = const ID_AA64MMFR1_PAN_SHIFT,
+            ssbs_shift = const ID_AA64PFR1_SSBS_SHIFT,
+            capture_system_registers = sym capture_system_registers,
+        )
+    }
+
+    /// Restores the CPU state, resuming execution as if [`capture`](Self::capture) had returned
+    /// true.
+    ///
+    /// Interrupts are masked while the state is restored, and then set as they were when the state
+    /// was captured.
+    ///
+    /// # Safety
+    ///
+    /// The state must have been captured by [`capture`](Self::capture) at the current exception
+    /// level. Unless all the memory it was using has been restored too, the function which called
+    /// `capture` must not have returned, and must not have changed any of its local variables since
+    /// `capture` first returned.
+    ///
+    /// The captured system registers must map the code which is running and the memory it uses at
+    /// the same addresses as the current configuration.
+    pub unsafe fn restore(&self) -> ! {
+        // SAFETY: Masking interrupts is always safe. They are unmasked according to the saved SPSR
+        // when the state is restored.
+        unsafe {
+            asm!(
+                "msr daifset, #0xf",
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        // SAFETY: Our caller guarantees that the captured system registers are compatible with the
+        // code which is running.
+        unsafe {
+            self.system_registers.write();
+        }
+        let elr = self.registers.elr as u64;
+        let spsr = self.registers.spsr;
+        // SAFETY: Interrupts are masked, so the ELR and SPSR are only used by `restore_registers`
+        // to resume from the captured state, which our caller guarantees is valid.
+        unsafe {
+            match current_el() {
+                1 => {
+                    write_sysreg!("elr_el1", elr);
+                    write_sysreg!("spsr_el1", spsr);
+                }
+                2 => {
+                    write_sysreg!("elr_el2", elr);
+                    write_sysreg!("spsr_el2", spsr);
+                }
+                _ => {
+                    write_sysreg!("elr_el3", elr);
+                    write_sysreg!("spsr_el3", spsr);
+                }
+            }
+            restore_registers(self)
+        }
+    }
+}
+
+impl Default for CpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fills in the system registers of the given CPU state, once [`CpuState::capture`] has saved the
+/// registers which the call may change.
+extern "C" fn capture_system_registers(state: &mut CpuState) -> bool {
+    state.system_registers = SystemRegisters::current();
+    false
+}
+
+/// Restores the stack pointers and general-purpose registers from the given CPU state, and returns
+/// from the current exception level to the ELR and SPSR which have already been set from it, with
+/// x0 set to 1.
+///
+/// # Safety
+///
+/// Interrupts must be masked, and the ELR and SPSR for the current exception level must have been
+/// set to those of the CPU state, which must have been captured by [`CpuState::capture`].
+#[unsafe(naked)]
+unsafe extern "C" fn restore_registers(state: &CpuState) -> ! {
+    naked_asm!(
+        // SP_EL0 can only be written while it isn't selected.
+        "msr spsel, #1",
+        "ldp x1, x2, [x0, #{stack_pointer_offset}]",
+        "msr sp_el0, x2",
+        "ldr x3, [x0, #{spsr_offset}]",
+        "and x3, x3, #1",
+        "msr spsel, x3",
+        "mov sp, x1",
+        "ldp x2, x3, [x0, #16]",
+        "ldp x4, x5, [x0, #32]",
+        "ldp x6, x7, [x0, #48]",
+        "ldp x8, x9, [x0, #64]",
+        "ldp x10, x11, [x0, #80]",
+        "ldp x12, x13, [x0, #96]",
+        "ldp x14, x15, [x0, #112]",
+        "ldp x16, x17, [x0, #128]",
+        "ldr x18, [x0, #144]",
+        "ldp x19, x20, [x0, #{callee_saved_offset}]",
+        "ldp x21, x22, [x0, #{callee_saved_offset} + 16]",
+        "ldp x23, x24, [x0, #{callee_saved_offset} + 32]",
+        "ldp x25, x26, [x0, #{callee_saved_offset} + 48]",
+        "ldp x27, x28, [x0, #{callee_saved_offset} + 64]",
+        "ldp x29, x30, [x0, #{fp_offset}]",
+        "ldr x1, [x0, #8]",
+        // `capture` returns true.
+        "mov x0, #1",
+        "eret",
+        fp_offset = const offset_of!(CpuState, registers.fp),
+        spsr_offset = const offset_of!(CpuState, registers.spsr),
+        callee_saved_offset = const offset_of!(CpuState, registers.callee_saved),
+        stack_pointer_offset = const offset_of!(CpuState, stack_pointer),
+    )
+}
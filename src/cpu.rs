@@ -0,0 +1,177 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Identification of the CPU core, and implementation-specific initialisation.
+
+use crate::sysreg::read_sysreg;
+use core::{
+    arch::naked_asm,
+    fmt::{self, Debug, Formatter},
+};
+
+/// The MIDR_EL1 implementer code for Arm Limited.
+pub const IMPLEMENTER_ARM: u8 = 0x41;
+
+/// The MIDR_EL1 part number of the Cortex-A53.
+pub const PART_CORTEX_A53: u16 = 0xd03;
+/// The MIDR_EL1 part number of the Cortex-A57.
+pub const PART_CORTEX_A57: u16 = 0xd07;
+/// The MIDR_EL1 part number of the Cortex-A72.
+pub const PART_CORTEX_A72: u16 = 0xd08;
+
+/// Enables hardware management of data coherency with other cores, in CPUECTLR_EL1.
+const CPUECTLR_SMPEN: u64 = 1 << 6;
+
+/// A value of the Main ID Register, identifying a CPU core.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct Midr(pub u64);
+
+impl Midr {
+    /// Returns the MIDR of the current core.
+    pub fn current() -> Self {
+        // SAFETY: Reading MIDR_EL1 is always safe.
+        Self(unsafe { read_sysreg!("midr_el1") })
+    }
+
+    /// Returns the implementer code.
+    pub const fn implementer(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    /// Returns the major revision number.
+    pub const fn variant(self) -> u8 {
+        ((self.0 >> 20) & 0xf) as u8
+    }
+
+    /// Returns the primary part number.
+    pub const fn part_number(self) -> u16 {
+        ((self.0 >> 4) & 0xfff) as u16
+    }
+
+    /// Returns the minor revision number.
+    pub const fn revision(self) -> u8 {
+        (self.0 & 0xf) as u8
+    }
+
+    /// Returns whether this is the given Arm Limited part.
+    pub const fn is_arm_part(self, part_number: u16) -> bool {
+        self.implementer() == IMPLEMENTER_ARM && self.part_number() == part_number
+    }
+
+    /// Returns whether this core must have CPUECTLR_EL1.SMPEN set before caches are enabled for its
+    /// data cache to be coherent with other cores.
+    ///
+    /// The entry points set this automatically when running at EL3. At lower exception levels it is
+    /// expected to have been set by firmware.
+    pub const fn needs_smpen(self) -> bool {
+        self.is_arm_part(PART_CORTEX_A53)
+            || self.is_arm_part(PART_CORTEX_A57)
+            || self.is_arm_part(PART_CORTEX_A72)
+    }
+}
+
+impl Debug for Midr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Midr {{ implementer: {:#04x}, part_number: {:#05x}, r{}p{} }}",
+            self.implementer(),
+            self.part_number(),
+            self.variant(),
+            self.revision()
+        )
+    }
+}
+
+/// Sets CPUECTLR_EL1.SMPEN if we are running at EL3 on a core which needs it, as identified by
+/// [`Midr::needs_smpen`].
+///
+/// This must be done before the data cache is enabled. At lower exception levels access to
+/// CPUECTLR_EL1 is usually trapped, so it is left to firmware.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code, early in the boot process.
+///
+/// Clobbers x9-x10.
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn enable_smpen() {
+    naked_asm!(
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #3",
+        "b.ne 1f",
+        "mrs x9, midr_el1",
+        "ubfx x10, x9, #24, #8",
+        "cmp x10, #{implementer_arm}",
+        "b.ne 1f",
+        "ubfx x10, x9, #4, #12",
+        "cmp x10, #{cortex_a53}",
+        "b.eq 0f",
+        "cmp x10, #{cortex_a57}",
+        "b.eq 0f",
+        "cmp x10, #{cortex_a72}",
+        "b.ne 1f",
+        "0:",
+        // CPUECTLR_EL1 has the same encoding on all of these cores.
+        "mrs x10, S3_1_C15_C2_1",
+        "orr x10, x10, #{smpen}",
+        "msr S3_1_C15_C2_1, x10",
+        "isb",
+        "1:",
+        "ret",
+        implementer_arm = const IMPLEMENTER_ARM,
+        cortex_a53 = const PART_CORTEX_A53,
+        cortex_a57 = const PART_CORTEX_A57,
+        cortex_a72 = const PART_CORTEX_A72,
+        smpen = const CPUECTLR_SMPEN,
+    )
+}
+
+/// Clears CPUECTLR_EL1.SMPEN if we are running at EL3 on a core which needs it, as identified by
+/// [`Midr::needs_smpen`], taking the core out of coherency with the other cores.
+///
+/// This must only be done after the data cache has been disabled and cleaned, immediately before
+/// the core is powered down.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code.
+///
+/// Clobbers x9-x10.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn disable_smpen() {
+    naked_asm!(
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #3",
+        "b.ne 1f",
+        "mrs x9, midr_el1",
+        "ubfx x10, x9, #24, #8",
+        "cmp x10, #{implementer_arm}",
+        "b.ne 1f",
+        "ubfx x10, x9, #4, #12",
+        "cmp x10, #{cortex_a53}",
+        "b.eq 0f",
+        "cmp x10, #{cortex_a57}",
+        "b.eq 0f",
+        "cmp x10, #{cortex_a72}",
+        "b.ne 1f",
+        "0:",
+        "mrs x10, S3_1_C15_C2_1",
+        "bic x10, x10, #{smpen}",
+        "msr S3_1_C15_C2_1, x10",
+        "isb",
+        "1:",
+        "ret",
+        implementer_arm = const IMPLEMENTER_ARM,
+        cortex_a53 = const PART_CORTEX_A53,
+        cortex_a57 = const PART_CORTEX_A57,
+        cortex_a72 = const PART_CORTEX_A72,
+        smpen = const CPUECTLR_SMPEN,
+    )
+}
@@ -0,0 +1,626 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Self-hosted hardware breakpoints and watchpoints.
+//!
+//! Breakpoints and watchpoints set with this module match accesses from EL1 and EL0. When one is
+//! hit, a synchronous exception is taken to EL1 and delivered to the
+//! [`sync_current`](crate::ExceptionHandlers::sync_current) (or
+//! [`sync_lower`](crate::ExceptionHandlers::sync_lower)) handler, which can use
+//! [`DebugException::current`] to find out what happened. A handler should clear the breakpoint or
+//! watchpoint before returning, or it will be hit again immediately.
+//!
+//! Debug exceptions must first be enabled with [`enable_debug_exceptions`].
+//!
+//! Unless the `debug-locked` feature is enabled, the entry points also clear the OS Lock, stop
+//! debug register accesses from being trapped to EL2 or EL3, and enable monitor debug events on
+//! each core, so that external and self-hosted debug work immediately after reset. With the
+//! `debug-lockdown` feature they instead disable secure debug, trace and external debugger access
+//! at EL3, for production secure firmware.
+//!
+//! Hypervisors and secure monitors can choose whether lower exception levels may use the PMU,
+//! statistical profiling and trace, rather than inheriting whatever MDCR_EL2 or MDCR_EL3 was left
+//! as by reset or earlier firmware, by calling [`set_lower_el_access`] on each core:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::debug::{LowerElAccess, set_lower_el_access};
+//!
+//! // Let the guest count events, but not profile or trace.
+//! set_lower_el_access(LowerElAccess {
+//!     pmu: true,
+//!     ..LowerElAccess::NONE
+//! });
+//! ```
+
+use crate::sysreg::{current_el, read_esr, read_far, read_sysreg, write_sysreg};
+use core::arch::{asm, naked_asm};
+
+/// OS Lock status, in OSLSR_EL1.
+const OSLSR_OSLK: u64 = 1 << 1;
+/// Monitor debug events, in MDSCR_EL1.
+const MDSCR_MDE: u64 = 1 << 15;
+/// Local (kernel) debug enable, in MDSCR_EL1.
+const MDSCR_KDE: u64 = 1 << 13;
+/// Trap debug OS-related register accesses, in MDCR_EL2 and MDCR_EL3.
+#[cfg(not(feature = "debug-locked"))]
+const MDCR_TDOSA: u64 = 1 << 10;
+/// Trap debug register accesses, in MDCR_EL2 and MDCR_EL3.
+#[cfg(not(feature = "debug-locked"))]
+const MDCR_TDA: u64 = 1 << 9;
+/// Trap debug ROM address register accesses, in MDCR_EL2.
+#[cfg(not(feature = "debug-locked"))]
+const MDCR_EL2_TDRA: u64 = 1 << 11;
+/// External debugger access to breakpoint and watchpoint registers disabled, in MDCR_EL3.
+#[cfg(any(not(feature = "debug-locked"), feature = "debug-lockdown"))]
+const MDCR_EL3_EDAD: u64 = 1 << 20;
+/// External debugger access to performance monitor registers disabled, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_EPMAD: u64 = 1 << 21;
+/// Secure cycle counter disable, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_SCCD: u64 = 1 << 23;
+/// Secure trace enable, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_STE: u64 = 1 << 18;
+/// Secure performance monitors enable, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_SPME: u64 = 1 << 17;
+/// Secure debug disable, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_SDD: u64 = 1 << 16;
+/// Secure AArch32 privileged debug field, in MDCR_EL3.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_SPD32: u64 = 0b11 << 14;
+/// Secure AArch32 privileged debug disabled, in MDCR_EL3.SPD32.
+#[cfg(feature = "debug-lockdown")]
+const MDCR_EL3_SPD32_DISABLE: u64 = 0b10 << 14;
+/// The value of ID_AA64DFR0_EL1.PMUVer for FEAT_PMUv3p5.
+#[cfg(feature = "debug-lockdown")]
+const ID_AA64DFR0_PMUVER_V3P5: u64 = 6;
+
+/// Trap performance monitor register accesses, in MDCR_EL2 and MDCR_EL3.
+const MDCR_TPM: u64 = 1 << 6;
+/// Trap trace filter control register accesses, in MDCR_EL2 and MDCR_EL3.
+const MDCR_TTRF: u64 = 1 << 19;
+/// The number of event counters accessible from EL1 and EL0, in MDCR_EL2.
+const MDCR_EL2_HPMN_MASK: u64 = 0x1f;
+/// Profiling buffer owning exception level and trap control, in MDCR_EL2.
+const MDCR_EL2_E2PB_MASK: u64 = 0b11 << 12;
+/// Trap statistical profiling control register accesses, in MDCR_EL2.
+const MDCR_EL2_TPMS: u64 = 1 << 14;
+/// Trace buffer owning exception level and trap control, in MDCR_EL2.
+const MDCR_EL2_E2TB_MASK: u64 = 0b11 << 24;
+/// Non-secure profiling buffer ownership and trap control, in MDCR_EL3.
+const MDCR_EL3_NSPB_MASK: u64 = 0b11 << 12;
+/// Non-secure trace buffer ownership and trap control, in MDCR_EL3.
+const MDCR_EL3_NSTB_MASK: u64 = 0b11 << 24;
+/// The number of event counters implemented, in PMCR_EL0.
+const PMCR_N_SHIFT: u32 = 11;
+
+/// Enable bit, in DBGBCR<n>_EL1 and DBGWCR<n>_EL1.
+const DBGXCR_E: u64 = 1 << 0;
+/// Match at EL1 and EL0, in DBGBCR<n>_EL1.PMC and DBGWCR<n>_EL1.PAC.
+const DBGXCR_EL1_EL0: u64 = 0b11 << 1;
+/// Match any A64 instruction at the address, in DBGBCR<n>_EL1.BAS.
+const DBGBCR_BAS_A64: u64 = 0b1111 << 5;
+
+const DBGWCR_BAS_SHIFT: u32 = 5;
+const DBGWCR_LSC_SHIFT: u32 = 3;
+const DBGWCR_MASK_SHIFT: u32 = 24;
+
+/// Applies the given macro to each possible breakpoint or watchpoint register index.
+macro_rules! for_each_debug_register {
+    ($index:expr, $action:ident, $($args:tt)*) => {
+        match $index {
+            0 => $action!(0, $($args)*),
+            1 => $action!(1, $($args)*),
+            2 => $action!(2, $($args)*),
+            3 => $action!(3, $($args)*),
+            4 => $action!(4, $($args)*),
+            5 => $action!(5, $($args)*),
+            6 => $action!(6, $($args)*),
+            7 => $action!(7, $($args)*),
+            8 => $action!(8, $($args)*),
+            9 => $action!(9, $($args)*),
+            10 => $action!(10, $($args)*),
+            11 => $action!(11, $($args)*),
+            12 => $action!(12, $($args)*),
+            13 => $action!(13, $($args)*),
+            14 => $action!(14, $($args)*),
+            15 => $action!(15, $($args)*),
+            _ => unreachable!(),
+        }
+    };
+}
+
+macro_rules! write_breakpoint {
+    ($n:literal, $value:expr, $control:expr) => {{
+        write_sysreg!(concat!("dbgbvr", $n, "_el1"), $value);
+        write_sysreg!(concat!("dbgbcr", $n, "_el1"), $control);
+    }};
+}
+
+macro_rules! write_watchpoint {
+    ($n:literal, $value:expr, $control:expr) => {{
+        write_sysreg!(concat!("dbgwvr", $n, "_el1"), $value);
+        write_sysreg!(concat!("dbgwcr", $n, "_el1"), $control);
+    }};
+}
+
+/// The kinds of data access which a watchpoint can match.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchpointAccess {
+    /// Match loads only.
+    Load = 0b01,
+    /// Match stores only.
+    Store = 0b10,
+    /// Match both loads and stores.
+    LoadStore = 0b11,
+}
+
+/// A debug exception which has been taken.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugException {
+    /// A hardware breakpoint was hit. The address of the instruction is in the saved ELR.
+    Breakpoint,
+    /// A hardware watchpoint was hit.
+    Watchpoint {
+        /// The virtual address of the data access which was matched.
+        address: u64,
+        /// Whether the access was a write.
+        write: bool,
+    },
+    /// A software step completed.
+    SoftwareStep,
+    /// A `BRK` instruction was executed.
+    Brk {
+        /// The immediate value of the `BRK` instruction.
+        comment: u16,
+    },
+}
+
+impl DebugException {
+    /// Decodes the given exception syndrome and fault address register values, returning `None` if
+    /// the exception was not a debug exception.
+    pub fn from_esr(esr: u64, far: u64) -> Option<Self> {
+        let iss = esr & 0x1ff_ffff;
+        match (esr >> 26) & 0x3f {
+            0x30 | 0x31 => Some(Self::Breakpoint),
+            0x32 | 0x33 => Some(Self::SoftwareStep),
+            0x34 | 0x35 => Some(Self::Watchpoint {
+                address: far,
+                write: iss & (1 << 6) != 0,
+            }),
+            0x3c => Some(Self::Brk {
+                comment: iss as u16,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the debug exception currently being handled, if any.
+    ///
+    /// This reads the ESR and FAR of the current exception level, so should be called from a
+    /// synchronous exception handler before anything else could cause another exception.
+    pub fn current() -> Option<Self> {
+        Self::from_esr(read_esr(), read_far())
+    }
+}
+
+/// Returns the number of hardware breakpoints which the CPU supports.
+pub fn breakpoint_count() -> usize {
+    // SAFETY: Reading ID_AA64DFR0_EL1 is always safe.
+    let dfr0 = unsafe { read_sysreg!("id_aa64dfr0_el1") };
+    ((dfr0 >> 12) & 0xf) as usize + 1
+}
+
+/// Returns the number of hardware watchpoints which the CPU supports.
+pub fn watchpoint_count() -> usize {
+    // SAFETY: Reading ID_AA64DFR0_EL1 is always safe.
+    let dfr0 = unsafe { read_sysreg!("id_aa64dfr0_el1") };
+    ((dfr0 >> 20) & 0xf) as usize + 1
+}
+
+/// Enables breakpoint and watchpoint debug exceptions at EL1.
+///
+/// This clears the OS Lock, enables monitor and kernel debug events in MDSCR_EL1, and unmasks debug
+/// exceptions in PSTATE. It must be called on each core which should take debug exceptions.
+pub fn enable_debug_exceptions() {
+    // SAFETY: Enabling debug exceptions doesn't affect memory safety. Any breakpoints or
+    // watchpoints which are hit will be delivered to the exception handlers.
+    unsafe {
+        if read_sysreg!("oslsr_el1") & OSLSR_OSLK != 0 {
+            write_sysreg!("oslar_el1", 0u64);
+        }
+        let mdscr = read_sysreg!("mdscr_el1");
+        write_sysreg!("mdscr_el1", mdscr | MDSCR_MDE | MDSCR_KDE);
+        asm!("isb", "msr daifclr, #8", options(nomem, nostack));
+    }
+}
+
+/// Clears the OS Lock and OS Double Lock, stops debug register accesses from being trapped to the
+/// current exception level if it is EL2 or EL3, and enables monitor debug events.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry points, before any Rust code is run.
+///
+/// Clobbers x9.
+#[cfg(not(feature = "debug-locked"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn configure_debug() {
+    naked_asm!(
+        "msr oslar_el1, xzr",
+        // Only clear the OS Double Lock if FEAT_DoubleLock is implemented.
+        "mrs x9, id_aa64dfr0_el1",
+        "ubfx x9, x9, #36, #4",
+        "cmp x9, #0xf",
+        "b.eq 0f",
+        "msr osdlr_el1, xzr",
+        "0:",
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #3",
+        "b.ne 1f",
+        "mrs x9, mdcr_el3",
+        "bic x9, x9, #{mdcr_untrap}",
+        "bic x9, x9, #{mdcr_el3_edad}",
+        "msr mdcr_el3, x9",
+        "b 2f",
+        "1:",
+        "cmp x9, #2",
+        "b.ne 2f",
+        "mrs x9, mdcr_el2",
+        "bic x9, x9, #{mdcr_el2_untrap}",
+        "msr mdcr_el2, x9",
+        "2:",
+        "mrs x9, mdscr_el1",
+        "orr x9, x9, #{mdscr_mde}",
+        "msr mdscr_el1, x9",
+        "isb",
+        "ret",
+        mdcr_untrap = const MDCR_TDOSA | MDCR_TDA,
+        mdcr_el3_edad = const MDCR_EL3_EDAD,
+        mdcr_el2_untrap = const MDCR_TDOSA | MDCR_TDA | MDCR_EL2_TDRA,
+        mdscr_mde = const MDSCR_MDE,
+    )
+}
+
+/// No-op when the `debug-locked` feature is enabled, so the debug state is left as it was at
+/// reset.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when the
+/// `debug-locked` feature is not enabled.
+#[cfg(all(feature = "debug-locked", not(feature = "debug-lockdown")))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn configure_debug() {
+    naked_asm!("ret")
+}
+
+/// Disables secure self-hosted debug, secure trace and secure performance monitoring, and external
+/// debugger access to the debug and PMU registers, via MDCR_EL3, if running at EL3.
+///
+/// This sets MDCR_EL3.SDD, sets MDCR_EL3.SPD32 to disable secure AArch32 debug (the AArch64
+/// equivalent of SDCR.SPD), sets EDAD and EPMAD, clears STE and SPME, and sets SCCD if FEAT_PMUv3p5
+/// is implemented so that the cycle counter doesn't count in Secure state. Non-secure self-hosted
+/// debug and trace are unaffected.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry points, before any Rust code is run.
+///
+/// Clobbers x9-x10.
+#[cfg(feature = "debug-lockdown")]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn configure_debug() {
+    naked_asm!(
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #3",
+        "b.ne 1f",
+        "mrs x9, mdcr_el3",
+        "ldr x10, ={mdcr_el3_clear}",
+        "bic x9, x9, x10",
+        "ldr x10, ={mdcr_el3_set}",
+        "orr x9, x9, x10",
+        // Only set SCCD if FEAT_PMUv3p5 is implemented, as it is RES0 otherwise.
+        "mrs x10, id_aa64dfr0_el1",
+        "ubfx x10, x10, #8, #4",
+        "cmp x10, #{pmuver_v3p5}",
+        "b.lt 0f",
+        "cmp x10, #0xf",
+        "b.eq 0f",
+        "orr x9, x9, #{mdcr_el3_sccd}",
+        "0:",
+        "msr mdcr_el3, x9",
+        "isb",
+        "1:",
+        "ret",
+        ".ltorg",
+        mdcr_el3_clear = const MDCR_EL3_SPD32 | MDCR_EL3_STE | MDCR_EL3_SPME,
+        mdcr_el3_set = const MDCR_EL3_SDD | MDCR_EL3_SPD32_DISABLE | MDCR_EL3_EDAD
+            | MDCR_EL3_EPMAD,
+        mdcr_el3_sccd = const MDCR_EL3_SCCD,
+        pmuver_v3p5 = const ID_AA64DFR0_PMUVER_V3P5,
+    )
+}
+
+/// Which of the performance monitors, statistical profiling and trace units lower exception levels
+/// may use directly, rather than having their accesses trapped to the current exception level.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LowerElAccess {
+    /// Whether the PMU event counters and control registers are accessible.
+    pub pmu: bool,
+    /// Whether the Statistical Profiling Extension sampling and profiling buffer controls are
+    /// accessible.
+    pub spe: bool,
+    /// Whether the trace filter and trace buffer controls are accessible.
+    pub trace: bool,
+}
+
+impl LowerElAccess {
+    /// Traps all accesses to the PMU, SPE and trace controls.
+    pub const NONE: Self = Self {
+        pmu: false,
+        spe: false,
+        trace: false,
+    };
+
+    /// Gives lower exception levels direct access to the PMU, SPE and trace controls.
+    pub const ALL: Self = Self {
+        pmu: true,
+        spe: true,
+        trace: true,
+    };
+}
+
+/// Sets whether lower exception levels may access the PMU, SPE and trace controls on the current
+/// core, via MDCR_EL2 or MDCR_EL3.
+///
+/// At EL2, exposing the PMU also gives EL1 and EL0 all of the event counters, and hiding SPE or the
+/// trace buffer makes EL2 their owner. At EL3 the settings apply to the Non-secure state, and
+/// exposing SPE or the trace buffer gives them to it. Controls for units which aren't implemented
+/// are left alone, as are the rest of the MDCR bits, including those for debug registers.
+///
+/// # Panics
+///
+/// Panics if not running at EL2 or EL3.
+pub fn set_lower_el_access(access: LowerElAccess) {
+    // SAFETY: Reading ID_AA64DFR0_EL1 is always safe.
+    let dfr0 = unsafe { read_sysreg!("id_aa64dfr0_el1") };
+    let features = DebugFeatures::from_dfr0(dfr0);
+    let mut clear = 0;
+    let mut set = 0;
+    match current_el() {
+        2 => {
+            if features.pmu {
+                clear |= MDCR_TPM;
+                if access.pmu {
+                    // SAFETY: Reading PMCR_EL0 at EL2 is safe when the PMU is implemented.
+                    let counters = (unsafe { read_sysreg!("pmcr_el0") } >> PMCR_N_SHIFT) & 0x1f;
+                    clear |= MDCR_EL2_HPMN_MASK;
+                    set |= counters;
+                } else {
+                    set |= MDCR_TPM;
+                }
+            }
+            if features.spe {
+                clear |= MDCR_EL2_E2PB_MASK | MDCR_EL2_TPMS;
+                set |= if access.spe {
+                    MDCR_EL2_E2PB_MASK
+                } else {
+                    MDCR_EL2_TPMS
+                };
+            }
+            if features.trace_filter {
+                clear |= MDCR_TTRF;
+                if !access.trace {
+                    set |= MDCR_TTRF;
+                }
+            }
+            if features.trace_buffer {
+                clear |= MDCR_EL2_E2TB_MASK;
+                if access.trace {
+                    set |= MDCR_EL2_E2TB_MASK;
+                }
+            }
+            // SAFETY: We are running at EL2, so can access MDCR_EL2. Trapping or untrapping these
+            // registers doesn't affect memory safety, and stopping the profiling or trace buffer
+            // from writing to memory owned by EL1 only makes it safer.
+            unsafe {
+                let mdcr = read_sysreg!("mdcr_el2") & !clear;
+                write_sysreg!("mdcr_el2", mdcr | set);
+            }
+        }
+        3 => {
+            if features.pmu {
+                clear |= MDCR_TPM;
+                if !access.pmu {
+                    set |= MDCR_TPM;
+                }
+            }
+            if features.spe {
+                clear |= MDCR_EL3_NSPB_MASK;
+                if access.spe {
+                    set |= MDCR_EL3_NSPB_MASK;
+                }
+            }
+            if features.trace_filter {
+                clear |= MDCR_TTRF;
+                if !access.trace {
+                    set |= MDCR_TTRF;
+                }
+            }
+            if features.trace_buffer {
+                clear |= MDCR_EL3_NSTB_MASK;
+                if access.trace {
+                    set |= MDCR_EL3_NSTB_MASK;
+                }
+            }
+            // SAFETY: We are running at EL3, so can access MDCR_EL3. Trapping or untrapping these
+            // registers doesn't affect memory safety.
+            unsafe {
+                let mdcr = read_sysreg!("mdcr_el3") & !clear;
+                write_sysreg!("mdcr_el3", mdcr | set);
+            }
+        }
+        el => panic!("Lower EL access to PMU, SPE and trace can't be configured at EL{el}"),
+    }
+    // SAFETY: An ISB doesn't affect memory safety.
+    unsafe {
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns which of the PMU, SPE and trace controls lower exception levels may access on the
+/// current core, as set by [`set_lower_el_access`] or inherited from reset or earlier firmware.
+///
+/// Units which aren't implemented are reported as not accessible.
+///
+/// # Panics
+///
+/// Panics if not running at EL2 or EL3.
+pub fn lower_el_access() -> LowerElAccess {
+    // SAFETY: Reading ID_AA64DFR0_EL1 is always safe.
+    let dfr0 = unsafe { read_sysreg!("id_aa64dfr0_el1") };
+    let features = DebugFeatures::from_dfr0(dfr0);
+    let (mdcr, spe_owned, trace_buffer_owned) = match current_el() {
+        2 => {
+            // SAFETY: We are running at EL2, so can read MDCR_EL2.
+            let mdcr = unsafe { read_sysreg!("mdcr_el2") };
+            (
+                mdcr,
+                mdcr & MDCR_EL2_E2PB_MASK == MDCR_EL2_E2PB_MASK && mdcr & MDCR_EL2_TPMS == 0,
+                mdcr & MDCR_EL2_E2TB_MASK == MDCR_EL2_E2TB_MASK,
+            )
+        }
+        3 => {
+            // SAFETY: We are running at EL3, so can read MDCR_EL3.
+            let mdcr = unsafe { read_sysreg!("mdcr_el3") };
+            (
+                mdcr,
+                mdcr & MDCR_EL3_NSPB_MASK == MDCR_EL3_NSPB_MASK,
+                mdcr & MDCR_EL3_NSTB_MASK == MDCR_EL3_NSTB_MASK,
+            )
+        }
+        el => panic!("Lower EL access to PMU, SPE and trace can't be read at EL{el}"),
+    };
+    LowerElAccess {
+        pmu: features.pmu && mdcr & MDCR_TPM == 0,
+        spe: features.spe && spe_owned,
+        trace: (features.trace_filter || features.trace_buffer)
+            && (!features.trace_filter || mdcr & MDCR_TTRF == 0)
+            && (!features.trace_buffer || trace_buffer_owned),
+    }
+}
+
+/// The units controlled by [`set_lower_el_access`] which are implemented, from ID_AA64DFR0_EL1.
+struct DebugFeatures {
+    pmu: bool,
+    spe: bool,
+    trace_filter: bool,
+    trace_buffer: bool,
+}
+
+impl DebugFeatures {
+    fn from_dfr0(dfr0: u64) -> Self {
+        let pmu_version = (dfr0 >> 8) & 0xf;
+        Self {
+            // 0xf means an IMPLEMENTATION DEFINED PMU rather than PMUv3.
+            pmu: pmu_version != 0 && pmu_version != 0xf,
+            spe: (dfr0 >> 32) & 0xf != 0,
+            trace_filter: (dfr0 >> 40) & 0xf != 0,
+            trace_buffer: (dfr0 >> 44) & 0xf != 0,
+        }
+    }
+}
+
+/// Sets hardware breakpoint `index` to match execution of the instruction at the given address.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`breakpoint_count`], or `address` is not 4-byte aligned.
+pub fn set_breakpoint(index: usize, address: usize) {
+    assert!(index < breakpoint_count());
+    assert!(address.is_multiple_of(4));
+    // SAFETY: Setting a breakpoint doesn't affect memory safety; hitting it will just cause a debug
+    // exception to be delivered to the exception handlers.
+    unsafe {
+        for_each_debug_register!(
+            index,
+            write_breakpoint,
+            address as u64,
+            DBGXCR_E | DBGXCR_EL1_EL0 | DBGBCR_BAS_A64
+        );
+        asm!("isb", options(nomem, nostack));
+    }
+}
+
+/// Disables hardware breakpoint `index`.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`breakpoint_count`].
+pub fn clear_breakpoint(index: usize) {
+    assert!(index < breakpoint_count());
+    // SAFETY: Disabling a breakpoint doesn't affect memory safety.
+    unsafe {
+        for_each_debug_register!(index, write_breakpoint, 0u64, 0u64);
+        asm!("isb", options(nomem, nostack));
+    }
+}
+
+/// Sets hardware watchpoint `index` to match the given kinds of access to the `len` bytes starting
+/// at `address`.
+///
+/// The watched region must either lie within a single aligned 8-byte doubleword, or be a power of
+/// two in size and aligned to its size.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`watchpoint_count`], or the region is not one that can be
+/// watched.
+pub fn set_watchpoint(index: usize, address: usize, len: usize, access: WatchpointAccess) {
+    assert!(index < watchpoint_count());
+    assert!(len > 0);
+    let offset = address % 8;
+    let (value, byte_select, mask) = if offset + len <= 8 {
+        (address - offset, ((1u64 << len) - 1) << offset, 0)
+    } else {
+        assert!(len.is_power_of_two() && address.is_multiple_of(len) && len <= 1 << 31);
+        (address, 0xff, len.trailing_zeros() as u64)
+    };
+    let control = DBGXCR_E
+        | DBGXCR_EL1_EL0
+        | (access as u64) << DBGWCR_LSC_SHIFT
+        | byte_select << DBGWCR_BAS_SHIFT
+        | mask << DBGWCR_MASK_SHIFT;
+    // SAFETY: Setting a watchpoint doesn't affect memory safety; hitting it will just cause a debug
+    // exception to be delivered to the exception handlers.
+    unsafe {
+        for_each_debug_register!(index, write_watchpoint, value as u64, control);
+        asm!("isb", options(nomem, nostack));
+    }
+}
+
+/// Disables hardware watchpoint `index`.
+///
+/// # Panics
+///
+/// Panics if `index` is not less than [`watchpoint_count`].
+pub fn clear_watchpoint(index: usize) {
+    assert!(index < watchpoint_count());
+    // SAFETY: Disabling a watchpoint doesn't affect memory safety.
+    unsafe {
+        for_each_debug_register!(index, write_watchpoint, 0u64, 0u64);
+        asm!("isb", options(nomem, nostack));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! DMA buffers, either allocated from a pool of non-cacheable memory or wrapping cacheable memory
+//! with the necessary cache maintenance.
+//!
+//! An image reserves the pool with the [`dma_pool!`](crate::dma_pool) macro, which the linker
+//! script places in the `.dma` section. Drivers can then allocate buffers from it with
+//! [`dma_alloc`], which are coherent with devices that don't snoop the caches, without having to
+//! do any cache maintenance themselves:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{dma::dma_alloc, dma_pool};
+//!
+//! // Reserve 64 KiB for DMA buffers.
+//! dma_pool!(16);
+//!
+//! let buffer = dma_alloc(4096, 64)?;
+//! // Pass `buffer.phys()` to the device, and access the buffer through `buffer.virt()`.
+//! ```
+//!
+//! The first call to `dma_alloc` remaps the pool as normal non-cacheable memory with
+//! [`make_non_cacheable`], unless it is already mapped that way. This only works if the active
+//! translation tables map the pool with its own pages or blocks, as `make_non_cacheable` can't
+//! split a block which is shared with the rest of the image while it is running. Otherwise the
+//! initial pagetable must map it as non-cacheable itself, or `dma_alloc` returns
+//! [`DmaAllocError::Unmappable`]. Buffers are never freed.
+//!
+//! Alternatively, an ordinary cacheable buffer can be lent to a device by wrapping it in a
+//! [`DmaBuffer`], which cleans and invalidates the data cache as needed when the device is given
+//! the buffer and when it is finished with it:
+//!
+//! ```rust,ignore
+//! let dma = DmaBuffer::new(&mut receive_buffer, DmaDirection::FromDevice)?;
+//! // Pass `dma.phys()` to the device, and wait for it to finish writing.
+//! let received = dma.finish();
+//! ```
+//!
+//! Such buffers must be aligned to the cache [`writeback_granule`], and be a multiple of it in
+//! length, if the device writes to them. Otherwise [`DmaBuffer::with_bounce`] can copy the data
+//! through a [`CoherentBuffer`] instead. The bounce buffer is only borrowed, so it can be reused
+//! for further transfers once the `DmaBuffer` is finished with.
+
+use crate::{
+    address::{PhysAddr, VirtAddr, virt_to_phys},
+    cache::{clean_dcache_range, clean_invalidate_dcache_range, writeback_granule},
+    mapping::{Attributes, MapError, find_entry, make_non_cacheable},
+};
+use core::{
+    hint::spin_loop,
+    mem::ManuallyDrop,
+    ptr::{NonNull, addr_of, copy_nonoverlapping},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+/// The attribute index bits of a block or page descriptor.
+const DESC_ATTRIBUTE_INDEX_MASK: u64 = 0b111 << 2;
+
+unsafe extern "C" {
+    static dma_begin: u8;
+    static dma_end: u8;
+}
+
+/// Memory reserved for DMA buffers.
+///
+/// This is used by the [`dma_pool!`](crate::dma_pool) macro.
+#[repr(C, align(4096))]
+pub struct DmaPool<const NUM_PAGES: usize>([DmaPage; NUM_PAGES]);
+
+impl<const NUM_PAGES: usize> DmaPool<NUM_PAGES> {
+    /// Creates a new zero-initialised pool.
+    pub const fn new() -> Self {
+        Self([const { DmaPage([0; 4096]) }; NUM_PAGES])
+    }
+}
+
+impl<const NUM_PAGES: usize> Default for DmaPool<NUM_PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C, align(4096))]
+struct DmaPage([u8; 4096]);
+
+/// Reserves the given number of 4 KiB pages as the pool for [`dma_alloc`](crate::dma::dma_alloc).
+///
+/// This should be used at most once in an image.
+#[macro_export]
+macro_rules! dma_pool {
+    ($pages:expr) => {
+        #[used]
+        #[unsafe(link_section = ".dma.pool")]
+        static mut __DMA_POOL: $crate::dma::DmaPool<{ $pages }> = $crate::dma::DmaPool::new();
+    };
+}
+
+/// A buffer allocated from the non-cacheable DMA pool.
+///
+/// Each buffer is only returned once by [`dma_alloc`], and can't be copied, so whoever owns it has
+/// exclusive use of its memory.
+#[derive(Debug, Eq, PartialEq)]
+pub struct CoherentBuffer {
+    virt: NonNull<u8>,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl CoherentBuffer {
+    /// Returns the address at which the buffer can be accessed.
+    pub fn virt(&self) -> NonNull<u8> {
+        self.virt
+    }
+
+    /// Returns the physical address of the buffer, to give to devices.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// An error allocating a buffer from the DMA pool.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DmaAllocError {
+    /// No pool was reserved with [`dma_pool!`](crate::dma_pool).
+    NoPool,
+    /// There isn't enough space left in the pool.
+    OutOfSpace,
+    /// The pool isn't mapped as non-cacheable and couldn't be remapped, e.g. because it shares a
+    /// block mapping with the rest of the image.
+    Unmappable,
+}
+
+/// The number of bytes of the pool which have been allocated.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Whether the pool has been mapped as non-cacheable, one of the `POOL_*` values.
+static POOL_STATE: AtomicU8 = AtomicU8::new(POOL_UNMAPPED);
+
+/// The pool hasn't been mapped as non-cacheable yet.
+const POOL_UNMAPPED: u8 = 0;
+/// A core is mapping the pool as non-cacheable.
+const POOL_MAPPING: u8 = 1;
+/// The pool is mapped as non-cacheable.
+const POOL_MAPPED: u8 = 2;
+/// The pool couldn't be mapped as non-cacheable.
+const POOL_UNMAPPABLE: u8 = 3;
+
+/// Returns the start and length of the DMA pool.
+fn pool() -> (usize, usize) {
+    let begin = addr_of!(dma_begin) as usize;
+    let end = addr_of!(dma_end) as usize;
+    (begin, end - begin)
+}
+
+/// Maps the pool as non-cacheable if it isn't already.
+///
+/// Only the first caller remaps the pool. Callers on other cores meanwhile wait for it to finish.
+fn ensure_mapped(begin: usize, len: usize) -> Result<(), DmaAllocError> {
+    loop {
+        match POOL_STATE.compare_exchange_weak(
+            POOL_UNMAPPED,
+            POOL_MAPPING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(POOL_MAPPED) => return Ok(()),
+            Err(POOL_UNMAPPABLE) => return Err(DmaAllocError::Unmappable),
+            Err(_) => spin_loop(),
+        }
+    }
+    // SAFETY: Nothing has been allocated from the pool yet, so nothing can be accessing it, and it
+    // is only mapped by the image's own mapping. No other core is changing its mapping, as they
+    // wait until POOL_STATE is no longer POOL_MAPPING.
+    let result =
+        match unsafe { make_non_cacheable(VirtAddr(begin), len, Attributes::NORMAL_NON_CACHEABLE) }
+        {
+            Ok(()) => Ok(()),
+            Err(MapError::PartialBlock(va)) if is_non_cacheable(va) => Ok(()),
+            Err(_) => Err(DmaAllocError::Unmappable),
+        };
+    let state = if result.is_ok() {
+        POOL_MAPPED
+    } else {
+        POOL_UNMAPPABLE
+    };
+    POOL_STATE.store(state, Ordering::Release);
+    result
+}
+
+/// Returns whether the given address is already mapped as normal non-cacheable memory.
+fn is_non_cacheable(va: VirtAddr) -> bool {
+    let Ok((entry, _)) = find_entry(va) else {
+        return false;
+    };
+    // SAFETY: `find_entry` returns a pointer to an entry in the active translation tables.
+    let desc = unsafe { entry.read_volatile() };
+    desc & DESC_ATTRIBUTE_INDEX_MASK == Attributes::ATTRIBUTE_INDEX_2.0
+}
+
+/// Allocates a buffer of `len` bytes with the given alignment from the DMA pool.
+///
+/// The first call remaps the pool as non-cacheable if needed, as described in the
+/// [module documentation](self).
+///
+/// # Panics
+///
+/// Panics if `align` isn't a power of two.
+pub fn dma_alloc(len: usize, align: usize) -> Result<CoherentBuffer, DmaAllocError> {
+    assert!(align.is_power_of_two());
+    let (begin, pool_len) = pool();
+    if pool_len == 0 {
+        return Err(DmaAllocError::NoPool);
+    }
+    ensure_mapped(begin, pool_len)?;
+    let mut start = 0;
+    ALLOCATED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
+            start = (begin + allocated).checked_next_multiple_of(align)? - begin;
+            let end = start.checked_add(len)?;
+            (end <= pool_len).then_some(end)
+        })
+        .map_err(|_| DmaAllocError::OutOfSpace)?;
+    let virt = VirtAddr(begin + start);
+    Ok(CoherentBuffer {
+        virt: NonNull::new(virt.as_mut_ptr()).ok_or(DmaAllocError::NoPool)?,
+        phys: virt_to_phys(virt),
+        len,
+    })
+}
+
+/// Which way data is transferred by DMA to or from a [`DmaBuffer`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DmaDirection {
+    /// The device only reads from the buffer.
+    ToDevice,
+    /// The device only writes to the buffer.
+    FromDevice,
+    /// The device may both read from and write to the buffer.
+    Bidirectional,
+}
+
+/// An error lending a buffer to a device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DmaError {
+    /// The device may write to the buffer, but it isn't aligned to the cache writeback granule or
+    /// a multiple of it in length.
+    Misaligned,
+    /// The bounce buffer is smaller than the buffer.
+    BounceTooSmall,
+}
+
+/// A cacheable buffer which has been lent to a device for DMA.
+///
+/// Creating it does the cache maintenance needed before the device accesses the buffer, and
+/// [`finish`](Self::finish) (or dropping it) does the cache maintenance needed before the CPU
+/// accesses it again. The buffer is mutably borrowed meanwhile, so the CPU can't access it while
+/// the device may be.
+#[derive(Debug)]
+pub struct DmaBuffer<'a> {
+    buffer: &'a mut [u8],
+    direction: DmaDirection,
+    bounce: Option<&'a mut CoherentBuffer>,
+}
+
+impl<'a> DmaBuffer<'a> {
+    /// Prepares the given buffer for the device to access it in the given direction.
+    ///
+    /// If the device may write to the buffer, then it must be aligned to the cache
+    /// [`writeback_granule`] and a multiple of it in length, as otherwise writing back a cache line
+    /// containing some other data could overwrite what the device has written.
+    pub fn new(buffer: &'a mut [u8], direction: DmaDirection) -> Result<Self, DmaError> {
+        if direction == DmaDirection::ToDevice {
+            // Make sure the device reads what the CPU wrote.
+            clean_dcache_range(buffer.as_ptr(), buffer.len());
+        } else {
+            let granule = writeback_granule();
+            if !(buffer.as_ptr() as usize).is_multiple_of(granule)
+                || !buffer.len().is_multiple_of(granule)
+            {
+                return Err(DmaError::Misaligned);
+            }
+            // Make sure no dirty lines are written back over what the device writes.
+            clean_invalidate_dcache_range(buffer.as_ptr(), buffer.len());
+        }
+        Ok(Self {
+            buffer,
+            direction,
+            bounce: None,
+        })
+    }
+
+    /// Prepares the given buffer for the device to access it in the given direction, via the given
+    /// non-cacheable bounce buffer.
+    ///
+    /// The device accesses the bounce buffer rather than `buffer`, so `buffer` needn't be aligned.
+    /// Its contents are copied to the bounce buffer first unless the direction is
+    /// [`DmaDirection::FromDevice`], and copied back when finished unless it is
+    /// [`DmaDirection::ToDevice`]. The bounce buffer is borrowed until then, so it can be reused
+    /// afterwards.
+    pub fn with_bounce(
+        buffer: &'a mut [u8],
+        direction: DmaDirection,
+        bounce: &'a mut CoherentBuffer,
+    ) -> Result<Self, DmaError> {
+        if bounce.len < buffer.len() {
+            return Err(DmaError::BounceTooSmall);
+        }
+        if direction != DmaDirection::FromDevice {
+            // SAFETY: The bounce buffer is valid for at least `buffer.len()` bytes, and is
+            // non-cacheable memory which doesn't overlap `buffer`. We have borrowed it mutably, so
+            // nothing else can be accessing it.
+            unsafe {
+                copy_nonoverlapping(buffer.as_ptr(), bounce.virt.as_ptr(), buffer.len());
+            }
+        }
+        Ok(Self {
+            buffer,
+            direction,
+            bounce: Some(bounce),
+        })
+    }
+
+    /// Returns the physical address for the device to access.
+    pub fn phys(&self) -> PhysAddr {
+        match &self.bounce {
+            Some(bounce) => bounce.phys,
+            None => virt_to_phys(VirtAddr::from_ptr(self.buffer.as_ptr())),
+        }
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Makes whatever the device wrote visible to the CPU, and returns the buffer.
+    ///
+    /// This must only be called once the device has finished accessing the buffer.
+    pub fn finish(self) -> &'a mut [u8] {
+        let mut this = ManuallyDrop::new(self);
+        this.complete();
+        // SAFETY: `this` is never used or dropped again, so moving the buffer out is fine.
+        unsafe { core::ptr::read(&this.buffer) }
+    }
+
+    fn complete(&mut self) {
+        if self.direction == DmaDirection::ToDevice {
+            return;
+        }
+        match &self.bounce {
+            // SAFETY: The bounce buffer is valid for at least `buffer.len()` bytes, and doesn't
+            // overlap `buffer`. We have borrowed it mutably, so nothing else can be accessing it.
+            Some(bounce) => unsafe {
+                copy_nonoverlapping(
+                    bounce.virt.as_ptr(),
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len(),
+                );
+            },
+            // The buffer was cleaned and the CPU hasn't written to it since, so this only discards
+            // any lines which were speculatively fetched while the device was writing.
+            None => clean_invalidate_dcache_range(self.buffer.as_ptr(), self.buffer.len()),
+        }
+    }
+}
+
+impl Drop for DmaBuffer<'_> {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
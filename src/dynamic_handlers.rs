@@ -0,0 +1,141 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Exception handlers which can be replaced at runtime.
+//!
+//! As an alternative to implementing [`ExceptionHandlers`] for a fixed type, an image can register
+//! [`DynamicHandlers`], which call through a function pointer for each kind of exception. The
+//! pointers can be set and replaced at any time with [`set_handler`], e.g. by plugins, or by a test
+//! harness which temporarily intercepts faults:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::dynamic_handlers::{DynamicHandlers, ExceptionKind, set_handler};
+//!
+//! exception_handlers!(DynamicHandlers);
+//!
+//! extern "C" fn expect_fault(register_state: RegisterStateRef) {
+//!     // Check the fault and skip the faulting instruction.
+//! }
+//!
+//! let previous = set_handler(ExceptionKind::SyncCurrent, Some(expect_fault));
+//! // Do something which should fault.
+//! set_handler(ExceptionKind::SyncCurrent, previous);
+//! ```
+//!
+//! Exceptions of a kind with no handler set are passed to the default implementations of the
+//! [`ExceptionHandlers`] methods.
+
+use crate::{ExceptionHandlers, RegisterStateRef};
+use core::{
+    arch::asm,
+    mem::transmute,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A function to handle an exception.
+pub type Handler = extern "C" fn(register_state: RegisterStateRef);
+
+/// The number of variants of [`ExceptionKind`].
+const EXCEPTION_KIND_COUNT: usize = 8;
+
+/// The handler function pointer for each kind of exception, or null if none has been set.
+static HANDLERS: [AtomicPtr<()>; EXCEPTION_KIND_COUNT] =
+    [const { AtomicPtr::new(null_mut()) }; EXCEPTION_KIND_COUNT];
+
+/// A kind of exception, corresponding to one of the [`ExceptionHandlers`] methods.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExceptionKind {
+    /// A synchronous exception from the current exception level.
+    SyncCurrent = 0,
+    /// An IRQ from the current exception level.
+    IrqCurrent = 1,
+    /// An FIQ from the current exception level.
+    FiqCurrent = 2,
+    /// An SError from the current exception level.
+    SErrorCurrent = 3,
+    /// A synchronous exception from a lower exception level.
+    SyncLower = 4,
+    /// An IRQ from a lower exception level.
+    IrqLower = 5,
+    /// An FIQ from a lower exception level.
+    FiqLower = 6,
+    /// An SError from a lower exception level.
+    SErrorLower = 7,
+}
+
+/// Sets the handler for the given kind of exception on all cores, or removes it if `handler` is
+/// `None`, and returns the previous handler.
+///
+/// Exceptions taken after this returns use the new handler.
+pub fn set_handler(kind: ExceptionKind, handler: Option<Handler>) -> Option<Handler> {
+    let previous = HANDLERS[kind as usize].swap(
+        handler.map_or(null_mut(), |h| h as *mut ()),
+        Ordering::AcqRel,
+    );
+    // SAFETY: An ISB doesn't affect memory safety.
+    unsafe {
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    to_handler(previous)
+}
+
+/// Returns the handler currently set for the given kind of exception, if any.
+pub fn handler(kind: ExceptionKind) -> Option<Handler> {
+    to_handler(HANDLERS[kind as usize].load(Ordering::Acquire))
+}
+
+fn to_handler(pointer: *mut ()) -> Option<Handler> {
+    if pointer.is_null() {
+        None
+    } else {
+        // SAFETY: The only non-null values stored in `HANDLERS` are valid `Handler` pointers.
+        Some(unsafe { transmute::<*mut (), Handler>(pointer) })
+    }
+}
+
+/// The default implementations of the exception handlers, used when no handler is set.
+struct DefaultHandlers;
+
+impl ExceptionHandlers for DefaultHandlers {}
+
+/// An implementation of [`ExceptionHandlers`] which calls the handlers set with [`set_handler`],
+/// to be registered with [`exception_handlers!`](crate::exception_handlers).
+pub struct DynamicHandlers;
+
+impl ExceptionHandlers for DynamicHandlers {
+    extern "C" fn sync_current(register_state: RegisterStateRef) {
+        handler(ExceptionKind::SyncCurrent).unwrap_or(DefaultHandlers::sync_current)(register_state)
+    }
+
+    extern "C" fn irq_current(register_state: RegisterStateRef) {
+        handler(ExceptionKind::IrqCurrent).unwrap_or(DefaultHandlers::irq_current)(register_state)
+    }
+
+    extern "C" fn fiq_current(register_state: RegisterStateRef) {
+        handler(ExceptionKind::FiqCurrent).unwrap_or(DefaultHandlers::fiq_current)(register_state)
+    }
+
+    extern "C" fn serror_current(register_state: RegisterStateRef) {
+        handler(ExceptionKind::SErrorCurrent).unwrap_or(DefaultHandlers::serror_current)(
+            register_state,
+        )
+    }
+
+    extern "C" fn sync_lower(register_state: RegisterStateRef) {
+        handler(ExceptionKind::SyncLower).unwrap_or(DefaultHandlers::sync_lower)(register_state)
+    }
+
+    extern "C" fn irq_lower(register_state: RegisterStateRef) {
+        handler(ExceptionKind::IrqLower).unwrap_or(DefaultHandlers::irq_lower)(register_state)
+    }
+
+    extern "C" fn fiq_lower(register_state: RegisterStateRef) {
+        handler(ExceptionKind::FiqLower).unwrap_or(DefaultHandlers::fiq_lower)(register_state)
+    }
+
+    extern "C" fn serror_lower(register_state: RegisterStateRef) {
+        handler(ExceptionKind::SErrorLower).unwrap_or(DefaultHandlers::serror_lower)(register_state)
+    }
+}
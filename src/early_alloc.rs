@@ -0,0 +1,191 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A bump allocator over the RAM after the image, for allocations needed before a real heap is
+//! set up, such as pagetable pools, a copy of the device tree or per-core structures.
+//!
+//! The allocator covers the memory from the end of the image, after its stacks and DMA pool, to
+//! the end of the RAM region which the image is linked into, given by the layout file or
+//! `AARCH64_RT_RAM_ORIGIN` and `AARCH64_RT_RAM_LENGTH`. If the RAM actually available is
+//! different, or something else such as the device tree lives in it, the end can be changed with
+//! [`set_early_alloc_end`] or found from the device tree with [`set_early_alloc_end_from_fdt`]
+//! before anything is allocated:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::early_alloc::{early_alloc, set_early_alloc_end_from_fdt};
+//!
+//! // SAFETY: The bootloader passed a valid device tree blob, and the RAM it describes after the
+//! // image isn't used for anything else.
+//! unsafe { set_early_alloc_end_from_fdt(fdt) };
+//! let pool = early_alloc(16 * 4096, 4096).unwrap();
+//! ```
+//!
+//! The memory must be mapped as normal memory by the active translation tables, and isn't zeroed.
+//! Allocations are never freed.
+
+use crate::{
+    address::{PhysAddr, VirtAddr, phys_to_virt, virt_to_phys},
+    fdt::{FDT_BEGIN_NODE, FDT_END_NODE, FDT_NOP, FDT_PROP, align4, c_str, fdt_slice, read_be32},
+};
+use core::{
+    ptr::{NonNull, addr_of},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+unsafe extern "C" {
+    static early_alloc_begin: u8;
+    static early_alloc_end: u8;
+}
+
+/// The address of the next free byte, or 0 if nothing has been allocated yet.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+/// The end of the memory available to allocate from, or 0 to use the end of the RAM region from
+/// the linker script.
+static END: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the start of the memory available to allocate from.
+fn begin() -> usize {
+    addr_of!(early_alloc_begin) as usize
+}
+
+/// Returns the end of the memory available to allocate from.
+fn end() -> usize {
+    match END.load(Ordering::Relaxed) {
+        0 => addr_of!(early_alloc_end) as usize,
+        end => end,
+    }
+}
+
+/// Sets the end of the memory available to [`early_alloc`], in place of the end of the RAM region
+/// from the linker script.
+///
+/// # Safety
+///
+/// The memory from the end of the image up to `end` must be RAM which is mapped as normal memory
+/// and not used for anything else, such as the device tree or another image.
+pub unsafe fn set_early_alloc_end(end: usize) {
+    END.store(end, Ordering::Relaxed);
+}
+
+/// Allocates `size` bytes with the given alignment from the RAM after the image.
+///
+/// The memory isn't initialised. Returns `None` if there isn't enough space left.
+///
+/// # Panics
+///
+/// Panics if `align` isn't a power of two.
+pub fn early_alloc(size: usize, align: usize) -> Option<NonNull<u8>> {
+    assert!(align.is_power_of_two());
+    let end = end();
+    let mut start = 0;
+    NEXT.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+        let next = if next == 0 { begin() } else { next };
+        start = next.checked_next_multiple_of(align)?;
+        let next = start.checked_add(size)?;
+        (next <= end).then_some(next)
+    })
+    .ok()?;
+    NonNull::new(start as *mut u8)
+}
+
+/// Returns the number of bytes which are still available to [`early_alloc`], ignoring alignment.
+pub fn early_alloc_remaining() -> usize {
+    let next = match NEXT.load(Ordering::Relaxed) {
+        0 => begin(),
+        next => next,
+    };
+    end().saturating_sub(next)
+}
+
+/// Sets the end of the memory available to [`early_alloc`] to the end of the `/memory` range in
+/// the given device tree which contains the image, or the start of the device tree itself if that
+/// is before it.
+///
+/// Returns the new end, or `None` if the device tree is invalid or no memory range contains the
+/// image, in which case the end is left unchanged. `/memreserve/` entries and `/reserved-memory`
+/// nodes aren't taken into account.
+///
+/// # Safety
+///
+/// `fdt` must point to a valid flattened device tree blob. The memory it describes after the image
+/// must be mapped as normal memory and not used for anything else.
+pub unsafe fn set_early_alloc_end_from_fdt(fdt: *const u8) -> Option<usize> {
+    let begin = begin();
+    // SAFETY: Our caller promised that `fdt` points to a valid device tree blob.
+    let fdt = unsafe { fdt_slice(fdt)? };
+    let mut end = memory_end(fdt, begin)?;
+    let fdt_begin = fdt.as_ptr() as usize;
+    if (begin..end).contains(&fdt_begin) {
+        end = fdt_begin;
+    }
+    // SAFETY: Our caller promised that the memory is available.
+    unsafe { set_early_alloc_end(end) };
+    Some(end)
+}
+
+/// Returns the virtual address of the end of the `/memory` range in the device tree which contains
+/// the given virtual address.
+fn memory_end(fdt: &[u8], address: usize) -> Option<usize> {
+    let physical = virt_to_phys(VirtAddr(address)).0;
+    let structs = read_be32(fdt, 8)? as usize;
+    let strings = read_be32(fdt, 12)? as usize;
+    let mut offset = structs;
+    let mut depth = 0;
+    let mut in_memory = false;
+    let mut address_cells = 2;
+    let mut size_cells = 1;
+    loop {
+        let token = read_be32(fdt, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(fdt.get(offset..)?)?;
+                offset = align4(offset + name.len() + 1);
+                depth += 1;
+                in_memory = depth == 2 && (name == b"memory" || name.starts_with(b"memory@"));
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+                in_memory = false;
+            }
+            FDT_PROP => {
+                let len = read_be32(fdt, offset)? as usize;
+                let name_offset = read_be32(fdt, offset + 4)? as usize;
+                let value = fdt.get(offset + 8..offset + 8 + len)?;
+                offset = align4(offset + 8 + len);
+                let name = c_str(fdt.get(strings + name_offset..)?)?;
+                match name {
+                    b"#address-cells" if depth == 1 => address_cells = read_be32(value, 0)?,
+                    b"#size-cells" if depth == 1 => size_cells = read_be32(value, 0)?,
+                    b"reg" if in_memory => {
+                        let entry_len = (address_cells + size_cells) as usize * 4;
+                        for entry in value.chunks_exact(entry_len) {
+                            let (base, size) = entry.split_at(address_cells as usize * 4);
+                            let base = read_cells(base)?;
+                            let end = base.checked_add(read_cells(size)?)?;
+                            if (base..end).contains(&physical) {
+                                return Some(phys_to_virt(PhysAddr(end)).0);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            _ => return None,
+        }
+    }
+}
+
+/// Reads a big-endian number of one or two cells.
+fn read_cells(cells: &[u8]) -> Option<usize> {
+    match cells.len() {
+        4 => Some(read_be32(cells, 0)? as usize),
+        8 => Some(((read_be32(cells, 0)? as usize) << 32) | read_be32(cells, 4)? as usize),
+        _ => None,
+    }
+}
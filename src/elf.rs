@@ -0,0 +1,425 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Loading and entering an embedded ELF64 payload, such as a BL33 or another image built with this
+//! crate, for multi-stage firmware.
+//!
+//! [`ElfImage::parse`] checks that a little-endian AArch64 ELF64 executable is well-formed, and
+//! [`ElfImage::load`] copies each `PT_LOAD` segment to its physical address, zeroes the rest of
+//! its memory size (e.g. its `.bss`) and cleans it to the point of coherency. [`enter_payload`] then
+//! jumps to the entry point at the chosen exception level, or [`boot_elf`] does all three:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::elf::{PayloadEl, boot_elf};
+//!
+//! static BL33: &[u8] = include_bytes!("bl33.elf");
+//!
+//! // SAFETY: The payload's segments don't overlap this image or anything else in use, and we don't
+//! // need anything to keep running afterwards.
+//! let error = unsafe { boot_elf(BL33, PayloadEl::El2, [fdt_address, 0, 0, 0]) }.unwrap_err();
+//! panic!("Failed to boot payload: {error:?}");
+//! ```
+//!
+//! When dropping from EL3 the payload is entered in Non-secure state. The target exception level is
+//! entered in AArch64 state at its own stack pointer, with its MMU and caches disabled and all
+//! interrupts masked. If the payload is entered at the current exception level instead, the MMU and
+//! data cache are disabled first, which requires this image to be identity mapped.
+
+use crate::{
+    address::{PhysAddr, phys_to_virt, virt_offset},
+    cache::clean_dcache_range,
+    sysreg::{
+        SCTLR_C, SCTLR_EL1_RES1, SCTLR_EL2_RES1, SCTLR_M, current_el, read_sysreg, write_sysreg,
+    },
+    timer::stop_periodic_tick,
+};
+use core::{
+    arch::{asm, naked_asm},
+    convert::Infallible,
+    ptr::{copy_nonoverlapping, write_bytes},
+};
+
+/// The size of an ELF64 file header.
+const EHDR_SIZE: usize = 64;
+/// The size of an ELF64 program header.
+const PHDR_SIZE: usize = 56;
+/// The ELF magic number at the start of the identification bytes.
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// `ELFCLASS64` in `e_ident[EI_CLASS]`.
+const ELFCLASS64: u8 = 2;
+/// `ELFDATA2LSB` in `e_ident[EI_DATA]`.
+const ELFDATA2LSB: u8 = 1;
+/// `ET_EXEC`, an executable file, in `e_type`.
+const ET_EXEC: u16 = 2;
+/// `EM_AARCH64` in `e_machine`.
+const EM_AARCH64: u16 = 183;
+/// `PT_LOAD`, a loadable segment, in `p_type`.
+const PT_LOAD: u32 = 1;
+/// `PF_X`, an executable segment, in `p_flags`.
+const PF_X: u32 = 1 << 0;
+
+/// Non-secure state for lower exception levels, in SCR_EL3.
+const SCR_NS: u64 = 1 << 0;
+/// HVC instruction enable, in SCR_EL3.
+const SCR_HCE: u64 = 1 << 8;
+/// Lower exception levels are AArch64, in SCR_EL3.
+const SCR_RW: u64 = 1 << 10;
+/// EL1 is AArch64, in HCR_EL2.
+const HCR_RW: u64 = 1 << 31;
+/// All exceptions masked, in SPSR_ELx.
+const SPSR_DAIF: u64 = 0xf << 6;
+/// AArch64 EL1 using SP_EL1, in SPSR_ELx.M.
+const SPSR_EL1H: u64 = 0b0101;
+/// AArch64 EL2 using SP_EL2, in SPSR_ELx.M.
+const SPSR_EL2H: u64 = 0b1001;
+/// EL2 field of ID_AA64PFR0_EL1, non-zero if EL2 is implemented.
+const ID_AA64PFR0_EL2_SHIFT: u64 = 8;
+
+/// An error loading or entering an ELF payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ElfError {
+    /// The file header is truncated, or isn't for a little-endian AArch64 ELF64 executable.
+    InvalidHeader,
+    /// A program header is truncated, or describes a segment which is outside the file or whose
+    /// file size is larger than its memory size.
+    InvalidProgramHeader,
+    /// The requested exception level is higher than the current one, or isn't implemented.
+    UnsupportedEl,
+    /// The payload was to be entered at the current exception level, but physical memory isn't
+    /// identity mapped, so the MMU can't be turned off.
+    NotIdentityMapped,
+}
+
+/// A loadable segment of an ELF payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Segment {
+    /// The physical address to load the segment to.
+    pub physical_address: u64,
+    /// The virtual address which the segment is linked at.
+    pub virtual_address: u64,
+    /// The offset of the segment's contents in the file.
+    pub file_offset: usize,
+    /// The number of bytes of the segment's contents in the file.
+    pub file_size: usize,
+    /// The number of bytes of memory the segment occupies, which may be more than the file size to
+    /// leave room for zero-initialised data.
+    pub memory_size: usize,
+    /// Whether the segment contains code.
+    pub executable: bool,
+}
+
+/// A parsed ELF64 payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ElfImage<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+impl<'a> ElfImage<'a> {
+    /// Parses and validates the headers of the given ELF file.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header = data.get(..EHDR_SIZE).ok_or(ElfError::InvalidHeader)?;
+        if &header[0..4] != ELF_MAGIC
+            || header[4] != ELFCLASS64
+            || header[5] != ELFDATA2LSB
+            || read_u16(header, 16) != ET_EXEC
+            || read_u16(header, 18) != EM_AARCH64
+            || usize::from(read_u16(header, 54)) != PHDR_SIZE
+        {
+            return Err(ElfError::InvalidHeader);
+        }
+        let image = Self {
+            data,
+            entry: read_u64(header, 24),
+            program_headers: read_u64(header, 32)
+                .try_into()
+                .map_err(|_| ElfError::InvalidHeader)?,
+            program_header_count: read_u16(header, 56).into(),
+        };
+        for index in 0..image.program_header_count {
+            image.program_header(index)?;
+        }
+        Ok(image)
+    }
+
+    /// Returns the entry point address.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns an iterator over the loadable segments.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.program_header_count).filter_map(|index| self.program_header(index).ok()?)
+    }
+
+    /// Returns the segment described by the program header with the given index, or `None` if it
+    /// isn't a loadable segment.
+    fn program_header(&self, index: usize) -> Result<Option<Segment>, ElfError> {
+        let offset = index
+            .checked_mul(PHDR_SIZE)
+            .and_then(|offset| offset.checked_add(self.program_headers))
+            .ok_or(ElfError::InvalidProgramHeader)?;
+        let header = offset
+            .checked_add(PHDR_SIZE)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or(ElfError::InvalidProgramHeader)?;
+        if read_u32(header, 0) != PT_LOAD {
+            return Ok(None);
+        }
+        let to_usize =
+            |value: u64| usize::try_from(value).map_err(|_| ElfError::InvalidProgramHeader);
+        let segment = Segment {
+            executable: read_u32(header, 4) & PF_X != 0,
+            file_offset: to_usize(read_u64(header, 8))?,
+            virtual_address: read_u64(header, 16),
+            physical_address: read_u64(header, 24),
+            file_size: to_usize(read_u64(header, 32))?,
+            memory_size: to_usize(read_u64(header, 40))?,
+        };
+        let file_end = segment
+            .file_offset
+            .checked_add(segment.file_size)
+            .ok_or(ElfError::InvalidProgramHeader)?;
+        if file_end > self.data.len()
+            || segment.file_size > segment.memory_size
+            || segment
+                .physical_address
+                .checked_add(segment.memory_size as u64)
+                .is_none()
+        {
+            return Err(ElfError::InvalidProgramHeader);
+        }
+        Ok(Some(segment))
+    }
+
+    /// Copies each loadable segment to its physical address, zeroes the remainder of its memory
+    /// size, and cleans it to the point of coherency.
+    ///
+    /// # Safety
+    ///
+    /// The physical memory of each segment must be mapped as normal writable memory at the virtual
+    /// address given by [`phys_to_virt`], and must not be used for anything else, including this
+    /// image, its stacks and the ELF file itself.
+    pub unsafe fn load(&self) {
+        for segment in self.segments() {
+            let dst = phys_to_virt(PhysAddr(segment.physical_address as usize)).as_mut_ptr::<u8>();
+            // SAFETY: Our caller promised that the segment's memory is mapped and unused, and
+            // `parse` checked that its contents are within the file.
+            unsafe {
+                copy_nonoverlapping(
+                    self.data[segment.file_offset..].as_ptr(),
+                    dst,
+                    segment.file_size,
+                );
+                write_bytes(
+                    dst.add(segment.file_size),
+                    0,
+                    segment.memory_size - segment.file_size,
+                );
+            }
+            clean_dcache_range(dst, segment.memory_size);
+        }
+    }
+}
+
+/// The exception level to enter a payload at.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadEl {
+    /// EL1, using SP_EL1.
+    El1,
+    /// EL2, using SP_EL2.
+    El2,
+    /// EL3, using SP_EL3.
+    El3,
+}
+
+impl PayloadEl {
+    const fn level(self) -> u8 {
+        match self {
+            Self::El1 => 1,
+            Self::El2 => 2,
+            Self::El3 => 3,
+        }
+    }
+}
+
+/// Masks interrupts, stops the periodic tick, and jumps to the given entry point at the given
+/// exception level with `args` in x0 to x3.
+///
+/// The instruction cache is invalidated before entering the payload. See the
+/// [module documentation](self) for the state the payload is entered in.
+///
+/// Returns an error without changing anything if the exception level is higher than the current
+/// one or isn't implemented, or if it is the current one but physical memory isn't identity mapped.
+///
+/// # Safety
+///
+/// The payload must have been loaded and cleaned to the point of coherency, e.g. with
+/// [`ElfImage::load`]. Other cores must not be relying on anything this core does. The application
+/// must not need anything to keep running after this, as no destructors are run.
+pub unsafe fn enter_payload(
+    entry: u64,
+    el: PayloadEl,
+    args: [u64; 4],
+) -> Result<Infallible, ElfError> {
+    let current = current_el();
+    let target = el.level();
+    if target > current {
+        return Err(ElfError::UnsupportedEl);
+    }
+    if target == current && virt_offset() != 0 {
+        return Err(ElfError::NotIdentityMapped);
+    }
+    // SAFETY: Reading ID_AA64PFR0_EL1 is always safe.
+    let el2_implemented =
+        (unsafe { read_sysreg!("id_aa64pfr0_el1") } >> ID_AA64PFR0_EL2_SHIFT) & 0xf != 0;
+    if target == 2 && !el2_implemented {
+        return Err(ElfError::UnsupportedEl);
+    }
+
+    // SAFETY: Masking interrupts doesn't affect memory safety.
+    unsafe {
+        asm!(
+            "msr daifset, #0xf",
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    stop_periodic_tick();
+
+    let spsr = match el {
+        PayloadEl::El1 => SPSR_DAIF | SPSR_EL1H,
+        PayloadEl::El2 => SPSR_DAIF | SPSR_EL2H,
+        // EL3 can only be the current exception level, which is branched to directly.
+        PayloadEl::El3 => 0,
+    };
+    // SAFETY: We only configure exception levels below the current one, which nothing else is
+    // running at, and check that EL2 is implemented before accessing its registers.
+    unsafe {
+        if current == 3 && target < 3 {
+            let hce = if target == 2 { SCR_HCE } else { 0 };
+            write_sysreg!("scr_el3", read_sysreg!("scr_el3") | SCR_NS | SCR_RW | hce);
+            if target == 1 && el2_implemented {
+                write_sysreg!("hcr_el2", HCR_RW);
+            }
+        } else if current == 2 && target == 1 {
+            write_sysreg!("hcr_el2", read_sysreg!("hcr_el2") | HCR_RW);
+        }
+        match (current > target, el) {
+            (true, PayloadEl::El1) => write_sysreg!("sctlr_el1", SCTLR_EL1_RES1),
+            (true, PayloadEl::El2) => write_sysreg!("sctlr_el2", SCTLR_EL2_RES1),
+            _ => {}
+        }
+    }
+
+    // SAFETY: Our caller promised that the payload has been loaded and that nothing else needs to
+    // keep running, and we checked above that the MMU can be disabled if entering at the current
+    // exception level.
+    unsafe {
+        jump_to_payload(
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+            entry,
+            if target == current { 0 } else { spsr },
+        )
+    }
+}
+
+/// Parses, loads and enters the given ELF payload at the given exception level, with `args` in x0
+/// to x3.
+///
+/// # Safety
+///
+/// See [`ElfImage::load`] and [`enter_payload`].
+pub unsafe fn boot_elf(data: &[u8], el: PayloadEl, args: [u64; 4]) -> Result<Infallible, ElfError> {
+    let image = ElfImage::parse(data)?;
+    // SAFETY: Our caller promised that the segments' memory is available.
+    unsafe { image.load() };
+    // SAFETY: The payload has just been loaded, and our caller promised that nothing else needs to
+    // keep running.
+    unsafe { enter_payload(image.entry(), el, args) }
+}
+
+/// Invalidates the instruction cache and enters `entry` with x0 to x3 as passed.
+///
+/// If `spsr` is non-zero then the entry point is entered with an exception return from the current
+/// exception level with that SPSR. Otherwise the MMU and data cache are disabled for the current
+/// exception level and the entry point is branched to.
+///
+/// # Safety
+///
+/// The payload must have been loaded and cleaned to the point of coherency. If `spsr` is zero then
+/// this code must be identity mapped.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn jump_to_payload(
+    x0: u64,
+    x1: u64,
+    x2: u64,
+    x3: u64,
+    entry: u64,
+    spsr: u64,
+) -> ! {
+    naked_asm!(
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cbz x5, 5f",
+        // Exception return to a lower exception level.
+        "ic iallu",
+        "dsb nsh",
+        "isb",
+        "cmp x9, #3",
+        "b.eq 3f",
+        "msr elr_el2, x4",
+        "msr spsr_el2, x5",
+        "eret",
+        "3:",
+        "msr elr_el3, x4",
+        "msr spsr_el3, x5",
+        "eret",
+        // Branch at the current exception level with the MMU off.
+        "5:",
+        "cmp x9, #2",
+        "b.eq 2f",
+        "b.hi 3f",
+        "mrs x10, sctlr_el1",
+        "bic x10, x10, #{sctlr_m}",
+        "bic x10, x10, #{sctlr_c}",
+        "msr sctlr_el1, x10",
+        "b 4f",
+        "2:",
+        "mrs x10, sctlr_el2",
+        "bic x10, x10, #{sctlr_m}",
+        "bic x10, x10, #{sctlr_c}",
+        "msr sctlr_el2, x10",
+        "b 4f",
+        "3:",
+        "mrs x10, sctlr_el3",
+        "bic x10, x10, #{sctlr_m}",
+        "bic x10, x10, #{sctlr_c}",
+        "msr sctlr_el3, x10",
+        "4:",
+        "isb",
+        "ic iallu",
+        "dsb nsh",
+        "isb",
+        "br x4",
+        sctlr_m = const SCTLR_M,
+        sctlr_c = const SCTLR_C,
+    )
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -4,14 +4,27 @@
 
 //! Entrypoint code
 
-use core::{arch::naked_asm, mem::offset_of};
+use core::{
+    arch::{global_asm, naked_asm},
+    mem::offset_of,
+};
 
-use crate::StartCoreStack;
+#[cfg(feature = "el3")]
+use crate::reset::reset_init;
+#[cfg(feature = "initial-pagetable")]
+use crate::{CoreMmuConfig, pagetable::BootMmuConfig};
+use crate::{
+    StartCoreStack,
+    boot_error::{__aarch64_rt_boot_failed, BootError, set_early_exception_vector},
+    cpu::enable_smpen,
+    debug::configure_debug,
+    hypervisor::configure_el2,
+};
 
 /// This is a generic entry point for an image. It carries out the operations required to prepare the
-/// loaded image to be run. Specifically, it zeroes the bss section using registers x25 and above,
-/// prepares the stack, enables floating point, and sets up the exception vector. It preserves x0-x3
-/// for the Rust entry point, as these may contain boot parameters.
+/// loaded image to be run. Specifically, it prepares the stack, zeroes the bss section, enables
+/// floating point, and sets up the exception vector. It preserves x0-x3 for the Rust entry point,
+/// as these may contain boot parameters.
 ///
 /// # Safety
 ///
@@ -26,35 +39,309 @@ unsafe extern "C" fn entry() -> ! {
         r"adrp \reg, \sym",
         r"add \reg, \reg, :lo12:\sym",
         ".endm",
+        // Stop if we can't run in the state we were entered in, and catch any exceptions before
+        // the exception vector is set.
+        "bl {check_entry_conditions}",
+        // Initialise the CPU and handle warm boots, if we are the reset vector.
+        "bl {reset_init}",
+        // Park all but the boot core, if necessary.
+        "bl {park_secondary_cores}",
+        // Move the image to the address it was linked at, if necessary.
+        "bl {copy_to_link_address}",
+        "bl {enable_smpen}",
+        "bl {configure_el2}",
+        // Apply the MPAM configuration registered with `mpam!`, if any.
+        "bl __aarch64_rt_configure_mpam",
+        // Apply the GIC CPU interface configuration registered with `gic_init!`, if any.
+        "bl __aarch64_rt_configure_gic",
+        "bl {configure_debug}",
+        // Apply the trap configuration registered with `trap_config!`, or allow floating point
+        // access by default. This must come before any Rust code runs, including the hook below.
+        "bl __aarch64_rt_configure_traps",
+        // Fill in the initial pagetable at runtime, if `initial_pagetable!` was given a function to
+        // do so.
+        "bl __aarch64_rt_prepare_pagetable",
         "bl enable_mmu",
-        // Disable trapping floating point access in EL1.
-        "mrs x30, cpacr_el1",
-        "orr x30, x30, #(0x3 << 20)",
-        "msr cpacr_el1, x30",
-        "isb",
-        // Zero out the bss section.
-        "adr_l x29, bss_begin",
-        "adr_l x30, bss_end",
+        // Prepare the stack.
+        "adr_l x30, boot_stack_end",
+        "mov sp, x30",
+        // Zero out the bss section, servicing the watchdog every 1 MiB.
+        "adr_l x9, bss_begin",
+        "adr_l x10, bss_end",
         "0:",
-        "cmp x29, x30",
+        "cmp x9, x10",
         "b.hs 1f",
-        "stp xzr, xzr, [x29], #16",
+        "stp xzr, xzr, [x9], #16",
+        "tst x9, #0xfffff",
+        "b.ne 0b",
+        "bl {kick_watchdog}",
         "b 0b",
         "1:",
-        // Prepare the stack.
-        "adr_l x30, boot_stack_end",
-        "mov sp, x30",
+        // Copy the initial contents of the data section from ROM, if necessary.
+        "bl {copy_data}",
         // Call into Rust code.
         "b {rust_entry}",
+        check_entry_conditions = sym check_entry_conditions,
+        copy_data = sym copy_data,
+        configure_el2 = sym configure_el2,
+        copy_to_link_address = sym copy_to_link_address,
+        enable_smpen = sym enable_smpen,
+        kick_watchdog = sym crate::watchdog::kick_preserving_registers,
+        park_secondary_cores = sym park_secondary_cores,
+        reset_init = sym reset_init,
         rust_entry = sym crate::rust_entry,
+        configure_debug = sym configure_debug,
+    )
+}
+
+// The default hook, which is overridden by the strong symbol defined by `initial_pagetable!` if it
+// is given a function to prepare the pagetable at runtime.
+global_asm!(
+    ".section .init.__aarch64_rt_prepare_pagetable, \"ax\"",
+    ".weak __aarch64_rt_prepare_pagetable",
+    ".type __aarch64_rt_prepare_pagetable, %function",
+    "__aarch64_rt_prepare_pagetable:",
+    "ret",
+);
+
+/// The exception level which the image must be entered at, or 0 if any of EL1-3 is allowed.
+const EXPECTED_EL: u64 = if cfg!(feature = "el1") {
+    1
+} else if cfg!(feature = "el2") {
+    2
+} else if cfg!(feature = "el3") {
+    3
+} else {
+    0
+};
+
+/// Checks that the core was entered in a state which the image can run in, i.e. at the expected
+/// exception level and, if the initial pagetable is used, with support for a 4 KiB translation
+/// granule, and then sets the early exception vector.
+///
+/// If not, the core stops with [`BootError::UnexpectedEl`] or [`BootError::GranuleUnsupported`],
+/// so that the reason can be found rather than failing in some less obvious way later. Being
+/// entered at EL0 or in AArch32 state can't be detected, as the code which would check it can't
+/// run there.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before any Rust code is run.
+///
+/// Clobbers x9-x11.
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn check_entry_conditions() {
+    naked_asm!(
+        "mrs x10, CurrentEL",
+        "ubfx x10, x10, #2, #2",
+        "mov x9, #{unexpected_el}",
+        ".if {expected_el} == 0",
+        "cbz x10, {boot_failed}",
+        ".else",
+        "cmp x10, #{expected_el}",
+        "b.ne {boot_failed}",
+        ".endif",
+        ".if {check_granule}",
+        // ID_AA64MMFR0_EL1.TGran4 is 0b1111 if the 4 KiB granule isn't supported.
+        "mrs x10, id_aa64mmfr0_el1",
+        "ubfx x11, x10, #28, #4",
+        "mov x9, #{granule_unsupported}",
+        "cmp x11, #0xf",
+        "b.eq {boot_failed}",
+        ".endif",
+        "b {set_early_exception_vector}",
+        expected_el = const EXPECTED_EL,
+        check_granule = const cfg!(feature = "initial-pagetable") as u8,
+        unexpected_el = const BootError::UnexpectedEl as u32,
+        granule_unsupported = const BootError::GranuleUnsupported as u32,
+        boot_failed = sym __aarch64_rt_boot_failed,
+        set_early_exception_vector = sym set_early_exception_vector,
+    )
+}
+
+/// Copies the initial contents of the data section from its load address in ROM to RAM.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before any Rust code is run.
+///
+/// Clobbers x9-x13.
+#[cfg(feature = "rom")]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn copy_data() {
+    naked_asm!(
+        "adrp x9, data_lma",
+        "add x9, x9, :lo12:data_lma",
+        "adrp x10, data_begin",
+        "add x10, x10, :lo12:data_begin",
+        "adrp x11, data_end",
+        "add x11, x11, :lo12:data_end",
+        "0:",
+        "cmp x10, x11",
+        "b.hs 1f",
+        "ldp x12, x13, [x9], #16",
+        "stp x12, x13, [x10], #16",
+        "ldp x12, x13, [x9], #16",
+        "stp x12, x13, [x10], #16",
+        "b 0b",
+        "1:",
+        "ret",
     )
 }
 
+/// No-op when the `rom` feature isn't enabled, as the data section is loaded in place.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when the `rom`
+/// feature is enabled.
+#[cfg(not(feature = "rom"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn copy_data() {
+    naked_asm!("ret")
+}
+
+/// No-op when the `el3` feature isn't enabled, as we aren't the reset vector.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when the `el3`
+/// feature is enabled.
+#[cfg(not(feature = "el3"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn reset_init() {
+    naked_asm!("ret")
+}
+
+/// Parks all cores other than core 0 in a spin-table loop, as the Raspberry Pi firmware may start
+/// all cores at the entry point.
+///
+/// Each parked core waits for an address to be written to its entry in
+/// [`RASPBERRY_PI_RELEASE_ADDRESSES`](crate::spin_table::RASPBERRY_PI_RELEASE_ADDRESSES), and then
+/// jumps to it. Core 0 returns to continue booting.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before any Rust code is run.
+///
+/// Clobbers x9-x11.
+#[cfg(feature = "raspberry-pi")]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn park_secondary_cores() {
+    naked_asm!(
+        "mrs x9, mpidr_el1",
+        "and x9, x9, #0xff",
+        "cbz x9, 1f",
+        "mov x10, #{release_addresses}",
+        "add x10, x10, x9, lsl #3",
+        "0:",
+        "wfe",
+        "ldr x11, [x10]",
+        "cbz x11, 0b",
+        "br x11",
+        "1:",
+        "ret",
+        release_addresses = const crate::spin_table::RASPBERRY_PI_RELEASE_ADDRESSES[0],
+    )
+}
+
+/// No-op when the `raspberry-pi` feature isn't enabled, as only the boot core enters the image.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when the
+/// `raspberry-pi` feature is enabled.
+#[cfg(not(feature = "raspberry-pi"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn park_secondary_cores() {
+    naked_asm!("ret")
+}
+
+/// Copies the image to the address it was linked at, if it was loaded somewhere else, and returns
+/// to the corresponding point in the copy.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before the MMU is enabled.
+///
+/// Clobbers x9-x14.
+#[cfg(feature = "copy-to-link-address")]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn copy_to_link_address() {
+    naked_asm!(
+        // Get the address the image was loaded at, and the address it was linked at.
+        "adr x9, text_begin",
+        "ldr x10, =text_begin",
+        "subs x11, x10, x9",
+        "b.eq 3f",
+        // Get the length of the loaded part of the image.
+        "ldr x12, =bin_end",
+        "sub x12, x12, x10",
+        // Copy backwards if the destination is after the source, in case they overlap.
+        "cmp x10, x9",
+        "b.hi 1f",
+        "0:",
+        "cbz x12, 2f",
+        "ldp x13, x14, [x9], #16",
+        "stp x13, x14, [x10], #16",
+        "sub x12, x12, #16",
+        "b 0b",
+        "1:",
+        "add x9, x9, x12",
+        "add x10, x10, x12",
+        "10:",
+        "cbz x12, 2f",
+        "ldp x13, x14, [x9, #-16]!",
+        "stp x13, x14, [x10, #-16]!",
+        "sub x12, x12, #16",
+        "b 10b",
+        "2:",
+        // Make sure the copied code is fetched rather than anything stale in the instruction
+        // cache.
+        "dsb sy",
+        "ic iallu",
+        "dsb sy",
+        "isb",
+        // Return to the same point in the copy.
+        "add x30, x30, x11",
+        "3:",
+        "ret",
+        ".ltorg",
+    )
+}
+
+/// No-op when the `copy-to-link-address` feature isn't enabled, as the image runs where it is
+/// loaded.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when the
+/// `copy-to-link-address` feature is enabled.
+#[cfg(not(feature = "copy-to-link-address"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn copy_to_link_address() {
+    naked_asm!("ret")
+}
+
 /// An assembly entry point for secondary cores.
 ///
-/// It will enable the MMU, disable trapping of floating point instructions, initialise the
-/// stack pointer to `stack_end` and then jump to the trampoline function pointer at the bottom
-/// of the stack with the closure pointer second on the stack as a parameter.
+/// It will enable the MMU (with the `CoreMmuConfig` passed on the stack if any, or otherwise the
+/// same configuration as the boot core, as returned by `boot_mmu_config`), disable trapping of
+/// floating point instructions, initialise the stack pointer to `stack_end` and then jump to the
+/// trampoline function pointer at the bottom of the stack with the closure pointer second on the
+/// stack as a parameter.
 ///
 /// # Safety
 ///
@@ -63,19 +350,18 @@ unsafe extern "C" fn entry() -> ! {
 #[unsafe(naked)]
 pub unsafe extern "C" fn secondary_entry(stack_end: *mut u64) -> ! {
     naked_asm!(
-        "bl enable_mmu",
-        // Disable trapping floating point access in EL1.
-        "mrs x30, cpacr_el1",
-        "orr x30, x30, #(0x3 << 20)",
-        "msr cpacr_el1, x30",
-        "isb",
+        // Use the `CoreMmuConfig` passed on the stack, if any.
+        "ldr x4, [x0, #{mmu_config_offset}]",
+        "mov x5, #0",
+        "bl {init_secondary_core}",
         // Set the stack pointer which was passed.
         "mov sp, x0",
         // Load the closure address into x19 and the trampoline address into x20.
         // This is loaded from StartCoreStack.
         "ldr x19, [sp, #{entry_ptr_offset}]",
         "ldr x20, [sp, #{trampoline_ptr_offset}]",
-        // Set the exception vector.
+        // Set the exception vector, now that nothing is counted as being handled.
+        "bl {reset_exception_depth}",
         "bl {set_exception_vector}",
         // Pass the entry point (closure) address to the trampoline function.
         "mov x0, x19",
@@ -85,6 +371,128 @@ pub unsafe extern "C" fn secondary_entry(stack_end: *mut u64) -> ! {
             - size_of::<StartCoreStack<()>>() as isize,
         trampoline_ptr_offset = const offset_of!(StartCoreStack<()>, trampoline_ptr) as isize
             - size_of::<StartCoreStack<()>>() as isize,
+        mmu_config_offset = const offset_of!(StartCoreStack<()>, mmu_config) as isize
+            - size_of::<StartCoreStack<()>>() as isize,
+        init_secondary_core = sym init_secondary_core,
+        reset_exception_depth = sym reset_exception_depth,
         set_exception_vector = sym crate::set_exception_vector,
     )
 }
+
+/// Resets the current core's count of exceptions being handled at the current exception level, for
+/// cores starting afresh which may have been powered off from an exception handler.
+///
+/// If `exceptions` is not enabled then this is a no-op.
+pub(crate) extern "C" fn reset_exception_depth() {
+    #[cfg(feature = "exceptions")]
+    crate::reset_exception_depth();
+}
+
+/// Initialises a core other than the boot core, or a core powering back on after losing its state,
+/// in the same way as the entry point initialises the boot core, up to configuring traps and
+/// enabling the MMU.
+///
+/// This is shared by [`secondary_entry`] and the resume paths for cores which were suspended or
+/// warm booted, so that they all go through the same checks and configuration.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code with the MMU off. It expects the address of the `CoreMmuConfig` to apply in
+/// x4, or 0 to apply the boot core's, and the address of a TTBR1_EL1 value to apply at EL1 in x5,
+/// or 0 to leave TTBR1_EL1 unchanged. The boot core's TTBR1_EL1 is always applied along with its
+/// configuration.
+///
+/// Preserves x0-x3 and x19-x28, and clobbers x4-x15.
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn init_secondary_core() {
+    naked_asm!(
+        "mov x7, x30",
+        "bl {check_entry_conditions}",
+        "bl {enable_smpen}",
+        "bl {configure_el2}",
+        // Apply the MPAM configuration registered with `mpam!`, if any.
+        "bl __aarch64_rt_configure_mpam",
+        // Apply the GIC CPU interface configuration registered with `gic_init!`, if any.
+        "bl __aarch64_rt_configure_gic",
+        "bl {configure_debug}",
+        // Apply the trap configuration registered with `trap_config!`, or allow floating point
+        // access by default.
+        "bl __aarch64_rt_configure_traps",
+        "bl {enable_secondary_mmu}",
+        "ret x7",
+        check_entry_conditions = sym check_entry_conditions,
+        configure_el2 = sym configure_el2,
+        enable_smpen = sym enable_smpen,
+        configure_debug = sym configure_debug,
+        enable_secondary_mmu = sym enable_secondary_mmu,
+    )
+}
+
+/// Enables the MMU for a core other than the boot core, with the given `CoreMmuConfig` if there is
+/// one, or otherwise the same configuration as the boot core enabled, as recorded in
+/// `BOOT_MMU_CONFIG`.
+///
+/// Applying the recorded values rather than calling `enable_mmu` again means that secondary cores
+/// always match the boot core, even if `enable_mmu` is overridden with something which depends on
+/// the state of the core running it.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from `init_secondary_core`, with the address of the `CoreMmuConfig` or 0 in x4 and the address
+/// of the TTBR1_EL1 value or 0 in x5.
+///
+/// Clobbers x8-x12.
+#[cfg(feature = "initial-pagetable")]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn enable_secondary_mmu() {
+    naked_asm!(
+        "mov x12, x4",
+        "mov x9, x5",
+        "cbnz x12, 0f",
+        "adrp x12, {boot_mmu_config}",
+        "add x12, x12, :lo12:{boot_mmu_config}",
+        "add x9, x12, #{ttbr1_offset}",
+        "0:",
+        // At EL1, also apply TTBR1_EL1 if given.
+        "cbz x9, 1f",
+        "mrs x8, CurrentEL",
+        "ubfx x8, x8, #2, #2",
+        "cmp x8, #1",
+        "b.ne 1f",
+        "ldr x8, [x9]",
+        "msr ttbr1_el1, x8",
+        "1:",
+        "ldp x8, x9, [x12, #{mair_offset}]",
+        "ldp x10, x11, [x12, #{tcr_offset}]",
+        "mrs x12, CurrentEL",
+        "ubfx x12, x12, #2, #2",
+        "cmp x12, #2",
+        "b.eq {enable_mmu_el2}",
+        "b.hi {enable_mmu_el3}",
+        "b {enable_mmu_el1}",
+        mair_offset = const offset_of!(CoreMmuConfig, mair),
+        tcr_offset = const offset_of!(CoreMmuConfig, tcr),
+        ttbr1_offset = const offset_of!(BootMmuConfig, ttbr1),
+        boot_mmu_config = sym crate::pagetable::BOOT_MMU_CONFIG,
+        enable_mmu_el1 = sym crate::pagetable::__enable_mmu_el1,
+        enable_mmu_el2 = sym crate::pagetable::__enable_mmu_el2,
+        enable_mmu_el3 = sym crate::pagetable::__enable_mmu_el3,
+    )
+}
+
+/// Enables the MMU or MPU for a secondary core with the initial configuration.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from `init_secondary_core`.
+#[cfg(not(feature = "initial-pagetable"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+unsafe extern "C" fn enable_secondary_mmu() {
+    naked_asm!("b enable_mmu")
+}
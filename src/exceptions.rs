@@ -2,7 +2,50 @@
 // This project is dual-licensed under Apache 2.0 and MIT terms.
 // See LICENSE-APACHE and LICENSE-MIT for details.
 
-use core::{borrow::Borrow, ops::Deref};
+use crate::{
+    gpf::GranuleProtectionFault,
+    ras::{SErrorAction, SErrorReport, resolve_serror},
+    sysreg::{MAX_CORES, core_index, current_el, read_esr, read_far},
+};
+use core::{
+    arch::global_asm,
+    borrow::Borrow,
+    ops::Deref,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+/// Exception class for an SMC instruction executed in AArch64 state, in ESR_ELx.
+const ESR_EC_SMC64: u64 = 0x17;
+
+/// The SMCCC return value for an unknown function identifier.
+const SMCCC_NOT_SUPPORTED: i64 = -1;
+
+/// The number of nested synchronous exceptions and SErrors a core may be handling at once before we
+/// give up and halt it, rather than risk overflowing its stack.
+const MAX_EXCEPTION_DEPTH: u8 = 8;
+
+/// The number of synchronous exceptions and SErrors which each core is currently handling at each of
+/// EL1 to EL3, indexed by its `core_index`. IRQs and FIQs aren't counted, so a fault in an interrupt
+/// handler isn't mistaken for a double fault.
+static EXCEPTION_DEPTH: [[AtomicU8; MAX_CORES]; 3] =
+    [const { [const { AtomicU8::new(0) }; MAX_CORES] }; 3];
+
+/// The number of emergency stacks, and so the number of cores which can report a bad stack pointer
+/// at once. Any more are halted.
+const EMERGENCY_STACK_COUNT: usize = 8;
+
+/// Log2 of the size in bytes of each emergency stack.
+const EMERGENCY_STACK_SHIFT: u32 = 11;
+
+/// For each emergency stack, 0 if it is free, or non-zero once a core has claimed it. Stacks are
+/// never released, as the core using one panics.
+static EMERGENCY_STACK_OWNERS: [AtomicUsize; EMERGENCY_STACK_COUNT] =
+    [const { AtomicUsize::new(0) }; EMERGENCY_STACK_COUNT];
+
+/// Stacks used to report exceptions taken with an implausible stack pointer.
+#[unsafe(link_section = ".stack.emergency")]
+static mut EMERGENCY_STACKS: [[u128; (1 << EMERGENCY_STACK_SHIFT) / 16]; EMERGENCY_STACK_COUNT] =
+    [[0; (1 << EMERGENCY_STACK_SHIFT) / 16]; EMERGENCY_STACK_COUNT];
 
 /// The register state saved before calling the exception handler.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -17,9 +60,68 @@ pub struct RegisterState {
     pub sp: u64,
     pub elr: usize,
     pub spsr: u64,
+    /// Registers x19-x28.
+    ///
+    /// These are callee-saved, so the handler would preserve them anyway, but are saved so that
+    /// it can read and change them, e.g. to emulate an instruction which accesses them.
+    pub callee_saved: [u64; 10],
+}
+
+const _: () = assert!(size_of::<RegisterState>() == 8 * 34);
+
+impl RegisterState {
+    /// Returns a register state with every register zero.
+    pub(crate) const fn empty() -> Self {
+        Self {
+            registers: [0; 19],
+            padding: 0,
+            fp: 0,
+            sp: 0,
+            elr: 0,
+            spsr: 0,
+            callee_saved: [0; 10],
+        }
+    }
 }
 
-const _: () = assert!(size_of::<RegisterState>() == 8 * 24);
+/// The addresses of an exception vector table for each of EL1 to EL3, such as one generated by
+/// [`exception_handlers!`](crate::exception_handlers), which can be installed with
+/// [`set_vector_table`](crate::set_vector_table).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VectorTable {
+    el1: usize,
+    el2: usize,
+    el3: usize,
+}
+
+impl VectorTable {
+    /// Returns a vector table with the given addresses for each exception level, or 0 for those
+    /// which it doesn't support.
+    ///
+    /// # Safety
+    ///
+    /// Each non-zero address must be that of a 2 KiB aligned exception vector table which correctly
+    /// handles exceptions taken to that exception level, and which remains valid for as long as it
+    /// is installed.
+    pub const unsafe fn new(el1: usize, el2: usize, el3: usize) -> Self {
+        Self { el1, el2, el3 }
+    }
+
+    /// Returns the address of the vector table for the given exception level, or 0 if there is none
+    /// because the image can't run at that exception level.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `el` isn't 1, 2 or 3.
+    pub fn address(&self, el: u8) -> usize {
+        match el {
+            1 => self.el1,
+            2 => self.el2,
+            3 => self.el3,
+            _ => panic!("No vector table for EL{el}"),
+        }
+    }
+}
 
 /// A reference to the register state saved when an exception happened.
 #[derive(Debug, Eq, PartialEq)]
@@ -63,13 +165,269 @@ impl Deref for RegisterStateRef<'_> {
     }
 }
 
+/// Resets the number of synchronous exceptions and SErrors which the current core is handling at
+/// the current exception level to zero.
+///
+/// The exception vector counts these to detect double faults, so a handler for one which never
+/// returns, e.g. because it powers the core off, should call this first. Otherwise the count stays
+/// raised, and the next such exception on the core is reported as a double fault. The entry points
+/// for secondary cores and warm boots reset it too, in case the core was powered off without it.
+pub fn reset_exception_depth() {
+    EXCEPTION_DEPTH[current_el() as usize - 1][core_index()].store(0, Ordering::Relaxed);
+}
+
+/// Reports a synchronous exception or SError taken while the core was already handling another
+/// exception, rather than calling the handler for it and likely faulting again.
+extern "C" fn double_fault(register_state: RegisterStateRef, depth: u64) -> ! {
+    panic!(
+        "Double fault: exception taken while handling {} other exception(s), ESR {:#x}, \
+         FAR {:#x}, ELR {:#x}",
+        depth - 1,
+        read_esr(),
+        read_far(),
+        register_state.elr,
+    );
+}
+
+/// Reports an exception taken with an implausible stack pointer, after switching to an emergency
+/// stack.
+///
+/// The saved values of x0 and x1 are lost in this case.
+extern "C" fn bad_stack_pointer(register_state: RegisterStateRef, sp: u64) -> ! {
+    panic!(
+        "Exception taken with implausible stack pointer {sp:#x}, ESR {:#x}, FAR {:#x}, ELR {:#x}",
+        read_esr(),
+        read_far(),
+        register_state.elr,
+    );
+}
+
+global_asm!(
+    r#"
+/**
+ * Restores the volatile registers from the stack.
+ */
+.macro restore_volatile_from_stack el:req
+	/* Restore registers x2-x18, x29 & x30. */
+	ldp x2, x3, [sp, #8 * 2]
+	ldp x4, x5, [sp, #8 * 4]
+	ldp x6, x7, [sp, #8 * 6]
+	ldp x8, x9, [sp, #8 * 8]
+	ldp x10, x11, [sp, #8 * 10]
+	ldp x12, x13, [sp, #8 * 12]
+	ldp x14, x15, [sp, #8 * 14]
+	ldp x16, x17, [sp, #8 * 16]
+	ldr x18, [sp, #8 * 18]
+	ldp x29, x30, [sp, #8 * 20]
+
+	/* Restore registers elr_elX & spsr_elX, using x0 & x1 as scratch. */
+	ldp x0, x1, [sp, #8 * 22]
+	msr elr_\el, x0
+	msr spsr_\el, x1
+
+	/* Restore x0 & x1, and release stack space. */
+	ldp x0, x1, [sp], #8 * 34
+.endm
+
+/**
+ * The common part of the exception handlers, which are jumped to from the
+ * vector table after the volatile registers have been saved to the stack, with
+ * the address of the Rust handler to call in x2. This saves x19-x28 too, so
+ * that the handler is given all the general-purpose registers, and restores
+ * them afterwards in case it changed them.
+ *
+ * If \fault is 1, i.e. for synchronous exceptions and SErrors, this keeps track
+ * of how many of them the core is handling. If the core is already handling
+ * another then a double fault is reported instead of calling the handler. If
+ * they are nested too deeply then the core is halted.
+ */
+.macro handle_exception el:req level:req fault:req
+	stp x19, x20, [sp, #8 * 24]
+	stp x21, x22, [sp, #8 * 26]
+	stp x23, x24, [sp, #8 * 28]
+	stp x25, x26, [sp, #8 * 30]
+	stp x27, x28, [sp, #8 * 32]
+
+.if \fault
+	/*
+	 * Increment the exception depth for this core at this EL, keeping the
+	 * handler address in x19 which has already been saved.
+	 */
+	mov x19, x2
+	bl {core_index}
+	mov x2, x19
+	adrp x4, {depth}
+	add x4, x4, :lo12:{depth}
+	add x4, x4, #(\level - 1) * {max_cores}
+	add x4, x4, x0
+	ldrb w5, [x4]
+	add w5, w5, #1
+	strb w5, [x4]
+	/* Keep the address of the counter in the padding of the register state. */
+	str x4, [sp, #8 * 19]
+
+	cmp w5, #{max_depth}
+	b.hi 2f
+	cmp w5, #1
+	b.hi 1f
+.endif
+
+	mov x0, sp
+	blr x2
+	ldp x19, x20, [sp, #8 * 24]
+	ldp x21, x22, [sp, #8 * 26]
+	ldp x23, x24, [sp, #8 * 28]
+	ldp x25, x26, [sp, #8 * 30]
+	ldp x27, x28, [sp, #8 * 32]
+
+.if \fault
+	/* Decrement the exception depth again. */
+	ldr x4, [sp, #8 * 19]
+	ldrb w5, [x4]
+	sub w5, w5, #1
+	strb w5, [x4]
+.endif
+
+	restore_volatile_from_stack \el
+	eret
+
+1:
+	mov x0, sp
+	mov x1, x5
+	bl {double_fault}
+2:
+	wfe
+	b 2b
+.endm
+
+/**
+ * Jumped to from the vector table if the stack pointer is implausible, with
+ * the stack pointer in x0 and the original value of x0 lost. Claims a free
+ * emergency stack and switches to it, saves the registers other than x0 and
+ * x1, and then reports the bad stack pointer. If all the emergency stacks are
+ * in use by other cores then the core is halted instead.
+ */
+.macro bad_stack_pointer el:req
+	/*
+	 * Keep the bad stack pointer in sp while claiming a stack, as only x0 and
+	 * x1 are free. A stack is claimed by storing the (non-zero) address of its
+	 * owner entry there.
+	 */
+	mov sp, x0
+	adrp x0, {emergency_stack_owners}
+	add x0, x0, :lo12:{emergency_stack_owners}
+0:
+	ldaxr x1, [x0]
+	cbnz x1, 1f
+	stxr w1, x0, [x0]
+	cbnz w1, 0b
+	b 2f
+1:
+	clrex
+	add x0, x0, #8
+	adrp x1, {emergency_stack_owners}
+	add x1, x1, :lo12:{emergency_stack_owners}
+	add x1, x1, #8 * {emergency_stack_count}
+	cmp x0, x1
+	b.lo 0b
+3:
+	wfe
+	b 3b
+
+2:
+	/* Get the address of the end of the claimed stack in x1. */
+	adrp x1, {emergency_stack_owners}
+	add x1, x1, :lo12:{emergency_stack_owners}
+	sub x0, x0, x1
+	add x0, x0, #8
+	adrp x1, {emergency_stacks}
+	add x1, x1, :lo12:{emergency_stacks}
+	add x1, x1, x0, lsl #{emergency_stack_shift} - 3
+	mov x0, sp
+	mov sp, x1
+
+	/* Save registers in the same layout as handle_exception. */
+	stp xzr, xzr, [sp, #-(8 * 34)]!
+	stp x2, x3, [sp, #8 * 2]
+	stp x4, x5, [sp, #8 * 4]
+	stp x6, x7, [sp, #8 * 6]
+	stp x8, x9, [sp, #8 * 8]
+	stp x10, x11, [sp, #8 * 10]
+	stp x12, x13, [sp, #8 * 12]
+	stp x14, x15, [sp, #8 * 14]
+	stp x16, x17, [sp, #8 * 16]
+	str x18, [sp, #8 * 18]
+	stp x29, x30, [sp, #8 * 20]
+	mrs x2, elr_\el
+	mrs x3, spsr_\el
+	stp x2, x3, [sp, #8 * 22]
+	stp x19, x20, [sp, #8 * 24]
+	stp x21, x22, [sp, #8 * 26]
+	stp x23, x24, [sp, #8 * 28]
+	stp x25, x26, [sp, #8 * 30]
+	stp x27, x28, [sp, #8 * 32]
+
+	mov x1, x0
+	mov x0, sp
+	bl {bad_stack_pointer}
+0:
+	wfe
+	b 0b
+.endm
+
+.macro common_handlers el:req level:req
+.section .text.aarch64_rt_handle_exception_\el, "ax"
+.global __aarch64_rt_bad_stack_pointer_\el
+__aarch64_rt_bad_stack_pointer_\el:
+	bad_stack_pointer \el
+
+.global __aarch64_rt_handle_exception_\el
+__aarch64_rt_handle_exception_\el:
+	handle_exception \el \level 0
+
+.global __aarch64_rt_handle_fault_\el
+__aarch64_rt_handle_fault_\el:
+	handle_exception \el \level 1
+.endm
+
+common_handlers el1 1
+common_handlers el2 2
+common_handlers el3 3
+    "#,
+    bad_stack_pointer = sym bad_stack_pointer,
+    core_index = sym core_index,
+    depth = sym EXCEPTION_DEPTH,
+    double_fault = sym double_fault,
+    emergency_stack_count = const EMERGENCY_STACK_COUNT,
+    emergency_stack_owners = sym EMERGENCY_STACK_OWNERS,
+    emergency_stack_shift = const EMERGENCY_STACK_SHIFT,
+    emergency_stacks = sym EMERGENCY_STACKS,
+    max_cores = const MAX_CORES,
+    max_depth = const MAX_EXCEPTION_DEPTH,
+);
+
 /// Functions to handle aarch64 exceptions.
 ///
 /// Each method has a default implementation which will panic.
+///
+/// The exception vector keeps track of how many synchronous exceptions and SErrors each core is
+/// handling, so if one is taken while the handler for another is running then it reports a double
+/// fault rather than calling the handler again. Handlers must therefore either return or panic,
+/// rather than continuing execution elsewhere, unless they call [`reset_exception_depth`] first. IRQs and FIQs aren't counted, so their handlers may
+/// take and handle synchronous exceptions.
 pub trait ExceptionHandlers {
     /// Handles synchronous exceptions from the current exception level.
-    extern "C" fn sync_current(register_state: RegisterStateRef) {
-        _ = register_state;
+    ///
+    /// The default implementation passes granule protection faults to
+    /// [`handle_granule_protection_fault`](Self::handle_granule_protection_fault), and panics for
+    /// anything else.
+    extern "C" fn sync_current(mut register_state: RegisterStateRef) {
+        if let Some(fault) = GranuleProtectionFault::current() {
+            if Self::handle_granule_protection_fault(&mut register_state, &fault) {
+                return;
+            }
+            panic!("Granule protection fault from current EL: {fault}");
+        }
         panic!("Unexpected synchronous exception from current EL");
     }
 
@@ -86,14 +444,38 @@ pub trait ExceptionHandlers {
     }
 
     /// Handles SErrors from the current exception level.
+    ///
+    /// The default implementation captures an [`SErrorReport`] and passes it to
+    /// [`classify_serror`](Self::classify_serror), then either resumes or panics accordingly.
     extern "C" fn serror_current(register_state: RegisterStateRef) {
         _ = register_state;
-        panic!("Unexpected SError from current EL");
+        let report = SErrorReport::current();
+        if !resolve_serror(&report, Self::classify_serror(&report)) {
+            panic!("Unexpected SError from current EL: {report}");
+        }
     }
 
     /// Handles synchronous exceptions from a lower exception level.
-    extern "C" fn sync_lower(register_state: RegisterStateRef) {
-        _ = register_state;
+    ///
+    /// The default implementation passes granule protection faults to
+    /// [`handle_granule_protection_fault`](Self::handle_granule_protection_fault) and SMCs taken to
+    /// EL3 to [`handle_smc`](Self::handle_smc), and panics for anything else.
+    extern "C" fn sync_lower(mut register_state: RegisterStateRef) {
+        if current_el() == 3 && (read_esr() >> 26) & 0x3f == ESR_EC_SMC64 {
+            if !Self::handle_smc(&mut register_state) {
+                // SAFETY: The caller of the SMC expects x0 to be set to the result.
+                unsafe {
+                    register_state.get_mut().registers[0] = SMCCC_NOT_SUPPORTED as u64;
+                }
+            }
+            return;
+        }
+        if let Some(fault) = GranuleProtectionFault::current() {
+            if Self::handle_granule_protection_fault(&mut register_state, &fault) {
+                return;
+            }
+            panic!("Granule protection fault from lower EL: {fault}");
+        }
         panic!("Unexpected synchronous exception from lower EL");
     }
 
@@ -110,20 +492,292 @@ pub trait ExceptionHandlers {
     }
 
     /// Handles SErrors from a lower exception level.
+    ///
+    /// The default implementation captures an [`SErrorReport`] and passes it to
+    /// [`classify_serror`](Self::classify_serror), then either resumes or panics accordingly.
     extern "C" fn serror_lower(register_state: RegisterStateRef) {
         _ = register_state;
-        panic!("Unexpected SError from lower EL");
+        let report = SErrorReport::current();
+        if !resolve_serror(&report, Self::classify_serror(&report)) {
+            panic!("Unexpected SError from lower EL: {report}");
+        }
+    }
+
+    /// Decides what to do about an SError, for the default implementations of
+    /// [`serror_current`](Self::serror_current) and [`serror_lower`](Self::serror_lower).
+    ///
+    /// The default implementation treats all SErrors as fatal. Uncontainable errors are always
+    /// treated as fatal, whatever this returns.
+    fn classify_serror(report: &SErrorReport) -> SErrorAction {
+        _ = report;
+        SErrorAction::Fatal
+    }
+
+    /// Handles a granule protection fault, for the default implementations of
+    /// [`sync_current`](Self::sync_current) and [`sync_lower`](Self::sync_lower).
+    ///
+    /// Returns whether execution may resume, e.g. because the handler has updated the granule
+    /// protection table or the saved ELR. The default implementation returns `false`, so the fault
+    /// is reported in a panic.
+    fn handle_granule_protection_fault(
+        register_state: &mut RegisterStateRef,
+        fault: &GranuleProtectionFault,
+    ) -> bool {
+        _ = register_state;
+        _ = fault;
+        false
+    }
+
+    /// Handles an SMC from a lower exception level taken to EL3, for the default implementation of
+    /// [`sync_lower`](Self::sync_lower).
+    ///
+    /// The SMCCC function identifier and arguments are in the saved x0-x17, and the handler should
+    /// set the results in the saved registers starting from x0. The saved ELR already points to the
+    /// instruction after the SMC. Returns whether the function was handled; if not, x0 is set to
+    /// the SMCCC `NOT_SUPPORTED` value. The default implementation handles nothing.
+    ///
+    /// With the `el3` feature, a monitor can pass calls to `psci_service::PsciService::handle_smc`
+    /// here to serve PSCI.
+    fn handle_smc(register_state: &mut RegisterStateRef) -> bool {
+        _ = register_state;
+        false
     }
 }
 
 /// Registers an implementation of the [`ExceptionHandlers`] trait to handle exceptions.
+///
+/// With just a type, this provides the vector table which the entry points install. With a name as
+/// well, it instead provides an additional vector table, and defines a function with that name
+/// returning it as a [`VectorTable`](crate::VectorTable), which can be installed later with
+/// [`set_vector_table`](crate::set_vector_table):
+///
+/// ```rust,ignore
+/// exception_handlers!(EarlyExceptions);
+/// exception_handlers!(FullExceptions, full_vector_table);
+///
+/// // Once everything the full handlers need is ready:
+/// set_vector_table(full_vector_table());
+/// ```
 #[macro_export]
 macro_rules! exception_handlers {
     ($handlers:ty) => {
         core::arch::global_asm!(
+            $crate::__vector_tables_for_el!("vector_table"),
+            sync_current = sym <$handlers as $crate::ExceptionHandlers>::sync_current,
+            irq_current = sym <$handlers as $crate::ExceptionHandlers>::irq_current,
+            fiq_current = sym <$handlers as $crate::ExceptionHandlers>::fiq_current,
+            serror_current = sym <$handlers as $crate::ExceptionHandlers>::serror_current,
+            sync_lower = sym <$handlers as $crate::ExceptionHandlers>::sync_lower,
+            irq_lower = sym <$handlers as $crate::ExceptionHandlers>::irq_lower,
+            fiq_lower = sym <$handlers as $crate::ExceptionHandlers>::fiq_lower,
+            serror_lower = sym <$handlers as $crate::ExceptionHandlers>::serror_lower,
+        );
+    };
+    ($handlers:ty, $name:ident) => {
+        core::arch::global_asm!(
+            $crate::__vector_tables_for_el!(stringify!($name)),
+            sync_current = sym <$handlers as $crate::ExceptionHandlers>::sync_current,
+            irq_current = sym <$handlers as $crate::ExceptionHandlers>::irq_current,
+            fiq_current = sym <$handlers as $crate::ExceptionHandlers>::fiq_current,
+            serror_current = sym <$handlers as $crate::ExceptionHandlers>::serror_current,
+            sync_lower = sym <$handlers as $crate::ExceptionHandlers>::sync_lower,
+            irq_lower = sym <$handlers as $crate::ExceptionHandlers>::irq_lower,
+            fiq_lower = sym <$handlers as $crate::ExceptionHandlers>::fiq_lower,
+            serror_lower = sym <$handlers as $crate::ExceptionHandlers>::serror_lower,
+        );
+
+        /// Returns the vector table generated for the handlers by `exception_handlers!`.
+        fn $name() -> $crate::VectorTable {
+            let (el1, el2, el3) = $crate::__vector_table_addresses!(stringify!($name));
+            // SAFETY: The addresses are of the vector tables generated above.
+            unsafe { $crate::VectorTable::new(el1, el2, el3) }
+        }
+    };
+}
+
+/// Registers an implementation of the [`ExceptionHandlers`] trait to handle exceptions at a lower
+/// exception level than the one the image is running at, such as one which the image drops to
+/// after initialisation.
+///
+/// This provides vector tables for EL1 and EL2, which can be installed with
+/// [`set_lower_exception_vector`](crate::set_lower_exception_vector) before dropping to that
+/// exception level, so that it doesn't run with an uninitialised vbar.
+#[macro_export]
+macro_rules! lower_exception_handlers {
+    ($handlers:ty) => {
+        core::arch::global_asm!(
+            $crate::__lower_vector_tables_for_el!("lower_vector_table"),
+            sync_current = sym <$handlers as $crate::ExceptionHandlers>::sync_current,
+            irq_current = sym <$handlers as $crate::ExceptionHandlers>::irq_current,
+            fiq_current = sym <$handlers as $crate::ExceptionHandlers>::fiq_current,
+            serror_current = sym <$handlers as $crate::ExceptionHandlers>::serror_current,
+            sync_lower = sym <$handlers as $crate::ExceptionHandlers>::sync_lower,
+            irq_lower = sym <$handlers as $crate::ExceptionHandlers>::irq_lower,
+            fiq_lower = sym <$handlers as $crate::ExceptionHandlers>::fiq_lower,
+            serror_lower = sym <$handlers as $crate::ExceptionHandlers>::serror_lower,
+        );
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// which the image may run at, as selected by the `el1`, `el2` and `el3` features.
+#[cfg(feature = "el1")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el1")
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// which the image may run at, as selected by the `el1`, `el2` and `el3` features.
+#[cfg(feature = "el2")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el2")
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// which the image may run at, as selected by the `el1`, `el2` and `el3` features.
+#[cfg(feature = "el3")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el3")
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// which the image may run at, as selected by the `el1`, `el2` and `el3` features.
+#[cfg(not(any(feature = "el1", feature = "el2", feature = "el3")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el1", "el2", "el3")
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// below the one which the image runs at, other than EL0.
+///
+/// There are none when running at EL1, so this just mentions the handlers in a comment, as every
+/// `sym` operand must be used.
+#[cfg(feature = "el1")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lower_vector_tables_for_el {
+    ($name:expr) => {
+        "/* {sync_current} {irq_current} {fiq_current} {serror_current} {sync_lower} {irq_lower} \
+            {fiq_lower} {serror_lower} */"
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// below the one which the image runs at, other than EL0.
+#[cfg(feature = "el2")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lower_vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el1")
+    };
+}
+
+/// Returns assembly code for exception vector tables with the given name for each exception level
+/// below the one which the image runs at, other than EL0.
+#[cfg(not(any(feature = "el1", feature = "el2")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __lower_vector_tables_for_el {
+    ($name:expr) => {
+        $crate::__vector_tables!($name, "el1", "el2")
+    };
+}
+
+/// Returns the addresses of the vector tables generated by `__vector_tables_for_el!` with the given
+/// name for EL1, EL2 and EL3, or 0 for those which weren't generated.
+#[cfg(feature = "el1")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_table_addresses {
+    ($name:expr) => {
+        ($crate::__vector_table_address!($name, "el1"), 0, 0)
+    };
+}
+
+/// Returns the addresses of the vector tables generated by `__vector_tables_for_el!` with the given
+/// name for EL1, EL2 and EL3, or 0 for those which weren't generated.
+#[cfg(feature = "el2")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_table_addresses {
+    ($name:expr) => {
+        (0, $crate::__vector_table_address!($name, "el2"), 0)
+    };
+}
+
+/// Returns the addresses of the vector tables generated by `__vector_tables_for_el!` with the given
+/// name for EL1, EL2 and EL3, or 0 for those which weren't generated.
+#[cfg(feature = "el3")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_table_addresses {
+    ($name:expr) => {
+        (0, 0, $crate::__vector_table_address!($name, "el3"))
+    };
+}
+
+/// Returns the addresses of the vector tables generated by `__vector_tables_for_el!` with the given
+/// name for EL1, EL2 and EL3, or 0 for those which weren't generated.
+#[cfg(not(any(feature = "el1", feature = "el2", feature = "el3")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_table_addresses {
+    ($name:expr) => {
+        (
+            $crate::__vector_table_address!($name, "el1"),
+            $crate::__vector_table_address!($name, "el2"),
+            $crate::__vector_table_address!($name, "el3"),
+        )
+    };
+}
+
+/// Returns the address of the vector table with the given name for the given exception level.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_table_address {
+    ($name:expr, $el:literal) => {{
+        let address: usize;
+        // SAFETY: This only computes the address of the vector table.
+        unsafe {
+            core::arch::asm!(
+                concat!("adrp {address}, ", $name, "_", $el),
+                concat!("add {address}, {address}, :lo12:", $name, "_", $el),
+                address = out(reg) address,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        address
+    }};
+}
+
+/// Returns assembly code for exception vector tables with the given name for each of the given
+/// exception levels, which call the handler functions given as `sym` operands.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __vector_tables {
+    ($name:expr, $($el:literal),*) => {
+        concat!(
             r#"
 /**
- * Saves the volatile registers onto the stack. This currently takes 14
+ * Saves the volatile registers onto the stack, leaving space after them for
+ * the common handler code to save x19-x28. This currently takes 14
  * instructions, so it can be used in exception handlers with 18 instructions
  * left.
  *
@@ -132,7 +786,7 @@ macro_rules! exception_handlers {
  */
 .macro save_volatile_to_stack el:req
 	/* Reserve stack space and save registers x0-x18, x29 & x30. */
-	stp x0, x1, [sp, #-(8 * 24)]!
+	stp x0, x1, [sp, #-(8 * 34)]!
 	stp x2, x3, [sp, #8 * 2]
 	stp x4, x5, [sp, #8 * 4]
 	stp x6, x7, [sp, #8 * 6]
@@ -154,135 +808,133 @@ macro_rules! exception_handlers {
 .endm
 
 /**
- * Restores the volatile registers from the stack. This currently takes 14
- * instructions, so it can be used in exception handlers while still leaving 18
- * instructions left; if paired with save_volatile_to_stack, there are 4
- * instructions to spare.
+ * Checks that the stack pointer is plausible before anything is saved to the
+ * stack: that it is 16-byte aligned, within the lower or upper 48-bit address
+ * range (i.e. its top 16 bits are all 0 or all 1, for TTBR0 or TTBR1), and not
+ * so close to zero that saving registers would wrap around. If not, jumps to
+ * code which reports the bad stack pointer from an emergency stack. This
+ * doesn't use any registers other than the stack pointer and x0, which are
+ * temporarily combined and then restored, and takes 14 instructions.
  */
-.macro restore_volatile_from_stack el:req
-	/* Restore registers x2-x18, x29 & x30. */
-	ldp x2, x3, [sp, #8 * 2]
-	ldp x4, x5, [sp, #8 * 4]
-	ldp x6, x7, [sp, #8 * 6]
-	ldp x8, x9, [sp, #8 * 8]
-	ldp x10, x11, [sp, #8 * 10]
-	ldp x12, x13, [sp, #8 * 12]
-	ldp x14, x15, [sp, #8 * 14]
-	ldp x16, x17, [sp, #8 * 16]
-	ldr x18, [sp, #8 * 18]
-	ldp x29, x30, [sp, #8 * 20]
-
-	/* Restore registers elr_elX & spsr_elX, using x0 & x1 as scratch. */
-	ldp x0, x1, [sp, #8 * 22]
-	msr elr_\el, x0
-	msr spsr_\el, x1
-
-	/* Restore x0 & x1, and release stack space. */
-	ldp x0, x1, [sp], #8 * 24
+.macro check_stack_pointer el:req
+	add sp, sp, x0
+	sub x0, sp, x0
+	tst x0, #0xf
+	b.ne __aarch64_rt_bad_stack_pointer_\el
+	/*
+	 * Rotate the top 16 bits to the bottom and add 1, so that both 0 and
+	 * 0xffff leave bits 1-15 clear, then undo it without changing the flags.
+	 */
+	ror x0, x0, #48
+	add x0, x0, #1
+	tst x0, #0xfffe
+	sub x0, x0, #1
+	ror x0, x0, #16
+	b.ne __aarch64_rt_bad_stack_pointer_\el
+	cmp x0, #8 * 34
+	b.lo __aarch64_rt_bad_stack_pointer_\el
+	sub x0, sp, x0
+	sub sp, sp, x0
 .endm
 
 /**
- * This is a generic handler for exceptions taken at the current EL. It saves
- * volatile registers to the stack, calls the Rust handler, restores volatile
- * registers, then returns.
+ * This is a generic handler for exceptions taken at the current or a lower EL.
+ * It saves volatile registers to the stack, then jumps to the common handler
+ * code with the address of the Rust handler in x2. The common code saves the
+ * remaining registers, calls the Rust handler, restores the registers, then
+ * returns.
  *
- * This also works for exceptions taken from lower ELs, if we don't care about
- * non-volatile registers.
- *
- * Saving state and jumping to the Rust handler takes 16 instructions, and
- * restoring and returning also takes 15 instructions, so we can fit the whole
- * handler in 31 instructions, under the limit of 32.
+ * `common` is the common handler code to use, either
+ * `__aarch64_rt_handle_exception` or `__aarch64_rt_handle_fault`, which also
+ * checks whether the exception was taken while handling another one. This all
+ * takes 31 instructions, under the limit of 32.
  */
-.macro current_exception handler:req el:req
+.macro current_exception handler:req el:req common:req
+	check_stack_pointer \el
 	save_volatile_to_stack \el
-	mov x0, sp
-	bl \handler
-	restore_volatile_from_stack \el
-	eret
+	adrp x2, \handler
+	add x2, x2, :lo12:\handler
+	b \common\()_\el
 .endm
 
-.macro vector_table el:req
-.section .text.vector_table_\el, "ax"
-.global vector_table_\el
+.macro vector_table name:req el:req
+.section .text.\name\()_\el, "ax"
+.global \name\()_\el
 .balign 0x800
-vector_table_\el:
-sync_cur_sp0_\el:
-	current_exception {sync_current} \el
+\name\()_\el:
+\name\()_sync_cur_sp0_\el:
+	current_exception {sync_current} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-irq_cur_sp0_\el:
-	current_exception {irq_current} \el
+\name\()_irq_cur_sp0_\el:
+	current_exception {irq_current} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-fiq_cur_sp0_\el:
-	current_exception {fiq_current} \el
+\name\()_fiq_cur_sp0_\el:
+	current_exception {fiq_current} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-serr_cur_sp0_\el:
-	current_exception {serror_current} \el
+\name\()_serr_cur_sp0_\el:
+	current_exception {serror_current} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-sync_cur_spx_\el:
-	current_exception {sync_current} \el
+\name\()_sync_cur_spx_\el:
+	current_exception {sync_current} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-irq_cur_spx_\el:
-	current_exception {irq_current} \el
+\name\()_irq_cur_spx_\el:
+	current_exception {irq_current} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-fiq_cur_spx_\el:
-	current_exception {fiq_current} \el
+\name\()_fiq_cur_spx_\el:
+	current_exception {fiq_current} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-serr_cur_spx_\el:
-	current_exception {serror_current} \el
+\name\()_serr_cur_spx_\el:
+	current_exception {serror_current} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-sync_lower_64_\el:
-	current_exception {sync_lower} \el
+\name\()_sync_lower_64_\el:
+	current_exception {sync_lower} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-irq_lower_64_\el:
-	current_exception {irq_lower} \el
+\name\()_irq_lower_64_\el:
+	current_exception {irq_lower} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-fiq_lower_64_\el:
-	current_exception {fiq_lower} \el
+\name\()_fiq_lower_64_\el:
+	current_exception {fiq_lower} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-serr_lower_64_\el:
-	current_exception {serror_lower} \el
+\name\()_serr_lower_64_\el:
+	current_exception {serror_lower} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-sync_lower_32_\el:
-	current_exception {sync_lower} \el
+\name\()_sync_lower_32_\el:
+	current_exception {sync_lower} \el __aarch64_rt_handle_fault
 
 .balign 0x80
-irq_lower_32_\el:
-	current_exception {irq_lower} \el
+\name\()_irq_lower_32_\el:
+	current_exception {irq_lower} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-fiq_lower_32_\el:
-	current_exception {fiq_lower} \el
+\name\()_fiq_lower_32_\el:
+	current_exception {fiq_lower} \el __aarch64_rt_handle_exception
 
 .balign 0x80
-serr_lower_32_\el:
-	current_exception {serror_lower} \el
+\name\()_serr_lower_32_\el:
+	current_exception {serror_lower} \el __aarch64_rt_handle_fault
 
 .endm
 
-vector_table el1
-vector_table el2
-vector_table el3
-            "#,
-            sync_current = sym <$handlers as $crate::ExceptionHandlers>::sync_current,
-            irq_current = sym <$handlers as $crate::ExceptionHandlers>::irq_current,
-            fiq_current = sym <$handlers as $crate::ExceptionHandlers>::fiq_current,
-            serror_current = sym <$handlers as $crate::ExceptionHandlers>::serror_current,
-            sync_lower = sym <$handlers as $crate::ExceptionHandlers>::sync_lower,
-            irq_lower = sym <$handlers as $crate::ExceptionHandlers>::irq_lower,
-            fiq_lower = sym <$handlers as $crate::ExceptionHandlers>::fiq_lower,
-            serror_lower = sym <$handlers as $crate::ExceptionHandlers>::serror_lower,
-        );
+"#,
+            $("vector_table ", $name, " ", $el, "\n",)*
+            r#"
+.purgem save_volatile_to_stack
+.purgem check_stack_pointer
+.purgem current_exception
+.purgem vector_table
+"#,
+        )
     };
 }
@@ -0,0 +1,405 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Minimal reading and patching of flattened device tree blobs.
+//!
+//! An image which loads another image, such as an operating system kernel, can use [`FdtPatch`] to
+//! make a copy of the device tree to pass on with some changes: `/memreserve/` entries for regions
+//! which must be preserved, such as a crash log or trace buffer, and the kernel command line and
+//! initrd range in `/chosen`:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::fdt::{FdtPatch, fdt_slice};
+//!
+//! // SAFETY: The bootloader passed a valid device tree blob.
+//! let fdt = unsafe { fdt_slice(fdt_address) }.unwrap();
+//! let mut patch = FdtPatch::new();
+//! patch.add_memreserve(CRASH_LOG_ADDRESS, CRASH_LOG_SIZE).unwrap();
+//! patch.set_bootargs("console=ttyAMA0");
+//! patch.set_initrd(initrd_start, initrd_end);
+//! let len = patch.apply(fdt, &mut FDT_BUFFER).unwrap();
+//! ```
+//!
+//! Everything else in the device tree is copied unchanged.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+pub(crate) const FDT_BEGIN_NODE: u32 = 1;
+pub(crate) const FDT_END_NODE: u32 = 2;
+pub(crate) const FDT_PROP: u32 = 3;
+pub(crate) const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Returns the flattened device tree blob at the given address as a slice of the size given in its
+/// header, or `None` if it doesn't have a valid header.
+///
+/// # Safety
+///
+/// `fdt` must point to a flattened device tree blob, which must be valid for reads of the size
+/// given in its header for the lifetime `'a`.
+pub unsafe fn fdt_slice<'a>(fdt: *const u8) -> Option<&'a [u8]> {
+    // SAFETY: Our caller promised that `fdt` points to a device tree header.
+    let header = unsafe { core::slice::from_raw_parts(fdt, FDT_HEADER_SIZE) };
+    if read_be32(header, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let total_size = read_be32(header, 4)? as usize;
+    // SAFETY: Our caller promised that the blob is valid for reads of the size in its header.
+    Some(unsafe { core::slice::from_raw_parts(fdt, total_size) })
+}
+
+/// Reads a big-endian `u32` from the given offset in the slice.
+pub(crate) fn read_be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns the NUL-terminated string at the start of the given slice, without the terminator.
+pub(crate) fn c_str(bytes: &[u8]) -> Option<&[u8]> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    Some(&bytes[..len])
+}
+
+/// Rounds the given offset up to a multiple of 4 bytes, the alignment of structure block tokens.
+pub(crate) fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+/// The size of a `/memreserve/` entry.
+const MEMRESERVE_ENTRY_SIZE: usize = 16;
+/// The device tree version which [`FdtPatch::apply`] writes.
+const FDT_VERSION: u32 = 17;
+/// The oldest device tree version which the blobs written by [`FdtPatch::apply`] are compatible
+/// with.
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+/// The maximum number of `/memreserve/` entries which an [`FdtPatch`] can add.
+pub const MAX_MEMRESERVE: usize = 8;
+
+const BOOTARGS: &[u8] = b"bootargs";
+const INITRD_START: &[u8] = b"linux,initrd-start";
+const INITRD_END: &[u8] = b"linux,initrd-end";
+
+/// An error patching a device tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FdtError {
+    /// The device tree header is invalid.
+    InvalidHeader,
+    /// The device tree structure is invalid or truncated.
+    InvalidStructure,
+    /// The buffer for the patched device tree is too small.
+    BufferTooSmall,
+    /// More than [`MAX_MEMRESERVE`] `/memreserve/` entries were added.
+    TooManyReservations,
+}
+
+/// A set of changes to make to a device tree with [`FdtPatch::apply`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FdtPatch<'a> {
+    memreserve: [(u64, u64); MAX_MEMRESERVE],
+    memreserve_count: usize,
+    bootargs: Option<&'a str>,
+    initrd: Option<(u64, u64)>,
+}
+
+impl<'a> FdtPatch<'a> {
+    /// Creates a patch which makes no changes.
+    pub const fn new() -> Self {
+        Self {
+            memreserve: [(0, 0); MAX_MEMRESERVE],
+            memreserve_count: 0,
+            bootargs: None,
+            initrd: None,
+        }
+    }
+
+    /// Adds a `/memreserve/` entry for the `size` bytes of physical memory starting at `address`,
+    /// so that the next stage doesn't use it.
+    pub fn add_memreserve(&mut self, address: u64, size: u64) -> Result<(), FdtError> {
+        let entry = self
+            .memreserve
+            .get_mut(self.memreserve_count)
+            .ok_or(FdtError::TooManyReservations)?;
+        *entry = (address, size);
+        self.memreserve_count += 1;
+        Ok(())
+    }
+
+    /// Sets the `bootargs` property of `/chosen`, i.e. the kernel command line.
+    pub fn set_bootargs(&mut self, bootargs: &'a str) {
+        self.bootargs = Some(bootargs);
+    }
+
+    /// Sets the `linux,initrd-start` and `linux,initrd-end` properties of `/chosen` to the given
+    /// physical addresses.
+    pub fn set_initrd(&mut self, start: u64, end: u64) {
+        self.initrd = Some((start, end));
+    }
+
+    /// Writes a copy of the device tree `fdt` with the changes applied to `out`, and returns its
+    /// total size.
+    ///
+    /// Properties of `/chosen` which are set by the patch replace any existing ones, and `/chosen`
+    /// is added if it doesn't exist. `out` must not overlap `fdt`.
+    pub fn apply(&self, fdt: &[u8], out: &mut [u8]) -> Result<usize, FdtError> {
+        if fdt.len() < FDT_HEADER_SIZE || read_be32(fdt, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::InvalidHeader);
+        }
+        let header = |offset| read_be32(fdt, offset).ok_or(FdtError::InvalidHeader);
+        let total_size = header(4)? as usize;
+        let structs = header(8)? as usize;
+        let strings = header(12)? as usize;
+        let memreserve = header(16)? as usize;
+        let boot_cpuid = header(28)?;
+        let strings_size = header(32)? as usize;
+        let strings = fdt
+            .get(strings..strings + strings_size)
+            .ok_or(FdtError::InvalidHeader)?;
+        let fdt = fdt.get(..total_size).ok_or(FdtError::InvalidHeader)?;
+
+        let mut writer = Writer { out, offset: 0 };
+        writer.skip(FDT_HEADER_SIZE)?;
+
+        // Copy the existing reservations and then add ours.
+        writer.align(8)?;
+        let out_memreserve = writer.offset;
+        let mut offset = memreserve;
+        loop {
+            let entry = fdt
+                .get(offset..offset + MEMRESERVE_ENTRY_SIZE)
+                .ok_or(FdtError::InvalidStructure)?;
+            if entry.iter().all(|&b| b == 0) {
+                break;
+            }
+            writer.bytes(entry)?;
+            offset += MEMRESERVE_ENTRY_SIZE;
+        }
+        for &(address, size) in &self.memreserve[..self.memreserve_count] {
+            writer.bytes(&address.to_be_bytes())?;
+            writer.bytes(&size.to_be_bytes())?;
+        }
+        writer.bytes(&[0; MEMRESERVE_ENTRY_SIZE])?;
+
+        // Work out where the names of any new properties will be in the strings block.
+        let mut new_strings = NewStrings {
+            original: strings,
+            names: [&[]; 3],
+            count: 0,
+        };
+        let bootargs_name = self.bootargs.map(|_| new_strings.offset_of(BOOTARGS));
+        let initrd_names = self.initrd.map(|_| {
+            (
+                new_strings.offset_of(INITRD_START),
+                new_strings.offset_of(INITRD_END),
+            )
+        });
+        let write_chosen_properties = |writer: &mut Writer| -> Result<(), FdtError> {
+            if let (Some(bootargs), Some(name)) = (self.bootargs, bootargs_name) {
+                writer.property(name, &[bootargs.as_bytes(), b"\0"])?;
+            }
+            if let (Some((start, end)), Some((start_name, end_name))) = (self.initrd, initrd_names)
+            {
+                writer.property(start_name, &[&start.to_be_bytes()])?;
+                writer.property(end_name, &[&end.to_be_bytes()])?;
+            }
+            Ok(())
+        };
+
+        // Copy the structure block, replacing or adding properties of `/chosen`.
+        let out_structs = writer.offset;
+        let mut offset = structs;
+        let mut depth = 0;
+        // The depth of `/chosen` while inside it, including inside any of its subnodes.
+        let mut chosen_depth = None;
+        let mut found_chosen = false;
+        // Whether the new properties have been written to `/chosen`. They must come before its
+        // first subnode, or at its end if it has none.
+        let mut chosen_written = false;
+        loop {
+            let token = read_be32(fdt, offset).ok_or(FdtError::InvalidStructure)?;
+            let start = offset;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(fdt.get(offset..).ok_or(FdtError::InvalidStructure)?)
+                        .ok_or(FdtError::InvalidStructure)?;
+                    offset = align4(offset + name.len() + 1);
+                    if chosen_depth == Some(depth) && !chosen_written {
+                        write_chosen_properties(&mut writer)?;
+                        chosen_written = true;
+                    }
+                    depth += 1;
+                    if depth == 2 && name == b"chosen" && !found_chosen {
+                        chosen_depth = Some(depth);
+                        found_chosen = true;
+                    }
+                }
+                FDT_END_NODE => {
+                    if chosen_depth == Some(depth) {
+                        if !chosen_written {
+                            write_chosen_properties(&mut writer)?;
+                            chosen_written = true;
+                        }
+                        chosen_depth = None;
+                    } else if depth == 1 && !found_chosen {
+                        writer.be32(FDT_BEGIN_NODE)?;
+                        writer.bytes(b"chosen\0")?;
+                        writer.align(4)?;
+                        write_chosen_properties(&mut writer)?;
+                        writer.be32(FDT_END_NODE)?;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = read_be32(fdt, offset).ok_or(FdtError::InvalidStructure)? as usize;
+                    let name_offset =
+                        read_be32(fdt, offset + 4).ok_or(FdtError::InvalidStructure)? as usize;
+                    offset = align4(offset + 8 + len);
+                    let name = c_str(
+                        strings
+                            .get(name_offset..)
+                            .ok_or(FdtError::InvalidStructure)?,
+                    )
+                    .ok_or(FdtError::InvalidStructure)?;
+                    let replaced = match name {
+                        BOOTARGS => self.bootargs.is_some(),
+                        INITRD_START | INITRD_END => self.initrd.is_some(),
+                        _ => false,
+                    };
+                    if chosen_depth == Some(depth) && replaced {
+                        continue;
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => {
+                    writer.be32(FDT_END)?;
+                    break;
+                }
+                _ => return Err(FdtError::InvalidStructure),
+            }
+            writer.bytes(fdt.get(start..offset).ok_or(FdtError::InvalidStructure)?)?;
+        }
+        let out_structs_size = writer.offset - out_structs;
+
+        // Copy the strings block, and append the new names.
+        let out_strings = writer.offset;
+        writer.bytes(strings)?;
+        for name in &new_strings.names[..new_strings.count] {
+            writer.bytes(name)?;
+            writer.bytes(b"\0")?;
+        }
+        let out_strings_size = writer.offset - out_strings;
+        let out_total_size = writer.offset;
+
+        for (offset, value) in [
+            (0, FDT_MAGIC),
+            (4, out_total_size as u32),
+            (8, out_structs as u32),
+            (12, out_strings as u32),
+            (16, out_memreserve as u32),
+            (20, FDT_VERSION),
+            (24, FDT_LAST_COMPATIBLE_VERSION),
+            (28, boot_cpuid),
+            (32, out_strings_size as u32),
+            (36, out_structs_size as u32),
+        ] {
+            writer.out[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        Ok(out_total_size)
+    }
+}
+
+/// Property names to be appended to a strings block.
+struct NewStrings<'a> {
+    original: &'a [u8],
+    names: [&'static [u8]; 3],
+    count: usize,
+}
+
+impl NewStrings<'_> {
+    /// Returns the offset of the given name in the strings block, appending it if it isn't already
+    /// there.
+    fn offset_of(&mut self, name: &'static [u8]) -> u32 {
+        if let Some(offset) = find_string(self.original, name) {
+            return offset as u32;
+        }
+        let mut offset = self.original.len();
+        for existing in &self.names[..self.count] {
+            if *existing == name {
+                return offset as u32;
+            }
+            offset += existing.len() + 1;
+        }
+        self.names[self.count] = name;
+        self.count += 1;
+        offset as u32
+    }
+}
+
+/// Returns the offset of the given NUL-terminated string in a strings block, if it is there.
+fn find_string(strings: &[u8], name: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset < strings.len() {
+        let string = c_str(&strings[offset..])?;
+        if string == name {
+            return Some(offset);
+        }
+        offset += string.len() + 1;
+    }
+    None
+}
+
+/// Writes a device tree blob to a buffer.
+struct Writer<'a> {
+    out: &'a mut [u8],
+    offset: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), FdtError> {
+        let end = self.offset + bytes.len();
+        self.out
+            .get_mut(self.offset..end)
+            .ok_or(FdtError::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.offset = end;
+        Ok(())
+    }
+
+    fn be32(&mut self, value: u32) -> Result<(), FdtError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes zeroes up to the given alignment.
+    fn align(&mut self, align: usize) -> Result<(), FdtError> {
+        let end = self.offset.next_multiple_of(align);
+        self.zeroes(end - self.offset)
+    }
+
+    /// Reserves the given number of bytes, and zeroes them.
+    fn skip(&mut self, len: usize) -> Result<(), FdtError> {
+        self.zeroes(len)
+    }
+
+    fn zeroes(&mut self, len: usize) -> Result<(), FdtError> {
+        let end = self.offset + len;
+        self.out
+            .get_mut(self.offset..end)
+            .ok_or(FdtError::BufferTooSmall)?
+            .fill(0);
+        self.offset = end;
+        Ok(())
+    }
+
+    /// Writes a property with the given name offset, and value made of the given parts.
+    fn property(&mut self, name_offset: u32, value: &[&[u8]]) -> Result<(), FdtError> {
+        self.be32(FDT_PROP)?;
+        self.be32(value.iter().map(|part| part.len()).sum::<usize>() as u32)?;
+        self.be32(name_offset)?;
+        for part in value {
+            self.bytes(part)?;
+        }
+        self.align(4)
+    }
+}
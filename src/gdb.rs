@@ -0,0 +1,470 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A minimal GDB remote serial protocol stub.
+//!
+//! This supports reading the saved registers, reading and writing memory, hardware breakpoints and
+//! watchpoints via the [`debug`](crate::debug) module, continuing and single-stepping. The stack
+//! pointer isn't saved on exception entry, so is reported to GDB as unavailable.
+//!
+//! Call [`GdbStub::handle_exception`] from your exception handlers, e.g. for debug exceptions from
+//! [`sync_current`](crate::ExceptionHandlers::sync_current). Call
+//! [`debug::enable_debug_exceptions`] first so that breakpoints and single-stepping work.
+
+use crate::{
+    RegisterState, RegisterStateRef,
+    cache::sync_icache,
+    debug::{self, DebugException, WatchpointAccess},
+    sysreg::{read_sysreg, write_sysreg},
+};
+use embedded_io::{Read, ReadExactError, Write};
+
+/// The maximum size of a packet which we can receive.
+const PACKET_SIZE: usize = 1024;
+
+/// Software step enable, in MDSCR_EL1.
+const MDSCR_SS: u64 = 1 << 0;
+/// Software step, in SPSR_ELx.
+const SPSR_SS: u64 = 1 << 21;
+/// Debug exception mask, in SPSR_ELx.
+const SPSR_D: u64 = 1 << 9;
+
+/// The GDB register number of the PC.
+const REGISTER_PC: usize = 32;
+/// The GDB register number of CPSR.
+const REGISTER_CPSR: usize = 33;
+
+/// A GDB remote serial protocol stub communicating over the given connection.
+pub struct GdbStub<T> {
+    connection: T,
+    breakpoints: [Option<usize>; 16],
+    watchpoints: [Option<usize>; 16],
+}
+
+impl<T: Read + Write> GdbStub<T> {
+    /// Creates a new GDB stub communicating over the given connection.
+    pub const fn new(connection: T) -> Self {
+        Self {
+            connection,
+            breakpoints: [None; 16],
+            watchpoints: [None; 16],
+        }
+    }
+
+    /// Reports the current exception to GDB, and processes commands until GDB asks to continue or
+    /// step.
+    ///
+    /// # Safety
+    ///
+    /// GDB may read and write arbitrary memory and modify the saved register state, which is
+    /// inherently unsafe. The caller must ensure that the connected debugger doesn't access memory
+    /// which isn't mapped or make changes which cause undefined behaviour when returning from the
+    /// exception.
+    pub unsafe fn handle_exception(
+        &mut self,
+        register_state: &mut RegisterStateRef,
+    ) -> Result<(), ReadExactError<T::Error>> {
+        // SAFETY: Our caller promised that the debugger won't make changes which cause undefined
+        // behaviour.
+        let registers = unsafe { register_state.get_mut() };
+
+        // Stop any single-step in progress.
+        // SAFETY: Clearing MDSCR_EL1.SS just disables software step.
+        unsafe {
+            let mdscr = read_sysreg!("mdscr_el1");
+            write_sysreg!("mdscr_el1", mdscr & !MDSCR_SS);
+        }
+        registers.spsr &= !SPSR_SS;
+
+        self.send_stop_reply()?;
+        let mut packet = [0; PACKET_SIZE];
+        loop {
+            let len = self.receive_packet(&mut packet)?;
+            let packet = &packet[..len];
+            let Some((&command, arguments)) = packet.split_first() else {
+                self.send_packet(b"")?;
+                continue;
+            };
+            match command {
+                b'?' => self.send_stop_reply()?,
+                b'g' => self.send_registers(registers)?,
+                b'p' => match parse_hex(arguments) {
+                    Some(register) => {
+                        let mut response = Response::start(&mut self.connection)?;
+                        write_register(&mut response, registers, register as usize)?;
+                        response.finish()?;
+                    }
+                    None => self.send_packet(b"E01")?,
+                },
+                b'P' => {
+                    let response: &[u8] =
+                        match split_at_byte(arguments, b'=').and_then(|(register, value)| {
+                            Some((parse_hex(register)?, parse_hex_le(value)?))
+                        }) {
+                            Some((register, value))
+                                if set_register(registers, register as usize, value) =>
+                            {
+                                b"OK"
+                            }
+                            _ => b"E01",
+                        };
+                    self.send_packet(response)?;
+                }
+                b'm' => match parse_address_length(arguments) {
+                    Some((address, length)) => {
+                        let mut response = Response::start(&mut self.connection)?;
+                        for i in 0..length {
+                            // SAFETY: Our caller promised that the debugger will only access
+                            // mapped memory.
+                            let byte =
+                                unsafe { (address.wrapping_add(i) as *const u8).read_volatile() };
+                            response.write_hex(&[byte])?;
+                        }
+                        response.finish()?;
+                    }
+                    None => self.send_packet(b"E01")?,
+                },
+                b'M' => {
+                    let response: &[u8] = match split_at_byte(arguments, b':')
+                        .and_then(|(range, data)| Some((parse_address_length(range)?, data)))
+                    {
+                        Some(((address, length), data)) if data.len() == length * 2 => {
+                            // SAFETY: Our caller promised that the debugger will only access
+                            // mapped memory.
+                            unsafe { write_memory(address, data) };
+                            b"OK"
+                        }
+                        _ => b"E01",
+                    };
+                    self.send_packet(response)?;
+                }
+                b'c' => return Ok(()),
+                b's' => {
+                    // SAFETY: Setting MDSCR_EL1.SS enables software step, which will cause a debug
+                    // exception after the next instruction.
+                    unsafe {
+                        let mdscr = read_sysreg!("mdscr_el1");
+                        write_sysreg!("mdscr_el1", mdscr | MDSCR_SS);
+                    }
+                    registers.spsr = (registers.spsr | SPSR_SS) & !SPSR_D;
+                    return Ok(());
+                }
+                b'Z' | b'z' => {
+                    let response: &[u8] = match self.update_breakpoint(command == b'Z', arguments) {
+                        Some(true) => b"OK",
+                        Some(false) => b"E01",
+                        None => b"",
+                    };
+                    self.send_packet(response)?;
+                }
+                b'q' => {
+                    let response: &[u8] = if packet.starts_with(b"qSupported") {
+                        b"PacketSize=400;hwbreak+"
+                    } else if packet.starts_with(b"qAttached") {
+                        b"1"
+                    } else {
+                        b""
+                    };
+                    self.send_packet(response)?;
+                }
+                b'H' => self.send_packet(b"OK")?,
+                b'D' => {
+                    self.clear_all_breakpoints();
+                    self.send_packet(b"OK")?;
+                    return Ok(());
+                }
+                b'k' => {
+                    self.clear_all_breakpoints();
+                    return Ok(());
+                }
+                _ => self.send_packet(b"")?,
+            }
+        }
+    }
+
+    /// Sends a stop reply for the current exception.
+    fn send_stop_reply(&mut self) -> Result<(), T::Error> {
+        let mut response = Response::start(&mut self.connection)?;
+        match DebugException::current() {
+            Some(DebugException::Watchpoint { address, write }) => {
+                response.write(if write { b"T05watch:" } else { b"T05rwatch:" })?;
+                response.write_hex_u64(address)?;
+                response.write(b";")?;
+            }
+            Some(DebugException::Breakpoint) => response.write(b"T05hwbreak:;")?,
+            _ => response.write(b"S05")?,
+        }
+        response.finish()
+    }
+
+    /// Sends the values of all registers.
+    fn send_registers(&mut self, registers: &RegisterState) -> Result<(), T::Error> {
+        let mut response = Response::start(&mut self.connection)?;
+        for register in 0..=REGISTER_CPSR {
+            write_register(&mut response, registers, register)?;
+        }
+        response.finish()
+    }
+
+    /// Sets or clears a hardware breakpoint or watchpoint, as requested by a `Z` or `z` packet.
+    ///
+    /// Returns `None` if the type is not supported, or whether the operation succeeded.
+    fn update_breakpoint(&mut self, insert: bool, arguments: &[u8]) -> Option<bool> {
+        let (kind, arguments) = split_at_byte(arguments, b',')?;
+        let access = match kind {
+            b"1" => None,
+            b"2" => Some(WatchpointAccess::Store),
+            b"3" => Some(WatchpointAccess::Load),
+            b"4" => Some(WatchpointAccess::LoadStore),
+            _ => return None,
+        };
+        let Some((address, length)) = parse_address_length(arguments) else {
+            return Some(false);
+        };
+        let (slots, count) = match access {
+            None => (&mut self.breakpoints, debug::breakpoint_count()),
+            Some(_) => (&mut self.watchpoints, debug::watchpoint_count()),
+        };
+        let slots = &mut slots[..count.min(16)];
+        if insert {
+            let Some(index) = slots.iter().position(Option::is_none) else {
+                return Some(false);
+            };
+            match access {
+                None if address.is_multiple_of(4) => debug::set_breakpoint(index, address),
+                Some(access) if is_watchable(address, length) => {
+                    debug::set_watchpoint(index, address, length, access)
+                }
+                _ => return Some(false),
+            }
+            slots[index] = Some(address);
+        } else {
+            let Some(index) = slots.iter().position(|slot| *slot == Some(address)) else {
+                return Some(false);
+            };
+            match access {
+                None => debug::clear_breakpoint(index),
+                Some(_) => debug::clear_watchpoint(index),
+            }
+            slots[index] = None;
+        }
+        Some(true)
+    }
+
+    /// Clears all breakpoints and watchpoints which GDB has set.
+    fn clear_all_breakpoints(&mut self) {
+        for (index, slot) in self.breakpoints.iter_mut().enumerate() {
+            if slot.take().is_some() {
+                debug::clear_breakpoint(index);
+            }
+        }
+        for (index, slot) in self.watchpoints.iter_mut().enumerate() {
+            if slot.take().is_some() {
+                debug::clear_watchpoint(index);
+            }
+        }
+    }
+
+    /// Sends a packet with the given contents.
+    fn send_packet(&mut self, data: &[u8]) -> Result<(), T::Error> {
+        let mut response = Response::start(&mut self.connection)?;
+        response.write(data)?;
+        response.finish()
+    }
+
+    /// Waits for a valid packet to be received, acknowledges it, and returns its length.
+    fn receive_packet(
+        &mut self,
+        packet: &mut [u8; PACKET_SIZE],
+    ) -> Result<usize, ReadExactError<T::Error>> {
+        loop {
+            // Skip anything before the start of the packet, including acknowledgements.
+            while self.read_byte()? != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    packet[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+            let mut expected = [0; 2];
+            self.connection.read_exact(&mut expected)?;
+            if !overflow && parse_hex(&expected) == Some(checksum.into()) {
+                self.connection.write_all(b"+")?;
+                return Ok(len);
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, ReadExactError<T::Error>> {
+        let mut byte = [0];
+        self.connection.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+/// A packet being sent, which keeps track of the checksum.
+struct Response<'a, T: Write> {
+    connection: &'a mut T,
+    checksum: u8,
+}
+
+impl<'a, T: Write> Response<'a, T> {
+    fn start(connection: &'a mut T) -> Result<Self, T::Error> {
+        connection.write_all(b"$")?;
+        Ok(Self {
+            connection,
+            checksum: 0,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), T::Error> {
+        self.checksum = data
+            .iter()
+            .fold(self.checksum, |checksum, byte| checksum.wrapping_add(*byte));
+        self.connection.write_all(data)
+    }
+
+    /// Writes the given bytes as pairs of hex digits.
+    fn write_hex(&mut self, bytes: &[u8]) -> Result<(), T::Error> {
+        for byte in bytes {
+            self.write(&[hex_digit(byte >> 4), hex_digit(*byte)])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the given value as a big-endian hex number, without leading zeroes.
+    fn write_hex_u64(&mut self, value: u64) -> Result<(), T::Error> {
+        let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for digit in (0..digits).rev() {
+            self.write(&[hex_digit((value >> (digit * 4)) as u8)])?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), T::Error> {
+        let checksum = self.checksum;
+        self.connection.write_all(b"#")?;
+        self.write_hex(&[checksum])?;
+        self.connection.flush()
+    }
+}
+
+/// Writes the value of the given GDB register number in target byte order, or `x`s if it isn't
+/// available.
+fn write_register<T: Write>(
+    response: &mut Response<T>,
+    registers: &RegisterState,
+    register: usize,
+) -> Result<(), T::Error> {
+    match get_register(registers, register) {
+        Some(value) if register == REGISTER_CPSR => {
+            response.write_hex(&(value as u32).to_le_bytes())
+        }
+        Some(value) => response.write_hex(&value.to_le_bytes()),
+        None if register == REGISTER_CPSR => response.write(b"xxxxxxxx"),
+        None => response.write(b"xxxxxxxxxxxxxxxx"),
+    }
+}
+
+/// Returns the saved value of the given GDB register number, if it is available.
+fn get_register(registers: &RegisterState, register: usize) -> Option<u64> {
+    match register {
+        0..=18 => Some(registers.registers[register]),
+        19..=28 => Some(registers.callee_saved[register - 19]),
+        29 => Some(registers.fp),
+        30 => Some(registers.sp),
+        REGISTER_PC => Some(registers.elr as u64),
+        REGISTER_CPSR => Some(registers.spsr),
+        _ => None,
+    }
+}
+
+/// Sets the saved value of the given GDB register number, returning whether it is available.
+fn set_register(registers: &mut RegisterState, register: usize, value: u64) -> bool {
+    match register {
+        0..=18 => registers.registers[register] = value,
+        19..=28 => registers.callee_saved[register - 19] = value,
+        29 => registers.fp = value,
+        30 => registers.sp = value,
+        REGISTER_PC => registers.elr = value as usize,
+        REGISTER_CPSR => registers.spsr = value,
+        _ => return false,
+    }
+    true
+}
+
+/// Writes the given hex-encoded data to memory at the given address, and synchronises the
+/// instruction cache in case it was code.
+///
+/// # Safety
+///
+/// The memory must be mapped and writable, and the write must not violate Rust's aliasing rules.
+unsafe fn write_memory(address: usize, data: &[u8]) {
+    for (i, pair) in data.chunks_exact(2).enumerate() {
+        let byte = parse_hex(pair).unwrap_or_default() as u8;
+        let pointer = address.wrapping_add(i) as *mut u8;
+        // SAFETY: Our caller promised that the memory is mapped and writable.
+        unsafe {
+            pointer.write_volatile(byte);
+        }
+    }
+    sync_icache(address as *const u8, data.len() / 2);
+}
+
+/// Returns whether a watchpoint can be set on the given region.
+fn is_watchable(address: usize, length: usize) -> bool {
+    length > 0
+        && (address % 8 + length <= 8
+            || (length.is_power_of_two() && address.is_multiple_of(length) && length <= 1 << 31))
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[usize::from(value & 0xf)]
+}
+
+/// Parses the given big-endian hex number.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, digit| {
+        Some(value << 4 | u64::from(char::from(*digit).to_digit(16)?))
+    })
+}
+
+/// Parses the given hex string of bytes in little-endian order, as used for register values.
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks_exact(2)
+        .rev()
+        .try_fold(0, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
+
+/// Parses an `address,length` pair.
+fn parse_address_length(arguments: &[u8]) -> Option<(usize, usize)> {
+    let (address, length) = split_at_byte(arguments, b',')?;
+    Some((parse_hex(address)? as usize, parse_hex(length)? as usize))
+}
+
+/// Splits the given slice at the first occurrence of the given separator, excluding the separator
+/// itself.
+fn split_at_byte(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = data.iter().position(|byte| *byte == separator)?;
+    Some((&data[..position], &data[position + 1..]))
+}
@@ -0,0 +1,630 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Access to the GICv3 CPU interface via system registers.
+//!
+//! These functions use the group 1 interrupt registers, and require the system register interface
+//! to be enabled for the current exception level (i.e. `ICC_SRE_ELx.SRE` to be set).
+//!
+//! If firmware may have left the CPU interface in memory-mapped mode, an image can register a
+//! configuration with the [`gic_init!`](crate::gic_init) macro, and the entry points then enable
+//! the system register interface, set the priority mask and enable the requested interrupt groups
+//! on each core before enabling the MMU:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{gic::GicInitConfig, gic_init};
+//!
+//! gic_init!(GicInitConfig::GROUP1);
+//! ```
+//!
+//! By default, [`end_interrupt`] both drops the running priority and deactivates the interrupt. If
+//! split EOI mode is enabled with [`set_split_eoi`] then it only drops the priority, allowing other
+//! interrupts to be taken, and the interrupt must later be deactivated with
+//! [`deactivate_interrupt`], e.g. once a deferred handler has finished with it.
+//!
+//! Inter-processor interrupts (IPIs) of each [`IpiKind`] can be sent to other cores with
+//! [`send_ipi`], using SGIs starting from [`IPI_SGI_BASE`]. The SGIs must be enabled in each core's
+//! redistributor by the application, and its IRQ handler should pass acknowledged interrupts to
+//! [`handle_ipi`], which calls the handler registered with [`set_ipi_handler`], e.g.:
+//!
+//! ```rust,ignore
+//! extern "C" fn irq_current(register_state: RegisterStateRef) {
+//!     if let Some(intid) = gic::acknowledge_interrupt() {
+//!         if !gic::handle_ipi(intid) {
+//!             // Handle other interrupts...
+//!         }
+//!         gic::end_interrupt(intid);
+//!     }
+//! }
+//! ```
+//!
+//! Handlers for other interrupts may be registered at link time for a fixed INTID with
+//! [`irq_handler!`](crate::irq_handler), or the `#[irq]` attribute with the `macros` feature.
+//! [`dispatch_irq`] calls the handler registered for an acknowledged interrupt, or handles it as an
+//! IPI:
+//!
+//! ```rust,ignore
+//! #[irq(intid = 30)]
+//! fn timer() {
+//!     timer::handle_tick();
+//! }
+//!
+//! extern "C" fn irq_current(register_state: RegisterStateRef) {
+//!     if let Some(intid) = gic::acknowledge_interrupt() {
+//!         if !gic::dispatch_irq(intid) {
+//!             panic!("Unexpected IRQ {intid}");
+//!         }
+//!         gic::end_interrupt(intid);
+//!     }
+//! }
+//! ```
+
+use crate::{
+    sysreg::{read_mpidr, read_sysreg, write_sysreg},
+    timer,
+};
+use core::{
+    arch::{asm, global_asm},
+    mem::transmute,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// The INTID returned when there is no pending interrupt to acknowledge.
+pub const SPECIAL_INTID_SPURIOUS: u32 = 1023;
+
+/// EOI mode, in ICC_CTLR_EL1.
+const ICC_CTLR_EOIMODE: u64 = 1 << 1;
+/// Enable Non-secure group 1 interrupts, in ICC_IGRPEN1_EL3.
+#[cfg(feature = "el3")]
+const ICC_IGRPEN1_EL3_NS: u64 = 1 << 0;
+/// Enable Secure group 1 interrupts, in ICC_IGRPEN1_EL3.
+#[cfg(feature = "el3")]
+const ICC_IGRPEN1_EL3_S: u64 = 1 << 1;
+/// Interrupt routing mode to all PEs except the current one, in ICC_SGI1R_EL1.
+const ICC_SGIR_IRM_ALL_OTHERS: u64 = 1 << 40;
+/// The shift of the INTID in ICC_SGI1R_EL1.
+const ICC_SGIR_INTID_SHIFT: u64 = 24;
+/// The shift of the affinity level 1 value in ICC_SGI1R_EL1.
+const ICC_SGIR_AFF1_SHIFT: u64 = 16;
+/// The shift of the affinity level 2 value in ICC_SGI1R_EL1.
+const ICC_SGIR_AFF2_SHIFT: u64 = 32;
+/// The shift of the range selector in ICC_SGI1R_EL1.
+const ICC_SGIR_RS_SHIFT: u64 = 44;
+/// The shift of the affinity level 3 value in ICC_SGI1R_EL1.
+const ICC_SGIR_AFF3_SHIFT: u64 = 48;
+
+/// The offset of GICR_TYPER in a redistributor's RD_base frame.
+const GICR_TYPER: usize = 0x8;
+/// The offset of GICR_WAKER in a redistributor's RD_base frame.
+const GICR_WAKER: usize = 0x14;
+/// The offset of a redistributor's SGI_base frame from its RD_base frame.
+const GICR_SGI_BASE: usize = 0x1_0000;
+/// The offset of GICR_IGROUPR0 in a redistributor's SGI_base frame.
+const GICR_IGROUPR0: usize = 0x80;
+/// The offset of GICR_ISENABLER0 in a redistributor's SGI_base frame.
+const GICR_ISENABLER0: usize = 0x100;
+/// The size of a redistributor's RD_base and SGI_base frames.
+const GICR_SIZE: usize = 0x2_0000;
+/// The size of a redistributor's frames if it also has the GICv4 VLPI frames.
+const GICR_SIZE_VLPIS: usize = 0x4_0000;
+/// Virtual LPIs supported, in GICR_TYPER.
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+/// Last redistributor in the region, in GICR_TYPER.
+const GICR_TYPER_LAST: u64 = 1 << 4;
+/// The processor is asleep, in GICR_WAKER.
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+/// The redistributor's connection to the CPU interface is quiescent, in GICR_WAKER.
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// The INTID of the SGI used for the first [`IpiKind`]. Each following kind uses the next SGI, and
+/// SGIs after those are left for the application.
+pub const IPI_SGI_BASE: u32 = 0;
+/// The number of variants of [`IpiKind`].
+const IPI_KIND_COUNT: usize = 4;
+
+/// The base address of the redistributor region, or 0 if it hasn't been registered.
+static REDISTRIBUTOR_REGION: AtomicUsize = AtomicUsize::new(0);
+
+/// The handler function pointer for each kind of IPI, or null if none has been registered.
+static IPI_HANDLERS: [AtomicPtr<()>; IPI_KIND_COUNT] =
+    [const { AtomicPtr::new(null_mut()) }; IPI_KIND_COUNT];
+
+/// A kind of inter-processor interrupt, each of which uses its own SGI.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiKind {
+    /// Asks the target core to run its scheduler.
+    Reschedule = 0,
+    /// Asks the target core to run function calls queued for it.
+    CallFunction = 1,
+    /// Asks the target core to invalidate TLB entries.
+    TlbShootdown = 2,
+    /// Wakes the target core from `wfi`, without asking it to do anything else.
+    Wake = 3,
+}
+
+impl IpiKind {
+    /// Returns the INTID of the SGI used for this kind of IPI.
+    pub const fn intid(self) -> u32 {
+        IPI_SGI_BASE + self as u32
+    }
+
+    /// Returns the kind of IPI which uses the given INTID, if any.
+    pub fn from_intid(intid: u32) -> Option<Self> {
+        match intid.checked_sub(IPI_SGI_BASE)? {
+            0 => Some(Self::Reschedule),
+            1 => Some(Self::CallFunction),
+            2 => Some(Self::TlbShootdown),
+            3 => Some(Self::Wake),
+            _ => None,
+        }
+    }
+}
+
+/// Acknowledges the highest priority pending group 1 interrupt, returning its INTID, or `None` if
+/// there was no pending interrupt.
+pub fn acknowledge_interrupt() -> Option<u32> {
+    // SAFETY: Reading ICC_IAR1_EL1 acknowledges an interrupt, which doesn't affect memory safety.
+    let intid = unsafe { read_sysreg!("icc_iar1_el1") } as u32;
+    // SAFETY: A barrier doesn't affect memory safety.
+    unsafe {
+        asm!("dsb sy", options(nomem, nostack, preserves_flags));
+    }
+    (intid != SPECIAL_INTID_SPURIOUS).then_some(intid)
+}
+
+/// Signals the end of handling the given group 1 interrupt, which must have previously been
+/// acknowledged.
+///
+/// This drops the running priority. Unless split EOI mode is enabled, it also deactivates the
+/// interrupt.
+pub fn end_interrupt(intid: u32) {
+    // SAFETY: Writing ICC_EOIR1_EL1 doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_eoir1_el1", intid);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Deactivates the given interrupt, after its priority has been dropped with [`end_interrupt`].
+///
+/// This is only needed if split EOI mode is enabled. It need not be called on the same core which
+/// acknowledged the interrupt.
+pub fn deactivate_interrupt(intid: u32) {
+    // SAFETY: Writing ICC_DIR_EL1 doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_dir_el1", intid);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Acknowledges the highest priority pending group 0 interrupt, returning its INTID, or `None` if
+/// there was no pending interrupt.
+///
+/// Group 0 interrupts are always signalled as FIQs, so this is normally called from an FIQ handler,
+/// e.g. in a secure monitor at EL3.
+pub fn acknowledge_group0_interrupt() -> Option<u32> {
+    // SAFETY: Reading ICC_IAR0_EL1 acknowledges an interrupt, which doesn't affect memory safety.
+    let intid = unsafe { read_sysreg!("icc_iar0_el1") } as u32;
+    // SAFETY: A barrier doesn't affect memory safety.
+    unsafe {
+        asm!("dsb sy", options(nomem, nostack, preserves_flags));
+    }
+    (intid != SPECIAL_INTID_SPURIOUS).then_some(intid)
+}
+
+/// Signals the end of handling the given group 0 interrupt, which must have previously been
+/// acknowledged with [`acknowledge_group0_interrupt`].
+pub fn end_group0_interrupt(intid: u32) {
+    // SAFETY: Writing ICC_EOIR0_EL1 doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_eoir0_el1", intid);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Enables or disables group 0 interrupts for the current core's CPU interface.
+pub fn set_group0_enabled(enabled: bool) {
+    // SAFETY: Enabling or disabling interrupts at the CPU interface doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_igrpen0_el1", u64::from(enabled));
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Enables or disables group 1 interrupts for the current security state on the current core's
+/// CPU interface.
+pub fn set_group1_enabled(enabled: bool) {
+    // SAFETY: Enabling or disabling interrupts at the CPU interface doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_igrpen1_el1", u64::from(enabled));
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Enables or disables Secure and Non-secure group 1 interrupts on the current core's CPU
+/// interface, from EL3.
+#[cfg(feature = "el3")]
+pub fn set_group1_enabled_el3(secure: bool, non_secure: bool) {
+    let igrpen = if non_secure { ICC_IGRPEN1_EL3_NS } else { 0 }
+        | if secure { ICC_IGRPEN1_EL3_S } else { 0 };
+    // SAFETY: Enabling or disabling interrupts at the CPU interface doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("icc_igrpen1_el3", igrpen);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Enables or disables split EOI mode for the current core.
+///
+/// This should only be changed while there are no active interrupts.
+pub fn set_split_eoi(enabled: bool) {
+    // SAFETY: Changing the EOI mode doesn't affect memory safety.
+    unsafe {
+        let ctlr = read_sysreg!("icc_ctlr_el1");
+        let ctlr = if enabled {
+            ctlr | ICC_CTLR_EOIMODE
+        } else {
+            ctlr & !ICC_CTLR_EOIMODE
+        };
+        write_sysreg!("icc_ctlr_el1", ctlr);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns whether split EOI mode is enabled for the current core.
+pub fn split_eoi_enabled() -> bool {
+    // SAFETY: Reading ICC_CTLR_EL1 is always safe.
+    unsafe { read_sysreg!("icc_ctlr_el1") & ICC_CTLR_EOIMODE != 0 }
+}
+
+/// Sends the given group 1 software generated interrupt to all cores except the current one.
+///
+/// # Panics
+///
+/// Panics if `intid` is not a valid SGI INTID, i.e. 16 or greater.
+pub fn send_sgi_to_others(intid: u32) {
+    assert!(intid < 16, "Invalid SGI INTID {intid}");
+    // SAFETY: Sending an SGI doesn't affect memory safety.
+    unsafe {
+        write_sysreg!(
+            "icc_sgi1r_el1",
+            ICC_SGIR_IRM_ALL_OTHERS | u64::from(intid) << ICC_SGIR_INTID_SHIFT
+        );
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Sends the given group 1 software generated interrupt to the core with the given MPIDR.
+///
+/// Targeting a core with an affinity level 0 value of 16 or more requires range selector support,
+/// i.e. `ICC_CTLR_EL1.RSS` to be set.
+///
+/// # Panics
+///
+/// Panics if `intid` is not a valid SGI INTID, i.e. 16 or greater.
+pub fn send_sgi(intid: u32, mpidr: u64) {
+    assert!(intid < 16, "Invalid SGI INTID {intid}");
+    let aff0 = mpidr & 0xff;
+    let aff1 = (mpidr >> 8) & 0xff;
+    let aff2 = (mpidr >> 16) & 0xff;
+    let aff3 = (mpidr >> 32) & 0xff;
+    // SAFETY: Sending an SGI doesn't affect memory safety.
+    unsafe {
+        write_sysreg!(
+            "icc_sgi1r_el1",
+            aff3 << ICC_SGIR_AFF3_SHIFT
+                | (aff0 >> 4) << ICC_SGIR_RS_SHIFT
+                | aff2 << ICC_SGIR_AFF2_SHIFT
+                | u64::from(intid) << ICC_SGIR_INTID_SHIFT
+                | aff1 << ICC_SGIR_AFF1_SHIFT
+                | 1 << (aff0 & 0xf)
+        );
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Sends an IPI of the given kind to the core with the given MPIDR.
+pub fn send_ipi(mpidr: u64, kind: IpiKind) {
+    send_sgi(kind.intid(), mpidr);
+}
+
+/// Sends an IPI of the given kind to all cores except the current one.
+pub fn send_ipi_to_others(kind: IpiKind) {
+    send_sgi_to_others(kind.intid());
+}
+
+/// Registers the base address of the GICv3 redistributor region, for [`enable_private_interrupt`] to
+/// find the current core's redistributor in.
+///
+/// # Safety
+///
+/// `base` must be the address of a contiguous GICv3 redistributor region, mapped as device memory
+/// on all cores which call [`enable_private_interrupt`] for as long as they do so. Nothing else may
+/// concurrently modify the GICR_IGROUPR0 or GICR_WAKER registers of the redistributors in it.
+pub unsafe fn set_redistributor_region(base: *mut u8) {
+    REDISTRIBUTOR_REGION.store(base as usize, Ordering::Release);
+}
+
+/// Returns the RD_base frame of the current core's redistributor, or `None` if no redistributor
+/// region has been registered or it has no redistributor for the current core.
+fn current_redistributor() -> Option<*mut u8> {
+    let mut frame = REDISTRIBUTOR_REGION.load(Ordering::Acquire) as *mut u8;
+    if frame.is_null() {
+        return None;
+    }
+    let mpidr = read_mpidr();
+    let affinity = ((mpidr >> 32) << 24) | (mpidr & 0xff_ffff);
+    loop {
+        // SAFETY: The caller of `set_redistributor_region` promised that the region is mapped, and
+        // we stop at the last redistributor in it.
+        let typer = unsafe { frame.wrapping_add(GICR_TYPER).cast::<u64>().read_volatile() };
+        if typer >> 32 == affinity {
+            return Some(frame);
+        }
+        if typer & GICR_TYPER_LAST != 0 {
+            return None;
+        }
+        let size = if typer & GICR_TYPER_VLPIS != 0 {
+            GICR_SIZE_VLPIS
+        } else {
+            GICR_SIZE
+        };
+        frame = frame.wrapping_add(size);
+    }
+}
+
+/// Enables the given SGI or PPI in the current core's redistributor, as a Non-secure group 1
+/// interrupt where the security configuration allows its group to be changed, and wakes the
+/// redistributor if it was asleep.
+///
+/// Returns false without enabling it if no redistributor region has been registered with
+/// [`set_redistributor_region`], or the region has no redistributor for the current core.
+///
+/// # Panics
+///
+/// Panics if `intid` is not a valid SGI or PPI INTID, i.e. 32 or greater.
+pub fn enable_private_interrupt(intid: u32) -> bool {
+    assert!(intid < 32, "Invalid SGI or PPI INTID {intid}");
+    let Some(frame) = current_redistributor() else {
+        return false;
+    };
+    let waker = frame.wrapping_add(GICR_WAKER).cast::<u32>();
+    let igroupr0 = frame
+        .wrapping_add(GICR_SGI_BASE + GICR_IGROUPR0)
+        .cast::<u32>();
+    let isenabler0 = frame
+        .wrapping_add(GICR_SGI_BASE + GICR_ISENABLER0)
+        .cast::<u32>();
+    // SAFETY: The frame is the current core's redistributor in the region which the caller of
+    // `set_redistributor_region` promised is mapped and not modified concurrently. Enabling an
+    // interrupt doesn't affect memory safety.
+    unsafe {
+        let sleep = waker.read_volatile();
+        if sleep & GICR_WAKER_PROCESSOR_SLEEP != 0 {
+            waker.write_volatile(sleep & !GICR_WAKER_PROCESSOR_SLEEP);
+            while waker.read_volatile() & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+        }
+        igroupr0.write_volatile(igroupr0.read_volatile() | 1 << intid);
+        isenabler0.write_volatile(1 << intid);
+    }
+    true
+}
+
+/// Registers the given function to be called by [`handle_ipi`] for IPIs of the given kind, on all
+/// cores.
+pub fn set_ipi_handler(kind: IpiKind, handler: fn()) {
+    IPI_HANDLERS[kind as usize].store(handler as *mut (), Ordering::Release);
+}
+
+/// Removes the handler registered for IPIs of the given kind, if any.
+pub fn clear_ipi_handler(kind: IpiKind) {
+    IPI_HANDLERS[kind as usize].store(null_mut(), Ordering::Release);
+}
+
+/// Handles the interrupt with the given INTID if it is an IPI, by calling the handler registered
+/// for its kind, if any.
+///
+/// This should be called from the IRQ handler after acknowledging the interrupt, and doesn't end
+/// it. Returns whether the interrupt was an IPI.
+pub fn handle_ipi(intid: u32) -> bool {
+    let Some(kind) = IpiKind::from_intid(intid) else {
+        return false;
+    };
+    let handler = IPI_HANDLERS[kind as usize].load(Ordering::Acquire);
+    if !handler.is_null() {
+        // SAFETY: IPI_HANDLERS entries are only ever set to null or a valid `fn()`.
+        let handler = unsafe { transmute::<*mut (), fn()>(handler) };
+        handler();
+    }
+    true
+}
+
+/// An interrupt handler registered at link time with [`irq_handler!`](crate::irq_handler).
+#[derive(Clone, Copy, Debug)]
+pub struct IrqHandler {
+    /// The INTID of the interrupt to handle.
+    pub intid: u32,
+    /// The function to call when the interrupt is taken.
+    pub handler: fn(),
+}
+
+crate::registry!(
+    #[doc(hidden)]
+    pub static __IRQ_HANDLERS: [IrqHandler]
+);
+
+/// Registers the given function to be called by [`dispatch_irq`](crate::gic::dispatch_irq) for the
+/// interrupt with the given INTID, on all cores.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::irq_handler;
+///
+/// irq_handler!(30, timer);
+///
+/// fn timer() {
+///     // ...
+/// }
+/// ```
+///
+/// This is what the `#[irq(intid = ...)]` attribute from the `macros` feature expands to. At most
+/// one handler should be registered for each INTID.
+#[macro_export]
+macro_rules! irq_handler {
+    ($intid:expr, $handler:path) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".registry.__IRQ_HANDLERS.1")]
+            static IRQ_HANDLER: $crate::gic::IrqHandler = $crate::gic::IrqHandler {
+                intid: $intid,
+                handler: $handler,
+            };
+        };
+    };
+}
+
+/// Returns all the interrupt handlers registered with [`irq_handler!`](crate::irq_handler).
+pub fn irq_handlers() -> &'static [IrqHandler] {
+    __IRQ_HANDLERS.entries()
+}
+
+/// Handles the interrupt with the given INTID by calling the handler registered for it with
+/// [`irq_handler!`](crate::irq_handler), with [`handle_ipi`] if it is an IPI, or with
+/// [`timer::handle_tick`] if it is the virtual timer interrupt.
+///
+/// This should be called from the IRQ handler after acknowledging the interrupt, and doesn't end
+/// it. Returns whether the interrupt was handled.
+pub fn dispatch_irq(intid: u32) -> bool {
+    if let Some(handler) = irq_handlers().iter().find(|handler| handler.intid == intid) {
+        (handler.handler)();
+        true
+    } else if intid == timer::VIRTUAL_TIMER_INTID {
+        timer::handle_tick();
+        true
+    } else {
+        handle_ipi(intid)
+    }
+}
+
+/// Configuration of the GICv3 CPU interface system register interface to apply at boot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GicInitConfig {
+    /// The initial priority mask. Only interrupts with a higher priority, i.e. a lower value, are
+    /// signalled to the core.
+    pub priority_mask: u8,
+    /// Whether to enable group 0 interrupts.
+    pub group0: bool,
+    /// Whether to enable group 1 interrupts for the current security state.
+    pub group1: bool,
+}
+
+impl GicInitConfig {
+    /// Unmasks all priorities and enables group 1 interrupts, as usually wanted by an application
+    /// handling IRQs at EL1 or EL2.
+    pub const GROUP1: Self = Self {
+        priority_mask: 0xff,
+        group0: false,
+        group1: true,
+    };
+
+    /// Returns 1 if group 0 should be enabled, or 0 otherwise.
+    #[doc(hidden)]
+    pub const fn group0_enable(self) -> u8 {
+        self.group0 as u8
+    }
+
+    /// Returns 1 if group 1 should be enabled, or 0 otherwise.
+    #[doc(hidden)]
+    pub const fn group1_enable(self) -> u8 {
+        self.group1 as u8
+    }
+}
+
+// A default no-op hook, which is overridden by the strong symbol defined by `gic_init!` if it is
+// used.
+global_asm!(
+    ".section .init.__aarch64_rt_configure_gic, \"ax\"",
+    ".weak __aarch64_rt_configure_gic",
+    ".type __aarch64_rt_configure_gic, %function",
+    "__aarch64_rt_configure_gic:",
+    "ret",
+);
+
+/// Registers a GICv3 CPU interface configuration for the entry points to apply, if the system
+/// register interface is implemented.
+///
+/// On each core, before enabling the MMU, the entry points then enable the system register
+/// interface for the current exception level and those below it, set the priority mask, and enable
+/// the requested interrupt groups. If a higher exception level doesn't allow the system register
+/// interface to be enabled then the rest of the configuration is skipped.
+///
+/// When the image runs at EL2, firmware at EL3 must have set `ICC_SRE_EL3.Enable`, as the write to
+/// `ICC_SRE_EL2` otherwise traps to EL3. There is no way to check this from EL2 without trapping.
+///
+/// The value must be a [`GicInitConfig`](crate::gic::GicInitConfig) constant expression.
+#[macro_export]
+macro_rules! gic_init {
+    ($config:expr) => {
+        core::arch::global_asm!(
+            ".section .init.__aarch64_rt_configure_gic, \"ax\"",
+            ".global __aarch64_rt_configure_gic",
+            "__aarch64_rt_configure_gic:",
+                // Skip everything if the GIC system register interface isn't implemented.
+                "mrs x9, id_aa64pfr0_el1",
+                "ubfx x9, x9, #24, #4",
+                "cbz x9, 3f",
+                "mrs x9, CurrentEL",
+                "ubfx x9, x9, #2, #2",
+                "cmp x9, #3",
+                "b.ne 1f",
+                // Set ICC_SRE_EL3.{SRE, DFB, DIB, Enable}, so that EL3 uses the system register
+                // interface and lower exception levels may enable it.
+                "mrs x10, icc_sre_el3",
+                "orr x10, x10, #0xf",
+                "msr icc_sre_el3, x10",
+                "isb",
+                // Do the same for EL2, if it is implemented.
+                "mrs x10, id_aa64pfr0_el1",
+                "ubfx x10, x10, #8, #4",
+                "cbz x10, 2f",
+                "b 0f",
+            "1:",
+                "cmp x9, #2",
+                "b.ne 2f",
+            "0:",
+                // Set ICC_SRE_EL2.{SRE, DFB, DIB, Enable}. When running at EL2 this traps to EL3
+                // unless ICC_SRE_EL3.Enable is set, which can't be checked first.
+                "mrs x10, icc_sre_el2",
+                "orr x10, x10, #0xf",
+                "msr icc_sre_el2, x10",
+                "isb",
+            "2:",
+                // Set ICC_SRE_EL1.{SRE, DFB, DIB}, and check that it was allowed.
+                "mrs x10, icc_sre_el1",
+                "orr x10, x10, #0x7",
+                "msr icc_sre_el1, x10",
+                "isb",
+                "mrs x10, icc_sre_el1",
+                "tbz x10, #0, 3f",
+                "mov x10, #{PMR}",
+                "msr icc_pmr_el1, x10",
+            ".if {GROUP0}",
+                "mov x10, #1",
+                "msr icc_igrpen0_el1, x10",
+            ".endif",
+            ".if {GROUP1}",
+                "mov x10, #1",
+                "msr icc_igrpen1_el1, x10",
+            ".endif",
+                "isb",
+            "3:",
+                "ret",
+            PMR = const { let config: $crate::gic::GicInitConfig = $config; config.priority_mask },
+            GROUP0 = const $crate::gic::GicInitConfig::group0_enable($config),
+            GROUP1 = const $crate::gic::GicInitConfig::group1_enable($config),
+        );
+    };
+}
@@ -0,0 +1,221 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Decoding of granule protection faults on systems with the Realm Management Extension.
+//!
+//! When the granule protection table (GPT) doesn't allow an access to a physical granule from the
+//! current security state, the access causes a granule protection fault. This is reported as an
+//! instruction or data abort to the exception level which the access was made from, or if it was
+//! made from EL3 or the GPT itself is invalid, as a granule protection check (GPC) exception to
+//! EL3.
+//!
+//! The default synchronous exception handlers of [`ExceptionHandlers`] decode these faults and pass
+//! them to [`handle_granule_protection_fault`]. Granule protection faults on accesses by other
+//! requesters, such as devices behind an SMMU, aren't reported to the PE, and any SErrors caused by
+//! them don't have an architected syndrome which identifies them, so they are left to
+//! [`ExceptionHandlers::classify_serror`].
+//!
+//! [`ExceptionHandlers`]: crate::ExceptionHandlers
+//! [`handle_granule_protection_fault`]: crate::ExceptionHandlers::handle_granule_protection_fault
+//! [`ExceptionHandlers::classify_serror`]: crate::ExceptionHandlers::classify_serror
+
+use crate::sysreg::{current_el, read_esr, read_far, read_sysreg, write_sysreg};
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+};
+
+/// Exception class for an instruction abort from a lower exception level, in ESR_ELx.
+const ESR_EC_IABT_LOWER: u64 = 0x20;
+/// Exception class for an instruction abort from the current exception level, in ESR_ELx.
+const ESR_EC_IABT_CURRENT: u64 = 0x21;
+/// Exception class for a data abort from a lower exception level, in ESR_ELx.
+const ESR_EC_DABT_LOWER: u64 = 0x24;
+/// Exception class for a data abort from the current exception level, in ESR_ELx.
+const ESR_EC_DABT_CURRENT: u64 = 0x25;
+/// Exception class for a granule protection check exception, in ESR_EL3.
+const ESR_EC_GPC: u64 = 0x1e;
+
+/// FAR not valid, in ESR_ELx.ISS for an instruction or data abort.
+const ESR_ISS_FNV: u64 = 1 << 10;
+/// Cache maintenance, in ESR_ELx.ISS for a data abort or GPC exception.
+const ESR_ISS_CM: u64 = 1 << 8;
+/// Write not read, in ESR_ELx.ISS for a data abort or GPC exception.
+const ESR_ISS_WNR: u64 = 1 << 6;
+/// Instruction not data, in ESR_EL3.ISS for a GPC exception.
+const ESR_ISS_GPC_IND: u64 = 1 << 20;
+
+/// Fault status code for a granule protection fault on a translation table walk at level -1, in
+/// ESR_ELx.ISS.xFSC. Levels 0 to 3 use the following codes.
+const FSC_GPF_WALK_LEVEL_MINUS_1: u64 = 0b100011;
+/// Fault status code for a granule protection fault on a translation table walk at level 3.
+const FSC_GPF_WALK_LEVEL_3: u64 = 0b100111;
+/// Fault status code for a granule protection fault other than on a translation table walk.
+const FSC_GPF: u64 = 0b101000;
+
+/// Fault status bit in PAR_EL1.
+const PAR_F: u64 = 1 << 0;
+/// The physical address in PAR_EL1 or MFAR_EL3.
+const PA_MASK: u64 = 0x00ff_ffff_ffff_f000;
+
+/// The kind of access which caused a granule protection fault.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GpfAccess {
+    /// An instruction fetch.
+    InstructionFetch,
+    /// A data read, including a translation table walk.
+    Read,
+    /// A data write, including a hardware update of a translation table.
+    Write,
+    /// A cache maintenance or address translation instruction.
+    CacheMaintenance,
+}
+
+/// What a granule protection fault was detected on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GpfCause {
+    /// The access itself.
+    Access,
+    /// A translation table walk or hardware update of a translation table, at the given level.
+    TableWalk {
+        /// The translation table level, from -1 to 3.
+        level: i8,
+    },
+    /// A granule protection check exception reported to EL3, with the given GPC status code from
+    /// ESR_EL3.ISS.GPCSC. This distinguishes faults on the access from invalid GPT entries and
+    /// external aborts on GPT fetches.
+    GpcException {
+        /// The GPC status code.
+        status: u8,
+    },
+}
+
+/// A granule protection fault which has been taken.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GranuleProtectionFault {
+    /// What the fault was detected on.
+    pub cause: GpfCause,
+    /// The kind of access which faulted.
+    pub access: GpfAccess,
+    /// Whether the access was made from a lower exception level.
+    pub from_lower_el: bool,
+    /// The virtual address which was accessed, if FAR_ELx is valid.
+    pub va: Option<u64>,
+    /// The physical address which was accessed, if it is known.
+    ///
+    /// This comes from MFAR_EL3 for a GPC exception, or from translating the virtual address for
+    /// an abort from the current exception level. It isn't known for aborts from lower exception
+    /// levels.
+    pub pa: Option<u64>,
+}
+
+impl GranuleProtectionFault {
+    /// Decodes the given exception syndrome and fault address register values, returning `None` if
+    /// the exception was not a granule protection fault.
+    ///
+    /// `pa` is the physical address of the access if it is known, which is only included in the
+    /// result for GPC exceptions and aborts from the current exception level.
+    pub fn from_esr(esr: u64, far: u64, pa: Option<u64>) -> Option<Self> {
+        let iss = esr & 0x1ff_ffff;
+        let ec = (esr >> 26) & 0x3f;
+        let fsc = iss & 0x3f;
+        let (cause, from_lower_el) = match ec {
+            ESR_EC_GPC => (
+                GpfCause::GpcException {
+                    status: ((iss >> 14) & 0x3f) as u8,
+                },
+                false,
+            ),
+            ESR_EC_IABT_LOWER | ESR_EC_IABT_CURRENT | ESR_EC_DABT_LOWER | ESR_EC_DABT_CURRENT => {
+                let cause = match fsc {
+                    FSC_GPF => GpfCause::Access,
+                    FSC_GPF_WALK_LEVEL_MINUS_1..=FSC_GPF_WALK_LEVEL_3 => GpfCause::TableWalk {
+                        level: (fsc - FSC_GPF_WALK_LEVEL_MINUS_1) as i8 - 1,
+                    },
+                    _ => return None,
+                };
+                (cause, ec == ESR_EC_IABT_LOWER || ec == ESR_EC_DABT_LOWER)
+            }
+            _ => return None,
+        };
+        let access = if ec == ESR_EC_IABT_LOWER
+            || ec == ESR_EC_IABT_CURRENT
+            || (ec == ESR_EC_GPC && iss & ESR_ISS_GPC_IND != 0)
+        {
+            GpfAccess::InstructionFetch
+        } else if iss & ESR_ISS_CM != 0 {
+            GpfAccess::CacheMaintenance
+        } else if iss & ESR_ISS_WNR != 0 {
+            GpfAccess::Write
+        } else {
+            GpfAccess::Read
+        };
+        let far_valid = ec == ESR_EC_GPC || iss & ESR_ISS_FNV == 0;
+        Some(Self {
+            cause,
+            access,
+            from_lower_el,
+            va: far_valid.then_some(far),
+            pa: if from_lower_el { None } else { pa },
+        })
+    }
+
+    /// Returns the granule protection fault currently being handled, if any.
+    ///
+    /// This reads the ESR and FAR of the current exception level, and MFAR_EL3 or PAR_EL1, so
+    /// should be called from a synchronous exception handler before anything else could cause
+    /// another exception.
+    pub fn current() -> Option<Self> {
+        let mut fault = Self::from_esr(read_esr(), read_far(), None)?;
+        fault.pa = if let GpfCause::GpcException { .. } = fault.cause {
+            // SAFETY: GPC exceptions are only taken to EL3 when FEAT_RME is implemented, so
+            // MFAR_EL3 is too.
+            let mfar = unsafe { read_sysreg!("s3_6_c6_c0_5") };
+            Some((mfar & PA_MASK) | (fault.va.unwrap_or_default() & 0xfff))
+        } else if fault.from_lower_el {
+            None
+        } else {
+            fault.va.and_then(translate)
+        };
+        Some(fault)
+    }
+}
+
+impl Display for GranuleProtectionFault {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.access)?;
+        match self.cause {
+            GpfCause::Access => {}
+            GpfCause::TableWalk { level } => write!(f, " (table walk at level {level})")?,
+            GpfCause::GpcException { status } => write!(f, " (GPC status {status:#x})")?,
+        }
+        if let Some(va) = self.va {
+            write!(f, ", VA {va:#x}")?;
+        }
+        if let Some(pa) = self.pa {
+            write!(f, ", PA {pa:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Translates the given virtual address with the stage 1 translation regime of the current
+/// exception level, returning the physical address if it is mapped.
+fn translate(va: u64) -> Option<u64> {
+    // SAFETY: Address translation instructions don't access memory other than the translation
+    // tables. PAR_EL1 is saved and restored, in case the interrupted code was using it.
+    let par = unsafe {
+        let saved = read_sysreg!("par_el1");
+        match current_el() {
+            1 => asm!("at s1e1r, {}", in(reg) va, options(nostack, preserves_flags)),
+            2 => asm!("at s1e2r, {}", in(reg) va, options(nostack, preserves_flags)),
+            _ => asm!("at s1e3r, {}", in(reg) va, options(nostack, preserves_flags)),
+        }
+        asm!("isb", options(nostack, preserves_flags));
+        let par = read_sysreg!("par_el1");
+        write_sysreg!("par_el1", saved);
+        par
+    };
+    (par & PAR_F == 0).then_some((par & PA_MASK) | (va & 0xfff))
+}
@@ -0,0 +1,1317 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Configuration for images running as a hypervisor at EL2.
+//!
+//! By default HCR_EL2 is left with whatever value the firmware set. An image may instead register a
+//! value with the [`hcr_el2!`](crate::hcr_el2) macro, which the entry point writes on each core
+//! before enabling the MMU, if it is running at EL2. For example:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{hcr_el2, hypervisor::Hcr};
+//!
+//! hcr_el2!(Hcr::RW.union(Hcr::AMO).union(Hcr::IMO).union(Hcr::FMO).union(Hcr::TSC));
+//! ```
+//!
+//! With [`Hcr::TID3`] set, reads of the ID registers by EL1 are trapped to EL2, and
+//! [`emulate_id_register_read`] can be called from
+//! [`sync_lower`](crate::ExceptionHandlers::sync_lower) to serve filtered values, e.g. to hide
+//! features from a guest:
+//!
+//! ```rust,ignore
+//! extern "C" fn sync_lower(mut register_state: RegisterStateRef) {
+//!     if emulate_id_register_read(&mut register_state, |register, value| {
+//!         if register == IdRegister::ID_AA64PFR0_EL1 {
+//!             // Hide SVE.
+//!             value & !(0xf << 32)
+//!         } else {
+//!             value
+//!         }
+//!     }) {
+//!         return;
+//!     }
+//!     // Handle other exceptions.
+//! }
+//! ```
+//!
+//! On Armv8.6 and later, [`set_fine_grained_traps`] traps accesses to individual EL1 and EL0
+//! system registers and instructions with FEAT_FGT, rather than the coarse groups trapped by
+//! HCR_EL2. Trapped register accesses can be decoded in `sync_lower` with `SysregAccess::current`,
+//! and completed with `SysregAccess::complete`:
+//!
+//! ```rust,ignore
+//! set_fine_grained_traps(&FineGrainedTraps::new().trap_writes(FgtRegisters::SCTLR_EL1)).unwrap();
+//!
+//! extern "C" fn sync_lower(mut register_state: RegisterStateRef) {
+//!     if let Some(access) = SysregAccess::current() {
+//!         // SCTLR_EL1 is op0 3, op1 0, CRn 1, CRm 0, op2 0.
+//!         if access.is_register(3, 0, 1, 0, 0) {
+//!             let value = access.write_value(&register_state).unwrap();
+//!             // Check and apply the new value.
+//!             access.complete(&mut register_state, 0);
+//!             return;
+//!         }
+//!     }
+//!     // Handle other exceptions.
+//! }
+//! ```
+//!
+//! Stage 2 faults on a guest's accesses, e.g. to MMIO regions which the hypervisor emulates, can be
+//! decoded in `sync_lower` with `GuestFault::current`, which gives the faulting intermediate
+//! physical address from HPFAR_EL2 along with the kind of access and the syndrome. Loads and stores
+//! of a single register can be emulated with [`emulate_mmio`], which passes the stored value to a
+//! closure or writes the value it returns to the guest's destination register:
+//!
+//! ```rust,ignore
+//! extern "C" fn sync_lower(mut register_state: RegisterStateRef) {
+//!     if emulate_mmio(&mut register_state, |access, write_value| {
+//!         VIRTUAL_UART.access(access.ipa.checked_sub(VIRTUAL_UART_BASE)?, write_value)
+//!     }) {
+//!         return;
+//!     }
+//!     // Handle other exceptions.
+//! }
+//! ```
+//!
+//! To prototype a nested hypervisor with FEAT_NV2, [`enable_nested_virt`] points VNCR_EL2 at a
+//! [`VncrPage`] for the guest hypervisor's virtual CPU and sets HCR_EL2.{NV, NV2}. The guest's
+//! accesses to many registers then go to the page, while the rest and its `ERET`s are trapped, and
+//! can be decoded in `sync_lower` with `NestedVirtTrap::current`.
+
+use crate::sysreg::{current_el, read_sysreg, write_sysreg};
+#[cfg(feature = "exceptions")]
+use crate::{RegisterState, RegisterStateRef, sysreg::read_esr};
+use core::{
+    arch::{asm, global_asm, naked_asm},
+    ops::{BitOr, BitOrAssign},
+};
+
+/// Exception class for a trapped MSR, MRS or system instruction.
+#[cfg(feature = "exceptions")]
+const ESR_EC_SYS64: u64 = 0x18;
+/// Exception class for a trapped `ERET`, `ERETAA` or `ERETAB`.
+#[cfg(feature = "exceptions")]
+const ESR_EC_ERET: u64 = 0x1a;
+/// Exception class for an instruction abort from a lower exception level.
+#[cfg(feature = "exceptions")]
+const ESR_EC_IABT_LOW: u64 = 0x20;
+/// Exception class for a data abort from a lower exception level.
+#[cfg(feature = "exceptions")]
+const ESR_EC_DABT_LOW: u64 = 0x24;
+/// The abort ISS bit indicating that the fault was on a stage 2 translation of a stage 1
+/// translation table walk.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_S1PTW: u64 = 1 << 7;
+/// The data abort ISS bit indicating that the instruction syndrome fields are valid.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_ISV: u64 = 1 << 24;
+/// The data abort ISS bit indicating that a load sign-extends the value.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_SSE: u64 = 1 << 21;
+/// The data abort ISS bit indicating that the register is 64 bits wide.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_SF: u64 = 1 << 15;
+/// The data abort ISS bit indicating that the access was a write.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_WNR: u64 = 1 << 6;
+/// The fault status code field of an abort ISS.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_FSC_MASK: u64 = 0x3f;
+/// The faulting IPA bits 12 and up, in bits 4 and up of HPFAR_EL2.
+#[cfg(feature = "exceptions")]
+const HPFAR_FIPA_MASK: u64 = 0x0000_ffff_ffff_fff0;
+/// The data abort ISS bit indicating that the fault was on an access redirected to the VNCR page.
+#[cfg(feature = "exceptions")]
+const ESR_ISS_VNCR: u64 = 1 << 13;
+
+/// The nested virtualization bits of HCR_EL2.
+const HCR_NESTED: u64 = Hcr::NV.0 | Hcr::NV1.0 | Hcr::NV2.0;
+
+/// A value of the Hypervisor Configuration Register, HCR_EL2.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Hcr(pub u64);
+
+impl Hcr {
+    /// Enables stage 2 address translation for the EL1&0 translation regime.
+    pub const VM: Self = Self(1 << 0);
+    /// Set/way invalidation override.
+    pub const SWIO: Self = Self(1 << 1);
+    /// Protected table walk.
+    pub const PTW: Self = Self(1 << 2);
+    /// Routes physical FIQs to EL2.
+    pub const FMO: Self = Self(1 << 3);
+    /// Routes physical IRQs to EL2.
+    pub const IMO: Self = Self(1 << 4);
+    /// Routes physical SErrors to EL2.
+    pub const AMO: Self = Self(1 << 5);
+    /// Force broadcast of TLB and cache maintenance instructions.
+    pub const FB: Self = Self(1 << 9);
+    /// Barrier shareability upgrade to inner shareable.
+    pub const BSU_INNER: Self = Self(1 << 10);
+    /// Traps `WFI` at EL1 and EL0 to EL2.
+    pub const TWI: Self = Self(1 << 13);
+    /// Traps `WFE` at EL1 and EL0 to EL2.
+    pub const TWE: Self = Self(1 << 14);
+    /// Traps reads of the ID group 3 registers, such as `ID_AA64PFR0_EL1`, to EL2.
+    pub const TID3: Self = Self(1 << 18);
+    /// Traps `SMC` at EL1 to EL2.
+    pub const TSC: Self = Self(1 << 19);
+    /// Traps accesses to ACTLR_EL1 to EL2.
+    pub const TACR: Self = Self(1 << 21);
+    /// Traps writes to the virtual memory control registers at EL1 to EL2.
+    pub const TVM: Self = Self(1 << 26);
+    /// Routes exceptions from EL0 to EL2.
+    pub const TGE: Self = Self(1 << 27);
+    /// Disables `HVC` at EL1 and EL2.
+    pub const HCD: Self = Self(1 << 29);
+    /// EL1 executes in AArch64 rather than AArch32.
+    pub const RW: Self = Self(1 << 31);
+    /// Enables the Virtualization Host Extensions.
+    pub const E2H: Self = Self(1 << 34);
+    /// Doesn't trap accesses to pointer authentication key registers at EL1 and EL0.
+    pub const APK: Self = Self(1 << 40);
+    /// Doesn't trap pointer authentication instructions at EL1 and EL0.
+    pub const API: Self = Self(1 << 41);
+    /// Traps EL2 register accesses and `ERET` at EL1 to EL2, for nested virtualization.
+    pub const NV: Self = Self(1 << 42);
+    /// Traps EL1 register accesses which are different for a guest hypervisor with HCR_EL2.E2H
+    /// clear, for nested virtualization.
+    pub const NV1: Self = Self(1 << 43);
+    /// Traps `AT S1E1*` and `AT S1E0*` at EL1 to EL2.
+    pub const AT: Self = Self(1 << 44);
+    /// Redirects EL1 accesses to many system registers to memory at VNCR_EL2, for nested
+    /// virtualization.
+    pub const NV2: Self = Self(1 << 45);
+
+    /// Returns a value with all of the bits set in either `self` or `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns whether all of the bits set in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Hcr {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for Hcr {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
+}
+
+/// Implements set operations for a newtype over a `u64` of trap bits.
+macro_rules! trap_bits {
+    ($name:ident) => {
+        impl $name {
+            /// Returns a value with all of the bits set in either `self` or `other`.
+            pub const fn union(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+
+            /// Returns whether all of the bits set in `other` are also set in `self`.
+            pub const fn contains(self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, rhs: Self) -> Self {
+                self.union(rhs)
+            }
+        }
+
+        impl BitOrAssign for $name {
+            fn bitor_assign(&mut self, rhs: Self) {
+                *self = self.union(rhs);
+            }
+        }
+    };
+}
+
+/// A set of EL1 and EL0 system registers whose accesses can be trapped to EL2 by the fine-grained
+/// traps of FEAT_FGT, with the bit layout of HFGRTR_EL2 and HFGWTR_EL2.
+///
+/// Only the positive-polarity traps from FEAT_FGT itself are included.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FgtRegisters(pub u64);
+
+impl FgtRegisters {
+    /// `AFSR0_EL1`.
+    pub const AFSR0_EL1: Self = Self(1 << 0);
+    /// `AFSR1_EL1`.
+    pub const AFSR1_EL1: Self = Self(1 << 1);
+    /// `AIDR_EL1`.
+    pub const AIDR_EL1: Self = Self(1 << 2);
+    /// `AMAIR_EL1`.
+    pub const AMAIR_EL1: Self = Self(1 << 3);
+    /// `APDAKeyHi_EL1` and `APDAKeyLo_EL1`.
+    pub const APDAKEY: Self = Self(1 << 4);
+    /// `APDBKeyHi_EL1` and `APDBKeyLo_EL1`.
+    pub const APDBKEY: Self = Self(1 << 5);
+    /// `APGAKeyHi_EL1` and `APGAKeyLo_EL1`.
+    pub const APGAKEY: Self = Self(1 << 6);
+    /// `APIAKeyHi_EL1` and `APIAKeyLo_EL1`.
+    pub const APIAKEY: Self = Self(1 << 7);
+    /// `APIBKeyHi_EL1` and `APIBKeyLo_EL1`.
+    pub const APIBKEY: Self = Self(1 << 8);
+    /// `CCSIDR_EL1`.
+    pub const CCSIDR_EL1: Self = Self(1 << 9);
+    /// `CLIDR_EL1`.
+    pub const CLIDR_EL1: Self = Self(1 << 10);
+    /// `CONTEXTIDR_EL1`.
+    pub const CONTEXTIDR_EL1: Self = Self(1 << 11);
+    /// `CPACR_EL1`.
+    pub const CPACR_EL1: Self = Self(1 << 12);
+    /// `CSSELR_EL1`.
+    pub const CSSELR_EL1: Self = Self(1 << 13);
+    /// `CTR_EL0`.
+    pub const CTR_EL0: Self = Self(1 << 14);
+    /// `DCZID_EL0`.
+    pub const DCZID_EL0: Self = Self(1 << 15);
+    /// `ESR_EL1`.
+    pub const ESR_EL1: Self = Self(1 << 16);
+    /// `FAR_EL1`.
+    pub const FAR_EL1: Self = Self(1 << 17);
+    /// `ISR_EL1`.
+    pub const ISR_EL1: Self = Self(1 << 18);
+    /// `LORC_EL1`.
+    pub const LORC_EL1: Self = Self(1 << 19);
+    /// `LOREA_EL1`.
+    pub const LOREA_EL1: Self = Self(1 << 20);
+    /// `LORID_EL1`.
+    pub const LORID_EL1: Self = Self(1 << 21);
+    /// `LORN_EL1`.
+    pub const LORN_EL1: Self = Self(1 << 22);
+    /// `LORSA_EL1`.
+    pub const LORSA_EL1: Self = Self(1 << 23);
+    /// `MAIR_EL1`.
+    pub const MAIR_EL1: Self = Self(1 << 24);
+    /// `MIDR_EL1`.
+    pub const MIDR_EL1: Self = Self(1 << 25);
+    /// `MPIDR_EL1`.
+    pub const MPIDR_EL1: Self = Self(1 << 26);
+    /// `PAR_EL1`.
+    pub const PAR_EL1: Self = Self(1 << 27);
+    /// `REVIDR_EL1`.
+    pub const REVIDR_EL1: Self = Self(1 << 28);
+    /// `SCTLR_EL1`.
+    pub const SCTLR_EL1: Self = Self(1 << 29);
+    /// `SCXTNUM_EL1`.
+    pub const SCXTNUM_EL1: Self = Self(1 << 30);
+    /// `SCXTNUM_EL0`.
+    pub const SCXTNUM_EL0: Self = Self(1 << 31);
+    /// `TCR_EL1`.
+    pub const TCR_EL1: Self = Self(1 << 32);
+    /// `TPIDR_EL1`.
+    pub const TPIDR_EL1: Self = Self(1 << 33);
+    /// `TPIDRRO_EL0`.
+    pub const TPIDRRO_EL0: Self = Self(1 << 34);
+    /// `TPIDR_EL0`.
+    pub const TPIDR_EL0: Self = Self(1 << 35);
+    /// `TTBR0_EL1`.
+    pub const TTBR0_EL1: Self = Self(1 << 36);
+    /// `TTBR1_EL1`.
+    pub const TTBR1_EL1: Self = Self(1 << 37);
+    /// `VBAR_EL1`.
+    pub const VBAR_EL1: Self = Self(1 << 38);
+    /// `ICC_IGRPEN0_EL1` and `ICC_IGRPEN1_EL1`.
+    pub const ICC_IGRPENN_EL1: Self = Self(1 << 39);
+    /// `ERRIDR_EL1`.
+    pub const ERRIDR_EL1: Self = Self(1 << 40);
+    /// `ERRSELR_EL1`.
+    pub const ERRSELR_EL1: Self = Self(1 << 41);
+    /// `ERXFR_EL1`.
+    pub const ERXFR_EL1: Self = Self(1 << 42);
+    /// `ERXCTLR_EL1`.
+    pub const ERXCTLR_EL1: Self = Self(1 << 43);
+    /// `ERXSTATUS_EL1`.
+    pub const ERXSTATUS_EL1: Self = Self(1 << 44);
+    /// `ERXMISC0_EL1` to `ERXMISC3_EL1`.
+    pub const ERXMISCN_EL1: Self = Self(1 << 45);
+    /// `ERXPFGF_EL1`.
+    pub const ERXPFGF_EL1: Self = Self(1 << 46);
+    /// `ERXPFGCTL_EL1`.
+    pub const ERXPFGCTL_EL1: Self = Self(1 << 47);
+    /// `ERXPFGCDN_EL1`.
+    pub const ERXPFGCDN_EL1: Self = Self(1 << 48);
+    /// `ERXADDR_EL1`.
+    pub const ERXADDR_EL1: Self = Self(1 << 49);
+
+    /// All of the traps defined here, which [`set_fine_grained_traps`] manages.
+    pub const ALL: Self = Self((1 << 50) - 1);
+    /// The registers which can't be written, so have no write trap in HFGWTR_EL2.
+    const READ_ONLY: Self = Self(
+        Self::AIDR_EL1.0
+            | Self::CCSIDR_EL1.0
+            | Self::CLIDR_EL1.0
+            | Self::CTR_EL0.0
+            | Self::DCZID_EL0.0
+            | Self::ISR_EL1.0
+            | Self::LORID_EL1.0
+            | Self::MIDR_EL1.0
+            | Self::MPIDR_EL1.0
+            | Self::REVIDR_EL1.0
+            | Self::ERRIDR_EL1.0
+            | Self::ERXFR_EL1.0
+            | Self::ERXPFGF_EL1.0,
+    );
+}
+
+trap_bits!(FgtRegisters);
+
+/// A set of EL1 and EL0 instructions which can be trapped to EL2 by the fine-grained traps of
+/// FEAT_FGT, with the bit layout of HFGITR_EL2.
+///
+/// Only the positive-polarity traps from FEAT_FGT itself are included.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FgtInstructions(pub u64);
+
+impl FgtInstructions {
+    /// `IC IALLUIS`.
+    pub const IC_IALLUIS: Self = Self(1 << 0);
+    /// `IC IALLU`.
+    pub const IC_IALLU: Self = Self(1 << 1);
+    /// `IC IVAU`.
+    pub const IC_IVAU: Self = Self(1 << 2);
+    /// `DC IVAC`.
+    pub const DC_IVAC: Self = Self(1 << 3);
+    /// `DC ISW`.
+    pub const DC_ISW: Self = Self(1 << 4);
+    /// `DC CSW`.
+    pub const DC_CSW: Self = Self(1 << 5);
+    /// `DC CISW`.
+    pub const DC_CISW: Self = Self(1 << 6);
+    /// `DC CVAU`.
+    pub const DC_CVAU: Self = Self(1 << 7);
+    /// `DC CVAP`.
+    pub const DC_CVAP: Self = Self(1 << 8);
+    /// `DC CVADP`.
+    pub const DC_CVADP: Self = Self(1 << 9);
+    /// `DC CIVAC`.
+    pub const DC_CIVAC: Self = Self(1 << 10);
+    /// `DC ZVA`.
+    pub const DC_ZVA: Self = Self(1 << 11);
+    /// `AT S1E1R`.
+    pub const AT_S1E1R: Self = Self(1 << 12);
+    /// `AT S1E1W`.
+    pub const AT_S1E1W: Self = Self(1 << 13);
+    /// `AT S1E0R`.
+    pub const AT_S1E0R: Self = Self(1 << 14);
+    /// `AT S1E0W`.
+    pub const AT_S1E0W: Self = Self(1 << 15);
+    /// `AT S1E1RP`.
+    pub const AT_S1E1RP: Self = Self(1 << 16);
+    /// `AT S1E1WP`.
+    pub const AT_S1E1WP: Self = Self(1 << 17);
+    /// `TLBI VMALLE1OS`.
+    pub const TLBI_VMALLE1OS: Self = Self(1 << 18);
+    /// `TLBI VAE1OS`.
+    pub const TLBI_VAE1OS: Self = Self(1 << 19);
+    /// `TLBI ASIDE1OS`.
+    pub const TLBI_ASIDE1OS: Self = Self(1 << 20);
+    /// `TLBI VAAE1OS`.
+    pub const TLBI_VAAE1OS: Self = Self(1 << 21);
+    /// `TLBI VALE1OS`.
+    pub const TLBI_VALE1OS: Self = Self(1 << 22);
+    /// `TLBI VAALE1OS`.
+    pub const TLBI_VAALE1OS: Self = Self(1 << 23);
+    /// `TLBI RVAE1OS`.
+    pub const TLBI_RVAE1OS: Self = Self(1 << 24);
+    /// `TLBI RVAAE1OS`.
+    pub const TLBI_RVAAE1OS: Self = Self(1 << 25);
+    /// `TLBI RVALE1OS`.
+    pub const TLBI_RVALE1OS: Self = Self(1 << 26);
+    /// `TLBI RVAALE1OS`.
+    pub const TLBI_RVAALE1OS: Self = Self(1 << 27);
+    /// `TLBI VMALLE1IS`.
+    pub const TLBI_VMALLE1IS: Self = Self(1 << 28);
+    /// `TLBI VAE1IS`.
+    pub const TLBI_VAE1IS: Self = Self(1 << 29);
+    /// `TLBI ASIDE1IS`.
+    pub const TLBI_ASIDE1IS: Self = Self(1 << 30);
+    /// `TLBI VAAE1IS`.
+    pub const TLBI_VAAE1IS: Self = Self(1 << 31);
+    /// `TLBI VALE1IS`.
+    pub const TLBI_VALE1IS: Self = Self(1 << 32);
+    /// `TLBI VAALE1IS`.
+    pub const TLBI_VAALE1IS: Self = Self(1 << 33);
+    /// `TLBI RVAE1IS`.
+    pub const TLBI_RVAE1IS: Self = Self(1 << 34);
+    /// `TLBI RVAAE1IS`.
+    pub const TLBI_RVAAE1IS: Self = Self(1 << 35);
+    /// `TLBI RVALE1IS`.
+    pub const TLBI_RVALE1IS: Self = Self(1 << 36);
+    /// `TLBI RVAALE1IS`.
+    pub const TLBI_RVAALE1IS: Self = Self(1 << 37);
+    /// `TLBI RVAE1`.
+    pub const TLBI_RVAE1: Self = Self(1 << 38);
+    /// `TLBI RVAAE1`.
+    pub const TLBI_RVAAE1: Self = Self(1 << 39);
+    /// `TLBI RVALE1`.
+    pub const TLBI_RVALE1: Self = Self(1 << 40);
+    /// `TLBI RVAALE1`.
+    pub const TLBI_RVAALE1: Self = Self(1 << 41);
+    /// `TLBI VMALLE1`.
+    pub const TLBI_VMALLE1: Self = Self(1 << 42);
+    /// `TLBI VAE1`.
+    pub const TLBI_VAE1: Self = Self(1 << 43);
+    /// `TLBI ASIDE1`.
+    pub const TLBI_ASIDE1: Self = Self(1 << 44);
+    /// `TLBI VAAE1`.
+    pub const TLBI_VAAE1: Self = Self(1 << 45);
+    /// `TLBI VALE1`.
+    pub const TLBI_VALE1: Self = Self(1 << 46);
+    /// `TLBI VAALE1`.
+    pub const TLBI_VAALE1: Self = Self(1 << 47);
+    /// `CFP RCTX`.
+    pub const CFP_RCTX: Self = Self(1 << 48);
+    /// `DVP RCTX`.
+    pub const DVP_RCTX: Self = Self(1 << 49);
+    /// `CPP RCTX`.
+    pub const CPP_RCTX: Self = Self(1 << 50);
+    /// `ERET`, `ERETAA` and `ERETAB`.
+    pub const ERET: Self = Self(1 << 51);
+    /// `SVC` at EL0.
+    pub const SVC_EL0: Self = Self(1 << 52);
+    /// `SVC` at EL1.
+    pub const SVC_EL1: Self = Self(1 << 53);
+    /// `DC CVAC`.
+    pub const DC_CVAC: Self = Self(1 << 54);
+
+    /// All of the traps defined here, which [`set_fine_grained_traps`] manages.
+    pub const ALL: Self = Self((1 << 55) - 1);
+}
+
+trap_bits!(FgtInstructions);
+
+/// A configuration of the FEAT_FGT fine-grained traps from EL1 and EL0 to EL2, to be applied with
+/// [`set_fine_grained_traps`].
+///
+/// ```rust,ignore
+/// use aarch64_rt::hypervisor::{FgtInstructions, FgtRegisters, FineGrainedTraps};
+///
+/// let traps = FineGrainedTraps::new()
+///     .trap_writes(FgtRegisters::SCTLR_EL1.union(FgtRegisters::TCR_EL1))
+///     .trap_accesses(FgtRegisters::ERRSELR_EL1)
+///     .trap_instructions(FgtInstructions::DC_ISW.union(FgtInstructions::DC_CISW));
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FineGrainedTraps {
+    /// Registers whose reads are trapped, for HFGRTR_EL2.
+    pub reads: FgtRegisters,
+    /// Registers whose writes are trapped, for HFGWTR_EL2.
+    pub writes: FgtRegisters,
+    /// Instructions which are trapped, for HFGITR_EL2.
+    pub instructions: FgtInstructions,
+}
+
+impl FineGrainedTraps {
+    /// Returns a configuration which doesn't trap anything.
+    pub const fn new() -> Self {
+        Self {
+            reads: FgtRegisters(0),
+            writes: FgtRegisters(0),
+            instructions: FgtInstructions(0),
+        }
+    }
+
+    /// Also traps reads of the given registers.
+    pub const fn trap_reads(mut self, registers: FgtRegisters) -> Self {
+        self.reads = self.reads.union(registers);
+        self
+    }
+
+    /// Also traps writes to the given registers. Read-only registers are ignored.
+    pub const fn trap_writes(mut self, registers: FgtRegisters) -> Self {
+        self.writes = FgtRegisters(self.writes.0 | (registers.0 & !FgtRegisters::READ_ONLY.0));
+        self
+    }
+
+    /// Also traps both reads and writes of the given registers.
+    pub const fn trap_accesses(self, registers: FgtRegisters) -> Self {
+        self.trap_reads(registers).trap_writes(registers)
+    }
+
+    /// Also traps the given instructions.
+    pub const fn trap_instructions(mut self, instructions: FgtInstructions) -> Self {
+        self.instructions = self.instructions.union(instructions);
+        self
+    }
+}
+
+/// An error configuring fine-grained traps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FgtError {
+    /// FEAT_FGT isn't implemented.
+    NotImplemented,
+    /// We aren't running at EL2.
+    UnsupportedEl,
+}
+
+/// Returns whether FEAT_FGT is implemented.
+pub fn fgt_implemented() -> bool {
+    // SAFETY: Reading ID_AA64MMFR0_EL1 is always safe.
+    let mmfr0 = unsafe { read_sysreg!("id_aa64mmfr0_el1") };
+    (mmfr0 >> 56) & 0xf != 0
+}
+
+/// Sets the fine-grained traps from EL1 and EL0 to EL2 on the current core, via HFGRTR_EL2,
+/// HFGWTR_EL2 and HFGITR_EL2.
+///
+/// Only the traps in [`FgtRegisters::ALL`] and [`FgtInstructions::ALL`] are changed; other bits,
+/// such as the negative-polarity traps added by later extensions, are left as they were. Trapped
+/// register accesses are taken to [`sync_lower`](crate::ExceptionHandlers::sync_lower), where
+/// `SysregAccess::current` can decode them.
+///
+/// If running at EL2 under EL3 firmware, the firmware must have set SCR_EL3.FGTEn.
+pub fn set_fine_grained_traps(traps: &FineGrainedTraps) -> Result<(), FgtError> {
+    if current_el() != 2 {
+        return Err(FgtError::UnsupportedEl);
+    }
+    if !fgt_implemented() {
+        return Err(FgtError::NotImplemented);
+    }
+    // SAFETY: We are running at EL2 and FEAT_FGT is implemented, so can access the fine-grained
+    // trap registers. Trapping lower exception level accesses to EL2 doesn't affect memory safety.
+    unsafe {
+        // HFGRTR_EL2, HFGWTR_EL2 and HFGITR_EL2 by encoding, as the assembler may not know them.
+        let hfgrtr = read_sysreg!("s3_4_c1_c1_4") & !FgtRegisters::ALL.0;
+        write_sysreg!("s3_4_c1_c1_4", hfgrtr | traps.reads.0);
+        let hfgwtr = read_sysreg!("s3_4_c1_c1_5") & !FgtRegisters::ALL.0;
+        write_sysreg!("s3_4_c1_c1_5", hfgwtr | traps.writes.0);
+        let hfgitr = read_sysreg!("s3_4_c1_c1_6") & !FgtInstructions::ALL.0;
+        write_sysreg!("s3_4_c1_c1_6", hfgitr | traps.instructions.0);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    Ok(())
+}
+
+/// An ID register in the group which is trapped by [`Hcr::TID3`], identified by the CRm and op2
+/// fields of its encoding. The op0, op1 and CRn fields are always 3, 0 and 0 respectively.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IdRegister {
+    /// The CRm field of the register encoding, from 1 to 7.
+    pub crm: u8,
+    /// The op2 field of the register encoding, from 0 to 7.
+    pub op2: u8,
+}
+
+#[cfg(feature = "exceptions")]
+impl IdRegister {
+    /// AArch64 Processor Feature Register 0.
+    pub const ID_AA64PFR0_EL1: Self = Self { crm: 4, op2: 0 };
+    /// AArch64 Processor Feature Register 1.
+    pub const ID_AA64PFR1_EL1: Self = Self { crm: 4, op2: 1 };
+    /// SVE Feature ID Register 0.
+    pub const ID_AA64ZFR0_EL1: Self = Self { crm: 4, op2: 4 };
+    /// SME Feature ID Register 0.
+    pub const ID_AA64SMFR0_EL1: Self = Self { crm: 4, op2: 5 };
+    /// AArch64 Debug Feature Register 0.
+    pub const ID_AA64DFR0_EL1: Self = Self { crm: 5, op2: 0 };
+    /// AArch64 Debug Feature Register 1.
+    pub const ID_AA64DFR1_EL1: Self = Self { crm: 5, op2: 1 };
+    /// AArch64 Instruction Set Attribute Register 0.
+    pub const ID_AA64ISAR0_EL1: Self = Self { crm: 6, op2: 0 };
+    /// AArch64 Instruction Set Attribute Register 1.
+    pub const ID_AA64ISAR1_EL1: Self = Self { crm: 6, op2: 1 };
+    /// AArch64 Instruction Set Attribute Register 2.
+    pub const ID_AA64ISAR2_EL1: Self = Self { crm: 6, op2: 2 };
+    /// AArch64 Memory Model Feature Register 0.
+    pub const ID_AA64MMFR0_EL1: Self = Self { crm: 7, op2: 0 };
+    /// AArch64 Memory Model Feature Register 1.
+    pub const ID_AA64MMFR1_EL1: Self = Self { crm: 7, op2: 1 };
+    /// AArch64 Memory Model Feature Register 2.
+    pub const ID_AA64MMFR2_EL1: Self = Self { crm: 7, op2: 2 };
+
+    /// Reads the real value of the register.
+    pub fn read(self) -> u64 {
+        macro_rules! read_id_register {
+            ($($crm:literal: $($op2:literal)*),*) => {
+                match (self.crm, self.op2) {
+                    $($(
+                        // SAFETY: Reading ID registers is always safe.
+                        ($crm, $op2) => unsafe {
+                            read_sysreg!(concat!("s3_0_c0_c", $crm, "_", $op2))
+                        },
+                    )*)*
+                    _ => 0,
+                }
+            };
+        }
+        read_id_register!(
+            1: 0 1 2 3 4 5 6 7,
+            2: 0 1 2 3 4 5 6 7,
+            3: 0 1 2 3 4 5 6 7,
+            4: 0 1 2 3 4 5 6 7,
+            5: 0 1 2 3 4 5 6 7,
+            6: 0 1 2 3 4 5 6 7,
+            7: 0 1 2 3 4 5 6 7
+        )
+    }
+}
+
+/// A system register access by a lower exception level which was trapped to EL2, such as by
+/// [`Hcr::TID3`] or the fine-grained traps, decoded from its exception syndrome.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SysregAccess {
+    /// The op0 field of the register encoding.
+    pub op0: u8,
+    /// The op1 field of the register encoding.
+    pub op1: u8,
+    /// The CRn field of the register encoding.
+    pub crn: u8,
+    /// The CRm field of the register encoding.
+    pub crm: u8,
+    /// The op2 field of the register encoding.
+    pub op2: u8,
+    /// The general-purpose register being read into or written from, where 31 is xzr.
+    pub rt: u8,
+    /// Whether the access is a read (`MRS`) rather than a write (`MSR`).
+    pub is_read: bool,
+}
+
+#[cfg(feature = "exceptions")]
+impl SysregAccess {
+    /// Decodes the given exception syndrome, returning `None` if it isn't for a trapped `MSR`,
+    /// `MRS` or system instruction.
+    pub fn from_esr(esr: u64) -> Option<Self> {
+        if (esr >> 26) & 0x3f != ESR_EC_SYS64 {
+            return None;
+        }
+        Some(Self {
+            op0: ((esr >> 20) & 0x3) as u8,
+            op2: ((esr >> 17) & 0x7) as u8,
+            op1: ((esr >> 14) & 0x7) as u8,
+            crn: ((esr >> 10) & 0xf) as u8,
+            rt: ((esr >> 5) & 0x1f) as u8,
+            crm: ((esr >> 1) & 0xf) as u8,
+            is_read: esr & 0x1 == 1,
+        })
+    }
+
+    /// Returns the trapped system register access currently being handled, if any.
+    ///
+    /// This reads the ESR of the current exception level, so should be called from
+    /// [`sync_lower`](crate::ExceptionHandlers::sync_lower) before anything else could cause
+    /// another exception.
+    pub fn current() -> Option<Self> {
+        Self::from_esr(read_esr())
+    }
+
+    /// Returns whether the access is to the register with the given encoding.
+    pub fn is_register(&self, op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> bool {
+        (self.op0, self.op1, self.crn, self.crm, self.op2) == (op0, op1, crn, crm, op2)
+    }
+
+    /// Returns the value which a trapped write was writing, or `None` if it is a read.
+    pub fn write_value(&self, register_state: &RegisterStateRef) -> Option<u64> {
+        if self.is_read {
+            return None;
+        }
+        Some(saved_register(register_state.as_ref(), self.rt))
+    }
+
+    /// Completes the trapped access as if it had happened, by writing `read_value` to the
+    /// destination register of a read and advancing the ELR past the trapped instruction.
+    pub fn complete(&self, register_state: &mut RegisterStateRef, read_value: u64) {
+        // SAFETY: We only write the destination register of the trapped instruction and move past
+        // it, which is what the instruction would have done if it hadn't been trapped.
+        let state = unsafe { register_state.get_mut() };
+        if self.is_read {
+            set_saved_register(state, self.rt, read_value);
+        }
+        state.elr += 4;
+    }
+}
+
+/// Returns the value of the given general-purpose register from the saved register state, where 31
+/// is xzr.
+#[cfg(feature = "exceptions")]
+fn saved_register(state: &RegisterState, register: u8) -> u64 {
+    match register {
+        0..=18 => state.registers[usize::from(register)],
+        19..=28 => state.callee_saved[usize::from(register) - 19],
+        29 => state.fp,
+        30 => state.sp,
+        _ => 0,
+    }
+}
+
+/// Sets the given general-purpose register in the saved register state, where 31 is xzr.
+///
+/// Writes to xzr are ignored.
+#[cfg(feature = "exceptions")]
+fn set_saved_register(state: &mut RegisterState, register: u8, value: u64) {
+    match register {
+        0..=18 => state.registers[usize::from(register)] = value,
+        19..=28 => state.callee_saved[usize::from(register) - 19] = value,
+        29 => state.fp = value,
+        30 => state.sp = value,
+        _ => {}
+    }
+}
+
+/// Emulates a read of an ID register by a lower exception level which was trapped to EL2, such as
+/// due to [`Hcr::TID3`].
+///
+/// If the exception being handled is such a read, this calls `filter` with the register and its
+/// real value, writes the value it returns to the destination register and advances the ELR past
+/// the trapped instruction, then returns true. Otherwise it returns false without changing
+/// anything.
+///
+/// This must only be called from [`sync_lower`](crate::ExceptionHandlers::sync_lower) at EL2.
+#[cfg(feature = "exceptions")]
+pub fn emulate_id_register_read(
+    register_state: &mut RegisterStateRef,
+    filter: impl FnOnce(IdRegister, u64) -> u64,
+) -> bool {
+    let Some(access) = SysregAccess::current() else {
+        return false;
+    };
+    if !access.is_read
+        || access.op0 != 3
+        || access.op1 != 0
+        || access.crn != 0
+        || !(1..=7).contains(&access.crm)
+    {
+        return false;
+    }
+
+    let register = IdRegister {
+        crm: access.crm,
+        op2: access.op2,
+    };
+    let value = filter(register, register.read());
+    access.complete(register_state, value);
+    true
+}
+
+/// The kind of access which caused a [`GuestFault`].
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuestAccess {
+    /// An instruction fetch.
+    Execute,
+    /// A data read.
+    Read,
+    /// A data write.
+    Write,
+    /// A read or write by the guest's stage 1 translation table walk.
+    TableWalk,
+}
+
+/// A stage 2 fault on an access by a lower exception level, taken to EL2 as an instruction or data
+/// abort, such as an access to an MMIO region which the hypervisor emulates.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GuestFault {
+    /// The intermediate physical address which faulted, from HPFAR_EL2 and the page offset in
+    /// FAR_EL2.
+    pub ipa: u64,
+    /// The kind of access which faulted.
+    pub access: GuestAccess,
+    /// The exception syndrome, from ESR_EL2. For a data abort, its ISS describes the access in more
+    /// detail.
+    pub syndrome: u64,
+}
+
+#[cfg(feature = "exceptions")]
+impl GuestFault {
+    /// Decodes the given values of ESR_EL2, FAR_EL2 and HPFAR_EL2, returning `None` if they aren't
+    /// for an abort from a lower exception level for which HPFAR_EL2 is valid.
+    ///
+    /// HPFAR_EL2 is only valid for translation, access flag and permission faults at stage 2,
+    /// including those on a stage 1 translation table walk.
+    pub fn from_registers(esr: u64, far: u64, hpfar: u64) -> Option<Self> {
+        let ec = (esr >> 26) & 0x3f;
+        if ec != ESR_EC_IABT_LOW && ec != ESR_EC_DABT_LOW {
+            return None;
+        }
+        // Translation, access flag and permission faults at levels 0 to 3, or a translation fault
+        // at level -1.
+        if !matches!(esr & ESR_ISS_FSC_MASK, 0x04..=0x0f | 0x2b) {
+            return None;
+        }
+        let access = if esr & ESR_ISS_S1PTW != 0 {
+            GuestAccess::TableWalk
+        } else if ec == ESR_EC_IABT_LOW {
+            GuestAccess::Execute
+        } else if esr & ESR_ISS_WNR != 0 {
+            GuestAccess::Write
+        } else {
+            GuestAccess::Read
+        };
+        Some(Self {
+            ipa: (hpfar & HPFAR_FIPA_MASK) << 8 | (far & 0xfff),
+            access,
+            syndrome: esr,
+        })
+    }
+
+    /// Returns the stage 2 fault currently being handled, if any.
+    ///
+    /// This reads ESR_EL2, FAR_EL2 and HPFAR_EL2, so should be called from
+    /// [`sync_lower`](crate::ExceptionHandlers::sync_lower) before anything else could cause
+    /// another exception. It returns `None` if not running at EL2.
+    pub fn current() -> Option<Self> {
+        if current_el() != 2 {
+            return None;
+        }
+        // SAFETY: Reading the fault registers at EL2 is always safe.
+        unsafe {
+            Self::from_registers(
+                read_sysreg!("esr_el2"),
+                read_sysreg!("far_el2"),
+                read_sysreg!("hpfar_el2"),
+            )
+        }
+    }
+}
+
+/// A load or store of a single general-purpose register by a lower exception level, which faulted
+/// at stage 2 and can be emulated, e.g. because it was to an MMIO region of a virtual device.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MmioAccess {
+    /// The intermediate physical address accessed.
+    pub ipa: u64,
+    /// The size of the access in bytes: 1, 2, 4 or 8.
+    pub size: u8,
+    /// The general-purpose register being loaded into or stored from, where 31 is xzr.
+    pub rt: u8,
+    /// Whether a load sign-extends the value to the width of the register.
+    pub sign_extend: bool,
+    /// Whether the register is 64 bits wide (`Xt`) rather than 32 bits (`Wt`).
+    pub wide: bool,
+    /// Whether the access is a store rather than a load.
+    pub is_write: bool,
+}
+
+#[cfg(feature = "exceptions")]
+impl MmioAccess {
+    /// Decodes the given stage 2 fault, returning `None` if it isn't a data access with a valid
+    /// instruction syndrome.
+    ///
+    /// The syndrome is only valid for loads and stores of a single general-purpose register without
+    /// writeback, so other instructions such as `LDP` can't be emulated this way.
+    pub fn from_guest_fault(fault: &GuestFault) -> Option<Self> {
+        let is_write = match fault.access {
+            GuestAccess::Read => false,
+            GuestAccess::Write => true,
+            GuestAccess::Execute | GuestAccess::TableWalk => return None,
+        };
+        let esr = fault.syndrome;
+        if esr & ESR_ISS_ISV == 0 {
+            return None;
+        }
+        Some(Self {
+            ipa: fault.ipa,
+            size: 1 << ((esr >> 22) & 0x3),
+            rt: ((esr >> 16) & 0x1f) as u8,
+            sign_extend: esr & ESR_ISS_SSE != 0,
+            wide: esr & ESR_ISS_SF != 0,
+            is_write,
+        })
+    }
+
+    /// Returns the emulatable MMIO access currently being handled, if any.
+    ///
+    /// As for [`GuestFault::current`], this should be called from
+    /// [`sync_lower`](crate::ExceptionHandlers::sync_lower) at EL2 before anything else could cause
+    /// another exception.
+    pub fn current() -> Option<Self> {
+        Self::from_guest_fault(&GuestFault::current()?)
+    }
+
+    /// Returns the value which a store was storing, truncated to the size of the access, or `None`
+    /// if it is a load.
+    pub fn write_value(&self, register_state: &RegisterStateRef) -> Option<u64> {
+        if !self.is_write {
+            return None;
+        }
+        Some(saved_register(register_state.as_ref(), self.rt) & self.size_mask())
+    }
+
+    /// Completes the access as if it had happened, by writing `read_value` to the destination
+    /// register of a load and advancing the ELR past the faulting instruction.
+    ///
+    /// `read_value` is truncated to the size of the access, then sign-extended if the instruction
+    /// requires, and truncated to 32 bits for a `Wt` register.
+    pub fn complete(&self, register_state: &mut RegisterStateRef, read_value: u64) {
+        // SAFETY: We only write the destination register of the faulting instruction and move past
+        // it, which is what the instruction would have done if it hadn't faulted.
+        let state = unsafe { register_state.get_mut() };
+        if !self.is_write {
+            set_saved_register(state, self.rt, self.extend(read_value));
+        }
+        state.elr += 4;
+    }
+
+    /// Returns a mask of the bits of a register which the access covers.
+    fn size_mask(&self) -> u64 {
+        u64::MAX >> (64 - u32::from(self.size) * 8)
+    }
+
+    /// Truncates the given value to the size of the access, and extends it to the width of the
+    /// destination register as a load would.
+    fn extend(&self, value: u64) -> u64 {
+        let bits = u32::from(self.size) * 8;
+        let value = if self.sign_extend {
+            (((value << (64 - bits)) as i64) >> (64 - bits)) as u64
+        } else {
+            value & self.size_mask()
+        };
+        if self.wide {
+            value
+        } else {
+            value & u64::from(u32::MAX)
+        }
+    }
+}
+
+/// Emulates a load or store by a lower exception level which faulted at stage 2 and was taken to
+/// EL2, such as an access to an MMIO region of a virtual device.
+///
+/// If the exception being handled is such an access, this calls `handler` with the access and, for
+/// a store, the value being stored. The handler returns the value for a load, which is written to
+/// the destination register, or `None` if it doesn't emulate the address. Unless the handler
+/// returns `None`, the ELR is then advanced past the faulting instruction and this returns true.
+/// Otherwise it returns false without changing anything. The value which the handler returns for a
+/// store is ignored.
+///
+/// This must only be called from [`sync_lower`](crate::ExceptionHandlers::sync_lower) at EL2, for
+/// an AArch64 guest.
+#[cfg(feature = "exceptions")]
+pub fn emulate_mmio(
+    register_state: &mut RegisterStateRef,
+    handler: impl FnOnce(&MmioAccess, Option<u64>) -> Option<u64>,
+) -> bool {
+    let Some(access) = MmioAccess::current() else {
+        return false;
+    };
+    let write_value = access.write_value(register_state);
+    let Some(read_value) = handler(&access, write_value) else {
+        return false;
+    };
+    access.complete(register_state, read_value);
+    true
+}
+
+/// A page of memory which EL1 accesses to many EL2 and EL1 system registers are redirected to by
+/// FEAT_NV2, pointed to by VNCR_EL2.
+///
+/// A nested hypervisor keeps one of these for each virtual CPU running a guest hypervisor, and
+/// reads and writes the guest's view of the registers here. The associated constants give the
+/// byte offsets of some of the registers within the page.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[repr(C, align(4096))]
+pub struct VncrPage(pub [u64; 512]);
+
+impl VncrPage {
+    /// `VTTBR_EL2`.
+    pub const VTTBR_EL2: usize = 0x020;
+    /// `VTCR_EL2`.
+    pub const VTCR_EL2: usize = 0x040;
+    /// `VMPIDR_EL2`.
+    pub const VMPIDR_EL2: usize = 0x050;
+    /// `CNTVOFF_EL2`.
+    pub const CNTVOFF_EL2: usize = 0x060;
+    /// `HCR_EL2`.
+    pub const HCR_EL2: usize = 0x078;
+    /// `HSTR_EL2`.
+    pub const HSTR_EL2: usize = 0x080;
+    /// `VPIDR_EL2`.
+    pub const VPIDR_EL2: usize = 0x088;
+    /// `TPIDR_EL2`.
+    pub const TPIDR_EL2: usize = 0x090;
+    /// `VNCR_EL2`.
+    pub const VNCR_EL2: usize = 0x0b0;
+    /// `CPACR_EL1`.
+    pub const CPACR_EL1: usize = 0x100;
+    /// `CONTEXTIDR_EL1`.
+    pub const CONTEXTIDR_EL1: usize = 0x108;
+    /// `SCTLR_EL1`.
+    pub const SCTLR_EL1: usize = 0x110;
+    /// `ACTLR_EL1`.
+    pub const ACTLR_EL1: usize = 0x118;
+    /// `TCR_EL1`.
+    pub const TCR_EL1: usize = 0x120;
+    /// `AFSR0_EL1`.
+    pub const AFSR0_EL1: usize = 0x128;
+    /// `AFSR1_EL1`.
+    pub const AFSR1_EL1: usize = 0x130;
+    /// `ESR_EL1`.
+    pub const ESR_EL1: usize = 0x138;
+    /// `MAIR_EL1`.
+    pub const MAIR_EL1: usize = 0x140;
+    /// `AMAIR_EL1`.
+    pub const AMAIR_EL1: usize = 0x148;
+    /// `MDSCR_EL1`.
+    pub const MDSCR_EL1: usize = 0x158;
+    /// `SPSR_EL1`.
+    pub const SPSR_EL1: usize = 0x160;
+    /// `CNTV_CVAL_EL0`.
+    pub const CNTV_CVAL_EL0: usize = 0x168;
+    /// `CNTV_CTL_EL0`.
+    pub const CNTV_CTL_EL0: usize = 0x170;
+    /// `CNTP_CVAL_EL0`.
+    pub const CNTP_CVAL_EL0: usize = 0x178;
+    /// `CNTP_CTL_EL0`.
+    pub const CNTP_CTL_EL0: usize = 0x180;
+    /// `HFGRTR_EL2`.
+    pub const HFGRTR_EL2: usize = 0x1b8;
+    /// `HFGWTR_EL2`.
+    pub const HFGWTR_EL2: usize = 0x1c0;
+    /// `HFGITR_EL2`.
+    pub const HFGITR_EL2: usize = 0x1c8;
+    /// `TTBR0_EL1`.
+    pub const TTBR0_EL1: usize = 0x200;
+    /// `TTBR1_EL1`.
+    pub const TTBR1_EL1: usize = 0x210;
+    /// `FAR_EL1`.
+    pub const FAR_EL1: usize = 0x220;
+    /// `ELR_EL1`.
+    pub const ELR_EL1: usize = 0x230;
+    /// `SP_EL1`.
+    pub const SP_EL1: usize = 0x240;
+    /// `VBAR_EL1`.
+    pub const VBAR_EL1: usize = 0x250;
+
+    /// Returns a page with all registers zero.
+    pub const fn new() -> Self {
+        Self([0; 512])
+    }
+
+    /// Returns the value of the register at the given byte offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` isn't a multiple of 8 within the page.
+    pub fn get(&self, offset: usize) -> u64 {
+        assert!(offset.is_multiple_of(8));
+        self.0[offset / 8]
+    }
+
+    /// Sets the value of the register at the given byte offset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` isn't a multiple of 8 within the page.
+    pub fn set(&mut self, offset: usize, value: u64) {
+        assert!(offset.is_multiple_of(8));
+        self.0[offset / 8] = value;
+    }
+}
+
+impl Default for VncrPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The level of nested virtualization support implemented, from ID_AA64MMFR2_EL1.NV.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum NestedVirtSupport {
+    /// Nested virtualization isn't supported.
+    None,
+    /// FEAT_NV: EL2 register accesses and `ERET` from EL1 can be trapped to EL2.
+    Nv,
+    /// FEAT_NV2: accesses to many registers are also redirected to memory through VNCR_EL2.
+    Nv2,
+}
+
+/// An error enabling nested virtualization.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NestedVirtError {
+    /// FEAT_NV2 isn't implemented.
+    NotImplemented,
+    /// We aren't running at EL2.
+    UnsupportedEl,
+}
+
+/// Returns the level of nested virtualization support implemented.
+pub fn nested_virt_support() -> NestedVirtSupport {
+    // SAFETY: Reading ID_AA64MMFR2_EL1 is always safe.
+    let mmfr2 = unsafe { read_sysreg!("id_aa64mmfr2_el1") };
+    match (mmfr2 >> 24) & 0xf {
+        0 => NestedVirtSupport::None,
+        1 => NestedVirtSupport::Nv,
+        _ => NestedVirtSupport::Nv2,
+    }
+}
+
+/// Enables FEAT_NV2 nested virtualization for the guest hypervisor about to run at EL1 on the
+/// current core, by pointing VNCR_EL2 at the given page and setting HCR_EL2.{NV, NV2}, and NV1 if
+/// `nv1` is true.
+///
+/// Afterwards the guest's accesses to EL2 registers and `ERET` are trapped to EL2 or redirected to
+/// the page, and `NestedVirtTrap::current` can decode the traps in `sync_lower`. Call this again
+/// with a different page to switch between virtual CPUs.
+///
+/// # Safety
+///
+/// `vncr` must point to a page which is mapped at the same address in the EL2 translation regime,
+/// stays valid for as long as nested virtualization is enabled with it, and isn't accessed by
+/// anything else while a guest runs with it.
+pub unsafe fn enable_nested_virt(vncr: *mut VncrPage, nv1: bool) -> Result<(), NestedVirtError> {
+    if current_el() != 2 {
+        return Err(NestedVirtError::UnsupportedEl);
+    }
+    if nested_virt_support() < NestedVirtSupport::Nv2 {
+        return Err(NestedVirtError::NotImplemented);
+    }
+    let nested = Hcr::NV.union(Hcr::NV2).0 | if nv1 { Hcr::NV1.0 } else { 0 };
+    // SAFETY: We are running at EL2 and FEAT_NV2 is implemented. Our caller promised that the page
+    // is valid for the hardware to redirect register accesses to.
+    unsafe {
+        // VNCR_EL2 by encoding, as the assembler may not know it.
+        write_sysreg!("s3_4_c2_c2_0", vncr as u64);
+        write_sysreg!("hcr_el2", (read_sysreg!("hcr_el2") & !HCR_NESTED) | nested);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    Ok(())
+}
+
+/// Disables nested virtualization on the current core, by clearing HCR_EL2.{NV, NV1, NV2}.
+///
+/// # Panics
+///
+/// Panics if not running at EL2.
+pub fn disable_nested_virt() {
+    assert_eq!(current_el(), 2);
+    // SAFETY: We are running at EL2. Stopping EL1 accesses from being redirected doesn't affect
+    // memory safety.
+    unsafe {
+        write_sysreg!("hcr_el2", read_sysreg!("hcr_el2") & !HCR_NESTED);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// An exception taken to EL2 from a guest hypervisor running at EL1 with nested virtualization.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NestedVirtTrap {
+    /// An access to a system register which wasn't redirected to the VNCR page, such as most EL2
+    /// registers with only FEAT_NV, or those which need emulating.
+    Sysreg(SysregAccess),
+    /// An `ERET`, with which the guest hypervisor is trying to enter its own guest.
+    Eret {
+        /// Whether it was `ERETAA` or `ERETAB` rather than a plain `ERET`.
+        authenticated: bool,
+        /// Whether it was `ERETAB`.
+        key_b: bool,
+    },
+    /// A fault on an access which was redirected to the VNCR page, e.g. because the page isn't
+    /// mapped at EL2.
+    VncrAbort,
+}
+
+#[cfg(feature = "exceptions")]
+impl NestedVirtTrap {
+    /// Decodes the given exception syndrome, returning `None` if it isn't one of the exceptions
+    /// caused by nested virtualization.
+    pub fn from_esr(esr: u64) -> Option<Self> {
+        match (esr >> 26) & 0x3f {
+            ESR_EC_SYS64 => SysregAccess::from_esr(esr).map(Self::Sysreg),
+            ESR_EC_ERET => Some(Self::Eret {
+                authenticated: esr & (1 << 1) != 0,
+                key_b: esr & (1 << 0) != 0,
+            }),
+            ESR_EC_DABT_LOW if esr & ESR_ISS_VNCR != 0 => Some(Self::VncrAbort),
+            _ => None,
+        }
+    }
+
+    /// Returns the nested virtualization exception currently being handled, if any.
+    ///
+    /// This reads the ESR of the current exception level, so should be called from
+    /// [`sync_lower`](crate::ExceptionHandlers::sync_lower) before anything else could cause
+    /// another exception.
+    pub fn current() -> Option<Self> {
+        Self::from_esr(read_esr())
+    }
+}
+
+// A default no-op hook, which is overridden by the strong symbol defined by `hcr_el2!` if it is
+// used.
+global_asm!(
+    ".section .init.__aarch64_rt_configure_hcr_el2, \"ax\"",
+    ".weak __aarch64_rt_configure_hcr_el2",
+    ".type __aarch64_rt_configure_hcr_el2, %function",
+    "__aarch64_rt_configure_hcr_el2:",
+    "ret",
+);
+
+/// Registers a value for the entry point to write to HCR_EL2 when running at EL2.
+///
+/// The value must be an [`Hcr`](crate::hypervisor::Hcr) constant expression.
+#[macro_export]
+macro_rules! hcr_el2 {
+    ($hcr:expr) => {
+        core::arch::global_asm!(
+            ".section .init.__aarch64_rt_configure_hcr_el2, \"ax\"",
+            ".global __aarch64_rt_configure_hcr_el2",
+            "__aarch64_rt_configure_hcr_el2:",
+            "movz x9, #({HCR_VALUE} >> 48), lsl #48",
+            "movk x9, #({HCR_VALUE} >> 32) & 0xffff, lsl #32",
+            "movk x9, #({HCR_VALUE} >> 16) & 0xffff, lsl #16",
+            "movk x9, #{HCR_VALUE} & 0xffff",
+            "msr hcr_el2, x9",
+            "isb",
+            "ret",
+            HCR_VALUE = const {
+                let hcr: $crate::hypervisor::Hcr = $hcr;
+                hcr.0
+            },
+        );
+    };
+}
+
+/// Writes the value registered with [`hcr_el2!`](crate::hcr_el2) to HCR_EL2, if any, and if we
+/// are running at EL2.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before the MMU is enabled.
+///
+/// Clobbers x9.
+#[cfg(not(any(feature = "el1", feature = "el3")))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn configure_el2() {
+    naked_asm!(
+        "mrs x9, CurrentEL",
+        "ubfx x9, x9, #2, #2",
+        "cmp x9, #2",
+        "b.eq __aarch64_rt_configure_hcr_el2",
+        "ret",
+    )
+}
+
+/// No-op when the `el1` or `el3` feature is enabled, as we aren't running at EL2.
+///
+/// # Safety
+///
+/// Not really unsafe in this case, but needs to be consistent with the signature when we may be
+/// running at EL2.
+#[cfg(any(feature = "el1", feature = "el3"))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn configure_el2() {
+    naked_asm!("ret")
+}
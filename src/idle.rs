@@ -0,0 +1,448 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Idling in PSCI CPU_SUSPEND low power states.
+//!
+//! A platform describes its idle states with a table of [`IdleState`]s, ordered from the shallowest
+//! to the deepest, either built in code or parsed from the `/cpus/idle-states` node of the device
+//! tree with [`IdleStates::from_fdt`]. [`idle`] then idles the current core without the periodic
+//! tick like [`tickless_idle`](crate::timer::tickless_idle), but enters the deepest state which is
+//! worthwhile for the time until the next event rather than just executing `wfi`:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::idle::{IdleStates, idle};
+//!
+//! // SAFETY: The bootloader passed a valid device tree blob.
+//! let states = unsafe { IdleStates::from_fdt(fdt) }.unwrap_or_default();
+//! loop {
+//!     let missed_ticks = idle(states.as_slice(), next_timer_event());
+//!     advance_timers(missed_ticks);
+//! }
+//! ```
+//!
+//! When the core resumes from a power-down state it goes through the same initialisation as
+//! [`secondary_entry`](crate::secondary_entry) before returning to the caller, restoring the MMU
+//! configuration it was using, the callee-saved registers, stack pointer and exception vector. Any other state which is lost when
+//! the core powers down, such as the GIC CPU interface and the timers, must be saved and restored
+//! by the caller. Power-down states are those with [`IdleState::power_down`] set.
+
+#[cfg(feature = "initial-pagetable")]
+use crate::pagetable::BootMmuConfig;
+use crate::{
+    cache::clean_invalidate_dcache_range,
+    entry::init_secondary_core,
+    fdt::{FDT_BEGIN_NODE, FDT_END_NODE, FDT_NOP, FDT_PROP, align4, c_str, fdt_slice, read_be32},
+    psci::AutoConduit,
+    timer::{Instant, tickless_idle_with},
+};
+use core::{
+    arch::{asm, naked_asm},
+    mem::{offset_of, size_of},
+    time::Duration,
+};
+use smccc::psci::{self, Error, PSCI_CPU_SUSPEND_64};
+
+/// The maximum number of idle states in an [`IdleStates`] table.
+pub const MAX_IDLE_STATES: usize = 8;
+
+/// PSCI_FEATURES flag for CPU_SUSPEND indicating that the extended StateID format is used.
+const PSCI_FEATURES_EXTENDED_STATE_ID: u32 = 1 << 1;
+/// StateType bit in the original power_state format, set for power-down states.
+const POWER_STATE_TYPE_ORIGINAL: u32 = 1 << 16;
+/// StateType bit in the extended power_state format, set for power-down states.
+const POWER_STATE_TYPE_EXTENDED: u32 = 1 << 30;
+
+/// A low power state which a core may enter with PSCI CPU_SUSPEND while idle.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IdleState {
+    /// The `power_state` parameter to pass to CPU_SUSPEND.
+    pub power_state: u32,
+    /// Whether the core loses its context in this state, so resumes through the entry point
+    /// rather than returning from CPU_SUSPEND.
+    pub power_down: bool,
+    /// The worst case time to enter the state.
+    pub entry_latency: Duration,
+    /// The worst case time to exit the state.
+    pub exit_latency: Duration,
+    /// The minimum time to stay in the state for it to save energy compared to shallower states.
+    pub min_residency: Duration,
+    /// Whether the local timer stops in this state, so can't wake the core.
+    pub local_timer_stop: bool,
+}
+
+impl IdleState {
+    /// Returns whether this state is worth entering if the core is expected to be idle for
+    /// `expected`, or indefinitely if it is `None`.
+    ///
+    /// If `timer_wakeup` is true then the core must be woken by the local timer, so states in which
+    /// it stops are never chosen.
+    pub fn fits(&self, expected: Option<Duration>, timer_wakeup: bool) -> bool {
+        if timer_wakeup && self.local_timer_stop {
+            return false;
+        }
+        expected.is_none_or(|expected| {
+            self.min_residency <= expected && self.entry_latency + self.exit_latency <= expected
+        })
+    }
+}
+
+/// A table of idle states, ordered from the shallowest to the deepest.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IdleStates {
+    states: [IdleState; MAX_IDLE_STATES],
+    len: usize,
+}
+
+impl IdleStates {
+    /// Creates a new empty table.
+    pub const fn new() -> Self {
+        Self {
+            states: [IdleState {
+                power_state: 0,
+                power_down: false,
+                entry_latency: Duration::ZERO,
+                exit_latency: Duration::ZERO,
+                min_residency: Duration::ZERO,
+                local_timer_stop: false,
+            }; MAX_IDLE_STATES],
+            len: 0,
+        }
+    }
+
+    /// Adds the given state to the end of the table, which should be deeper than those already in
+    /// it.
+    ///
+    /// Returns the state back if the table is full.
+    pub const fn push(&mut self, state: IdleState) -> Result<(), IdleState> {
+        if self.len == MAX_IDLE_STATES {
+            return Err(state);
+        }
+        self.states[self.len] = state;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns the states in the table.
+    pub fn as_slice(&self) -> &[IdleState] {
+        &self.states[..self.len]
+    }
+
+    /// Parses the idle states from the `/cpus/idle-states` node of the given flattened device tree.
+    ///
+    /// Each child node compatible with `arm,idle-state` and with an `arm,psci-suspend-param`
+    /// property is added in order, up to [`MAX_IDLE_STATES`]. Whether each state is a power-down
+    /// state is determined from its `power_state` parameter, which depends on the format reported
+    /// by PSCI_FEATURES, so the PSCI conduit must be configured first.
+    ///
+    /// Returns `None` if the device tree is invalid.
+    ///
+    /// # Safety
+    ///
+    /// `fdt` must point to a flattened device tree blob, which must be valid for reads of the size
+    /// given in its header.
+    pub unsafe fn from_fdt(fdt: *const u8) -> Option<Self> {
+        // SAFETY: Our caller promised that `fdt` points to a valid device tree blob.
+        let fdt = unsafe { fdt_slice(fdt)? };
+        let extended = extended_state_id();
+        let mut states = Self::new();
+        let structs = read_be32(fdt, 8)? as usize;
+        let strings = read_be32(fdt, 12)? as usize;
+        let mut offset = structs;
+        let mut depth = 0;
+        let mut in_cpus = false;
+        let mut in_idle_states = false;
+        let mut state = None;
+        loop {
+            let token = read_be32(fdt, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(fdt.get(offset..)?)?;
+                    offset = align4(offset + name.len() + 1);
+                    depth += 1;
+                    match depth {
+                        2 => in_cpus = name == b"cpus",
+                        3 => in_idle_states = in_cpus && name == b"idle-states",
+                        4 if in_idle_states => state = Some(FdtIdleState::default()),
+                        _ => {}
+                    }
+                }
+                FDT_END_NODE => {
+                    match depth {
+                        0 => return None,
+                        1 => return Some(states),
+                        4 => {
+                            if let Some(state) =
+                                state.take().and_then(|s| s.to_idle_state(extended))
+                            {
+                                // Any states beyond the maximum are ignored.
+                                let _ = states.push(state);
+                            }
+                        }
+                        _ => {}
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = read_be32(fdt, offset)? as usize;
+                    let name_offset = read_be32(fdt, offset + 4)? as usize;
+                    let value = fdt.get(offset + 8..offset + 8 + len)?;
+                    offset = align4(offset + 8 + len);
+                    if depth == 4
+                        && let Some(state) = &mut state
+                    {
+                        state.set_property(c_str(fdt.get(strings + name_offset..)?)?, value);
+                    }
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Returns the deepest state in the table which is worth entering if the core is expected to be
+    /// idle for `expected`, or indefinitely if it is `None`.
+    ///
+    /// See [`IdleState::fits`].
+    pub fn select(&self, expected: Option<Duration>, timer_wakeup: bool) -> Option<&IdleState> {
+        select(self.as_slice(), expected, timer_wakeup)
+    }
+}
+
+/// The properties of an idle state node in the device tree.
+#[derive(Default)]
+struct FdtIdleState {
+    compatible: bool,
+    power_state: Option<u32>,
+    entry_latency_us: u32,
+    exit_latency_us: u32,
+    min_residency_us: u32,
+    local_timer_stop: bool,
+}
+
+impl FdtIdleState {
+    fn set_property(&mut self, name: &[u8], value: &[u8]) {
+        match name {
+            b"compatible" => {
+                self.compatible = value.split(|&b| b == 0).any(|c| c == b"arm,idle-state");
+            }
+            b"arm,psci-suspend-param" => self.power_state = read_be32(value, 0),
+            b"entry-latency-us" => self.entry_latency_us = read_be32(value, 0).unwrap_or_default(),
+            b"exit-latency-us" => self.exit_latency_us = read_be32(value, 0).unwrap_or_default(),
+            b"min-residency-us" => self.min_residency_us = read_be32(value, 0).unwrap_or_default(),
+            b"local-timer-stop" => self.local_timer_stop = true,
+            _ => {}
+        }
+    }
+
+    fn to_idle_state(&self, extended: bool) -> Option<IdleState> {
+        if !self.compatible {
+            return None;
+        }
+        let power_state = self.power_state?;
+        Some(IdleState {
+            power_state,
+            power_down: is_power_down(power_state, extended),
+            entry_latency: Duration::from_micros(self.entry_latency_us.into()),
+            exit_latency: Duration::from_micros(self.exit_latency_us.into()),
+            min_residency: Duration::from_micros(self.min_residency_us.into()),
+            local_timer_stop: self.local_timer_stop,
+        })
+    }
+}
+
+/// Returns whether PSCI CPU_SUSPEND uses the extended `power_state` format.
+fn extended_state_id() -> bool {
+    psci::psci_features::<AutoConduit>(PSCI_CPU_SUSPEND_64)
+        .is_ok_and(|features| features & PSCI_FEATURES_EXTENDED_STATE_ID != 0)
+}
+
+/// Returns whether the given CPU_SUSPEND `power_state` parameter is for a power-down state, in the
+/// extended format if `extended` is true or the original format otherwise.
+fn is_power_down(power_state: u32, extended: bool) -> bool {
+    let state_type = if extended {
+        POWER_STATE_TYPE_EXTENDED
+    } else {
+        POWER_STATE_TYPE_ORIGINAL
+    };
+    power_state & state_type != 0
+}
+
+/// Returns the deepest of the given states, which are ordered from the shallowest, which is worth
+/// entering if the core is expected to be idle for `expected`, or indefinitely if it is `None`.
+fn select(
+    states: &[IdleState],
+    expected: Option<Duration>,
+    timer_wakeup: bool,
+) -> Option<&IdleState> {
+    states
+        .iter()
+        .rev()
+        .find(|state| state.fits(expected, timer_wakeup))
+}
+
+/// Idles the current core without the periodic tick until the next interrupt, setting the virtual
+/// timer to wake it at `next_event` if given, and returns the number of ticks which were missed.
+///
+/// This is like [`tickless_idle`](crate::timer::tickless_idle), but enters the deepest of the given
+/// states which is worthwhile for the time until `next_event`, falling back to `wfi` if none are
+/// or CPU_SUSPEND fails. States in which the local timer stops are only used if there is no
+/// `next_event`.
+///
+/// If the core powers down then state other than that restored by [`cpu_suspend`] is lost, and
+/// must be saved and restored by the caller.
+pub fn idle(states: &[IdleState], next_event: Option<Instant>) -> u64 {
+    tickless_idle_with(next_event, || {
+        let expected = next_event.map(|next_event| next_event.duration_since(Instant::now()));
+        if let Some(state) = select(states, expected, next_event.is_some())
+            && cpu_suspend(state).is_ok()
+        {
+            return;
+        }
+        // SAFETY: Waiting for an interrupt doesn't affect memory safety.
+        unsafe {
+            asm!("wfi", options(nomem, nostack, preserves_flags));
+        }
+    })
+}
+
+/// Enters the given idle state with PSCI CPU_SUSPEND, returning once the core is woken.
+///
+/// If the core powers down, it resumes by going through the same initialisation as
+/// [`secondary_entry`](crate::secondary_entry), then restores the stack pointer and callee-saved
+/// registers and returns from this function. Other state, such as the GIC CPU interface, timers
+/// and floating point registers, is lost and must be saved and restored by the caller.
+///
+/// The core is woken by any pending interrupt, even if it is masked, so this should normally be
+/// called with IRQs masked to avoid missing a wakeup which happens just before it.
+pub fn cpu_suspend(state: &IdleState) -> Result<(), Error> {
+    // SAFETY: `suspend_with_context` saves the callee-saved registers and restores them before
+    // returning, whether or not the core powered down, so behaves like a normal function call.
+    match unsafe { suspend_with_context(state.power_state) } {
+        0 => Ok(()),
+        error => Err(error.into()),
+    }
+}
+
+/// The state saved by [`suspend_with_context`] on the stack, for [`suspend_resume_entry`] to
+/// restore.
+#[repr(C)]
+struct SuspendContext {
+    /// x19 to x28.
+    callee_saved: [u64; 10],
+    frame_pointer: u64,
+    link_register: u64,
+    /// The MMU configuration which the core was using, including TTBR1_EL1 at EL1, so that it
+    /// resumes with the same one even if it was started with its own `CoreMmuConfig`.
+    #[cfg(feature = "initial-pagetable")]
+    mmu_config: BootMmuConfig,
+}
+
+/// The offset of the MMU configuration in [`SuspendContext`], or 0 if there is none.
+#[cfg(feature = "initial-pagetable")]
+const MMU_CONFIG_OFFSET: usize = offset_of!(SuspendContext, mmu_config);
+/// The offset of the MMU configuration in [`SuspendContext`], or 0 if there is none.
+#[cfg(not(feature = "initial-pagetable"))]
+const MMU_CONFIG_OFFSET: usize = 0;
+/// The offset of TTBR1_EL1 in the MMU configuration.
+#[cfg(feature = "initial-pagetable")]
+const TTBR1_OFFSET: usize = offset_of!(BootMmuConfig, ttbr1);
+/// The offset of TTBR1_EL1 in the MMU configuration.
+#[cfg(not(feature = "initial-pagetable"))]
+const TTBR1_OFFSET: usize = 0;
+
+/// Saves the callee-saved registers on the stack and calls [`suspend`] with a pointer to them.
+///
+/// Returns 0 on success or a PSCI error code, whether the core returned from CPU_SUSPEND directly
+/// or resumed through [`suspend_resume_entry`].
+///
+/// # Safety
+///
+/// The current core must have been started by this crate, so that it can resume through the same
+/// initialisation as [`secondary_entry`](crate::secondary_entry).
+#[unsafe(naked)]
+unsafe extern "C" fn suspend_with_context(power_state: u32) -> i64 {
+    naked_asm!(
+        "sub sp, sp, #{context_size}",
+        "stp x19, x20, [sp, #0]",
+        "stp x21, x22, [sp, #16]",
+        "stp x23, x24, [sp, #32]",
+        "stp x25, x26, [sp, #48]",
+        "stp x27, x28, [sp, #64]",
+        "stp x29, x30, [sp, #{frame_pointer_offset}]",
+        // Pass the context, which is also the stack pointer to restore.
+        "mov x1, sp",
+        "bl {suspend}",
+        // CPU_SUSPEND returned without powering down, so only the link register needs restoring.
+        "ldp x29, x30, [sp, #{frame_pointer_offset}]",
+        "add sp, sp, #{context_size}",
+        "ret",
+        context_size = const size_of::<SuspendContext>().next_multiple_of(16),
+        frame_pointer_offset = const offset_of!(SuspendContext, frame_pointer),
+        suspend = sym suspend,
+    )
+}
+
+/// Cleans the saved context to the point of coherency and calls CPU_SUSPEND, passing the context
+/// pointer for [`suspend_resume_entry`].
+extern "C" fn suspend(power_state: u32, context: *mut SuspendContext) -> i64 {
+    // SAFETY: `suspend_with_context` passes a pointer to the context it reserved on the stack.
+    #[cfg(feature = "initial-pagetable")]
+    unsafe {
+        (&raw mut (*context).mmu_config).write(BootMmuConfig::current());
+    }
+    // Make sure the context can be read on resume even if the caches were lost.
+    clean_invalidate_dcache_range(context.cast(), size_of::<SuspendContext>());
+    match psci::cpu_suspend::<AutoConduit>(
+        power_state,
+        suspend_resume_entry as *const () as usize as u64,
+        context as u64,
+    ) {
+        Ok(()) => 0,
+        Err(error) => error.into(),
+    }
+}
+
+/// The entry point at which the core resumes after powering down in CPU_SUSPEND, with the context
+/// saved by [`suspend_with_context`] in `x0`.
+///
+/// This goes through the same initialisation as [`secondary_entry`](crate::secondary_entry), with
+/// the MMU configuration saved in the context, then restores the stack and registers from the
+/// context and returns 0 from `suspend_with_context`.
+///
+/// # Safety
+///
+/// Must only be used as the entry point for CPU_SUSPEND, with the context ID set to the context
+/// saved by `suspend_with_context`.
+#[unsafe(naked)]
+unsafe extern "C" fn suspend_resume_entry(context: *const SuspendContext) -> ! {
+    naked_asm!(
+        // Apply the MMU configuration saved in the context, if any.
+        "mov x4, #0",
+        "mov x5, #0",
+        ".if {mmu_config_offset}",
+        "add x4, x0, #{mmu_config_offset}",
+        "add x5, x4, #{ttbr1_offset}",
+        ".endif",
+        "bl {init_secondary_core}",
+        // The context is at the stack pointer which was saved.
+        "mov sp, x0",
+        "bl {set_exception_vector}",
+        "ldp x19, x20, [sp, #0]",
+        "ldp x21, x22, [sp, #16]",
+        "ldp x23, x24, [sp, #32]",
+        "ldp x25, x26, [sp, #48]",
+        "ldp x27, x28, [sp, #64]",
+        "ldp x29, x30, [sp, #{frame_pointer_offset}]",
+        "add sp, sp, #{context_size}",
+        "mov x0, #0",
+        "ret",
+        context_size = const size_of::<SuspendContext>().next_multiple_of(16),
+        frame_pointer_offset = const offset_of!(SuspendContext, frame_pointer),
+        mmu_config_offset = const MMU_CONFIG_OFFSET,
+        ttbr1_offset = const TTBR1_OFFSET,
+        init_secondary_core = sym init_secondary_core,
+        set_exception_vector = sym crate::set_exception_vector,
+    )
+}
@@ -0,0 +1,102 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Optional initialisation steps, which only run on cores implementing the architectural feature
+//! they need.
+//!
+//! Each step declares the ID register field which indicates that its feature is implemented, and is
+//! skipped at boot if the field is zero rather than faulting when it accesses registers which don't
+//! exist. This lets the same image run on different revisions of the hardware, including
+//! heterogeneous systems where only some cores implement a feature.
+//!
+//! Enabling the AMU counters with the `amu` feature is currently the only such step. The SVE, SME
+//! and trace trap configuration in [`traps`](crate::traps) is skipped in the same way for units
+//! which aren't implemented, using the same [`IdField`]s, but runs from assembly before any Rust
+//! code so isn't one of these steps. The crate doesn't configure MTE, pointer authentication or
+//! the PMU at boot, so they have no steps.
+
+use crate::sysreg::read_sysreg;
+
+/// An AArch64 ID register describing implemented features.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum IdRegister {
+    /// ID_AA64PFR0_EL1.
+    Pfr0,
+    /// ID_AA64PFR1_EL1.
+    Pfr1,
+    /// ID_AA64DFR0_EL1.
+    Dfr0,
+}
+
+impl IdRegister {
+    fn read(self) -> u64 {
+        // SAFETY: Reading ID registers is always safe.
+        unsafe {
+            match self {
+                Self::Pfr0 => read_sysreg!("id_aa64pfr0_el1"),
+                Self::Pfr1 => read_sysreg!("id_aa64pfr1_el1"),
+                Self::Dfr0 => read_sysreg!("id_aa64dfr0_el1"),
+            }
+        }
+    }
+}
+
+/// A 4-bit field of an ID register, which is non-zero if some feature is implemented.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct IdField {
+    register: IdRegister,
+    shift: u32,
+}
+
+impl IdField {
+    /// ID_AA64PFR0_EL1.SVE: the Scalable Vector Extension.
+    pub(crate) const SVE: Self = Self::new(IdRegister::Pfr0, 32);
+    /// ID_AA64PFR0_EL1.AMU: the Activity Monitors Unit.
+    pub(crate) const AMU: Self = Self::new(IdRegister::Pfr0, 44);
+    /// ID_AA64PFR1_EL1.SME: the Scalable Matrix Extension.
+    pub(crate) const SME: Self = Self::new(IdRegister::Pfr1, 24);
+    /// ID_AA64DFR0_EL1.TraceVer: the trace unit system registers.
+    pub(crate) const TRACE: Self = Self::new(IdRegister::Dfr0, 4);
+
+    const fn new(register: IdRegister, shift: u32) -> Self {
+        Self { register, shift }
+    }
+
+    /// Returns the bit position of the field within its register, for use in assembly.
+    pub(crate) const fn shift(self) -> u32 {
+        self.shift
+    }
+
+    /// Returns whether the feature is implemented on the current core.
+    pub(crate) fn is_implemented(self) -> bool {
+        (self.register.read() >> self.shift) & 0xf != 0
+    }
+}
+
+/// An optional initialisation step.
+struct InitStep {
+    /// The feature which must be implemented for the step to run.
+    requires: IdField,
+    /// The function to run.
+    run: fn(),
+}
+
+/// The optional steps which the entry points run on each core, in order, before calling into the
+/// application.
+static INIT_STEPS: &[InitStep] = &[
+    #[cfg(feature = "amu")]
+    InitStep {
+        requires: IdField::AMU,
+        run: crate::amu::enable,
+    },
+];
+
+/// Runs the optional initialisation steps for the features implemented on the current core.
+pub(crate) fn run_init_steps() {
+    for step in INIT_STEPS {
+        if step.requires.is_implemented() {
+            (step.run)();
+        }
+    }
+}
@@ -15,26 +15,125 @@
 ))]
 compile_error!("Only one `el` feature may be enabled at once.");
 
+#[cfg(all(feature = "initial-mpu", feature = "initial-pagetable"))]
+compile_error!("The `initial-mpu` and `initial-pagetable` features may not be enabled together.");
+
+#[cfg(all(feature = "copy-to-link-address", feature = "rom"))]
+compile_error!("The `copy-to-link-address` and `rom` features may not be enabled together.");
+
+#[cfg(all(feature = "debug-lockdown", not(feature = "el3")))]
+compile_error!("The `debug-lockdown` feature requires the `el3` feature.");
+
+#[cfg(all(feature = "initial-mpu", feature = "el3"))]
+compile_error!("Armv8-R AArch64 processors don't implement EL3.");
+
+pub mod address;
+pub mod amu;
+pub mod asid;
+pub mod boot_error;
+pub mod bootlog;
+pub mod cache;
+#[cfg(feature = "exceptions")]
+pub mod checkpoint;
+pub mod cpu;
+pub mod debug;
+pub mod dma;
+#[cfg(feature = "exceptions")]
+pub mod dynamic_handlers;
+pub mod early_alloc;
+pub mod elf;
 mod entry;
 #[cfg(feature = "exceptions")]
 mod exceptions;
+pub mod fdt;
+#[cfg(feature = "gdb")]
+pub mod gdb;
+pub mod gic;
+#[cfg(feature = "exceptions")]
+pub mod gpf;
+pub mod hypervisor;
+#[cfg(feature = "psci")]
+pub mod idle;
+mod init_steps;
+#[cfg(feature = "psci")]
+pub mod linux;
+pub mod mair;
+pub mod mapping;
+#[cfg(feature = "mem")]
+mod mem;
+#[cfg(feature = "el3")]
+pub mod monitor;
+pub mod mpam;
+#[cfg(feature = "initial-mpu")]
+mod mpu;
 #[cfg(feature = "initial-pagetable")]
 mod pagetable;
+#[cfg(feature = "paging")]
+pub mod paging;
+pub mod park;
+pub mod platform;
+pub mod power;
+#[cfg(feature = "psci")]
+pub mod psci;
+#[cfg(all(feature = "psci", feature = "exceptions"))]
+pub mod psci_proxy;
+#[cfg(all(
+    feature = "el3",
+    feature = "psci",
+    feature = "exceptions",
+    not(feature = "rom")
+))]
+pub mod psci_service;
+#[cfg(feature = "exceptions")]
+pub mod ras;
+mod registry;
+pub mod relocate;
+#[cfg(feature = "el3")]
+pub mod reset;
+pub mod smp;
+pub mod spe;
+pub mod spin_table;
+pub mod spsel;
+mod sysreg;
+pub mod timer;
+pub mod traps;
+pub mod watchdog;
 
 #[cfg(feature = "initial-pagetable")]
 #[doc(hidden)]
 pub mod __private {
-    pub use crate::pagetable::{__enable_mmu_el1, __enable_mmu_el2, __enable_mmu_el3};
+    pub use crate::pagetable::{
+        __enable_mmu_el1, __enable_mmu_el2, __enable_mmu_el3, __prepare_pagetable,
+        __prepare_writable_pagetable,
+    };
 }
 
-#[cfg(any(feature = "exceptions", feature = "psci"))]
+#[cfg(feature = "initial-mpu")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::mpu::{__enable_mpu_el1, __enable_mpu_el2};
+}
+
+#[cfg(feature = "macros")]
+pub use aarch64_rt_macros::irq;
+#[cfg(feature = "exceptions")]
 use core::arch::asm;
-#[cfg(not(feature = "initial-pagetable"))]
+#[cfg(not(any(feature = "initial-mpu", feature = "initial-pagetable")))]
 use core::arch::naked_asm;
-use core::mem::ManuallyDrop;
+use core::{arch::global_asm, mem::ManuallyDrop};
 pub use entry::secondary_entry;
 #[cfg(feature = "exceptions")]
-pub use exceptions::{ExceptionHandlers, RegisterState, RegisterStateRef};
+pub use exceptions::{
+    ExceptionHandlers, RegisterState, RegisterStateRef, VectorTable, reset_exception_depth,
+};
+#[cfg(feature = "initial-mpu")]
+pub use mpu::{DEFAULT_MAIR, DEFAULT_SCTLR, MpuRegion};
+#[cfg(all(feature = "initial-pagetable", feature = "el1"))]
+pub use pagetable::DEFAULT_TCR_48BIT_EL1 as DEFAULT_TCR_48BIT;
+#[cfg(all(feature = "initial-pagetable", feature = "el2"))]
+pub use pagetable::DEFAULT_TCR_48BIT_EL2 as DEFAULT_TCR_48BIT;
+#[cfg(all(feature = "initial-pagetable", feature = "el3"))]
+pub use pagetable::DEFAULT_TCR_48BIT_EL3 as DEFAULT_TCR_48BIT;
 #[cfg(all(feature = "initial-pagetable", feature = "el1"))]
 pub use pagetable::DEFAULT_TCR_EL1 as DEFAULT_TCR;
 #[cfg(all(feature = "initial-pagetable", feature = "el2"))]
@@ -43,17 +142,21 @@ pub use pagetable::DEFAULT_TCR_EL2 as DEFAULT_TCR;
 pub use pagetable::DEFAULT_TCR_EL3 as DEFAULT_TCR;
 #[cfg(feature = "initial-pagetable")]
 pub use pagetable::{
-    DEFAULT_MAIR, DEFAULT_SCTLR, DEFAULT_TCR_EL1, DEFAULT_TCR_EL2, DEFAULT_TCR_EL3,
-    InitialPagetable,
+    AlignmentCheck, BlockDescriptor, CoreMmuConfig, DEFAULT_DUAL_TCR_EL1, DEFAULT_MAIR,
+    DEFAULT_SCTLR, DEFAULT_SCTLR_WXN, DEFAULT_TCR_48BIT_EL1, DEFAULT_TCR_48BIT_EL2,
+    DEFAULT_TCR_48BIT_EL3, DEFAULT_TCR_EL1, DEFAULT_TCR_EL2, DEFAULT_TCR_EL3, InitialPagetable,
+    InitialPagetables, Level0Pagetable, PreparePagetable, SecurityState, Shareability,
+    WalkAttributes, WalkCacheability, boot_mmu_config,
 };
+pub use registry::Registry;
 
-/// No-op when the `initial-pagetable` feature isn't enabled.
+/// No-op when neither the `initial-pagetable` nor the `initial-mpu` feature is enabled.
 ///
 /// # Safety
 ///
 /// Not really unsafe in this case, but needs to be consistent with the signature when the
-/// `initial-pagetable` feature is enabled.
-#[cfg(not(feature = "initial-pagetable"))]
+/// `initial-pagetable` or `initial-mpu` feature is enabled.
+#[cfg(not(any(feature = "initial-mpu", feature = "initial-pagetable")))]
 #[unsafe(naked)]
 #[unsafe(link_section = ".init")]
 #[unsafe(export_name = "enable_mmu")]
@@ -61,9 +164,10 @@ pub unsafe extern "C" fn enable_mmu() {
     naked_asm!("ret")
 }
 
-#[cfg(feature = "initial-pagetable")]
+#[cfg(any(feature = "initial-mpu", feature = "initial-pagetable"))]
 unsafe extern "C" {
-    /// Enables the MMU and caches with the initial pagetable.
+    /// Enables the MMU and caches with the initial pagetable, or the MPU and caches with the
+    /// initial MPU regions.
     ///
     /// This is called automatically from entry point code both for primary and secondary CPUs so
     /// you usually won't need to call this yourself, but is available in case you need to implement
@@ -71,7 +175,7 @@ unsafe extern "C" {
     ///
     /// # Safety
     ///
-    /// The initial pagetable must correctly map everything that the program uses.
+    /// The initial pagetable or MPU regions must correctly map everything that the program uses.
     pub unsafe fn enable_mmu();
 }
 
@@ -159,16 +263,105 @@ pub extern "C" fn set_exception_vector() {
     }
 }
 
+/// Sets the vbar for the current exception level to point to the given vector table, so that
+/// exceptions taken from then on are handled by it.
+///
+/// This can be used to switch from minimal handlers used during early initialisation to the full
+/// handlers, or back to the table registered with [`exception_handlers!`] with
+/// [`set_exception_vector`]. It only affects the current core.
+///
+/// # Panics
+///
+/// Panics if the table has no vectors for the current exception level.
+#[cfg(feature = "exceptions")]
+pub fn set_vector_table(table: VectorTable) {
+    let el = sysreg::current_el();
+    let address = table.address(el) as u64;
+    assert_ne!(address, 0, "No vector table for EL{el}");
+    // SAFETY: `VectorTable` can only be constructed with valid vector tables.
+    unsafe {
+        match el {
+            1 => sysreg::write_sysreg!("vbar_el1", address),
+            2 => sysreg::write_sysreg!("vbar_el2", address),
+            _ => sysreg::write_sysreg!("vbar_el3", address),
+        }
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Sets the vbar for the given lower exception level to point to the vector table registered with
+/// [`lower_exception_handlers!`].
+///
+/// This should be called before dropping to a lower exception level, so that exceptions taken
+/// there are handled.
+///
+/// # Panics
+///
+/// Panics if `el` is not 1 or 2, or is not lower than the current exception level.
+#[cfg(feature = "exceptions")]
+pub fn set_lower_exception_vector(el: u8) {
+    assert!(
+        (1..=2).contains(&el) && el < sysreg::current_el(),
+        "Can't set vector table for EL{el}"
+    );
+    // The tables are only generated for exception levels below the one the image runs at, so
+    // don't refer to those which can't exist.
+    match el {
+        // SAFETY: The vector table is provided by `lower_exception_handlers!`.
+        #[cfg(not(feature = "el1"))]
+        1 => unsafe {
+            asm!(
+                "adr x9, lower_vector_table_el1",
+                "msr vbar_el1, x9",
+                options(nomem, nostack, preserves_flags),
+                out("x9") _,
+            );
+        },
+        // SAFETY: The vector table is provided by `lower_exception_handlers!`.
+        #[cfg(not(any(feature = "el1", feature = "el2")))]
+        2 => unsafe {
+            asm!(
+                "adr x9, lower_vector_table_el2",
+                "msr vbar_el2, x9",
+                options(nomem, nostack, preserves_flags),
+                out("x9") _,
+            );
+        },
+        _ => unreachable!(),
+    }
+}
+
 extern "C" fn rust_entry(arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> ! {
+    address::record_virt_offset();
     set_exception_vector();
+    init_steps::run_init_steps();
+    #[cfg(feature = "initial-pagetable")]
+    pagetable::record_boot_mmu_config();
     __main(arg0, arg1, arg2, arg3)
 }
 
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
+
 unsafe extern "Rust" {
     /// Main function provided by the application using the `main!` macro.
     safe fn __main(arg0: u64, arg1: u64, arg2: u64, arg3: u64) -> !;
 }
 
+unsafe extern "C" {
+    /// Late initialisation hook for secondary cores, registered with the `secondary_init!` macro.
+    safe fn __aarch64_rt_secondary_init();
+}
+
+// The default no-op hook, which is overridden by the strong symbol defined by `secondary_init!` if
+// it is used.
+global_asm!(
+    ".section .text.__aarch64_rt_secondary_init, \"ax\"",
+    ".weak __aarch64_rt_secondary_init",
+    ".type __aarch64_rt_secondary_init, %function",
+    "__aarch64_rt_secondary_init:",
+    "ret",
+);
+
 /// Marks the main function of the binary and reserves space for the boot stack.
 ///
 /// Example:
@@ -182,18 +375,18 @@ unsafe extern "Rust" {
 /// }
 /// ```
 ///
-/// 40 pages (160 KiB) is reserved for the boot stack by default; a different size may be configured
-/// by passing the number of pages as a second argument to the macro, e.g. `entry!(main, 10);` to
-/// reserve only 10 pages.
+/// [`DEFAULT_BOOT_STACK_PAGES`] pages (160 KiB unless configured otherwise) are reserved for the
+/// boot stack by default; a different size may be configured by passing the number of pages as a
+/// second argument to the macro, e.g. `entry!(main, 10);` to reserve only 10 pages.
 #[macro_export]
 macro_rules! entry {
     ($name:path) => {
-        entry!($name, 40);
+        $crate::entry!($name, $crate::DEFAULT_BOOT_STACK_PAGES);
     };
     ($name:path, $boot_stack_pages:expr) => {
         #[unsafe(export_name = "boot_stack")]
         #[unsafe(link_section = ".stack.boot_stack")]
-        static mut __BOOT_STACK: $crate::Stack<$boot_stack_pages> = $crate::Stack::new();
+        static mut __BOOT_STACK: $crate::Stack<{ $boot_stack_pages }> = $crate::Stack::new();
 
         // Export a symbol with a name matching the extern declaration above.
         #[unsafe(export_name = "__main")]
@@ -206,7 +399,8 @@ macro_rules! entry {
 
 /// A stack for some CPU core.
 ///
-/// This is used by the [`entry!`] macro to reserve space for the boot stack.
+/// This is used by the [`entry!`] macro to reserve space for the boot stack, and by
+/// [`per_core_stacks!`] for secondary cores.
 #[repr(C, align(4096))]
 pub struct Stack<const NUM_PAGES: usize>([StackPage; NUM_PAGES]);
 
@@ -223,6 +417,81 @@ impl<const NUM_PAGES: usize> Default for Stack<NUM_PAGES> {
     }
 }
 
+/// Registers a function for every secondary core to run before the entry closure passed to
+/// [`start_core`], `start_core_with_mmu_config` or [`spin_table::start_core`].
+///
+/// By the time it is called the core has the same setup as the boot core has when `main` is called:
+/// the exception vector is set, floating point access is allowed or trapped according to
+/// [`trap_config!`], the GIC CPU interface is configured if [`gic_init!`] was used, and the MMU or
+/// MPU is enabled if configured. This is the place to do any further initialisation which every
+/// core needs, such as pointing TPIDR_ELx at the core's per-CPU data or enabling its interrupts, so
+/// that the entry closures can rely on it. It isn't called on the boot core, so `main` should call
+/// the same function if it needs it.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::secondary_init;
+///
+/// secondary_init!(init_core);
+/// fn init_core() {
+///     set_per_cpu_data();
+///     gic::irq_enable();
+/// }
+/// ```
+#[macro_export]
+macro_rules! secondary_init {
+    ($name:path) => {
+        // Export a symbol with a name matching the extern declaration above.
+        #[unsafe(export_name = "__aarch64_rt_secondary_init")]
+        extern "C" fn __aarch64_rt_secondary_init() {
+            // Ensure that the function provided by the application has the correct type.
+            $name()
+        }
+    };
+}
+
+/// Reserves stacks for the given number of secondary cores, each of the given number of pages, in
+/// the `.stack` section.
+///
+/// If the number of pages isn't given, [`DEFAULT_SECONDARY_STACK_PAGES`] pages (32 KiB unless
+/// configured otherwise) are reserved for each core.
+///
+/// This evaluates to an array of pointers to the stacks, of type `[*mut Stack<PAGES>; NUM_CORES]`,
+/// which can be passed to [`start_core`] or [`spin_table::start_core`]. Each invocation of the
+/// macro reserves its own stacks, but evaluating the same invocation more than once returns the
+/// same pointers, so each stack must still only be used for one core at a time.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::{per_core_stacks, psci::AutoConduit, start_core};
+///
+/// const SECONDARY_CORES: usize = 3;
+///
+/// let stacks = per_core_stacks!(SECONDARY_CORES, 8);
+/// for (i, stack) in stacks.into_iter().enumerate() {
+///     // SAFETY: Each stack is only used by the core it is passed to.
+///     unsafe {
+///         start_core::<AutoConduit, _, _>(i as u64 + 1, stack, secondary_main).unwrap();
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! per_core_stacks {
+    ($num_cores:expr) => {
+        $crate::per_core_stacks!($num_cores, $crate::DEFAULT_SECONDARY_STACK_PAGES)
+    };
+    ($num_cores:expr, $pages:expr) => {{
+        #[unsafe(link_section = ".stack.per_core_stacks")]
+        static mut __PER_CORE_STACKS: [$crate::Stack<{ $pages }>; $num_cores] =
+            [const { $crate::Stack::new() }; $num_cores];
+
+        let stacks = (&raw mut __PER_CORE_STACKS).cast::<$crate::Stack<{ $pages }>>();
+        core::array::from_fn::<_, { $num_cores }, _>(|i| stacks.wrapping_add(i))
+    }};
+}
+
 #[repr(C, align(4096))]
 struct StackPage([u8; 4096]);
 
@@ -236,11 +505,17 @@ impl StackPage {
 pub(crate) struct StartCoreStack<F> {
     entry_ptr: *mut ManuallyDrop<F>,
     trampoline_ptr: unsafe extern "C" fn(&mut ManuallyDrop<F>) -> !,
+    /// The address of the `CoreMmuConfig` to use in place of the initial pagetable, or 0 to use the
+    /// initial pagetable.
+    mmu_config: usize,
 }
 
 #[cfg(feature = "psci")]
 /// Issues a PSCI CPU_ON call to start the CPU core with the given MPIDR.
 ///
+/// The PSCI call is made via the conduit `C`, which may be [`psci::AutoConduit`] to use the conduit
+/// configured at runtime.
+///
 /// This starts the core with an assembly entry point which will enable the MMU, disable trapping of
 /// floating point instructions, initialise the stack pointer to the given value, and then jump to
 /// the given Rust entry point function, passing it the given argument value.
@@ -263,6 +538,73 @@ pub unsafe fn start_core<C: smccc::Call, F: FnOnce() + Send + 'static, const N:
     stack: *mut Stack<N>,
     rust_entry: F,
 ) -> Result<(), smccc::psci::Error> {
+    // SAFETY: Our caller promised that the stack is valid and nothing else will access it.
+    let stack_end = unsafe { prepare_start_core_stack(stack, rust_entry, 0) };
+
+    smccc::psci::cpu_on::<C>(
+        mpidr,
+        secondary_entry as *const () as usize as _,
+        stack_end as usize as _,
+    )
+}
+
+/// Issues a PSCI CPU_ON call to start the CPU core with the given MPIDR, with its own MMU
+/// configuration rather than the initial pagetable.
+///
+/// This is the same as [`start_core`], except that the core enables its MMU with the given MAIR,
+/// SCTLR, TCR and TTBR0 values instead of those given to [`initial_pagetable!`]. Other cores are
+/// unaffected, so this may be used to run a core with a different mapping or ASID.
+///
+/// Note that if the core is suspended with the `idle` module, it resumes with the initial
+/// pagetable.
+///
+/// # Safety
+///
+/// As for [`start_core`], except that the stack must be mapped by the given configuration rather
+/// than the initial pagetable. The configuration must map the code and data which the core uses,
+/// including the image's entry point and the `rust_entry` closure, and its pagetables must remain
+/// valid as long as the core uses them.
+#[cfg(all(feature = "psci", feature = "initial-pagetable"))]
+pub unsafe fn start_core_with_mmu_config<
+    C: smccc::Call,
+    F: FnOnce() + Send + 'static,
+    const N: usize,
+>(
+    mpidr: u64,
+    stack: *mut Stack<N>,
+    mmu_config: &'static CoreMmuConfig,
+    rust_entry: F,
+) -> Result<(), smccc::psci::Error> {
+    // The new core reads the configuration with its MMU off.
+    cache::clean_dcache_range((&raw const *mmu_config).cast(), size_of::<CoreMmuConfig>());
+    // SAFETY: Our caller promised that the stack is valid and nothing else will access it.
+    let stack_end =
+        unsafe { prepare_start_core_stack(stack, rust_entry, &raw const *mmu_config as usize) };
+
+    smccc::psci::cpu_on::<C>(
+        mpidr,
+        secondary_entry as *const () as usize as _,
+        stack_end as usize as _,
+    )
+}
+
+/// Writes the given entry closure and the parameters needed by [`secondary_entry`] to the given
+/// stack, and returns the initial stack pointer value to pass to it.
+///
+/// `mmu_config` is the address of the `CoreMmuConfig` for the core, or 0 to use the initial
+/// pagetable.
+///
+/// # Safety
+///
+/// `stack` must point to a region of memory which is reserved for the new core's stack, and which
+/// nothing else will access while it is running.
+// TODO: change `F` generic bounds to `FnOnce() -> !` when the never type is stabilized:
+// https://github.com/rust-lang/rust/issues/35121
+pub(crate) unsafe fn prepare_start_core_stack<F: FnOnce() + Send + 'static, const N: usize>(
+    stack: *mut Stack<N>,
+    rust_entry: F,
+    mmu_config: usize,
+) -> *mut Stack<N> {
     const {
         assert!(
             size_of::<StartCoreStack<F>>()
@@ -294,21 +636,20 @@ pub unsafe fn start_core<C: smccc::Call, F: FnOnce() + Send + 'static, const N:
         *params = StartCoreStack {
             entry_ptr,
             trampoline_ptr: trampoline::<F>,
+            mmu_config,
         };
     };
 
-    // Wait for the stores above to complete before starting the secondary CPU core.
-    dsb_st();
+    // The secondary CPU core may start with its MMU and caches off, so clean the parameters to the
+    // point of coherency before starting it. This also waits for the stores above to complete.
+    cache::clean_dcache_range(entry_ptr.cast(), size_of::<F>());
+    cache::clean_dcache_range(params.cast(), size_of::<StartCoreStack<F>>());
 
-    smccc::psci::cpu_on::<C>(
-        mpidr,
-        secondary_entry as usize as _,
-        stack_end as usize as _,
-    )
+    stack_end
 }
 
-#[cfg(feature = "psci")]
-/// Used by [`start_core`] as an entry point for the secondary CPU core.
+/// Used by [`start_core`] and [`spin_table::start_core`] as an entry point for the secondary CPU
+/// core.
 ///
 /// # Safety
 ///
@@ -320,16 +661,9 @@ unsafe extern "C" fn trampoline<F: FnOnce() + Send + 'static>(entry: &mut Manual
     // SAFETY: the trampoline function is only ever called once after creating ManuallyDrop
     // instance, so we won't call ManuallyDrop::take more than once.
     let entry = unsafe { ManuallyDrop::take(entry) };
+    init_steps::run_init_steps();
+    __aarch64_rt_secondary_init();
     entry();
 
     panic!("rust_entry function passed to start_core should never return");
 }
-
-/// Data synchronisation barrier that waits for stores to complete, for the full system.
-#[cfg(feature = "psci")]
-fn dsb_st() {
-    // SAFETY: A synchronisation barrier is always safe.
-    unsafe {
-        asm!("dsb st", options(nostack));
-    }
-}
@@ -0,0 +1,243 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Booting a Linux kernel image in place of the current application.
+//!
+//! [`boot_linux`] takes an arm64 Linux `Image`, e.g. embedded with `include_bytes!`, and a device
+//! tree blob, and hands the system over to the kernel:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::linux::boot_linux;
+//!
+//! static KERNEL: &[u8] = include_bytes!("Image");
+//!
+//! // SAFETY: Nothing else is using the memory after the image, and the application doesn't need
+//! // anything to keep running.
+//! let error = unsafe { boot_linux(KERNEL, fdt, "console=ttyAMA0") }.unwrap_err();
+//! panic!("Failed to boot Linux: {error:?}");
+//! ```
+//!
+//! It satisfies the requirements of the kernel's arm64 booting protocol:
+//!
+//! - The kernel is copied to a 2 MiB aligned base plus the `text_offset` from its header, in memory
+//!   from [`early_alloc`] which also covers the `image_size` it
+//!   needs for its BSS.
+//! - The device tree is copied next to it with its `/chosen/bootargs` set to the command line, at
+//!   an 8 byte aligned address.
+//! - Other cores which have called `smp::enable_remote_calls` are
+//!   powered off with PSCI `CPU_OFF`, and `AFFINITY_INFO` is polled until each reports that it is
+//!   off, so the kernel can start them again with `CPU_ON`. Any other secondary cores must already
+//!   be off.
+//! - Interrupts are masked, the periodic tick is stopped, and at EL2 the EL1 physical timer and
+//!   counter are made accessible and the virtual counter offset is cleared.
+//! - The kernel and device tree are cleaned to the point of coherency, then the MMU and data cache
+//!   are disabled and the instruction cache invalidated.
+//! - The kernel is entered with the physical address of the device tree in x0, and x1 to x3 zero.
+//!
+//! This is only supported at EL1 and EL2, with physical memory identity mapped.
+
+use crate::{
+    address::virt_offset,
+    cache::clean_dcache_range,
+    early_alloc::early_alloc,
+    elf::jump_to_payload,
+    fdt::{FdtError, FdtPatch},
+    power::power_off_core,
+    psci::AutoConduit,
+    smp::{disable_remote_calls, for_each_other_online_core, stop_other_cores},
+    sysreg::{MAX_CORES, current_el, read_sysreg, write_sysreg},
+    timer::stop_periodic_tick,
+};
+use core::{arch::asm, convert::Infallible, hint::spin_loop, ptr::copy_nonoverlapping};
+use smccc::psci::{AffinityState, LowestAffinityLevel, affinity_info};
+
+/// The magic number at offset 56 of an arm64 Linux image header, "ARM\x64".
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241;
+/// The size of an arm64 Linux image header.
+const HEADER_SIZE: usize = 64;
+/// The text offset to assume if the image size in the header is 0, as for kernels before 3.17.
+const LEGACY_TEXT_OFFSET: u64 = 0x8_0000;
+/// The alignment of the base which the kernel's text offset is relative to.
+const KERNEL_BASE_ALIGN: usize = 2 << 20;
+/// The maximum size of the device tree blob.
+const MAX_DTB_SIZE: usize = 2 << 20;
+/// Extra space to allow in the patched device tree, on top of the command line itself, for a
+/// `/chosen` node and `bootargs` property.
+const DTB_SLACK: usize = 64;
+
+/// Big-endian kernel, in the image header flags.
+const FLAG_BIG_ENDIAN: u64 = 1 << 0;
+
+/// EL1 physical counter access enable, in CNTHCTL_EL2 when HCR_EL2.E2H is 0.
+const CNTHCTL_EL1PCTEN: u64 = 1 << 0;
+/// EL1 physical timer access enable, in CNTHCTL_EL2 when HCR_EL2.E2H is 0.
+const CNTHCTL_EL1PCEN: u64 = 1 << 1;
+
+/// The fields of an arm64 Linux image header which are needed to load it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LinuxImageHeader {
+    /// The offset from a 2 MiB aligned base at which the image must be loaded.
+    pub text_offset: u64,
+    /// The size of memory the kernel needs from the load address, including its BSS, or 0 if
+    /// unknown.
+    pub image_size: u64,
+    /// The kernel flags, giving its endianness, page size and placement requirements.
+    pub flags: u64,
+}
+
+impl LinuxImageHeader {
+    /// Parses the header at the start of the given arm64 Linux image.
+    ///
+    /// Returns `None` if the image is too short or doesn't have the arm64 magic number.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        let header = image.get(..HEADER_SIZE)?;
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        let magic = u32::from_le_bytes(header[56..60].try_into().unwrap());
+        if magic != ARM64_IMAGE_MAGIC {
+            return None;
+        }
+        let image_size = read_u64(16);
+        Some(Self {
+            // The text offset is only meaningful if the image size is set.
+            text_offset: if image_size == 0 {
+                LEGACY_TEXT_OFFSET
+            } else {
+                read_u64(8)
+            },
+            image_size,
+            flags: read_u64(24),
+        })
+    }
+}
+
+/// An error booting a Linux kernel with [`boot_linux`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinuxBootError {
+    /// The kernel image doesn't have a valid arm64 header, or is big-endian.
+    InvalidImage,
+    /// The current exception level isn't EL1 or EL2.
+    UnsupportedEl,
+    /// Physical memory isn't identity mapped, so the MMU can't be turned off.
+    NotIdentityMapped,
+    /// There isn't enough memory left for the kernel or device tree.
+    OutOfMemory,
+    /// The patched device tree would be larger than 2 MiB.
+    DtbTooLarge,
+    /// The device tree couldn't be patched.
+    Fdt(FdtError),
+}
+
+impl From<FdtError> for LinuxBootError {
+    fn from(error: FdtError) -> Self {
+        Self::Fdt(error)
+    }
+}
+
+/// Boots the given arm64 Linux kernel image with the given device tree blob and command line,
+/// replacing the current application on all cores.
+///
+/// See the [module documentation](self) for the steps taken. Only returns if something is wrong
+/// with the arguments or there isn't enough memory, in which case nothing has been changed other
+/// than memory being allocated.
+///
+/// # Safety
+///
+/// The memory returned by `early_alloc` must not be used by anything else, and must not overlap
+/// the device tree or anything else the kernel needs. The code of this image must be identity
+/// mapped, executable and remain so until the kernel starts. Any cores which haven't enabled
+/// remote calls must already be powered off or parked where the kernel won't find them. The
+/// application must not need anything to keep running after this, as no destructors are run.
+pub unsafe fn boot_linux(
+    image: &[u8],
+    fdt: &[u8],
+    cmdline: &str,
+) -> Result<Infallible, LinuxBootError> {
+    let header = LinuxImageHeader::parse(image).ok_or(LinuxBootError::InvalidImage)?;
+    if header.flags & FLAG_BIG_ENDIAN != 0 {
+        return Err(LinuxBootError::InvalidImage);
+    }
+    let el = current_el();
+    if el != 1 && el != 2 {
+        return Err(LinuxBootError::UnsupportedEl);
+    }
+    if virt_offset() != 0 {
+        return Err(LinuxBootError::NotIdentityMapped);
+    }
+
+    let dtb_size = fdt.len() + cmdline.len() + DTB_SLACK;
+    if dtb_size > MAX_DTB_SIZE {
+        return Err(LinuxBootError::DtbTooLarge);
+    }
+    let text_offset = header.text_offset as usize;
+    let image_size = (header.image_size as usize).max(image.len());
+    let kernel_base = early_alloc(text_offset + image_size, KERNEL_BASE_ALIGN)
+        .ok_or(LinuxBootError::OutOfMemory)?;
+    let dtb = early_alloc(dtb_size, 8).ok_or(LinuxBootError::OutOfMemory)?;
+
+    // SAFETY: The allocation is `dtb_size` bytes long, and nothing else uses it.
+    let dtb_out = unsafe { &mut *core::ptr::slice_from_raw_parts_mut(dtb.as_ptr(), dtb_size) };
+    let mut patch = FdtPatch::new();
+    patch.set_bootargs(cmdline);
+    let dtb_len = patch.apply(fdt, dtb_out)?;
+
+    // SAFETY: The allocation is large enough for the image at the text offset, and nothing else
+    // uses it.
+    let kernel = unsafe { kernel_base.as_ptr().add(text_offset) };
+    // SAFETY: `kernel` is valid for `image.len()` bytes, as above.
+    unsafe { copy_nonoverlapping(image.as_ptr(), kernel, image.len()) };
+
+    power_off_other_cores();
+    // SAFETY: Masking interrupts doesn't affect memory safety.
+    unsafe {
+        asm!(
+            "msr daifset, #0xf",
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    disable_remote_calls();
+    stop_periodic_tick();
+    if el == 2 {
+        // SAFETY: The kernel expects the EL1 physical timer to be accessible and the virtual
+        // counter offset to be consistent across cores, and nothing else uses the timers now.
+        unsafe {
+            write_sysreg!("cntvoff_el2", 0u64);
+            write_sysreg!(
+                "cnthctl_el2",
+                read_sysreg!("cnthctl_el2") | CNTHCTL_EL1PCTEN | CNTHCTL_EL1PCEN
+            );
+        }
+    }
+
+    clean_dcache_range(kernel, image.len());
+    clean_dcache_range(dtb.as_ptr(), dtb_len);
+    // SAFETY: The kernel and device tree have been written and cleaned to the point of coherency,
+    // physical memory is identity mapped, and our caller promised that nothing else needs to keep
+    // running. An SPSR of 0 disables the MMU and data cache and branches to the kernel at the
+    // current exception level.
+    unsafe { jump_to_payload(dtb.as_ptr() as u64, 0, 0, 0, kernel as u64, 0) }
+}
+
+/// Powers off all other cores which have enabled remote calls, and waits until PSCI
+/// `AFFINITY_INFO` reports that each of them is off.
+///
+/// A core disables remote calls shortly before it calls `CPU_OFF`, so the kernel could otherwise
+/// try to start it again with `CPU_ON` before it has finished powering down.
+fn power_off_other_cores() {
+    let mut mpidrs = [0; MAX_CORES];
+    let mut count = 0;
+    for_each_other_online_core(|mpidr| {
+        mpidrs[count] = mpidr;
+        count += 1;
+    });
+    stop_other_cores(power_off_core);
+    for &mpidr in &mpidrs[..count] {
+        // If `AFFINITY_INFO` fails then there is nothing more to wait for.
+        while let Ok(AffinityState::On | AffinityState::OnPending) =
+            affinity_info::<AutoConduit>(mpidr, LowestAffinityLevel::All)
+        {
+            spin_loop();
+        }
+    }
+}
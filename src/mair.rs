@@ -0,0 +1,144 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Assignment of MAIR_ELx attribute indices to memory types.
+//!
+//! Each block or page descriptor selects one of the eight memory types in MAIR_ELx by its index, so
+//! the MAIR value and the descriptors must agree. A [`MairRegistry`] assigns the indices as memory
+//! types are added, and then provides both the MAIR value and the descriptor attributes for each
+//! type, so they can't get out of step:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{
+//!     InitialPagetable,
+//!     mair::{MairRegistry, MemoryType},
+//!     mapping::Attributes,
+//! };
+//!
+//! const MAIR: MairRegistry = MairRegistry::new()
+//!     .with(MemoryType::DEVICE_NGNRE)
+//!     .with(MemoryType::NORMAL_WRITE_BACK);
+//!
+//! const DEVICE_ATTRIBUTES: Attributes = MAIR
+//!     .attributes(MemoryType::DEVICE_NGNRE)
+//!     .union(Attributes::ACCESSED)
+//!     .union(Attributes::UXN);
+//!
+//! initial_pagetable!(
+//!     InitialPagetable::empty().with_attributes(0, 1, DEVICE_ATTRIBUTES),
+//!     MAIR.mair()
+//! );
+//! ```
+//!
+//! The registry is usually a constant, so that using a memory type which wasn't added, or adding
+//! more than eight, fails at build time.
+
+use crate::mapping::Attributes;
+
+/// The number of attribute indices in MAIR_ELx.
+const MAIR_ATTRIBUTE_COUNT: usize = 8;
+
+/// A memory type, as an 8-bit attribute field of MAIR_ELx.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemoryType(pub u8);
+
+impl MemoryType {
+    /// Device non-Gathering, non-Reordering, no Early write acknowledgement memory.
+    pub const DEVICE_NGNRNE: Self = Self(0x00);
+    /// Device non-Gathering, non-Reordering, Early write acknowledgement memory, as usual for MMIO.
+    pub const DEVICE_NGNRE: Self = Self(0x04);
+    /// Device Gathering, Reordering, Early write acknowledgement memory, e.g. for a framebuffer.
+    pub const DEVICE_GRE: Self = Self(0x0c);
+    /// Normal memory, inner and outer write-back non-transient read-allocate write-allocate
+    /// cacheable.
+    pub const NORMAL_WRITE_BACK: Self = Self(0xff);
+    /// Normal memory, inner and outer write-through non-transient read-allocate cacheable.
+    pub const NORMAL_WRITE_THROUGH: Self = Self(0xaa);
+    /// Normal memory, inner and outer non-cacheable.
+    pub const NORMAL_NON_CACHEABLE: Self = Self(0x44);
+}
+
+/// Memory types assigned to MAIR_ELx attribute indices, in the order they were added.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MairRegistry {
+    types: [MemoryType; MAIR_ATTRIBUTE_COUNT],
+    count: usize,
+}
+
+impl MairRegistry {
+    /// Returns a registry with no memory types.
+    pub const fn new() -> Self {
+        Self {
+            types: [MemoryType::DEVICE_NGNRNE; MAIR_ATTRIBUTE_COUNT],
+            count: 0,
+        }
+    }
+
+    /// Returns the registry with the given memory type assigned the next free index, if it doesn't
+    /// already have one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all eight indices are already in use by other memory types.
+    pub const fn with(mut self, memory_type: MemoryType) -> Self {
+        if self.find(memory_type).is_none() {
+            assert!(
+                self.count < MAIR_ATTRIBUTE_COUNT,
+                "All MAIR attribute indices are in use"
+            );
+            self.types[self.count] = memory_type;
+            self.count += 1;
+        }
+        self
+    }
+
+    /// Returns the attribute index assigned to the given memory type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory type hasn't been added to the registry.
+    pub const fn index(&self, memory_type: MemoryType) -> u8 {
+        match self.find(memory_type) {
+            Some(index) => index,
+            None => panic!("Memory type not in MAIR registry"),
+        }
+    }
+
+    /// Returns the descriptor attributes selecting the given memory type, i.e. its AttrIndx field.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory type hasn't been added to the registry.
+    pub const fn attributes(&self, memory_type: MemoryType) -> Attributes {
+        Attributes((self.index(memory_type) as u64) << 2)
+    }
+
+    /// Returns the value for MAIR_ELx. Unused indices are device nGnRnE memory.
+    pub const fn mair(&self) -> u64 {
+        let mut mair = 0;
+        let mut i = 0;
+        while i < self.count {
+            mair |= (self.types[i].0 as u64) << (i * 8);
+            i += 1;
+        }
+        mair
+    }
+
+    const fn find(&self, memory_type: MemoryType) -> Option<u8> {
+        let mut i = 0;
+        while i < self.count {
+            if self.types[i].0 == memory_type.0 {
+                return Some(i as u8);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+impl Default for MairRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
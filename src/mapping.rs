@@ -0,0 +1,591 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Minimal helpers to edit the active translation tables at runtime.
+//!
+//! These are for images which need a few dynamic mappings, such as a device or a bank of RAM
+//! discovered after boot, without depending on a full paging crate. They operate on whatever
+//! tables are active for the current exception level, which must use a 4 KiB granule and be
+//! accessible at the virtual addresses given by [`phys_to_virt`]. The attribute indices in
+//! [`Attributes`] assume that MAIR has the value of [`DEFAULT_MAIR`](crate::DEFAULT_MAIR).
+//!
+//! If the active tables are the initial pagetable, it must be given to
+//! [`initial_pagetable!`](crate::initial_pagetable) with `writable:` or `prepare:` so that it is in
+//! writable memory, rather than read-only data.
+//!
+//! Code can be written to a writable mapping and then remapped with [`make_executable`], so that no
+//! mapping is ever both writable and executable. Code for EL0 may be mapped execute-only, so that
+//! it can't be read as data by EL0 or by EL1 while PSTATE.PAN is set, on cores with FEAT_EPAN once
+//! it is enabled with [`enable_execute_only`]. Other cores, and privileged code, fall back to
+//! read-only and executable mappings.
+//!
+//! New subtables are taken from a [`TablePool`], which is typically a static:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{address::{PhysAddr, VirtAddr}, mapping::{Attributes, TablePool, map_region}};
+//!
+//! static TABLES: TablePool<4> = TablePool::new();
+//!
+//! // SAFETY: Nothing else is mapped at this address, and it is a device.
+//! unsafe {
+//!     map_region(VirtAddr(0x1000_0000), PhysAddr(0x1000_0000), 0x1000, Attributes::DEVICE, &TABLES)
+//!         .unwrap();
+//! }
+//! ```
+
+use crate::{
+    address::{PhysAddr, VirtAddr, phys_to_virt, virt_to_phys},
+    cache::{clean_invalidate_dcache_range, sync_icache},
+    sysreg::{current_el, read_sysreg, write_sysreg},
+};
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    ops::{BitOr, BitOrAssign},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The valid bit in a translation table descriptor.
+pub(crate) const DESC_VALID: u64 = 1 << 0;
+/// The table bit in a level 0-2 descriptor, or the page bit in a level 3 descriptor.
+pub(crate) const DESC_TABLE_OR_PAGE: u64 = 1 << 1;
+/// The output address or next-level table address in a descriptor, for a 4 KiB granule.
+pub(crate) const DESC_ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
+/// The root table address in TTBRn_ELx.
+pub(crate) const TTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+/// The size of a page, with a 4 KiB granule.
+const PAGE_SIZE: usize = 4096;
+/// The PAN field of ID_AA64MMFR1_EL1.
+const ID_AA64MMFR1_PAN_MASK: u64 = 0xf << 20;
+/// The value of the PAN field of ID_AA64MMFR1_EL1 indicating FEAT_PAN3, which includes EPAN.
+const ID_AA64MMFR1_PAN_EPAN: u64 = 3 << 20;
+/// Enhanced privileged access never, in SCTLR_EL1.
+const SCTLR_EL1_EPAN: u64 = 1 << 57;
+
+/// The attributes of a block or page mapping.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Attributes(pub u64);
+
+impl Attributes {
+    /// Uses the memory attributes at index 0 in MAIR, which is device nGnRE memory in
+    /// [`DEFAULT_MAIR`](crate::DEFAULT_MAIR).
+    pub const ATTRIBUTE_INDEX_0: Self = Self(0 << 2);
+    /// Uses the memory attributes at index 1 in MAIR, which is normal write-back cacheable memory
+    /// in [`DEFAULT_MAIR`](crate::DEFAULT_MAIR).
+    pub const ATTRIBUTE_INDEX_1: Self = Self(1 << 2);
+    /// Uses the memory attributes at index 2 in MAIR, which is normal non-cacheable memory in
+    /// [`DEFAULT_MAIR`](crate::DEFAULT_MAIR).
+    pub const ATTRIBUTE_INDEX_2: Self = Self(2 << 2);
+    /// The output address is in the Non-secure physical address space. This is RES0 in the
+    /// Non-secure EL1&0 and EL2 translation regimes.
+    pub const NS: Self = Self(1 << 5);
+    /// Accessible from EL0.
+    pub const USER: Self = Self(1 << 6);
+    /// Read-only.
+    pub const READ_ONLY: Self = Self(1 << 7);
+    /// Outer shareable.
+    pub const OUTER_SHAREABLE: Self = Self(2 << 8);
+    /// Inner shareable.
+    pub const INNER_SHAREABLE: Self = Self(3 << 8);
+    /// The access flag, without which accesses cause an access flag fault.
+    pub const ACCESSED: Self = Self(1 << 10);
+    /// Only applies to the current ASID.
+    pub const NON_GLOBAL: Self = Self(1 << 11);
+    /// Privileged execute-never.
+    pub const PXN: Self = Self(1 << 53);
+    /// Unprivileged execute-never, or execute-never for a regime with only one privilege level.
+    pub const UXN: Self = Self(1 << 54);
+
+    /// Device memory, which can't be executed.
+    pub const DEVICE: Self = Self::ATTRIBUTE_INDEX_0
+        .union(Self::ACCESSED)
+        .union(Self::PXN)
+        .union(Self::UXN);
+    /// Normal cacheable memory.
+    pub const NORMAL: Self = Self::ATTRIBUTE_INDEX_1
+        .union(Self::INNER_SHAREABLE)
+        .union(Self::ACCESSED);
+    /// Normal non-cacheable memory, e.g. for buffers shared with devices which aren't coherent with
+    /// the caches.
+    pub const NORMAL_NON_CACHEABLE: Self = Self::ATTRIBUTE_INDEX_2
+        .union(Self::OUTER_SHAREABLE)
+        .union(Self::ACCESSED);
+    /// Privileged code, which is read-only and can't be accessed or executed from EL0.
+    pub const READ_EXECUTE: Self = Self::NORMAL.union(Self::READ_ONLY).union(Self::UXN);
+    /// Code for EL0, which is read-only and can be read and executed from EL0 but not executed by
+    /// privileged code.
+    pub const USER_READ_EXECUTE: Self = Self::NORMAL
+        .union(Self::USER)
+        .union(Self::READ_ONLY)
+        .union(Self::PXN);
+    /// Code for EL0, which can be executed from EL0 but not read as data, and can't be executed by
+    /// privileged code.
+    ///
+    /// Privileged code can still read it unless FEAT_EPAN is enabled and PSTATE.PAN is set, so
+    /// [`user_text_attributes`] should generally be used rather than this.
+    pub const USER_EXECUTE_ONLY: Self = Self::NORMAL.union(Self::READ_ONLY).union(Self::PXN);
+
+    /// Returns a value with all of the bits set in either `self` or `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns whether all of the bits set in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for Attributes {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = self.union(rhs);
+    }
+}
+
+/// An error editing the active translation tables.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapError {
+    /// An address or length isn't a multiple of the page size.
+    Misaligned,
+    /// The virtual address isn't in the range covered by the active translation tables.
+    AddressRange(VirtAddr),
+    /// The virtual address is already mapped.
+    AlreadyMapped(VirtAddr),
+    /// The virtual address isn't mapped.
+    NotMapped(VirtAddr),
+    /// The virtual address is in a block which extends outside the region, which would need to be
+    /// split into smaller mappings.
+    PartialBlock(VirtAddr),
+    /// There are no tables left in the pool for a new subtable.
+    OutOfTables,
+}
+
+/// A translation table for a 4 KiB granule.
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+/// A fixed number of tables which [`map_region`] may use as new subtables.
+pub struct TablePool<const N: usize> {
+    tables: UnsafeCell<[Table; N]>,
+    next: AtomicUsize,
+}
+
+// SAFETY: Each table is handed out at most once, as `next` is only ever incremented atomically.
+unsafe impl<const N: usize> Sync for TablePool<N> {}
+
+impl<const N: usize> TablePool<N> {
+    /// Creates a new pool of `N` empty tables.
+    pub const fn new() -> Self {
+        Self {
+            tables: UnsafeCell::new([const { Table([0; 512]) }; N]),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes an unused table from the pool, and returns its physical address.
+    fn take(&self) -> Option<PhysAddr> {
+        let index = self
+            .next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                (next < N).then_some(next + 1)
+            })
+            .ok()?;
+        let table = self.tables.get().cast::<Table>().wrapping_add(index);
+        Some(virt_to_phys(VirtAddr::from_ptr(table)))
+    }
+}
+
+impl<const N: usize> Default for TablePool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The active translation tables for a virtual address.
+struct ActiveTables {
+    /// The physical address of the root table.
+    root: PhysAddr,
+    /// The level of the root table.
+    root_level: usize,
+}
+
+impl ActiveTables {
+    /// Returns the active translation tables which translate the given virtual address.
+    fn for_address(va: VirtAddr) -> Result<Self, MapError> {
+        // SAFETY: Reading the translation configuration of the current exception level is always
+        // safe.
+        let (ttbr, tsz) = unsafe {
+            match current_el() {
+                1 if va.0 >> 63 != 0 => {
+                    let tcr = read_sysreg!("tcr_el1");
+                    (read_sysreg!("ttbr1_el1"), (tcr >> 16) & 0x3f)
+                }
+                1 => (read_sysreg!("ttbr0_el1"), read_sysreg!("tcr_el1") & 0x3f),
+                2 => (read_sysreg!("ttbr0_el2"), read_sysreg!("tcr_el2") & 0x3f),
+                _ => (read_sysreg!("ttbr0_el3"), read_sysreg!("tcr_el3") & 0x3f),
+            }
+        };
+        let va_bits = 64 - tsz as usize;
+        let top_bits = va.0 >> va_bits;
+        let expected_top_bits = if va.0 >> 63 != 0 && current_el() == 1 {
+            usize::MAX >> va_bits
+        } else {
+            0
+        };
+        if top_bits != expected_top_bits {
+            return Err(MapError::AddressRange(va));
+        }
+        Ok(Self {
+            root: PhysAddr((ttbr & TTBR_BADDR_MASK) as usize),
+            root_level: 4 - (va_bits - 12).div_ceil(9),
+        })
+    }
+}
+
+/// Returns the log2 of the size of the region mapped by each entry of a table at the given level.
+pub(crate) fn level_shift(level: usize) -> usize {
+    12 + 9 * (3 - level)
+}
+
+/// Returns a pointer to the entry for the given virtual address in the given table.
+fn entry_pointer(table: PhysAddr, level: usize, va: VirtAddr) -> *mut u64 {
+    let index = (va.0 >> level_shift(level)) & 0x1ff;
+    phys_to_virt(table).as_mut_ptr::<u64>().wrapping_add(index)
+}
+
+/// Returns whether the given descriptor at the given level points to a next-level table.
+fn is_table(desc: u64, level: usize) -> bool {
+    level < 3 && desc & DESC_TABLE_OR_PAGE != 0
+}
+
+/// Finds the block or page entry mapping the given virtual address in the active translation
+/// tables, and returns a pointer to it along with its level.
+pub fn find_entry(va: VirtAddr) -> Result<(*mut u64, usize), MapError> {
+    let tables = ActiveTables::for_address(va)?;
+    let mut table = tables.root;
+    let mut level = tables.root_level;
+    loop {
+        let entry = entry_pointer(table, level, va);
+        // SAFETY: The entry is part of the active translation tables, which are accessible at
+        // `phys_to_virt` addresses.
+        let desc = unsafe { entry.read_volatile() };
+        if desc & DESC_VALID == 0 {
+            return Err(MapError::NotMapped(va));
+        }
+        if !is_table(desc, level) {
+            return Ok((entry, level));
+        }
+        table = PhysAddr((desc & DESC_ADDRESS_MASK) as usize);
+        level += 1;
+    }
+}
+
+/// Finds the block or page entry mapping the given virtual address, and checks that it doesn't
+/// extend outside the region ending at `end`. Returns a pointer to it and the size it maps.
+fn find_whole_mapping(va: VirtAddr, end: usize) -> Result<(*mut u64, usize), MapError> {
+    let (entry, level) = find_entry(va)?;
+    let size = 1 << level_shift(level);
+    if !va.0.is_multiple_of(size) || end - va.0 < size {
+        return Err(MapError::PartialBlock(va));
+    }
+    Ok((entry, size))
+}
+
+/// Invalidates any TLB entries for the given virtual address in the current translation regime,
+/// on all cores in the inner shareable domain, and waits for that to complete.
+fn invalidate_tlb(va: VirtAddr) {
+    let operand = (va.0 >> 12) & 0xfff_ffff_ffff;
+    // SAFETY: Invalidating TLB entries doesn't affect memory safety.
+    unsafe {
+        match current_el() {
+            1 => asm!("tlbi vaae1is, {}", in(reg) operand, options(nostack, preserves_flags)),
+            2 => asm!("tlbi vae2is, {}", in(reg) operand, options(nostack, preserves_flags)),
+            _ => asm!("tlbi vae3is, {}", in(reg) operand, options(nostack, preserves_flags)),
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+    }
+}
+
+/// Replaces the descriptor at `entry`, which translates `va`, with `new_desc` using the
+/// break-before-make sequence required by the architecture: the entry is made invalid, the TLB
+/// entries for `va` are invalidated on all cores, and only then is the new descriptor written.
+///
+/// This must be used whenever a live block or page entry is changed to one with a different
+/// output address, block size or memory attributes, or replaced with a table, as otherwise the
+/// TLB may hold both the old and new translations and report a TLB conflict abort. Entries can be
+/// found with [`find_entry`].
+///
+/// # Safety
+///
+/// `entry` must be a block or page entry in the active translation tables for the current
+/// exception level, and `va` must be an address it translates. No code which is running or might
+/// run before this returns may access the memory it maps, and the new descriptor must not alias
+/// any memory in a way which breaks Rust's aliasing rules.
+pub unsafe fn remap_block(entry: *mut u64, va: VirtAddr, new_desc: u64) {
+    // SAFETY: Our caller promised that `entry` is in the active translation tables, and that
+    // nothing accesses the memory it maps while it is invalid.
+    unsafe {
+        entry.write_volatile(0);
+        asm!("dsb ishst", options(nostack, preserves_flags));
+    }
+    invalidate_tlb(va);
+    // SAFETY: As above.
+    unsafe {
+        entry.write_volatile(new_desc);
+        asm!("dsb ishst", "isb", options(nostack, preserves_flags));
+    }
+}
+
+/// Checks that the given region is page-aligned, and returns its end address.
+fn region_end(va: VirtAddr, len: usize) -> Result<usize, MapError> {
+    if !va.0.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) {
+        return Err(MapError::Misaligned);
+    }
+    va.0.checked_add(len).ok_or(MapError::AddressRange(va))
+}
+
+/// Maps `len` bytes of physical memory starting at `pa` to the virtual addresses starting at `va`
+/// with the given attributes, in the active translation tables.
+///
+/// Blocks are used where the addresses are suitably aligned, and new subtables are taken from
+/// `tables` as needed. The region must not already be mapped, so no TLB maintenance is needed.
+///
+/// # Safety
+///
+/// The new mapping must not alias any memory in a way which breaks Rust's aliasing rules, and the
+/// attributes must be appropriate for whatever is at the physical address.
+pub unsafe fn map_region<const N: usize>(
+    va: VirtAddr,
+    pa: PhysAddr,
+    len: usize,
+    attributes: Attributes,
+    tables: &TablePool<N>,
+) -> Result<(), MapError> {
+    let end = region_end(va, len)?;
+    if !pa.0.is_multiple_of(PAGE_SIZE) {
+        return Err(MapError::Misaligned);
+    }
+    let mut va = va;
+    let mut pa = pa;
+    while va.0 < end {
+        let active = ActiveTables::for_address(va)?;
+        let mut table = active.root;
+        let mut level = active.root_level;
+        loop {
+            let entry = entry_pointer(table, level, va);
+            // SAFETY: The entry is part of the active translation tables, which are accessible at
+            // `phys_to_virt` addresses.
+            let desc = unsafe { entry.read_volatile() };
+            let size = 1 << level_shift(level);
+            if desc & DESC_VALID == 0
+                && level >= 1
+                && va.0.is_multiple_of(size)
+                && pa.0.is_multiple_of(size)
+                && end - va.0 >= size
+            {
+                let page_or_block = if level == 3 {
+                    DESC_VALID | DESC_TABLE_OR_PAGE
+                } else {
+                    DESC_VALID
+                };
+                // SAFETY: The entry is invalid so nothing can be relying on it, and our caller
+                // promised that the new mapping is safe.
+                unsafe {
+                    entry.write_volatile(pa.0 as u64 | attributes.0 | page_or_block);
+                }
+                va.0 += size;
+                pa.0 += size;
+                break;
+            } else if desc & DESC_VALID == 0 {
+                let new_table = tables.take().ok_or(MapError::OutOfTables)?;
+                // SAFETY: The entry is invalid so nothing can be relying on it, and the new table
+                // is empty.
+                unsafe {
+                    asm!("dsb ishst", options(nostack, preserves_flags));
+                    entry.write_volatile(new_table.0 as u64 | DESC_VALID | DESC_TABLE_OR_PAGE);
+                }
+                table = new_table;
+            } else if is_table(desc, level) {
+                table = PhysAddr((desc & DESC_ADDRESS_MASK) as usize);
+            } else {
+                return Err(MapError::AlreadyMapped(va));
+            }
+            level += 1;
+        }
+    }
+    // SAFETY: Barriers are always safe.
+    unsafe {
+        asm!("dsb ishst", "isb", options(nostack, preserves_flags));
+    }
+    Ok(())
+}
+
+/// Unmaps the `len` bytes of virtual memory starting at `va` from the active translation tables,
+/// and invalidates any TLB entries for them.
+///
+/// Every block or page in the region must be mapped, and blocks must lie entirely within it, as
+/// they aren't split. Subtables are left in place.
+///
+/// # Safety
+///
+/// Nothing may access the region after it is unmapped.
+pub unsafe fn unmap_region(va: VirtAddr, len: usize) -> Result<(), MapError> {
+    let end = region_end(va, len)?;
+    let mut va = va;
+    while va.0 < end {
+        let (entry, size) = find_whole_mapping(va, end)?;
+        // SAFETY: The entry is in the active translation tables, and our caller promised that
+        // nothing will access the memory it maps.
+        unsafe {
+            entry.write_volatile(0);
+            asm!("dsb ishst", options(nostack, preserves_flags));
+        }
+        invalidate_tlb(va);
+        va.0 += size;
+    }
+    // SAFETY: An instruction synchronisation barrier is always safe.
+    unsafe {
+        asm!("isb", options(nostack, preserves_flags));
+    }
+    Ok(())
+}
+
+/// Changes the attributes of the `len` bytes of virtual memory starting at `va` in the active
+/// translation tables, keeping the same physical addresses.
+///
+/// Every block or page in the region must be mapped, and blocks must lie entirely within it, as
+/// they aren't split. Each entry is changed with the break-before-make sequence, so it is briefly
+/// unmapped.
+///
+/// # Safety
+///
+/// Nothing may access the region while its attributes are being changed, and the new attributes
+/// must be appropriate for whatever is mapped there.
+pub unsafe fn change_attributes(
+    va: VirtAddr,
+    len: usize,
+    attributes: Attributes,
+) -> Result<(), MapError> {
+    let end = region_end(va, len)?;
+    let mut va = va;
+    while va.0 < end {
+        let (entry, size) = find_whole_mapping(va, end)?;
+        // SAFETY: The entry is in the active translation tables.
+        let desc = unsafe { entry.read_volatile() };
+        let new = (desc & (DESC_ADDRESS_MASK | DESC_VALID | DESC_TABLE_OR_PAGE)) | attributes.0;
+        // SAFETY: The entry is a block or page in the active translation tables, and our caller
+        // promised that nothing will access the memory it maps meanwhile.
+        unsafe {
+            remap_block(entry, va, new);
+        }
+        va.0 += size;
+    }
+    Ok(())
+}
+
+/// Changes the attributes of the `len` bytes of normal cacheable memory starting at `va` to the
+/// given non-cacheable attributes, such as [`Attributes::NORMAL_NON_CACHEABLE`] or
+/// [`Attributes::DEVICE`], and then cleans and invalidates the data cache for it.
+///
+/// This is for buffers shared with devices which aren't coherent with the caches, such as DMA
+/// buffers on platforms without hardware coherency. Any data written to the region before is
+/// preserved, and no stale cache lines are left which could later be written back over data
+/// written by a device. The same restrictions apply as for [`change_attributes`].
+///
+/// # Safety
+///
+/// Nothing may access the region while its attributes are being changed, and there must be no
+/// other cacheable mapping of the same physical memory.
+pub unsafe fn make_non_cacheable(
+    va: VirtAddr,
+    len: usize,
+    attributes: Attributes,
+) -> Result<(), MapError> {
+    // SAFETY: Our caller promised that nothing accesses the region while it is changed.
+    unsafe {
+        change_attributes(va, len, attributes)?;
+    }
+    // Now that there is no cacheable mapping, no new lines can be allocated for the region, so
+    // write back and discard any which were allocated before.
+    clean_invalidate_dcache_range(va.as_ptr(), len);
+    Ok(())
+}
+
+/// Returns whether execute-only mappings for EL0 can be protected from privileged reads, i.e.
+/// whether the current exception level is EL1 and FEAT_EPAN is implemented.
+pub fn execute_only_supported() -> bool {
+    // SAFETY: Reading ID_AA64MMFR1_EL1 is always safe.
+    current_el() == 1
+        && unsafe { read_sysreg!("id_aa64mmfr1_el1") } & ID_AA64MMFR1_PAN_MASK
+            >= ID_AA64MMFR1_PAN_EPAN
+}
+
+/// Enables FEAT_EPAN if it is supported, so that PSTATE.PAN prevents privileged reads from
+/// execute-only mappings for EL0 as well as from mappings which EL0 can read.
+///
+/// Returns whether it was enabled, i.e. [`execute_only_supported`].
+///
+/// # Safety
+///
+/// Privileged code must not rely on reading EL0 execute-only memory while PSTATE.PAN is set.
+pub unsafe fn enable_execute_only() -> bool {
+    if !execute_only_supported() {
+        return false;
+    }
+    // SAFETY: We are at EL1, FEAT_EPAN is implemented, and our caller promised that nothing relies
+    // on the reads which this prevents.
+    unsafe {
+        write_sysreg!("sctlr_el1", read_sysreg!("sctlr_el1") | SCTLR_EL1_EPAN);
+        asm!("isb", options(nostack, preserves_flags));
+    }
+    true
+}
+
+/// Returns the attributes to map code for EL0 with: [`Attributes::USER_EXECUTE_ONLY`] if
+/// [`execute_only_supported`], otherwise [`Attributes::USER_READ_EXECUTE`].
+pub fn user_text_attributes() -> Attributes {
+    if execute_only_supported() {
+        Attributes::USER_EXECUTE_ONLY
+    } else {
+        Attributes::USER_READ_EXECUTE
+    }
+}
+
+/// Makes code which has been written to the `len` bytes of normal memory starting at `va` visible
+/// to instruction fetches, and then remaps it as read-only and executable so that it is never
+/// writable and executable at once.
+///
+/// If `user` is true the code is mapped for EL0, with [`user_text_attributes`], so is execute-only
+/// where supported. Otherwise it is mapped for privileged code with [`Attributes::READ_EXECUTE`].
+/// Returns the attributes used. The same restrictions apply as for [`change_attributes`].
+///
+/// # Safety
+///
+/// Nothing may access the region while its attributes are being changed, and nothing may rely on
+/// writing to it afterwards.
+pub unsafe fn make_executable(
+    va: VirtAddr,
+    len: usize,
+    user: bool,
+) -> Result<Attributes, MapError> {
+    let attributes = if user {
+        user_text_attributes()
+    } else {
+        Attributes::READ_EXECUTE
+    };
+    sync_icache(va.as_ptr(), len);
+    // SAFETY: Our caller promised that nothing accesses the region while it is changed.
+    unsafe {
+        change_attributes(va, len, attributes)?;
+    }
+    Ok(attributes)
+}
@@ -0,0 +1,223 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Optimised implementations of the memory functions which the compiler may call.
+//!
+//! These override the generic implementations from `compiler_builtins`, which are provided as weak
+//! symbols. They use unaligned 16-byte loads and stores, and `DC ZVA` for large zeroing, so they
+//! require the memory being accessed to be mapped as normal memory with the MMU enabled.
+
+use core::arch::naked_asm;
+
+/// The minimum length for which `memset` will use `DC ZVA` to zero memory, if possible.
+const ZVA_THRESHOLD: usize = 512;
+
+/// Copies `n` bytes from `src` to `dst`, which must not overlap.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `n` bytes and `dst` must be valid for writes of `n` bytes, and
+/// the two regions must not overlap.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memcpy(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    naked_asm!(
+        "mov x3, x0",
+        // Copy 64 bytes at a time.
+        "0:",
+        "cmp x2, #64",
+        "b.lo 1f",
+        "ldp x4, x5, [x1]",
+        "ldp x6, x7, [x1, #16]",
+        "ldp x8, x9, [x1, #32]",
+        "ldp x10, x11, [x1, #48]",
+        "stp x4, x5, [x3]",
+        "stp x6, x7, [x3, #16]",
+        "stp x8, x9, [x3, #32]",
+        "stp x10, x11, [x3, #48]",
+        "add x1, x1, #64",
+        "add x3, x3, #64",
+        "sub x2, x2, #64",
+        "b 0b",
+        // Then 16 bytes at a time.
+        "1:",
+        "cmp x2, #16",
+        "b.lo 2f",
+        "ldp x4, x5, [x1], #16",
+        "stp x4, x5, [x3], #16",
+        "sub x2, x2, #16",
+        "b 1b",
+        // Then any remaining bytes.
+        "2:",
+        "cbz x2, 3f",
+        "ldrb w4, [x1], #1",
+        "strb w4, [x3], #1",
+        "sub x2, x2, #1",
+        "b 2b",
+        "3:",
+        "ret",
+    )
+}
+
+/// Copies `n` bytes from `src` to `dst`, which may overlap.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `n` bytes and `dst` must be valid for writes of `n` bytes.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memmove(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    naked_asm!(
+        // If dst is before src or after the end of the source region, a forward copy is safe.
+        "sub x3, x0, x1",
+        "cmp x3, x2",
+        "b.hs {memcpy}",
+        // Otherwise copy backwards from the end, 16 bytes at a time.
+        "add x4, x1, x2",
+        "add x5, x0, x2",
+        "0:",
+        "cmp x2, #16",
+        "b.lo 1f",
+        "ldp x6, x7, [x4, #-16]!",
+        "stp x6, x7, [x5, #-16]!",
+        "sub x2, x2, #16",
+        "b 0b",
+        // Then any remaining bytes.
+        "1:",
+        "cbz x2, 2f",
+        "ldrb w6, [x4, #-1]!",
+        "strb w6, [x5, #-1]!",
+        "sub x2, x2, #1",
+        "b 1b",
+        "2:",
+        "ret",
+        memcpy = sym memcpy,
+    )
+}
+
+/// Sets `n` bytes starting at `dst` to the byte value `c`.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `n` bytes.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memset(dst: *mut u8, c: i32, n: usize) -> *mut u8 {
+    naked_asm!(
+        "mov x3, x0",
+        // Replicate the byte value to all 8 bytes of x1.
+        "and x1, x1, #0xff",
+        "orr x1, x1, x1, lsl #8",
+        "orr x1, x1, x1, lsl #16",
+        "orr x1, x1, x1, lsl #32",
+        // Use DC ZVA for large regions of zeroes, if it is permitted.
+        "cbnz x1, 2f",
+        "cmp x2, #{zva_threshold}",
+        "b.lo 2f",
+        "mrs x4, dczid_el0",
+        "tbnz x4, #4, 2f",
+        // Get the DC ZVA block size in bytes, and make sure that there are at least two blocks.
+        "and x4, x4, #0xf",
+        "mov x5, #4",
+        "lsl x5, x5, x4",
+        "cmp x2, x5, lsl #1",
+        "b.lo 2f",
+        // Zero bytes up to the next block boundary.
+        "sub x6, x5, #1",
+        "0:",
+        "tst x3, x6",
+        "b.eq 1f",
+        "strb wzr, [x3], #1",
+        "sub x2, x2, #1",
+        "b 0b",
+        // Zero whole blocks.
+        "1:",
+        "cmp x2, x5",
+        "b.lo 2f",
+        "dc zva, x3",
+        "add x3, x3, x5",
+        "sub x2, x2, x5",
+        "b 1b",
+        // Set 64 bytes at a time.
+        "2:",
+        "cmp x2, #64",
+        "b.lo 3f",
+        "stp x1, x1, [x3]",
+        "stp x1, x1, [x3, #16]",
+        "stp x1, x1, [x3, #32]",
+        "stp x1, x1, [x3, #48]",
+        "add x3, x3, #64",
+        "sub x2, x2, #64",
+        "b 2b",
+        // Then 16 bytes at a time.
+        "3:",
+        "cmp x2, #16",
+        "b.lo 4f",
+        "stp x1, x1, [x3], #16",
+        "sub x2, x2, #16",
+        "b 3b",
+        // Then any remaining bytes.
+        "4:",
+        "cbz x2, 5f",
+        "strb w1, [x3], #1",
+        "sub x2, x2, #1",
+        "b 4b",
+        "5:",
+        "ret",
+        zva_threshold = const ZVA_THRESHOLD,
+    )
+}
+
+/// Compares `n` bytes at `a` and `b`, returning a negative value if the first differing byte in `a`
+/// is less than that in `b`, a positive value if it is greater, or 0 if they are equal.
+///
+/// # Safety
+///
+/// `a` and `b` must both be valid for reads of `n` bytes.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    naked_asm!(
+        // Compare 8 bytes at a time.
+        "0:",
+        "cmp x2, #8",
+        "b.lo 1f",
+        "ldr x3, [x0], #8",
+        "ldr x4, [x1], #8",
+        "sub x2, x2, #8",
+        "cmp x3, x4",
+        "b.eq 0b",
+        // Byte-reverse so that the first differing byte is the most significant.
+        "rev x3, x3",
+        "rev x4, x4",
+        "cmp x3, x4",
+        "mov w0, #1",
+        "cneg w0, w0, lo",
+        "ret",
+        // Then compare any remaining bytes.
+        "1:",
+        "cbz x2, 2f",
+        "ldrb w3, [x0], #1",
+        "ldrb w4, [x1], #1",
+        "sub x2, x2, #1",
+        "subs w3, w3, w4",
+        "b.eq 1b",
+        "mov w0, w3",
+        "ret",
+        "2:",
+        "mov w0, #0",
+        "ret",
+    )
+}
+
+/// Compares `n` bytes at `a` and `b`, returning 0 if they are equal or a non-zero value otherwise.
+///
+/// # Safety
+///
+/// `a` and `b` must both be valid for reads of `n` bytes.
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    naked_asm!("b {memcmp}", memcmp = sym memcmp)
+}
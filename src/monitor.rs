@@ -0,0 +1,331 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Configuration for images running as the secure monitor at EL3.
+//!
+//! [`set_interrupt_routing`] chooses whether IRQs, FIQs and external aborts (including SErrors) are
+//! taken to EL3 or left to lower exception levels, via SCR_EL3. Together with the GIC CPU interface
+//! group enables this can express the usual split for a secure monitor with GICv3, in which group
+//! 0 interrupts are signalled as FIQs and taken to EL3, while Non-secure group 1 interrupts are
+//! signalled as IRQs and left to the normal world:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{gic, monitor::{InterruptRouting, set_interrupt_routing}};
+//!
+//! set_interrupt_routing(InterruptRouting::FIQ_TO_EL3);
+//! gic::set_group0_enabled(true);
+//! gic::set_group1_enabled_el3(false, true);
+//! ```
+//!
+//! Assigning individual interrupts to groups is done in the GIC distributor and redistributors,
+//! which is left to the application. Routing is configured separately for each core, so must be
+//! done on each core which the monitor runs on.
+//!
+//! With the `exceptions` feature, [`switch_world`] switches the current core between the Secure
+//! and Non-secure worlds (or any other lower exception level contexts) from an exception handler,
+//! saving the state of the world which took the exception to one [`WorldContext`] and loading the
+//! other world's from another, so that returning from the exception enters the other world:
+//!
+//! ```rust,ignore
+//! fn handle_smc(register_state: &mut RegisterStateRef) -> bool {
+//!     let core = core_index();
+//!     // SAFETY: The contexts are only used by this core, and stay valid until the handler
+//!     // returns.
+//!     unsafe {
+//!         switch_world(
+//!             register_state,
+//!             &mut *(&raw mut NON_SECURE_CONTEXTS[core]),
+//!             &*(&raw const SECURE_CONTEXTS[core]),
+//!         );
+//!     }
+//!     true
+//! }
+//! ```
+
+#[cfg(feature = "exceptions")]
+use crate::RegisterStateRef;
+use crate::sysreg::{read_sysreg, write_sysreg};
+use core::arch::asm;
+
+/// Physical IRQ routing to EL3, in SCR_EL3.
+const SCR_IRQ: u64 = 1 << 1;
+/// Physical FIQ routing to EL3, in SCR_EL3.
+const SCR_FIQ: u64 = 1 << 2;
+/// External abort and SError routing to EL3, in SCR_EL3.
+const SCR_EA: u64 = 1 << 3;
+
+/// Which exceptions are taken to EL3, rather than to the exception level determined by the
+/// configuration of lower exception levels.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InterruptRouting {
+    /// Whether physical IRQs are taken to EL3.
+    pub irq: bool,
+    /// Whether physical FIQs are taken to EL3.
+    pub fiq: bool,
+    /// Whether external aborts and SErrors are taken to EL3.
+    pub external_abort: bool,
+}
+
+impl InterruptRouting {
+    /// Leaves all interrupts and external aborts to lower exception levels.
+    pub const LOWER_EL: Self = Self {
+        irq: false,
+        fiq: false,
+        external_abort: false,
+    };
+
+    /// Takes FIQs to EL3, and leaves IRQs and external aborts to lower exception levels. With
+    /// GICv3 this routes group 0 interrupts, and group 1 interrupts for the other security state,
+    /// to EL3.
+    pub const FIQ_TO_EL3: Self = Self {
+        irq: false,
+        fiq: true,
+        external_abort: false,
+    };
+
+    /// Takes FIQs and external aborts to EL3, and leaves IRQs to lower exception levels.
+    pub const FIQ_AND_EA_TO_EL3: Self = Self {
+        irq: false,
+        fiq: true,
+        external_abort: true,
+    };
+
+    fn to_scr_bits(self) -> u64 {
+        (if self.irq { SCR_IRQ } else { 0 })
+            | (if self.fiq { SCR_FIQ } else { 0 })
+            | (if self.external_abort { SCR_EA } else { 0 })
+    }
+}
+
+/// Sets which interrupts and external aborts are taken to EL3 on the current core, via
+/// SCR_EL3.{IRQ, FIQ, EA}.
+///
+/// When an exception is routed to EL3 it is taken there from any lower exception level, whatever
+/// its masks there are, and at EL3 itself only if it is unmasked in PSTATE. Other bits of SCR_EL3
+/// are left unchanged.
+pub fn set_interrupt_routing(routing: InterruptRouting) {
+    // SAFETY: We are running at EL3, so can access SCR_EL3. Changing the routing of interrupts and
+    // external aborts doesn't affect memory safety; they are still delivered to an exception vector.
+    unsafe {
+        let scr = read_sysreg!("scr_el3") & !(SCR_IRQ | SCR_FIQ | SCR_EA);
+        write_sysreg!("scr_el3", scr | routing.to_scr_bits());
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns which interrupts and external aborts are currently taken to EL3 on the current core.
+pub fn interrupt_routing() -> InterruptRouting {
+    // SAFETY: We are running at EL3, so can read SCR_EL3.
+    let scr = unsafe { read_sysreg!("scr_el3") };
+    InterruptRouting {
+        irq: scr & SCR_IRQ != 0,
+        fiq: scr & SCR_FIQ != 0,
+        external_abort: scr & SCR_EA != 0,
+    }
+}
+
+/// Declares a struct with a `u64` field for each of the given system registers, and methods to save
+/// and restore them.
+#[cfg(feature = "exceptions")]
+macro_rules! sysreg_context {
+    ($(#[$attrs:meta])* $name:ident { $($sysreg:ident),* $(,)? }) => {
+        $(#[$attrs])*
+        #[derive(Clone, Debug, Default, Eq, PartialEq)]
+        #[repr(C)]
+        pub struct $name {
+            $(
+                #[doc = concat!("The value of `", stringify!($sysreg), "`.")]
+                pub $sysreg: u64,
+            )*
+        }
+
+        impl $name {
+            /// Saves the current values of the registers.
+            pub fn save(&mut self) {
+                // SAFETY: We are running at EL3, so can read all lower EL system registers.
+                unsafe {
+                    $(self.$sysreg = read_sysreg!(stringify!($sysreg));)*
+                }
+            }
+
+            /// Restores the registers to the saved values.
+            ///
+            /// # Safety
+            ///
+            /// The values must be valid for the world which will run with them, as they control
+            /// its translation regime and exception handling.
+            pub unsafe fn restore(&self) {
+                // SAFETY: Our caller promised that the values are valid for the lower exception
+                // levels, and they don't affect EL3.
+                unsafe {
+                    $(write_sysreg!(stringify!($sysreg), self.$sysreg);)*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "exceptions")]
+sysreg_context!(
+    /// The EL1 and EL0 system registers of a world.
+    El1Context {
+        sctlr_el1,
+        actlr_el1,
+        cpacr_el1,
+        csselr_el1,
+        sp_el1,
+        esr_el1,
+        ttbr0_el1,
+        ttbr1_el1,
+        mair_el1,
+        amair_el1,
+        tcr_el1,
+        tpidr_el1,
+        tpidr_el0,
+        tpidrro_el0,
+        par_el1,
+        far_el1,
+        afsr0_el1,
+        afsr1_el1,
+        contextidr_el1,
+        vbar_el1,
+        elr_el1,
+        spsr_el1,
+        mdscr_el1,
+        cntkctl_el1,
+        sp_el0,
+    }
+);
+
+#[cfg(feature = "exceptions")]
+sysreg_context!(
+    /// The EL2 system registers of a world, which are only switched if EL2 is implemented.
+    El2Context {
+        hcr_el2,
+        sctlr_el2,
+        actlr_el2,
+        cptr_el2,
+        hstr_el2,
+        mdcr_el2,
+        vbar_el2,
+        ttbr0_el2,
+        tcr_el2,
+        mair_el2,
+        amair_el2,
+        vttbr_el2,
+        vtcr_el2,
+        elr_el2,
+        spsr_el2,
+        esr_el2,
+        far_el2,
+        hpfar_el2,
+        afsr0_el2,
+        afsr1_el2,
+        sp_el2,
+        tpidr_el2,
+        cnthctl_el2,
+        cntvoff_el2,
+        vpidr_el2,
+        vmpidr_el2,
+    }
+);
+
+/// The saved state of a world: the general-purpose registers and lower exception level system
+/// registers of one security state, and the EL3 registers used to return to it.
+///
+/// Floating point and SIMD registers, and the registers of optional features such as SVE, MTE or
+/// pointer authentication keys, aren't included. If both worlds use them, the monitor must switch
+/// them itself.
+#[cfg(feature = "exceptions")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct WorldContext {
+    /// Registers x0-x30.
+    pub registers: [u64; 31],
+    /// The exception return address, ELR_EL3.
+    pub elr_el3: u64,
+    /// The saved program status, SPSR_EL3.
+    pub spsr_el3: u64,
+    /// The secure configuration, SCR_EL3, which selects the security state of the world among
+    /// other things.
+    pub scr_el3: u64,
+    /// The EL1 and EL0 system registers.
+    pub el1: El1Context,
+    /// The EL2 system registers.
+    pub el2: El2Context,
+}
+
+#[cfg(feature = "exceptions")]
+impl WorldContext {
+    /// Creates a context to enter the given entry point with the given SCR_EL3 and SPSR_EL3 values,
+    /// with all other registers zero.
+    pub fn new(entry_point: u64, scr_el3: u64, spsr_el3: u64) -> Self {
+        Self {
+            elr_el3: entry_point,
+            spsr_el3,
+            scr_el3,
+            ..Default::default()
+        }
+    }
+}
+
+/// Switches from the world which took the current exception to another, when the exception
+/// returns.
+///
+/// This saves the registers of the current world to `from`, and loads those of the next world
+/// from `to`: the general-purpose registers, ELR_EL3 and SPSR_EL3 via `register_state`, and SCR_EL3
+/// and the lower exception level system registers directly.
+///
+/// # Safety
+///
+/// This must be called from an exception handler at EL3 for an exception from a lower exception
+/// level, which must then return normally without calling it again. `to` must contain a valid
+/// context to return to, e.g. one saved by a previous switch or created with [`WorldContext::new`]
+/// for an entry point.
+#[cfg(feature = "exceptions")]
+pub unsafe fn switch_world(
+    register_state: &mut RegisterStateRef,
+    from: &mut WorldContext,
+    to: &WorldContext,
+) {
+    let el2 = el2_implemented();
+    from.registers[..19].copy_from_slice(&register_state.registers);
+    from.registers[19..29].copy_from_slice(&register_state.callee_saved);
+    from.registers[29] = register_state.fp;
+    from.registers[30] = register_state.sp;
+    from.elr_el3 = register_state.elr as u64;
+    from.spsr_el3 = register_state.spsr;
+    // SAFETY: We are running at EL3, so can read SCR_EL3.
+    from.scr_el3 = unsafe { read_sysreg!("scr_el3") };
+    from.el1.save();
+    if el2 {
+        from.el2.save();
+    }
+
+    // SAFETY: Our caller promised that `to` is a valid context to return to, and that we are
+    // handling an exception from a lower exception level, so changing the state of lower
+    // exception levels doesn't affect the handler.
+    unsafe {
+        let state = register_state.get_mut();
+        state.registers.copy_from_slice(&to.registers[..19]);
+        state.callee_saved.copy_from_slice(&to.registers[19..29]);
+        state.fp = to.registers[29];
+        state.sp = to.registers[30];
+        state.elr = to.elr_el3 as usize;
+        state.spsr = to.spsr_el3;
+        write_sysreg!("scr_el3", to.scr_el3);
+        to.el1.restore();
+        if el2 {
+            to.el2.restore();
+        }
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns whether EL2 is implemented, from ID_AA64PFR0_EL1.
+#[cfg(feature = "exceptions")]
+fn el2_implemented() -> bool {
+    // SAFETY: Reading ID_AA64PFR0_EL1 is always safe.
+    unsafe { (read_sysreg!("id_aa64pfr0_el1") >> 8) & 0xf != 0 }
+}
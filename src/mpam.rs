@@ -0,0 +1,150 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Boot-time configuration of the Memory Partitioning and Monitoring (MPAM) extension.
+//!
+//! If an image registers a configuration with the [`mpam!`](macro@crate::mpam) macro, then the entry
+//! points set the default PARTID and PMG for the current exception level on each core before
+//! enabling the MMU, if MPAM is implemented. At EL3 this also enables MPAM, and at EL2 or EL3
+//! accesses to the MPAM registers from lower exception levels may be trapped. For example:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{mpam, mpam::MpamConfig};
+//!
+//! mpam!(MpamConfig { partid: 1, pmg: 0, trap_lower: true });
+//! ```
+
+use crate::sysreg::read_sysreg;
+use core::arch::global_asm;
+
+/// MPAM3_EL3.MPAMEN: enables MPAM for all exception levels.
+const MPAM3_MPAMEN: u64 = 1 << 63;
+/// MPAM3_EL3.TRAPLOWER: traps accesses to MPAM registers from lower exception levels to EL3.
+const MPAM3_TRAPLOWER: u64 = 1 << 62;
+/// MPAM2_EL2.TRAPMPAM1EL1: traps accesses to MPAM1_EL1 from EL1 to EL2.
+const MPAM2_TRAPMPAM1EL1: u64 = 1 << 48;
+/// MPAM2_EL2.TRAPMPAM0EL1: traps accesses to MPAM0_EL1 from EL1 to EL2.
+const MPAM2_TRAPMPAM0EL1: u64 = 1 << 49;
+
+/// MPAM configuration to apply at boot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MpamConfig {
+    /// The default partition ID for both instruction and data accesses.
+    pub partid: u16,
+    /// The default performance monitoring group for both instruction and data accesses.
+    pub pmg: u8,
+    /// Whether to trap accesses to the MPAM registers from lower exception levels, when running at
+    /// EL2 or EL3.
+    pub trap_lower: bool,
+}
+
+impl MpamConfig {
+    /// Returns the PARTID and PMG fields common to the MPAMn_ELx registers.
+    const fn fields(self) -> u64 {
+        let partid = self.partid as u64;
+        let pmg = self.pmg as u64;
+        pmg << 40 | pmg << 32 | partid << 16 | partid
+    }
+
+    /// Returns the value to write to MPAM3_EL3.
+    #[doc(hidden)]
+    pub const fn mpam3_el3(self) -> u64 {
+        let trap = if self.trap_lower { MPAM3_TRAPLOWER } else { 0 };
+        MPAM3_MPAMEN | trap | self.fields()
+    }
+
+    /// Returns the value to write to MPAM2_EL2.
+    #[doc(hidden)]
+    pub const fn mpam2_el2(self) -> u64 {
+        let trap = if self.trap_lower {
+            MPAM2_TRAPMPAM1EL1 | MPAM2_TRAPMPAM0EL1
+        } else {
+            0
+        };
+        trap | self.fields()
+    }
+
+    /// Returns the value to write to MPAM1_EL1.
+    #[doc(hidden)]
+    pub const fn mpam1_el1(self) -> u64 {
+        self.fields()
+    }
+}
+
+/// Returns whether the MPAM extension is implemented.
+pub fn mpam_implemented() -> bool {
+    // SAFETY: Reading ID registers is always safe.
+    let (pfr0, pfr1) = unsafe {
+        (
+            read_sysreg!("id_aa64pfr0_el1"),
+            read_sysreg!("id_aa64pfr1_el1"),
+        )
+    };
+    (pfr0 >> 40) & 0xf != 0 || (pfr1 >> 16) & 0xf != 0
+}
+
+// A default no-op hook, which is overridden by the strong symbol defined by `mpam!` if it is used.
+global_asm!(
+    ".section .init.__aarch64_rt_configure_mpam, \"ax\"",
+    ".weak __aarch64_rt_configure_mpam",
+    ".type __aarch64_rt_configure_mpam, %function",
+    "__aarch64_rt_configure_mpam:",
+    "ret",
+);
+
+/// Registers an MPAM configuration for the entry points to apply, if MPAM is implemented.
+///
+/// The value must be an [`MpamConfig`](crate::mpam::MpamConfig) constant expression.
+#[macro_export]
+macro_rules! mpam {
+    ($config:expr) => {
+        core::arch::global_asm!(
+            r".macro mov_i, reg:req, imm:req",
+                r"movz \reg, #(\imm >> 48), lsl #48",
+                r"movk \reg, #(\imm >> 32) & 0xffff, lsl #32",
+                r"movk \reg, #(\imm >> 16) & 0xffff, lsl #16",
+                r"movk \reg, #\imm & 0xffff",
+            r".endm",
+
+            ".section .init.__aarch64_rt_configure_mpam, \"ax\"",
+            ".global __aarch64_rt_configure_mpam",
+            "__aarch64_rt_configure_mpam:",
+                // Skip everything if MPAM isn't implemented.
+                "mrs x9, id_aa64pfr0_el1",
+                "ubfx x9, x9, #40, #4",
+                "cbnz x9, 0f",
+                "mrs x9, id_aa64pfr1_el1",
+                "ubfx x9, x9, #16, #4",
+                "cbz x9, 3f",
+            "0:",
+                "mrs x9, CurrentEL",
+                "ubfx x9, x9, #2, #2",
+                "cmp x9, #3",
+                "b.ne 1f",
+                "mov_i x10, {MPAM3_EL3}",
+                // MPAM3_EL3
+                "msr S3_6_C10_C5_0, x10",
+                "b 3f",
+            "1:",
+                "cmp x9, #2",
+                "b.ne 2f",
+                "mov_i x10, {MPAM2_EL2}",
+                // MPAM2_EL2
+                "msr S3_4_C10_C5_0, x10",
+                "b 3f",
+            "2:",
+                "mov_i x10, {MPAM1_EL1}",
+                // MPAM1_EL1
+                "msr S3_0_C10_C5_0, x10",
+            "3:",
+                "isb",
+                "ret",
+
+            ".purgem mov_i",
+            MPAM3_EL3 = const $crate::mpam::MpamConfig::mpam3_el3($config),
+            MPAM2_EL2 = const $crate::mpam::MpamConfig::mpam2_el2($config),
+            MPAM1_EL1 = const $crate::mpam::MpamConfig::mpam1_el1($config),
+        );
+    };
+}
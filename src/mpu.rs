@@ -0,0 +1,245 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Code to set up the initial MPU regions on Armv8-R AArch64 processors such as the Cortex-R82.
+
+use core::arch::naked_asm;
+
+const MAIR_DEV_NGNRE: u64 = 0x04;
+const MAIR_MEM_WBWA: u64 = 0xff;
+/// The default value used for MAIR_ELx.
+///
+/// This matches the attribute indices used by [`MpuRegion::device`] and [`MpuRegion::normal`].
+pub const DEFAULT_MAIR: u64 = MAIR_DEV_NGNRE | MAIR_MEM_WBWA << 8;
+
+/// Stage 1 instruction access cacheability is unaffected.
+const SCTLR_ELX_I: u64 = 0x1 << 12;
+/// SP alignment fault if SP is not aligned to a 16 byte boundary.
+const SCTLR_ELX_SA: u64 = 0x1 << 3;
+/// Stage 1 data access cacheability is unaffected.
+const SCTLR_ELX_C: u64 = 0x1 << 2;
+/// EL0 and EL1 stage 1 MPU enabled.
+const SCTLR_ELX_M: u64 = 0x1 << 0;
+/// Privileged Access Never is unchanged on taking an exception to ELx.
+const SCTLR_ELX_SPAN: u64 = 0x1 << 23;
+/// SETEND instruction disabled at EL0 in aarch32 mode.
+const SCTLR_ELX_SED: u64 = 0x1 << 8;
+/// Various IT instructions are disabled at EL0 in aarch32 mode.
+const SCTLR_ELX_ITD: u64 = 0x1 << 7;
+const SCTLR_ELX_RES1: u64 = (0x1 << 11) | (0x1 << 20) | (0x1 << 22) | (0x1 << 28) | (0x1 << 29);
+/// The default value used for SCTLR_ELx.
+pub const DEFAULT_SCTLR: u64 = SCTLR_ELX_M
+    | SCTLR_ELX_C
+    | SCTLR_ELX_SA
+    | SCTLR_ELX_ITD
+    | SCTLR_ELX_SED
+    | SCTLR_ELX_I
+    | SCTLR_ELX_SPAN
+    | SCTLR_ELX_RES1;
+
+/// Execute never, in PRBAR_ELx.
+const PRBAR_XN: u64 = 0x1 << 0;
+/// Read/write at EL1 and above, no access at EL0, in PRBAR_ELx.
+const PRBAR_AP_RW: u64 = 0x0 << 2;
+/// Inner shareable, in PRBAR_ELx.
+const PRBAR_SH_INNER: u64 = 0x3 << 4;
+/// Region enabled, in PRLAR_ELx.
+const PRLAR_EN: u64 = 0x1 << 0;
+/// The shift of the attribute index, in PRLAR_ELx.
+const PRLAR_ATTRINDX_SHIFT: u64 = 1;
+/// The base and limit addresses are in units of 64 bytes.
+const REGION_ADDRESS_MASK: u64 = 0xffff_ffff_ffc0;
+
+/// An MPU region, as the values to write to the PRBAR_ELx and PRLAR_ELx registers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct MpuRegion {
+    /// The value for PRBAR_ELx, with the base address and access permissions.
+    pub prbar: u64,
+    /// The value for PRLAR_ELx, with the limit address, attribute index and enable bit.
+    pub prlar: u64,
+}
+
+impl MpuRegion {
+    /// Returns an enabled region covering `start..end` with the given PRBAR_ELx permission bits and
+    /// MAIR attribute index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `start` or `end` is not aligned to 64 bytes, or the region is empty.
+    pub const fn new(start: u64, end: u64, permissions: u64, attribute_index: u8) -> Self {
+        assert!(start & !REGION_ADDRESS_MASK == 0 && end & !REGION_ADDRESS_MASK == 0);
+        assert!(start < end);
+        assert!(attribute_index < 8);
+        Self {
+            prbar: start | (permissions & !REGION_ADDRESS_MASK),
+            prlar: ((end - 1) & REGION_ADDRESS_MASK)
+                | (attribute_index as u64) << PRLAR_ATTRINDX_SHIFT
+                | PRLAR_EN,
+        }
+    }
+
+    /// Returns a region covering `start..end` of normal write-back cacheable memory, which may be
+    /// read, written and executed at EL1 and above.
+    pub const fn normal(start: u64, end: u64) -> Self {
+        Self::new(start, end, PRBAR_AP_RW | PRBAR_SH_INNER, 1)
+    }
+
+    /// Returns a region covering `start..end` of device memory, which may be read and written but
+    /// not executed at EL1 and above.
+    pub const fn device(start: u64, end: u64) -> Self {
+        Self::new(start, end, PRBAR_AP_RW | PRBAR_XN, 0)
+    }
+}
+
+/// Provides the initial MPU regions which will be configured before any Rust code is run.
+///
+/// The first argument is an array of [`MpuRegion`]s, which are programmed in order starting from
+/// region 0. It must not contain more regions than the processor supports. Any regions not
+/// configured are left as they were on entry, which is disabled out of reset.
+///
+/// The `initial-mpu` feature must be enabled for this to be used.
+#[macro_export]
+macro_rules! initial_mpu {
+    ($regions:expr, $mair:expr, $sctlr:expr) => {
+        const INITIAL_MPU_REGION_COUNT: usize = {
+            let regions: &[$crate::MpuRegion] = &$regions;
+            regions.len()
+        };
+        static INITIAL_MPU_REGIONS: [$crate::MpuRegion; INITIAL_MPU_REGION_COUNT] = $regions;
+
+        $crate::enable_mpu!(INITIAL_MPU_REGIONS, INITIAL_MPU_REGION_COUNT, $mair, $sctlr);
+    };
+    ($regions:expr, $mair:expr) => {
+        $crate::initial_mpu!($regions, $mair, $crate::DEFAULT_SCTLR);
+    };
+    ($regions:expr) => {
+        $crate::initial_mpu!($regions, $crate::DEFAULT_MAIR, $crate::DEFAULT_SCTLR);
+    };
+}
+
+/// Generates assembly code to program the MPU regions and enable the MPU and caches before any Rust
+/// code is run.
+///
+/// This may be used indirectly via the [`initial_mpu!`] macro.
+#[macro_export]
+macro_rules! enable_mpu {
+    ($regions:path, $count:expr, $mair:expr, $sctlr:expr) => {
+        core::arch::global_asm!(
+            r".macro mov_i, reg:req, imm:req",
+                r"movz \reg, :abs_g3:\imm",
+                r"movk \reg, :abs_g2_nc:\imm",
+                r"movk \reg, :abs_g1_nc:\imm",
+                r"movk \reg, :abs_g0_nc:\imm",
+            r".endm",
+
+            ".section .init, \"ax\"",
+            ".global enable_mmu",
+            "enable_mmu:",
+                "mov_i x8, {MAIR_VALUE}",
+                "mov_i x9, {SCTLR_VALUE}",
+                "mov_i x10, {COUNT}",
+                "adrp x11, {regions}",
+                "add x11, x11, :lo12:{regions}",
+
+                "mrs x12, CurrentEL",
+                "ubfx x12, x12, #2, #2",
+                "cmp x12, #2",
+                "b.eq {enable_mpu_el2}",
+                "b {enable_mpu_el1}",
+
+            ".purgem mov_i",
+            MAIR_VALUE = const $mair,
+            SCTLR_VALUE = const $sctlr,
+            COUNT = const $count,
+            regions = sym $regions,
+            enable_mpu_el1 = sym $crate::__private::__enable_mpu_el1,
+            enable_mpu_el2 = sym $crate::__private::__enable_mpu_el2,
+        );
+    };
+}
+
+/// Programs the MPU regions and enables the MPU and caches, assuming that we are running at EL1.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code, early in the boot process.
+///
+/// Expects the MAIR value in x8, the SCTLR value in x9, the number of regions in x10 and the
+/// address of the array of regions in x11.
+///
+/// Clobbers x10-x14.
+#[doc(hidden)]
+#[unsafe(naked)]
+pub unsafe extern "C" fn __enable_mpu_el1() {
+    naked_asm!(
+        "msr mair_el1, x8",
+        // Program each region in turn, via PRSELR_EL1, PRBAR_EL1 and PRLAR_EL1.
+        "mov x12, #0",
+        "0:",
+        "cmp x12, x10",
+        "b.hs 1f",
+        "msr S3_0_C6_C2_1, x12",
+        "isb",
+        "ldp x13, x14, [x11], #16",
+        "msr S3_0_C6_C8_0, x13",
+        "msr S3_0_C6_C8_1, x14",
+        "add x12, x12, #1",
+        "b 0b",
+        "1:",
+        // Ensure everything before this point has completed, then invalidate the instruction cache.
+        "dsb sy",
+        "isb",
+        "ic iallu",
+        "dsb nsh",
+        "isb",
+        // Configure SCTLR_EL1 to enable MPU and cache and don't proceed until this has completed.
+        "msr sctlr_el1, x9",
+        "isb",
+        "ret"
+    );
+}
+
+/// Programs the MPU regions and enables the MPU and caches, assuming that we are running at EL2.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code, early in the boot process.
+///
+/// Expects the MAIR value in x8, the SCTLR value in x9, the number of regions in x10 and the
+/// address of the array of regions in x11.
+///
+/// Clobbers x10-x14.
+#[doc(hidden)]
+#[unsafe(naked)]
+pub unsafe extern "C" fn __enable_mpu_el2() {
+    naked_asm!(
+        "msr mair_el2, x8",
+        // Program each region in turn, via PRSELR_EL2, PRBAR_EL2 and PRLAR_EL2.
+        "mov x12, #0",
+        "0:",
+        "cmp x12, x10",
+        "b.hs 1f",
+        "msr S3_4_C6_C2_1, x12",
+        "isb",
+        "ldp x13, x14, [x11], #16",
+        "msr S3_4_C6_C8_0, x13",
+        "msr S3_4_C6_C8_1, x14",
+        "add x12, x12, #1",
+        "b 0b",
+        "1:",
+        // Ensure everything before this point has completed, then invalidate the instruction cache.
+        "dsb sy",
+        "isb",
+        "ic iallu",
+        "dsb nsh",
+        "isb",
+        // Configure SCTLR_EL2 to enable MPU and cache and don't proceed until this has completed.
+        "msr sctlr_el2, x9",
+        "isb",
+        "ret"
+    );
+}
@@ -4,12 +4,18 @@
 
 //! Code to set up an initial pagetable.
 
-use core::arch::naked_asm;
+use crate::{
+    cache::{clean_dcache_range, invalidate_dcache_range},
+    mapping::Attributes,
+    sysreg::{current_el, read_sysreg},
+};
+use core::{arch::naked_asm, ptr::null};
 
 const MAIR_DEV_NGNRE: u64 = 0x04;
 const MAIR_MEM_WBWA: u64 = 0xff;
+const MAIR_MEM_NC: u64 = 0x44;
 /// The default value used for MAIR_ELx.
-pub const DEFAULT_MAIR: u64 = MAIR_DEV_NGNRE | MAIR_MEM_WBWA << 8;
+pub const DEFAULT_MAIR: u64 = MAIR_DEV_NGNRE | MAIR_MEM_WBWA << 8 | MAIR_MEM_NC << 16;
 
 /// 4 KiB granule size for TTBR1_ELx.
 const TCR_TG1_4KB: u64 = 0x2 << 30;
@@ -19,6 +25,10 @@ const TCR_EPD1: u64 = 0x1 << 23;
 const TCR_EL1_IPS_1TB: u64 = 0x2 << 32;
 /// 40 bits, 1 TiB.
 const TCR_EL2_PS_1TB: u64 = 0x2 << 16;
+/// 48 bits, 256 TiB.
+const TCR_EL1_IPS_256TB: u64 = 0x5 << 32;
+/// 48 bits, 256 TiB.
+const TCR_PS_256TB: u64 = 0x5 << 16;
 /// 4 KiB granule size for TTBR0_ELx.
 const TCR_TG0_4KB: u64 = 0x0 << 14;
 /// Translation table walks for TTBR0_ELx are inner sharable.
@@ -31,6 +41,19 @@ const TCR_RGN_OWB: u64 = 0x1 << 10;
 const TCR_RGN_IWB: u64 = 0x1 << 8;
 /// Size offset for TTBR0_ELx is 2**39 bytes (512 GiB).
 const TCR_T0SZ_512: u64 = 64 - 39;
+/// Size offset for TTBR0_ELx is 2**48 bytes (256 TiB), so translation starts at level 0.
+const TCR_T0SZ_256T: u64 = 64 - 48;
+/// Translation table walks for TTBR1_EL1 are inner sharable.
+const TCR_SH1_INNER: u64 = 0x3 << 28;
+/// Translation table walks for TTBR1_EL1 are outer write-back read-allocate write-allocate
+/// cacheable.
+const TCR_ORGN1_WB: u64 = 0x1 << 26;
+/// Translation table walks for TTBR1_EL1 are inner write-back read-allocate write-allocate
+/// cacheable.
+const TCR_IRGN1_WB: u64 = 0x1 << 24;
+/// Size offset for TTBR1_EL1 is 2**39 bytes (512 GiB), i.e. it covers addresses from
+/// 0xffff_ff80_0000_0000 upwards.
+const TCR_T1SZ_512: u64 = (64 - 39) << 16;
 /// The default value used for TCR_EL1.
 pub const DEFAULT_TCR_EL1: u64 = TCR_EL1_IPS_1TB
     | TCR_TG1_4KB
@@ -40,12 +63,43 @@ pub const DEFAULT_TCR_EL1: u64 = TCR_EL1_IPS_1TB
     | TCR_RGN_OWB
     | TCR_RGN_IWB
     | TCR_T0SZ_512;
+/// The default value used for TCR_EL1 when an initial pagetable is provided for both TTBR0_EL1 and
+/// TTBR1_EL1.
+pub const DEFAULT_DUAL_TCR_EL1: u64 = TCR_EL1_IPS_1TB
+    | TCR_TG1_4KB
+    | TCR_SH1_INNER
+    | TCR_ORGN1_WB
+    | TCR_IRGN1_WB
+    | TCR_T1SZ_512
+    | TCR_TG0_4KB
+    | TCR_SH_INNER
+    | TCR_RGN_OWB
+    | TCR_RGN_IWB
+    | TCR_T0SZ_512;
 /// The default value used for TCR_EL2.
 pub const DEFAULT_TCR_EL2: u64 =
     TCR_EL2_PS_1TB | TCR_TG0_4KB | TCR_SH_INNER | TCR_RGN_OWB | TCR_RGN_IWB | TCR_T0SZ_512;
 /// The default value used for TCR_EL3.
 pub const DEFAULT_TCR_EL3: u64 =
     TCR_TG0_4KB | TCR_RGN_OWB | TCR_RGN_IWB | TCR_SH_INNER | TCR_T0SZ_512;
+/// The value used for TCR_EL1 with a 48-bit virtual address space, for use with
+/// [`InitialPagetables`].
+pub const DEFAULT_TCR_48BIT_EL1: u64 = TCR_EL1_IPS_256TB
+    | TCR_TG1_4KB
+    | TCR_EPD1
+    | TCR_TG0_4KB
+    | TCR_SH_INNER
+    | TCR_RGN_OWB
+    | TCR_RGN_IWB
+    | TCR_T0SZ_256T;
+/// The value used for TCR_EL2 with a 48-bit virtual address space, for use with
+/// [`InitialPagetables`].
+pub const DEFAULT_TCR_48BIT_EL2: u64 =
+    TCR_PS_256TB | TCR_TG0_4KB | TCR_SH_INNER | TCR_RGN_OWB | TCR_RGN_IWB | TCR_T0SZ_256T;
+/// The value used for TCR_EL3 with a 48-bit virtual address space, for use with
+/// [`InitialPagetables`].
+pub const DEFAULT_TCR_48BIT_EL3: u64 =
+    TCR_PS_256TB | TCR_TG0_4KB | TCR_RGN_OWB | TCR_RGN_IWB | TCR_SH_INNER | TCR_T0SZ_256T;
 
 /// Stage 1 instruction access cacheability is unaffected.
 const SCTLR_ELX_I: u64 = 0x1 << 12;
@@ -61,6 +115,12 @@ const SCTLR_ELX_SPAN: u64 = 0x1 << 23;
 const SCTLR_ELX_SED: u64 = 0x1 << 8;
 /// Various IT instructions are disabled at EL0 in aarch32 mode.
 const SCTLR_ELX_ITD: u64 = 0x1 << 7;
+/// Write permission implies execute-never.
+const SCTLR_ELX_WXN: u64 = 0x1 << 19;
+/// Alignment fault checking for all data accesses.
+const SCTLR_ELX_A: u64 = 0x1 << 1;
+/// With FEAT_LSE2, load-acquire, store-release and similar instructions don't require alignment.
+const SCTLR_ELX_NAA: u64 = 0x1 << 6;
 const SCTLR_ELX_RES1: u64 = (0x1 << 11) | (0x1 << 20) | (0x1 << 22) | (0x1 << 28) | (0x1 << 29);
 /// The default value used for SCTLR_ELx.
 pub const DEFAULT_SCTLR: u64 = SCTLR_ELX_M
@@ -71,16 +131,370 @@ pub const DEFAULT_SCTLR: u64 = SCTLR_ELX_M
     | SCTLR_ELX_I
     | SCTLR_ELX_SPAN
     | SCTLR_ELX_RES1;
+/// The default value used for SCTLR_ELx, with WXN also set so that writable memory is never
+/// executable.
+///
+/// This can only be used with an initial pagetable which maps the image's code read-only, e.g. with
+/// [`InitialPagetable::with_code`], as the normal memory mapped by
+/// [`InitialPagetable::with_memory`] is writable so wouldn't be executable.
+pub const DEFAULT_SCTLR_WXN: u64 = DEFAULT_SCTLR | SCTLR_ELX_WXN;
+
+/// How strictly the alignment of data accesses is checked, via SCTLR_ELx.A and nAA.
+///
+/// Unaligned accesses to device memory always fault, whichever of these is used. To use a
+/// different setting for the initial pagetable, apply it to the SCTLR value passed to
+/// [`initial_pagetable!`](crate::initial_pagetable):
+///
+/// ```rust,ignore
+/// initial_pagetable!(
+///     IDMAP,
+///     DEFAULT_MAIR,
+///     AlignmentCheck::Strict.apply_to(DEFAULT_SCTLR),
+///     DEFAULT_TCR
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AlignmentCheck {
+    /// Ordinary loads and stores to normal memory may be unaligned, but load-acquire,
+    /// store-release, exclusive and atomic instructions must be aligned, or with FEAT_LSE2 must not
+    /// cross a 16-byte boundary. This is what [`DEFAULT_SCTLR`] uses.
+    #[default]
+    Default,
+    /// All unaligned data accesses fault, which catches misaligned accesses early, e.g. to memory
+    /// which will later be device memory.
+    ///
+    /// This can't be used with the `mem` feature, whose memory functions rely on unaligned loads
+    /// and stores.
+    Strict,
+    /// As for [`AlignmentCheck::Default`], but with FEAT_LSE2 load-acquire and store-release
+    /// instructions may also be unaligned, for code which relies on them being so. Without
+    /// FEAT_LSE2 this is the same as [`AlignmentCheck::Default`].
+    Relaxed,
+}
+
+impl AlignmentCheck {
+    /// Returns the given SCTLR_ELx value with the A and nAA bits set for this setting.
+    ///
+    /// # Panics
+    ///
+    /// Panics for [`AlignmentCheck::Strict`] if the `mem` feature is enabled, which fails the build
+    /// when used for the SCTLR value passed to [`initial_pagetable!`](crate::initial_pagetable).
+    pub const fn apply_to(self, sctlr: u64) -> u64 {
+        assert!(
+            !(cfg!(feature = "mem") && matches!(self, Self::Strict)),
+            "AlignmentCheck::Strict can't be used with the `mem` feature's unaligned accesses",
+        );
+        let sctlr = sctlr & !(SCTLR_ELX_A | SCTLR_ELX_NAA);
+        match self {
+            Self::Default => sctlr,
+            Self::Strict => sctlr | SCTLR_ELX_A,
+            Self::Relaxed => sctlr | SCTLR_ELX_NAA,
+        }
+    }
+}
+
+/// The SH0 field of TCR_ELx.
+const TCR_SH0_MASK: u64 = 0x3 << 12;
+/// The ORGN0 field of TCR_ELx.
+const TCR_ORGN0_MASK: u64 = 0x3 << 10;
+/// The IRGN0 field of TCR_ELx.
+const TCR_IRGN0_MASK: u64 = 0x3 << 8;
+
+/// The shareability of memory, or of translation table walks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Shareability {
+    /// Not shared with other observers, e.g. for an image which only ever runs on one core and
+    /// doesn't share memory with coherent devices.
+    NonShareable,
+    /// Shared with all observers in the outer shareable domain.
+    OuterShareable,
+    /// Shared with the other cores in the inner shareable domain, as usual for SMP.
+    InnerShareable,
+}
+
+impl Shareability {
+    /// Returns the value of a 2-bit SH field for this shareability.
+    const fn field(self) -> u64 {
+        match self {
+            Self::NonShareable => 0b00,
+            Self::OuterShareable => 0b10,
+            Self::InnerShareable => 0b11,
+        }
+    }
+}
+
+/// The cacheability of translation table walks, for the IRGN and ORGN fields of TCR_ELx.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WalkCacheability {
+    /// Normal memory, non-cacheable.
+    NonCacheable,
+    /// Normal memory, write-back read-allocate write-allocate cacheable.
+    WriteBackWriteAllocate,
+    /// Normal memory, write-through read-allocate no write-allocate cacheable.
+    WriteThrough,
+    /// Normal memory, write-back read-allocate no write-allocate cacheable.
+    WriteBackNoWriteAllocate,
+}
+
+impl WalkCacheability {
+    /// Returns the value of a 2-bit RGN field for this cacheability.
+    const fn field(self) -> u64 {
+        match self {
+            Self::NonCacheable => 0b00,
+            Self::WriteBackWriteAllocate => 0b01,
+            Self::WriteThrough => 0b10,
+            Self::WriteBackNoWriteAllocate => 0b11,
+        }
+    }
+}
+
+/// The shareability and cacheability of translation table walks for TTBR0_ELx.
+///
+/// To use different attributes for the initial pagetable, apply them to the TCR value passed to
+/// [`initial_pagetable!`](crate::initial_pagetable):
+///
+/// ```rust,ignore
+/// initial_pagetable!(
+///     IDMAP,
+///     DEFAULT_MAIR,
+///     DEFAULT_SCTLR,
+///     WalkAttributes::SINGLE_CORE.apply_to(DEFAULT_TCR)
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WalkAttributes {
+    /// The shareability of the walks.
+    pub shareability: Shareability,
+    /// The inner cacheability of the walks.
+    pub inner: WalkCacheability,
+    /// The outer cacheability of the walks.
+    pub outer: WalkCacheability,
+}
+
+impl WalkAttributes {
+    /// Inner shareable and write-back cacheable walks, as used by the default TCR values.
+    pub const SMP: Self = Self {
+        shareability: Shareability::InnerShareable,
+        inner: WalkCacheability::WriteBackWriteAllocate,
+        outer: WalkCacheability::WriteBackWriteAllocate,
+    };
+
+    /// Non-shareable walks which are write-back cacheable, but don't write-allocate in the outer
+    /// cache, for an image running on a single core without coherent devices walking the tables.
+    pub const SINGLE_CORE: Self = Self {
+        shareability: Shareability::NonShareable,
+        inner: WalkCacheability::WriteBackWriteAllocate,
+        outer: WalkCacheability::WriteBackNoWriteAllocate,
+    };
+
+    /// Returns the given TCR_ELx value with the SH0, ORGN0 and IRGN0 fields set to these
+    /// attributes.
+    ///
+    /// The corresponding fields for TTBR1_EL1 are left unchanged.
+    pub const fn apply_to(self, tcr: u64) -> u64 {
+        (tcr & !(TCR_SH0_MASK | TCR_ORGN0_MASK | TCR_IRGN0_MASK))
+            | self.shareability.field() << 12
+            | self.outer.field() << 10
+            | self.inner.field() << 8
+    }
+}
 
 /// Provides an initial pagetable which can be used before any Rust code is run.
 ///
 /// The `initial-pagetable` feature must be enabled for this to be used.
+///
+/// With the `el1` feature, separate tables may be given for TTBR0_EL1 and TTBR1_EL1, e.g. an
+/// identity mapping for the low half of the address space and a mapping of the image in the high
+/// half:
+///
+/// ```rust,ignore
+/// initial_pagetable!(ttbr0: IDMAP, ttbr1: KERNEL_MAP);
+/// ```
+///
+/// In this case the TCR value defaults to [`DEFAULT_DUAL_TCR_EL1`](crate::DEFAULT_DUAL_TCR_EL1),
+/// which enables translation table walks for both.
+///
+/// For a 48-bit virtual address space, e.g. to map MMIO regions above 512 GiB, a static of type
+/// [`InitialPagetables`](crate::InitialPagetables) may be given with `va48:`, in which case the TCR
+/// value defaults to `DEFAULT_TCR_48BIT` for the exception level:
+///
+/// ```rust,ignore
+/// static IDMAP: InitialPagetables<2> = InitialPagetables::empty()
+///     .with_device(0, 1, SecurityState::Secure)
+///     .with_memory(1, 2, SecurityState::Secure)
+///     .with_device(768, 769, SecurityState::Secure);
+///
+/// initial_pagetable!(va48: IDMAP);
+/// ```
+///
+/// The pagetable is usually read-only data. To extend it later in place, e.g. with the
+/// [`mapping`](crate::mapping) helpers, it may be given with `writable:` instead, which puts it in
+/// the data section:
+///
+/// ```rust,ignore
+/// initial_pagetable!(writable: qemu_virt::INITIAL_PAGETABLE);
+/// ```
+///
+/// If the map depends on something only known at runtime, such as the size of DRAM, a
+/// [`PreparePagetable`](crate::PreparePagetable) function may be given with `prepare:` instead. The
+/// boot core calls it to fill in an empty pagetable before enabling the MMU, and secondary cores
+/// then use the same pagetable:
+///
+/// ```rust,ignore
+/// extern "C" fn prepare_idmap(
+///     idmap: &mut InitialPagetable,
+///     fdt_address: u64,
+///     _: u64,
+///     _: u64,
+///     _: u64,
+/// ) {
+///     *idmap = InitialPagetable::empty()
+///         .with_device(0, 1, SecurityState::Secure)
+///         .with_memory(1, 1 + dram_gib(fdt_address), SecurityState::Secure);
+/// }
+///
+/// initial_pagetable!(prepare: prepare_idmap);
+/// ```
+///
+/// The function runs on the boot stack with the MMU and caches off, before the bss section is
+/// zeroed, the data section is copied from ROM or the image's relocations are applied, so it must
+/// not use any statics. As all memory is then treated as Device-nGnRnE, unaligned accesses fault.
+/// So do the `DC ZVA` instructions which the `mem` feature's `memset` uses for large zeroing, so
+/// with that feature the function must not create zeroed values as large as a pagetable, as the
+/// example does with `InitialPagetable::empty()`. The pagetable it is given is already empty, so it
+/// can instead set the descriptors it needs in place, e.g. by copying them from a constant
+/// pagetable.
+///
+/// If the SCTLR value sets WXN, such as [`DEFAULT_SCTLR_WXN`](crate::DEFAULT_SCTLR_WXN), the build
+/// fails unless the pagetable maps the block containing the image's origin from the memory layout
+/// read-only and executable, or maps something so if the layout doesn't give an origin, as the
+/// default read-write mappings of normal memory would all be execute-never. An image which is
+/// loaded elsewhere than its origin must make sure that its load address is mapped so.
 #[cfg(any(feature = "el1", feature = "el2", feature = "el3"))]
 #[macro_export]
 macro_rules! initial_pagetable {
+    (va48: $tables:path, $mair:expr, $sctlr:expr, $tcr:expr) => {
+        static INITIAL_PAGETABLE: $crate::Level0Pagetable = $crate::Level0Pagetable::new(&$tables);
+
+        const _: () = assert!(
+            $tables.supports_sctlr($sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetables map nothing executable",
+        );
+
+        $crate::enable_mmu!(INITIAL_PAGETABLE, $mair, $sctlr, $tcr);
+    };
+    (va48: $tables:path, $mair:expr) => {
+        $crate::initial_pagetable!(
+            va48: $tables,
+            $mair,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR_48BIT
+        );
+    };
+    (va48: $tables:path) => {
+        $crate::initial_pagetable!(
+            va48: $tables,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR_48BIT
+        );
+    };
+    (writable: $value:expr, $mair:expr, $sctlr:expr, $tcr:expr) => {
+        #[unsafe(link_section = ".data.initial_pagetable")]
+        static mut INITIAL_PAGETABLE: $crate::InitialPagetable = $value;
+
+        const _: () = assert!(
+            $crate::InitialPagetable::supports_sctlr(&$value, $sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetable maps nothing executable",
+        );
+
+        $crate::__writable_pagetable_hook!(INITIAL_PAGETABLE);
+        $crate::enable_mmu!(INITIAL_PAGETABLE, $mair, $sctlr, $tcr);
+    };
+    (writable: $value:expr, $mair:expr) => {
+        $crate::initial_pagetable!(
+            writable: $value,
+            $mair,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR
+        );
+    };
+    (writable: $value:expr) => {
+        $crate::initial_pagetable!(
+            writable: $value,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR
+        );
+    };
+    (prepare: $prepare:path, $mair:expr, $sctlr:expr, $tcr:expr) => {
+        #[unsafe(link_section = ".noinit.initial_pagetable")]
+        static mut INITIAL_PAGETABLE: $crate::InitialPagetable = $crate::InitialPagetable::empty();
+
+        $crate::__prepare_pagetable_hook!(INITIAL_PAGETABLE, $prepare);
+        $crate::enable_mmu!(INITIAL_PAGETABLE, $mair, $sctlr, $tcr);
+    };
+    (prepare: $prepare:path, $mair:expr) => {
+        $crate::initial_pagetable!(
+            prepare: $prepare,
+            $mair,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR
+        );
+    };
+    (prepare: $prepare:path) => {
+        $crate::initial_pagetable!(
+            prepare: $prepare,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR
+        );
+    };
+    (ttbr0: $ttbr0:expr, ttbr1: $ttbr1:expr, $mair:expr, $sctlr:expr, $tcr:expr) => {
+        static INITIAL_PAGETABLE: $crate::InitialPagetable = $ttbr0;
+        static INITIAL_PAGETABLE_TTBR1: $crate::InitialPagetable = $ttbr1;
+
+        const _: () = assert!(
+            INITIAL_PAGETABLE.supports_sctlr($sctlr, $crate::__IMAGE_ORIGIN)
+                || ($crate::__IMAGE_ORIGIN.is_none()
+                    && INITIAL_PAGETABLE_TTBR1.supports_sctlr($sctlr, None)),
+            "SCTLR_ELx.WXN is set but the initial pagetables map nothing executable",
+        );
+
+        $crate::enable_mmu!(
+            ttbr0: INITIAL_PAGETABLE,
+            ttbr1: INITIAL_PAGETABLE_TTBR1,
+            $mair,
+            $sctlr,
+            $tcr
+        );
+    };
+    (ttbr0: $ttbr0:expr, ttbr1: $ttbr1:expr, $mair:expr) => {
+        $crate::initial_pagetable!(
+            ttbr0: $ttbr0,
+            ttbr1: $ttbr1,
+            $mair,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_DUAL_TCR_EL1
+        );
+    };
+    (ttbr0: $ttbr0:expr, ttbr1: $ttbr1:expr) => {
+        $crate::initial_pagetable!(
+            ttbr0: $ttbr0,
+            ttbr1: $ttbr1,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_DUAL_TCR_EL1
+        );
+    };
     ($value:expr, $mair:expr, $sctlr:expr, $tcr:expr) => {
         static INITIAL_PAGETABLE: $crate::InitialPagetable = $value;
 
+        const _: () = assert!(
+            INITIAL_PAGETABLE.supports_sctlr($sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetable maps nothing executable",
+        );
+
         $crate::enable_mmu!(INITIAL_PAGETABLE, $mair, $sctlr, $tcr);
     };
     ($value:expr, $mair:expr) => {
@@ -99,12 +513,133 @@ macro_rules! initial_pagetable {
 /// Provides an initial pagetable which can be used before any Rust code is run.
 ///
 /// The `initial-pagetable` feature must be enabled for this to be used.
+///
+/// For a 48-bit virtual address space, a static of type
+/// [`InitialPagetables`](crate::InitialPagetables) may be given with `va48:`.
+///
+/// A [`PreparePagetable`](crate::PreparePagetable) function may be given with `prepare:` to fill
+/// in the pagetable at runtime instead.
+///
+/// A pagetable given with `writable:` is put in the data section, so that it can be extended later
+/// in place.
+///
+/// If the SCTLR value sets WXN, such as [`DEFAULT_SCTLR_WXN`](crate::DEFAULT_SCTLR_WXN), the build
+/// fails unless the pagetable maps the block containing the image's origin from the memory layout
+/// read-only and executable, or maps something so if the layout doesn't give an origin, as the
+/// default read-write mappings of normal memory would all be execute-never. An image which is
+/// loaded elsewhere than its origin must make sure that its load address is mapped so.
 #[cfg(not(any(feature = "el1", feature = "el2", feature = "el3")))]
 #[macro_export]
 macro_rules! initial_pagetable {
+    (
+        va48: $tables:path,
+        $mair:expr,
+        $sctlr:expr,
+        $tcr_el1:expr,
+        $tcr_el2:expr,
+        $tcr_el3:expr
+    ) => {
+        static INITIAL_PAGETABLE: $crate::Level0Pagetable = $crate::Level0Pagetable::new(&$tables);
+
+        const _: () = assert!(
+            $tables.supports_sctlr($sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetables map nothing executable",
+        );
+
+        $crate::enable_mmu!(
+            INITIAL_PAGETABLE,
+            $mair,
+            $sctlr,
+            $tcr_el1,
+            $tcr_el2,
+            $tcr_el3
+        );
+    };
+    (va48: $tables:path) => {
+        initial_pagetable!(
+            va48: $tables,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR_48BIT_EL1,
+            $crate::DEFAULT_TCR_48BIT_EL2,
+            $crate::DEFAULT_TCR_48BIT_EL3
+        );
+    };
+    (
+        writable: $value:expr,
+        $mair:expr,
+        $sctlr:expr,
+        $tcr_el1:expr,
+        $tcr_el2:expr,
+        $tcr_el3:expr
+    ) => {
+        #[unsafe(link_section = ".data.initial_pagetable")]
+        static mut INITIAL_PAGETABLE: $crate::InitialPagetable = $value;
+
+        const _: () = assert!(
+            $crate::InitialPagetable::supports_sctlr(&$value, $sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetable maps nothing executable",
+        );
+
+        $crate::__writable_pagetable_hook!(INITIAL_PAGETABLE);
+        $crate::enable_mmu!(
+            INITIAL_PAGETABLE,
+            $mair,
+            $sctlr,
+            $tcr_el1,
+            $tcr_el2,
+            $tcr_el3
+        );
+    };
+    (writable: $value:expr) => {
+        initial_pagetable!(
+            writable: $value,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR_EL1,
+            $crate::DEFAULT_TCR_EL2,
+            $crate::DEFAULT_TCR_EL3
+        );
+    };
+    (
+        prepare: $prepare:path,
+        $mair:expr,
+        $sctlr:expr,
+        $tcr_el1:expr,
+        $tcr_el2:expr,
+        $tcr_el3:expr
+    ) => {
+        #[unsafe(link_section = ".noinit.initial_pagetable")]
+        static mut INITIAL_PAGETABLE: $crate::InitialPagetable = $crate::InitialPagetable::empty();
+
+        $crate::__prepare_pagetable_hook!(INITIAL_PAGETABLE, $prepare);
+        $crate::enable_mmu!(
+            INITIAL_PAGETABLE,
+            $mair,
+            $sctlr,
+            $tcr_el1,
+            $tcr_el2,
+            $tcr_el3
+        );
+    };
+    (prepare: $prepare:path) => {
+        initial_pagetable!(
+            prepare: $prepare,
+            $crate::DEFAULT_MAIR,
+            $crate::DEFAULT_SCTLR,
+            $crate::DEFAULT_TCR_EL1,
+            $crate::DEFAULT_TCR_EL2,
+            $crate::DEFAULT_TCR_EL3
+        );
+    };
     ($value:expr, $mair:expr, $sctlr:expr, $tcr_el1:expr, $tcr_el2:expr, $tcr_el3:expr) => {
         static INITIAL_PAGETABLE: $crate::InitialPagetable = $value;
 
+        const _: () = assert!(
+            INITIAL_PAGETABLE.supports_sctlr($sctlr, $crate::__IMAGE_ORIGIN),
+            "SCTLR_ELx.WXN is set but the initial pagetable maps nothing executable",
+        );
+
         $crate::enable_mmu!(
             INITIAL_PAGETABLE,
             $mair,
@@ -136,6 +671,181 @@ macro_rules! initial_pagetable {
     };
 }
 
+/// A function to fill in the initial pagetable at runtime, before the MMU is enabled, given to
+/// [`initial_pagetable!`](crate::initial_pagetable) with `prepare:`.
+///
+/// It is passed the empty pagetable and the boot parameters which the entry point was called with
+/// in x0-x3, e.g. the address of a device tree describing the size of DRAM.
+pub type PreparePagetable = extern "C" fn(&mut InitialPagetable, u64, u64, u64, u64);
+
+/// Defines the hook which the boot core's entry point calls to fill in a runtime initial pagetable.
+///
+/// This is used by [`initial_pagetable!`](crate::initial_pagetable) with `prepare:`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __prepare_pagetable_hook {
+    ($pagetable:path, $prepare:path) => {
+        const _: $crate::PreparePagetable = $prepare;
+
+        core::arch::global_asm!(
+            ".section .init.__aarch64_rt_prepare_pagetable, \"ax\"",
+            ".global __aarch64_rt_prepare_pagetable",
+            "__aarch64_rt_prepare_pagetable:",
+                "adrp x4, {pagetable}",
+                "add x4, x4, :lo12:{pagetable}",
+                "adrp x5, {prepare}",
+                "add x5, x5, :lo12:{prepare}",
+                "b {prepare_pagetable}",
+            pagetable = sym $pagetable,
+            prepare = sym $prepare,
+            prepare_pagetable = sym $crate::__private::__prepare_pagetable,
+        );
+    };
+}
+
+/// Sets up the boot stack, empties the pagetable and calls [`prepare_pagetable`], preserving the
+/// boot parameters.
+///
+/// The pagetable is zeroed here rather than in Rust, where the assignment may become a call to
+/// `memset`, which with the `mem` feature uses `DC ZVA` and so faults with the MMU off.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the boot core's entry point, before the MMU is enabled.
+///
+/// Expects the boot parameters in x0-x3, the address of the pagetable in x4 and the function to
+/// fill it in in x5. Clobbers the stack pointer and the registers which a call may clobber, other
+/// than x0-x3.
+#[doc(hidden)]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub unsafe extern "C" fn __prepare_pagetable() {
+    naked_asm!(
+        "adrp x9, boot_stack_end",
+        "add x9, x9, :lo12:boot_stack_end",
+        "mov sp, x9",
+        // An empty pagetable is all invalid descriptors, which are zero.
+        "mov x9, x4",
+        "add x10, x4, #{size}",
+        "0:",
+        "stp xzr, xzr, [x9], #16",
+        "cmp x9, x10",
+        "b.lo 0b",
+        "stp x29, x30, [sp, #-48]!",
+        "mov x29, sp",
+        "stp x0, x1, [sp, #16]",
+        "stp x2, x3, [sp, #32]",
+        "bl {prepare_pagetable}",
+        "ldp x2, x3, [sp, #32]",
+        "ldp x0, x1, [sp, #16]",
+        "ldp x29, x30, [sp], #48",
+        "ret",
+        prepare_pagetable = sym prepare_pagetable,
+        size = const size_of::<InitialPagetable>(),
+    )
+}
+
+/// Calls `prepare` to fill in the given empty pagetable, and then invalidates any stale copy of it
+/// in the data cache, as it was written with the caches off.
+///
+/// # Safety
+///
+/// `pagetable` must be valid to write, and nothing else may be using it.
+unsafe extern "C" fn prepare_pagetable(
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    pagetable: *mut InitialPagetable,
+    prepare: PreparePagetable,
+) {
+    // SAFETY: Our caller guarantees that the pagetable is valid and not otherwise in use.
+    let pagetable = unsafe { &mut *pagetable };
+    prepare(pagetable, arg0, arg1, arg2, arg3);
+    // SAFETY: The pagetable is page aligned and a whole page long, so doesn't share cache lines
+    // with anything else, and any dirty lines for it are stale.
+    unsafe {
+        invalidate_dcache_range(
+            (&raw const *pagetable).cast(),
+            size_of::<InitialPagetable>(),
+        );
+    }
+}
+
+/// Defines the hook which the boot core's entry point calls to get a writable initial pagetable
+/// ready for the MMU to use.
+///
+/// This is used by [`initial_pagetable!`](crate::initial_pagetable) with `writable:`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __writable_pagetable_hook {
+    ($pagetable:path) => {
+        core::arch::global_asm!(
+            ".section .init.__aarch64_rt_prepare_pagetable, \"ax\"",
+            ".global __aarch64_rt_prepare_pagetable",
+            "__aarch64_rt_prepare_pagetable:",
+                "adrp x4, {pagetable}",
+                "add x4, x4, :lo12:{pagetable}",
+                "b {prepare_writable_pagetable}",
+            pagetable = sym $pagetable,
+            prepare_writable_pagetable = sym $crate::__private::__prepare_writable_pagetable,
+        );
+    };
+}
+
+/// Gets a writable initial pagetable in the data section ready for the MMU to use.
+///
+/// With the `rom` feature this copies the pagetable from ROM, as the rest of the data section isn't
+/// copied until after the MMU is enabled. It then invalidates any stale copy of the pagetable in
+/// the data cache, as the MMU's table walks will be cacheable.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the boot core's entry point, before the MMU is enabled.
+///
+/// Expects the address of the pagetable in x4. Clobbers x9-x13.
+#[doc(hidden)]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub unsafe extern "C" fn __prepare_writable_pagetable() {
+    naked_asm!(
+        ".if {rom}",
+        // Find the pagetable's load address from its offset in the data section.
+        "adrp x9, data_begin",
+        "add x9, x9, :lo12:data_begin",
+        "adrp x10, data_lma",
+        "add x10, x10, :lo12:data_lma",
+        "sub x9, x4, x9",
+        "add x9, x10, x9",
+        "mov x10, x4",
+        "add x11, x4, #{size}",
+        "0:",
+        "ldp x12, x13, [x9], #16",
+        "stp x12, x13, [x10], #16",
+        "cmp x10, x11",
+        "b.lo 0b",
+        ".endif",
+        // CTR_EL0.DminLine is the log2 of the number of words in the smallest data cache line.
+        "mrs x9, ctr_el0",
+        "ubfx x9, x9, #16, #4",
+        "mov x10, #4",
+        "lsl x10, x10, x9",
+        "mov x11, x4",
+        "add x12, x4, #{size}",
+        "1:",
+        "dc ivac, x11",
+        "add x11, x11, x10",
+        "cmp x11, x12",
+        "b.lo 1b",
+        "dsb sy",
+        "ret",
+        rom = const cfg!(feature = "rom") as u8,
+        size = const size_of::<InitialPagetable>(),
+    )
+}
+
 /// Enables the MMU and caches, assuming that we are running at EL1.
 ///
 /// # Safety
@@ -193,9 +903,16 @@ pub unsafe extern "C" fn __enable_mmu_el2() {
         // caches.
         "msr mair_el2, x8",
         "msr ttbr0_el2, x11",
-        // Copy the supported PA range into TCR_EL2.IPS.
+        // Copy the supported PA range into TCR_EL2.PS, or TCR_EL2.IPS if HCR_EL2.E2H is set.
+        "mrs x8, hcr_el2",
+        "tbnz x8, #34, 0f",
+        "mrs x8, id_aa64mmfr0_el1",
+        "bfi x10, x8, #16, #3",
+        "b 1f",
+        "0:",
         "mrs x8, id_aa64mmfr0_el1",
         "bfi x10, x8, #32, #4",
+        "1:",
         "msr tcr_el2, x10",
         // Ensure everything before this point has completed, then invalidate any
         // potentially stale local TLB entries before they start being used.
@@ -231,9 +948,9 @@ pub unsafe extern "C" fn __enable_mmu_el3() {
         // caches.
         "msr mair_el3, x8",
         "msr ttbr0_el3, x11",
-        // Copy the supported PA range into TCR_EL3.IPS.
+        // Copy the supported PA range into TCR_EL3.PS.
         "mrs x8, id_aa64mmfr0_el1",
-        "bfi x10, x8, #32, #4",
+        "bfi x10, x8, #16, #3",
         "msr tcr_el3, x10",
         // Ensure everything before this point has completed, then invalidate any
         // potentially stale local TLB entries before they start being used.
@@ -254,9 +971,44 @@ pub unsafe extern "C" fn __enable_mmu_el3() {
 /// Rust code is run.
 ///
 /// This may be used indirectly via the [`initial_pagetable!`] macro.
+///
+/// Separate pagetables may be given for TTBR0_EL1 and TTBR1_EL1 with `ttbr0:` and `ttbr1:`, in
+/// which case the TCR value must enable translation table walks for both, such as
+/// [`DEFAULT_DUAL_TCR_EL1`](crate::DEFAULT_DUAL_TCR_EL1).
 #[cfg(feature = "el1")]
 #[macro_export]
 macro_rules! enable_mmu {
+    (ttbr0: $ttbr0:path, ttbr1: $ttbr1:path, $mair:expr, $sctlr:expr, $tcr:expr) => {
+        core::arch::global_asm!(
+            r".macro mov_i, reg:req, imm:req",
+                r"movz \reg, :abs_g3:\imm",
+                r"movk \reg, :abs_g2_nc:\imm",
+                r"movk \reg, :abs_g1_nc:\imm",
+                r"movk \reg, :abs_g0_nc:\imm",
+            r".endm",
+
+            ".section .init, \"ax\"",
+            ".global enable_mmu",
+            "enable_mmu:",
+                "mov_i x8, {MAIR_VALUE}",
+                "mov_i x9, {SCTLR_VALUE}",
+                "mov_i x10, {TCR_VALUE}",
+                "adrp x11, {ttbr0}",
+                // `__enable_mmu_el1` only sets TTBR0_EL1, and its ISB also covers this.
+                "adrp x12, {ttbr1}",
+                "msr ttbr1_el1, x12",
+
+                "b {enable_mmu_el1}",
+
+            ".purgem mov_i",
+            MAIR_VALUE = const $mair,
+            SCTLR_VALUE = const $sctlr,
+            TCR_VALUE = const $tcr,
+            ttbr0 = sym $ttbr0,
+            ttbr1 = sym $ttbr1,
+            enable_mmu_el1 = sym $crate::__private::__enable_mmu_el1,
+        );
+    };
     ($pagetable:path, $mair:expr, $sctlr:expr, $tcr:expr) => {
         core::arch::global_asm!(
             r".macro mov_i, reg:req, imm:req",
@@ -430,6 +1182,542 @@ macro_rules! enable_mmu {
     };
 }
 
+/// The MMU configuration for a secondary core started with
+/// [`start_core_with_mmu_config`](crate::start_core_with_mmu_config), in place of the initial
+/// pagetable and the configuration given to [`initial_pagetable!`].
+///
+/// This lets cores of an asymmetric system use different mappings, e.g. to run a different payload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct CoreMmuConfig {
+    /// The value for MAIR_ELx.
+    pub mair: u64,
+    /// The value for SCTLR_ELx, which should enable the MMU and caches.
+    pub sctlr: u64,
+    /// The value for TCR_ELx. The IPS or PS field is filled in from the supported physical address
+    /// range.
+    pub tcr: u64,
+    /// The value for TTBR0_ELx: the physical address of the root pagetable, which may include an
+    /// ASID in bits 48 to 63 at EL1.
+    pub ttbr0: u64,
+}
+
+impl CoreMmuConfig {
+    /// Returns a configuration using the given root pagetable, with the default MAIR, SCTLR and TCR
+    /// values for the exception level the image is built for.
+    ///
+    /// The pagetable's virtual address is used as its physical address, so it must be identity
+    /// mapped.
+    pub fn new(pagetable: &'static InitialPagetable) -> Self {
+        Self::with_asid(pagetable, 0)
+    }
+
+    /// Returns a configuration using the given root pagetable and ASID, with the default MAIR,
+    /// SCTLR and TCR values for the exception level the image is built for.
+    ///
+    /// The ASID must be 0 unless the core runs at EL1. The pagetable's virtual address is used as
+    /// its physical address, so it must be identity mapped.
+    pub fn with_asid(pagetable: &'static InitialPagetable, asid: u16) -> Self {
+        Self {
+            mair: DEFAULT_MAIR,
+            sctlr: DEFAULT_SCTLR,
+            tcr: match current_el() {
+                1 => DEFAULT_TCR_EL1,
+                2 => DEFAULT_TCR_EL2,
+                _ => DEFAULT_TCR_EL3,
+            },
+            ttbr0: (&raw const *pagetable) as u64 | u64::from(asid) << 48,
+        }
+    }
+
+    /// Returns the configuration which the current core's MMU is using, read from its system
+    /// registers for the current exception level.
+    pub fn current() -> Self {
+        // SAFETY: Reading the MMU configuration registers for the current exception level is
+        // always safe.
+        unsafe {
+            match current_el() {
+                1 => Self {
+                    mair: read_sysreg!("mair_el1"),
+                    sctlr: read_sysreg!("sctlr_el1"),
+                    tcr: read_sysreg!("tcr_el1"),
+                    ttbr0: read_sysreg!("ttbr0_el1"),
+                },
+                2 => Self {
+                    mair: read_sysreg!("mair_el2"),
+                    sctlr: read_sysreg!("sctlr_el2"),
+                    tcr: read_sysreg!("tcr_el2"),
+                    ttbr0: read_sysreg!("ttbr0_el2"),
+                },
+                _ => Self {
+                    mair: read_sysreg!("mair_el3"),
+                    sctlr: read_sysreg!("sctlr_el3"),
+                    tcr: read_sysreg!("tcr_el3"),
+                    ttbr0: read_sysreg!("ttbr0_el3"),
+                },
+            }
+        }
+    }
+
+    /// Returns the configuration with SCTLR_ELx.WXN set or cleared.
+    ///
+    /// When WXN is set all writable memory is execute-never, so the pagetable must map the code
+    /// which the core runs read-only, e.g. with [`InitialPagetable::with_code`].
+    pub fn with_wxn(mut self, wxn: bool) -> Self {
+        if wxn {
+            self.sctlr |= SCTLR_ELX_WXN;
+        } else {
+            self.sctlr &= !SCTLR_ELX_WXN;
+        }
+        self
+    }
+
+    /// Returns the configuration with SCTLR_ELx.A and nAA set for the given alignment checking.
+    ///
+    /// # Panics
+    ///
+    /// Panics for [`AlignmentCheck::Strict`] if the `mem` feature is enabled.
+    pub fn with_alignment_check(mut self, alignment_check: AlignmentCheck) -> Self {
+        self.sctlr = alignment_check.apply_to(self.sctlr);
+        self
+    }
+
+    /// Returns the configuration with the given attributes for translation table walks.
+    pub fn with_walk_attributes(mut self, walk_attributes: WalkAttributes) -> Self {
+        self.tcr = walk_attributes.apply_to(self.tcr);
+        self
+    }
+}
+
+/// The MMU configuration which the boot core enabled, for secondary cores to apply, or which a core
+/// had enabled before it was suspended, to apply when it resumes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub(crate) struct BootMmuConfig {
+    /// The configuration of the core's MMU.
+    pub(crate) config: CoreMmuConfig,
+    /// The value of TTBR1_EL1 at EL1, which isn't part of [`CoreMmuConfig`].
+    pub(crate) ttbr1: u64,
+}
+
+impl BootMmuConfig {
+    /// Returns the configuration which the current core's MMU is using.
+    pub(crate) fn current() -> Self {
+        Self {
+            config: CoreMmuConfig::current(),
+            ttbr1: if current_el() == 1 {
+                // SAFETY: Reading TTBR1_EL1 at EL1 is always safe.
+                unsafe { read_sysreg!("ttbr1_el1") }
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// The MMU configuration which the boot core enabled, recorded before `main` is called.
+///
+/// Secondary cores read this with their MMU off, so it must be cleaned to the point of coherency
+/// after it is written.
+pub(crate) static mut BOOT_MMU_CONFIG: BootMmuConfig = BootMmuConfig {
+    config: CoreMmuConfig {
+        mair: 0,
+        sctlr: 0,
+        tcr: 0,
+        ttbr0: 0,
+    },
+    ttbr1: 0,
+};
+
+/// Records the MMU configuration which the boot core enabled with the initial pagetable, for
+/// secondary cores to apply in `secondary_entry` rather than calling `enable_mmu` again.
+///
+/// This must only be called from the boot core before any secondary cores are started.
+pub(crate) fn record_boot_mmu_config() {
+    let boot_mmu_config = BootMmuConfig::current();
+    // SAFETY: Only the boot core writes this, before any secondary cores are started to read it.
+    unsafe {
+        (&raw mut BOOT_MMU_CONFIG).write(boot_mmu_config);
+    }
+    clean_dcache_range(
+        (&raw const BOOT_MMU_CONFIG).cast(),
+        size_of::<BootMmuConfig>(),
+    );
+}
+
+/// Returns the MMU configuration which the boot core enabled with the initial pagetable, before
+/// `main` was called.
+///
+/// Secondary cores started with [`start_core`](crate::start_core) or
+/// [`secondary_entry`](crate::secondary_entry) apply the same MAIR, SCTLR, TCR and TTBR values,
+/// other than the IPS or PS field of TCR which is filled in from each core's supported physical
+/// address range. At EL1 they also use the same TTBR1_EL1 value.
+pub fn boot_mmu_config() -> CoreMmuConfig {
+    // SAFETY: This is only written before `main` is called, by the boot core.
+    unsafe { (&raw const BOOT_MMU_CONFIG).read().config }
+}
+
 /// A hardcoded pagetable.
+///
+/// This may be built with the const methods below, which identity map 1 GiB blocks, or by filling
+/// in the descriptors directly.
 #[repr(C, align(4096))]
-pub struct InitialPagetable(pub [usize; 512]);
+pub struct InitialPagetable(pub [BlockDescriptor; 512]);
+
+/// A level 1 descriptor in an [`InitialPagetable`].
+///
+/// This has the same layout as the raw 64-bit descriptor which the MMU reads. Descriptors built
+/// with [`new`](Self::new) are always valid 1 GiB blocks, with the attributes and output address
+/// kept in their own fields; [`from_bits`](Self::from_bits) allows anything else, such as a table
+/// descriptor.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct BlockDescriptor(u64);
+
+impl BlockDescriptor {
+    /// An invalid descriptor, which maps nothing.
+    pub const INVALID: Self = Self(0);
+
+    /// Returns a valid descriptor mapping the 1 GiB block at the given physical address with the
+    /// given attributes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the output address isn't a 1 GiB aligned 48-bit address, or if the attributes
+    /// include bits other than the lower and upper attribute fields.
+    pub const fn new(output_address: u64, attributes: Attributes) -> Self {
+        assert!(
+            output_address & !DESC_BLOCK_ADDRESS_MASK == 0,
+            "Block output address must be 1 GiB aligned and fit in 48 bits"
+        );
+        assert!(
+            attributes.0 & !DESC_ATTRIBUTES_MASK == 0,
+            "Block attributes overlap the output address or descriptor type"
+        );
+        Self(DESC_VALID | attributes.0 | output_address)
+    }
+
+    /// Returns a descriptor with the given raw value.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw value of the descriptor.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns whether the descriptor is valid.
+    pub const fn is_valid(self) -> bool {
+        self.0 & DESC_VALID != 0
+    }
+
+    /// Returns whether the descriptor points to a next-level table rather than a block.
+    pub const fn is_table(self) -> bool {
+        self.is_valid() && self.0 & DESC_TABLE != 0
+    }
+
+    /// Returns the output address of a block descriptor.
+    pub const fn output_address(self) -> u64 {
+        self.0 & DESC_BLOCK_ADDRESS_MASK
+    }
+
+    /// Returns the lower and upper attributes of a block descriptor.
+    pub const fn attributes(self) -> Attributes {
+        Attributes(self.0 & DESC_ATTRIBUTES_MASK)
+    }
+
+    /// Returns whether the descriptor maps a read-only block which is executable at the current
+    /// exception level even if SCTLR_ELx.WXN is set, or points to a next-level table which might.
+    const fn is_executable_or_table(self) -> bool {
+        let attributes = self.attributes();
+        self.is_table()
+            || (self.is_valid()
+                && attributes.contains(Attributes::READ_ONLY)
+                && attributes.0 & DESC_PRIVILEGED_XN.0 == 0)
+    }
+}
+
+impl From<BlockDescriptor> for u64 {
+    fn from(descriptor: BlockDescriptor) -> Self {
+        descriptor.0
+    }
+}
+
+/// Block descriptor attributes for device memory, using attribute index 0.
+const DEVICE_ATTRIBUTES: Attributes = Attributes::ATTRIBUTE_INDEX_0
+    .union(Attributes::ACCESSED)
+    .union(Attributes::UXN);
+/// Block descriptor attributes for normal memory, using attribute index 1.
+const MEMORY_ATTRIBUTES: Attributes = Attributes::ATTRIBUTE_INDEX_1
+    .union(Attributes::INNER_SHAREABLE)
+    .union(Attributes::ACCESSED)
+    .union(Attributes::NON_GLOBAL);
+/// Block descriptor attributes for read-only normal memory, using attribute index 1.
+const CODE_ATTRIBUTES: Attributes = MEMORY_ATTRIBUTES.union(Attributes::READ_ONLY);
+
+const DESC_VALID: u64 = 1 << 0;
+/// The descriptor points to a next-level table rather than a block.
+const DESC_TABLE: u64 = 1 << 1;
+/// The SH field of a block descriptor.
+const DESC_SH_MASK: u64 = 3 << 8;
+/// The output address of a level 1 block descriptor, for a 4 KiB granule.
+const DESC_BLOCK_ADDRESS_MASK: u64 = 0x0000_ffff_c000_0000;
+/// The lower and upper attribute fields of a block descriptor.
+const DESC_ATTRIBUTES_MASK: u64 = 0xfffc_0000_0000_0ffc;
+/// The execute-never bits which apply to the exception level the image runs at.
+#[cfg(feature = "el1")]
+const DESC_PRIVILEGED_XN: Attributes = Attributes::PXN;
+/// The execute-never bits which apply to the exception level the image runs at.
+#[cfg(not(feature = "el1"))]
+const DESC_PRIVILEGED_XN: Attributes = Attributes::PXN.union(Attributes::UXN);
+
+/// The size of the region mapped by each entry in an initial pagetable.
+const BLOCK_SIZE: usize = 1 << 30;
+
+/// The physical address space which a region of an initial pagetable is mapped to.
+///
+/// This only matters at EL3 (or Secure EL1): the NS bit which selects the Non-secure physical
+/// address space is RES0 in the Non-secure EL1&0 and EL2 translation regimes, so images running
+/// there should always use [`SecurityState::Secure`], which leaves it clear.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SecurityState {
+    /// The Secure physical address space, or the only one for a Non-secure translation regime.
+    #[default]
+    Secure,
+    /// The Non-secure physical address space, e.g. for DRAM which a secure monitor shares with the
+    /// normal world. Mapping it as Secure instead would fault if the memory is only accessible to
+    /// the Non-secure state, or create cache lines which aren't coherent with the normal world's.
+    NonSecure,
+}
+
+impl SecurityState {
+    const fn attributes(self) -> Attributes {
+        match self {
+            Self::Secure => Attributes(0),
+            Self::NonSecure => Attributes::NS,
+        }
+    }
+}
+
+/// Returns the block descriptor attributes for normal memory with the given shareability.
+const fn memory_attributes(shareability: Shareability) -> Attributes {
+    Attributes((MEMORY_ATTRIBUTES.0 & !DESC_SH_MASK) | shareability.field() << 8)
+}
+
+impl InitialPagetable {
+    /// Returns a pagetable with nothing mapped.
+    pub const fn empty() -> Self {
+        Self([BlockDescriptor::INVALID; 512])
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as device memory
+    /// in the given physical address space.
+    pub const fn with_device(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, DEVICE_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as normal cacheable
+    /// memory in the given physical address space.
+    ///
+    /// The memory is writable and executable, so isn't executable if SCTLR_ELx.WXN is set.
+    pub const fn with_memory(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, MEMORY_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as normal cacheable
+    /// memory with the given shareability, in the given physical address space.
+    ///
+    /// [`with_memory`](Self::with_memory) maps memory as inner shareable, which is unnecessary for
+    /// an image which only runs on one core and doesn't share the memory with coherent devices.
+    pub const fn with_memory_shareability(
+        self,
+        start: usize,
+        end: usize,
+        security: SecurityState,
+        shareability: Shareability,
+    ) -> Self {
+        self.with_attributes(
+            start,
+            end,
+            memory_attributes(shareability).union(security.attributes()),
+        )
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as read-only
+    /// normal cacheable memory in the given physical address space, which is executable even if
+    /// SCTLR_ELx.WXN is set.
+    ///
+    /// The image's writable sections, including its stacks, must be mapped elsewhere.
+    pub const fn with_code(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, CODE_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Returns whether the pagetable can be used with the given SCTLR_ELx value, by an image whose
+    /// entry point is at the given address if it is known.
+    ///
+    /// If WXN is set, this requires that the 1 GiB block containing the entry point be mapped
+    /// read-only and executable, or by a next-level table which can't be checked here, as otherwise
+    /// nothing could run once the MMU is enabled. If the entry point isn't known then some block
+    /// must be mapped so. [`initial_pagetable!`](crate::initial_pagetable) checks this at build
+    /// time, with the origin of the image from the memory layout if it has one.
+    pub const fn supports_sctlr(&self, sctlr: u64, entry: Option<u64>) -> bool {
+        if sctlr & SCTLR_ELX_WXN == 0 {
+            return true;
+        }
+        if let Some(entry) = entry {
+            let block = entry as usize / BLOCK_SIZE;
+            return block < self.0.len() && self.0[block].is_executable_or_table();
+        }
+        let mut i = 0;
+        while i < self.0.len() {
+            if self.0[i].is_executable_or_table() {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` with the given
+    /// attributes, e.g. to use memory types other than those in [`DEFAULT_MAIR`].
+    pub const fn with_attributes(
+        mut self,
+        start: usize,
+        end: usize,
+        attributes: Attributes,
+    ) -> Self {
+        let mut block = start;
+        while block < end {
+            self.0[block] = BlockDescriptor::new((block * BLOCK_SIZE) as u64, attributes);
+            block += 1;
+        }
+        self
+    }
+}
+
+/// Hardcoded level 1 pagetables for the first `N` 512 GiB regions of a 48-bit virtual address
+/// space, to be used with a TCR value such as [`DEFAULT_TCR_48BIT_EL1`] via
+/// `initial_pagetable!(va48: ...)`.
+///
+/// Like [`InitialPagetable`] these identity map 1 GiB blocks, but the block numbers may go up to
+/// `N * 512`, so that MMIO regions at high physical addresses can be mapped.
+#[repr(C, align(4096))]
+pub struct InitialPagetables<const N: usize>(pub [InitialPagetable; N]);
+
+impl<const N: usize> InitialPagetables<N> {
+    /// Returns pagetables with nothing mapped.
+    pub const fn empty() -> Self {
+        Self([const { InitialPagetable::empty() }; N])
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as device memory
+    /// in the given physical address space.
+    pub const fn with_device(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, DEVICE_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as normal cacheable
+    /// memory in the given physical address space.
+    ///
+    /// The memory is writable and executable, so isn't executable if SCTLR_ELx.WXN is set.
+    pub const fn with_memory(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, MEMORY_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as normal cacheable
+    /// memory with the given shareability, in the given physical address space.
+    ///
+    /// [`with_memory`](Self::with_memory) maps memory as inner shareable, which is unnecessary for
+    /// an image which only runs on one core and doesn't share the memory with coherent devices.
+    pub const fn with_memory_shareability(
+        self,
+        start: usize,
+        end: usize,
+        security: SecurityState,
+        shareability: Shareability,
+    ) -> Self {
+        self.with_attributes(
+            start,
+            end,
+            memory_attributes(shareability).union(security.attributes()),
+        )
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` as read-only
+    /// normal cacheable memory in the given physical address space, which is executable even if
+    /// SCTLR_ELx.WXN is set.
+    pub const fn with_code(self, start: usize, end: usize, security: SecurityState) -> Self {
+        self.with_attributes(start, end, CODE_ATTRIBUTES.union(security.attributes()))
+    }
+
+    /// Returns whether the pagetables can be used with the given SCTLR_ELx value, as for
+    /// [`InitialPagetable::supports_sctlr`].
+    pub const fn supports_sctlr(&self, sctlr: u64, entry: Option<u64>) -> bool {
+        if sctlr & SCTLR_ELX_WXN == 0 {
+            return true;
+        }
+        if let Some(entry) = entry {
+            let table = entry as usize / (512 * BLOCK_SIZE);
+            return table < N
+                && self.0[table].supports_sctlr(sctlr, Some(entry % (512 * BLOCK_SIZE) as u64));
+        }
+        let mut i = 0;
+        while i < N {
+            if self.0[i].supports_sctlr(sctlr, None) {
+                return true;
+            }
+            i += 1;
+        }
+        N == 0
+    }
+
+    /// Identity maps the 1 GiB blocks from `start` up to but not including `end` with the given
+    /// attributes, as for [`InitialPagetable::with_attributes`].
+    pub const fn with_attributes(
+        mut self,
+        start: usize,
+        end: usize,
+        attributes: Attributes,
+    ) -> Self {
+        let mut block = start;
+        while block < end {
+            self.0[block / 512].0[block % 512] =
+                BlockDescriptor::new((block * BLOCK_SIZE) as u64, attributes);
+            block += 1;
+        }
+        self
+    }
+}
+
+/// A level 0 pagetable pointing to [`InitialPagetables`], which
+/// [`initial_pagetable!`](crate::initial_pagetable) generates for a 48-bit virtual address space.
+///
+/// The entries hold the addresses which the level 1 tables are linked at, so the image must be
+/// running at its link address when the MMU is enabled, e.g. with the `copy-to-link-address`
+/// feature.
+#[repr(C, align(4096))]
+pub struct Level0Pagetable([*const u8; 512]);
+
+// SAFETY: The pagetable is never modified, and the pointers in it are only used by the MMU.
+unsafe impl Sync for Level0Pagetable {}
+
+impl Level0Pagetable {
+    /// Returns a level 0 pagetable with table descriptors for each of the given level 1 tables.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than 512 level 1 tables.
+    pub const fn new<const N: usize>(tables: &'static InitialPagetables<N>) -> Self {
+        assert!(N <= 512);
+        let mut entries = [null(); 512];
+        let mut i = 0;
+        while i < N {
+            entries[i] = (&raw const tables.0[i])
+                .cast::<u8>()
+                .wrapping_add((DESC_VALID | DESC_TABLE) as usize);
+            i += 1;
+        }
+        Self(entries)
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Adapter from the initial pagetable to `aarch64-paging`.
+//!
+//! [`initial_idmap`] reads the translation tables which are currently active in TTBR0 for the
+//! current exception level, such as those set up by
+//! [`initial_pagetable!`](crate::initial_pagetable), and builds an [`IdMap`] with the same
+//! mappings. The application can then activate it and continue editing it with `aarch64-paging`,
+//! rather than describing its memory map a second time. As the new tables translate every address
+//! in the same way as the live ones, switching to them doesn't need break-before-make.
+//!
+//! The tables are allocated by `aarch64-paging`, so a global allocator is needed.
+
+use crate::{
+    address::{PhysAddr, phys_to_virt},
+    mapping::{DESC_ADDRESS_MASK, DESC_TABLE_OR_PAGE, DESC_VALID, TTBR_BADDR_MASK, level_shift},
+    sysreg::{current_el, read_sysreg},
+};
+use aarch64_paging::{
+    MapError,
+    descriptor::Attributes,
+    idmap::IdMap,
+    paging::{MemoryRegion, TranslationRegime},
+};
+
+/// The lower and upper attributes of a block or page descriptor.
+const DESC_ATTRIBUTES_MASK: u64 = 0x0000_0000_0000_0ffc | 0x07fc_0000_0000_0000;
+
+/// An error building an [`IdMap`] from the active translation tables.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InitialMapError {
+    /// A virtual address is mapped to a different physical address, which an `IdMap` can't
+    /// represent.
+    NotIdentityMapped {
+        /// The virtual address of the block or page.
+        va: usize,
+        /// The physical address it is mapped to.
+        pa: usize,
+    },
+    /// Adding a mapping to the new `IdMap` failed.
+    Map(MapError),
+}
+
+impl From<MapError> for InitialMapError {
+    fn from(e: MapError) -> Self {
+        Self::Map(e)
+    }
+}
+
+/// Returns an [`IdMap`] with the same mappings as the translation tables currently active in
+/// TTBR0 for the current exception level.
+///
+/// The active tables must use a 4 KiB granule and identity map everything they map, and all of
+/// their levels must be accessible at the virtual addresses given by
+/// [`phys_to_virt`](crate::address::phys_to_virt).
+pub fn initial_idmap(asid: usize) -> Result<IdMap, InitialMapError> {
+    // SAFETY: Reading the translation configuration of the current exception level is always
+    // safe.
+    let (ttbr, tcr, regime) = unsafe {
+        match current_el() {
+            1 => (
+                read_sysreg!("ttbr0_el1"),
+                read_sysreg!("tcr_el1"),
+                TranslationRegime::El1And0,
+            ),
+            2 => (
+                read_sysreg!("ttbr0_el2"),
+                read_sysreg!("tcr_el2"),
+                TranslationRegime::El2,
+            ),
+            _ => (
+                read_sysreg!("ttbr0_el3"),
+                read_sysreg!("tcr_el3"),
+                TranslationRegime::El3,
+            ),
+        }
+    };
+    let va_bits = 64 - (tcr & 0x3f) as usize;
+    let root_level = 4 - (va_bits - 12).div_ceil(9);
+    let root_entries = 1 << (va_bits - level_shift(root_level));
+
+    let mut idmap = IdMap::new(asid, root_level, regime);
+    copy_table(
+        &mut idmap,
+        ttbr & TTBR_BADDR_MASK,
+        root_level,
+        root_entries,
+        0,
+    )?;
+    Ok(idmap)
+}
+
+/// Adds the mappings of the given table and its subtables to `idmap`.
+fn copy_table(
+    idmap: &mut IdMap,
+    table: u64,
+    level: usize,
+    entries: usize,
+    va_base: usize,
+) -> Result<(), InitialMapError> {
+    let table = phys_to_virt(PhysAddr(table as usize)).as_ptr::<u64>();
+    let entry_size = 1 << level_shift(level);
+    for i in 0..entries {
+        // SAFETY: The table is part of the active translation tables, which physical memory is
+        // linearly mapped by, so it is accessible at this address. `entries` is no more than the
+        // number of entries it has.
+        let desc = unsafe { table.add(i).read_volatile() };
+        if desc & DESC_VALID == 0 {
+            continue;
+        }
+        let va = va_base + i * entry_size;
+        if level < 3 && desc & DESC_TABLE_OR_PAGE != 0 {
+            copy_table(idmap, desc & DESC_ADDRESS_MASK, level + 1, 512, va)?;
+            continue;
+        }
+        let pa = (desc & DESC_ADDRESS_MASK) as usize;
+        if pa != va {
+            return Err(InitialMapError::NotIdentityMapped { va, pa });
+        }
+        let flags =
+            Attributes::from_bits_retain((desc & (DESC_ATTRIBUTES_MASK | DESC_VALID)) as usize);
+        idmap.map_range(&MemoryRegion::new(va, va + entry_size), flags)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Parking a core until it is woken, e.g. for the idle loop of an async executor.
+//!
+//! A [`Parker`] lets a core sleep with `wfe` until it is unparked, either from an interrupt handler
+//! on the same core or from another core, without missing wakeups which happen just before it goes
+//! to sleep. [`Parker::waker`] turns it into a [`Waker`], so an executor can poll its futures
+//! until none are ready and then park until one of them is woken:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::park::Parker;
+//! use core::{pin::pin, task::{Context, Poll}};
+//!
+//! static PARKER: Parker = Parker::new();
+//!
+//! fn block_on<F: Future>(future: F) -> F::Output {
+//!     let mut future = pin!(future);
+//!     let waker = PARKER.waker();
+//!     let mut context = Context::from_waker(&waker);
+//!     loop {
+//!         if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+//!             return output;
+//!         }
+//!         PARKER.park();
+//!     }
+//! }
+//! ```
+//!
+//! Interrupts also wake a core from `wfe` if they aren't masked, so a parked core still handles
+//! them, and goes back to sleep afterwards unless the handler unparked it.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{RawWaker, RawWakerVTable, Waker},
+};
+
+/// Lets a core sleep until it is unparked.
+#[derive(Debug, Default)]
+pub struct Parker {
+    unparked: AtomicBool,
+}
+
+impl Parker {
+    /// Creates a new parker, which hasn't been unparked.
+    pub const fn new() -> Self {
+        Self {
+            unparked: AtomicBool::new(false),
+        }
+    }
+
+    /// Sleeps until the parker is unparked, or returns immediately if it has been unparked since
+    /// the last call.
+    ///
+    /// If the parker is unparked after it is checked but before the `wfe`, the event register will
+    /// already be set so the `wfe` won't wait.
+    pub fn park(&self) {
+        while !self.unparked.swap(false, Ordering::Acquire) {
+            // SAFETY: Waiting for an event doesn't affect memory safety.
+            unsafe {
+                asm!("wfe", options(nomem, nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Unparks the parker, waking the core which is parked on it if any, or making its next call
+    /// to [`park`](Self::park) return immediately.
+    ///
+    /// This may be called from an interrupt handler, or from another core.
+    pub fn unpark(&self) {
+        self.unparked.store(true, Ordering::Release);
+        // SAFETY: Barriers and sending an event don't affect memory safety. The barrier ensures
+        // that the parked core sees the store when it wakes.
+        unsafe {
+            asm!("dsb ish", "sev", options(nomem, nostack, preserves_flags));
+        }
+    }
+
+    /// Returns a [`Waker`] which unparks this parker when woken.
+    pub fn waker(&'static self) -> Waker {
+        // SAFETY: The vtable functions uphold the `RawWaker` contract, as the data pointer is a
+        // `&'static Parker` which is never freed.
+        unsafe { Waker::from_raw(raw_waker(self)) }
+    }
+}
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker, drop_waker);
+
+fn raw_waker(parker: &'static Parker) -> RawWaker {
+    RawWaker::new((parker as *const Parker).cast(), &WAKER_VTABLE)
+}
+
+/// # Safety
+///
+/// `data` must be a `&'static Parker`, as passed to `raw_waker`.
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    // SAFETY: Our caller promised that `data` is a `&'static Parker`.
+    raw_waker(unsafe { &*data.cast::<Parker>() })
+}
+
+/// # Safety
+///
+/// `data` must be a `&'static Parker`, as passed to `raw_waker`.
+unsafe fn wake_waker(data: *const ()) {
+    // SAFETY: Our caller promised that `data` is a `&'static Parker`.
+    unsafe { &*data.cast::<Parker>() }.unpark();
+}
+
+fn drop_waker(_data: *const ()) {}
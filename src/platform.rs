@@ -0,0 +1,322 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Platform runtime services, and memory map constants and initial pagetables for some common
+//! platforms.
+//!
+//! An application can provide services such as a console and entropy source by implementing the
+//! [`Platform`] trait and registering it with the [`platform!`](macro@crate::platform) macro.
+//! Optional parts of the runtime which need these services get them from [`platform()`], falling
+//! back to the trait's default implementations if no platform has been registered. For example:
+//!
+//! ```rust,ignore
+//! struct Board;
+//!
+//! impl Platform for Board {
+//!     fn console_write(&self, s: &str) {
+//!         // Write to the board's UART.
+//!     }
+//! }
+//!
+//! platform!(Board);
+//! ```
+//!
+//! The initial pagetables identity map RAM as normal memory and MMIO regions as device memory using
+//! 1 GiB blocks, with the attribute indices used by [`DEFAULT_MAIR`](crate::DEFAULT_MAIR), so they
+//! can be used with the default arguments of [`initial_pagetable!`](crate::initial_pagetable),
+//! e.g.:
+//!
+//! ```rust,ignore
+//! initial_pagetable!(aarch64_rt::platform::qemu_virt::INITIAL_PAGETABLE);
+//! ```
+
+#[cfg(feature = "initial-pagetable")]
+use crate::{InitialPagetable, SecurityState};
+use crate::{
+    bootlog, power,
+    sysreg::read_sysreg,
+    timer::{counter_frequency, virtual_counter},
+};
+use core::{alloc::Layout, arch::asm, fmt, fmt::Write, time::Duration};
+
+/// Runtime services provided by the platform.
+///
+/// All methods have default implementations, which use architectural features where possible.
+pub trait Platform: Sync {
+    /// Writes the given string to the platform's console.
+    ///
+    /// By default this does nothing.
+    fn console_write(&self, _s: &str) {}
+
+    /// Returns whether the console is ready to be written to.
+    ///
+    /// While this returns false, anything written to [`Console`] is kept in the
+    /// [`bootlog`] to be written once it is ready. By default this returns true.
+    fn console_ready(&self) -> bool {
+        true
+    }
+
+    /// Resets the system.
+    ///
+    /// By default this uses PSCI if available, or semihosting if the `semihosting` feature is
+    /// enabled.
+    fn reset(&self) -> ! {
+        power::default_reboot()
+    }
+
+    /// Returns a random number from a hardware entropy source, or `None` if none is available.
+    ///
+    /// By default this uses the `RNDR` instruction if the CPU supports it.
+    fn entropy(&self) -> Option<u64> {
+        rndr()
+    }
+
+    /// Returns the time since the system counter started.
+    ///
+    /// By default this is calculated from the virtual counter.
+    fn now(&self) -> Duration {
+        let ticks = u128::from(virtual_counter());
+        Duration::from_nanos((ticks * 1_000_000_000 / u128::from(counter_frequency())) as u64)
+    }
+
+    /// Handles a failure to allocate memory with the given layout.
+    ///
+    /// This is intended to be called by global allocators or allocation error handlers, so that the
+    /// policy for running out of memory (e.g. rebooting rather than halting) can be chosen by the
+    /// platform. By default this writes the size of the failed allocation to the console and then
+    /// panics.
+    fn out_of_memory(&self, layout: Layout) -> ! {
+        let _ = writeln!(
+            Console,
+            "Out of memory allocating {} bytes with alignment {}",
+            layout.size(),
+            layout.align()
+        );
+        panic!("Out of memory");
+    }
+}
+
+/// The platform used if none has been registered, which uses the default implementations.
+struct DefaultPlatform;
+
+impl Platform for DefaultPlatform {}
+
+crate::registry!(
+    #[doc(hidden)]
+    pub static __PLATFORM: [&'static dyn Platform]
+);
+
+/// Registers the given value as the [`Platform`] implementation for the image.
+///
+/// This must be used at most once in an image.
+#[macro_export]
+macro_rules! platform {
+    ($platform:expr) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".registry.__PLATFORM.1")]
+            static PLATFORM: &'static dyn $crate::platform::Platform = &$platform;
+        };
+    };
+}
+
+/// Returns the platform registered with [`platform!`](macro@crate::platform), or a default
+/// implementation if none has been registered.
+///
+/// # Panics
+///
+/// Panics if more than one platform has been registered.
+pub fn platform() -> &'static dyn Platform {
+    match __PLATFORM.entries() {
+        [] => &DefaultPlatform,
+        [platform] => *platform,
+        _ => panic!("More than one platform registered"),
+    }
+}
+
+/// A [`fmt::Write`] implementation which writes to the registered platform's console, or to the
+/// [`bootlog`] if the console isn't ready yet.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let platform = platform();
+        if platform.console_ready() {
+            bootlog::flush();
+            platform.console_write(s);
+        } else {
+            bootlog::capture(s);
+        }
+        Ok(())
+    }
+}
+
+/// Returns a random number from the `RNDR` instruction, if it is supported and succeeds.
+fn rndr() -> Option<u64> {
+    // SAFETY: Reading ID_AA64ISAR0_EL1 is always safe.
+    let isar0 = unsafe { read_sysreg!("id_aa64isar0_el1") };
+    if (isar0 >> 60) & 0xf == 0 {
+        return None;
+    }
+    let value: u64;
+    let failed: u64;
+    // SAFETY: Reading RNDR doesn't affect memory safety, and we checked that it is implemented.
+    unsafe {
+        asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "cset {failed}, eq",
+            value = out(reg) value,
+            failed = out(reg) failed,
+            options(nomem, nostack),
+        );
+    }
+    (failed == 0).then_some(value)
+}
+
+/// Returns an initial pagetable which maps the given ranges of 1 GiB blocks as device memory and
+/// normal memory respectively, and leaves the rest unmapped.
+///
+/// With the `el3` feature, only the first 1 GiB block of memory, which the image is loaded into, is
+/// mapped in the Secure physical address space. The rest of the memory is mapped in the Non-secure
+/// physical address space, as on these platforms it is the DRAM which a secure monitor hands to the
+/// normal world.
+#[cfg(feature = "initial-pagetable")]
+const fn identity_map(device: &[(usize, usize)], memory: &[(usize, usize)]) -> InitialPagetable {
+    let mut idmap = InitialPagetable::empty();
+    let mut i = 0;
+    while i < device.len() {
+        idmap = idmap.with_device(device[i].0, device[i].1, SecurityState::Secure);
+        i += 1;
+    }
+    let mut i = 0;
+    while i < memory.len() {
+        let (mut start, end) = memory[i];
+        if cfg!(feature = "el3") {
+            if i == 0 {
+                // Keep the block containing the image in the Secure physical address space.
+                idmap = idmap.with_memory(start, start + 1, SecurityState::Secure);
+                start += 1;
+            }
+            idmap = idmap.with_memory(start, end, SecurityState::NonSecure);
+        } else {
+            idmap = idmap.with_memory(start, end, SecurityState::Secure);
+        }
+        i += 1;
+    }
+    idmap
+}
+
+/// QEMU's `virt` machine.
+pub mod qemu_virt {
+    #[cfg(feature = "initial-pagetable")]
+    use crate::InitialPagetable;
+
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0x4000_0000;
+    /// Base address of the first PL011 UART.
+    pub const PL011_BASE_ADDRESS: usize = 0x900_0000;
+    /// Base address of the GIC distributor.
+    pub const GICD_BASE_ADDRESS: usize = 0x800_0000;
+    /// Base address of the GICv3 redistributors.
+    pub const GICR_BASE_ADDRESS: usize = 0x80a_0000;
+
+    /// An initial pagetable mapping the low MMIO region, the first 1 GiB of RAM and the high PCIe
+    /// ECAM region.
+    #[cfg(feature = "initial-pagetable")]
+    pub const INITIAL_PAGETABLE: InitialPagetable =
+        super::identity_map(&[(0, 1), (256, 257)], &[(1, 2)]);
+}
+
+/// The crosvm VMM.
+pub mod crosvm {
+    #[cfg(feature = "initial-pagetable")]
+    use crate::InitialPagetable;
+
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0x8000_0000;
+    /// Base address of the first 16550 UART.
+    pub const UART_BASE_ADDRESS: usize = 0x3f8;
+    /// Base address of the GIC distributor.
+    pub const GICD_BASE_ADDRESS: usize = 0x3fff_0000;
+    /// Base address of the GICv3 redistributors.
+    pub const GICR_BASE_ADDRESS: usize = 0x3ffd_0000;
+
+    /// An initial pagetable mapping the first 2 GiB as MMIO and the following 2 GiB as RAM.
+    #[cfg(feature = "initial-pagetable")]
+    pub const INITIAL_PAGETABLE: InitialPagetable = super::identity_map(&[(0, 2)], &[(2, 4)]);
+}
+
+/// The Arm Fixed Virtual Platform Base model.
+pub mod fvp_base {
+    #[cfg(feature = "initial-pagetable")]
+    use crate::InitialPagetable;
+
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0x8000_0000;
+    /// Base address of the first PL011 UART.
+    pub const PL011_BASE_ADDRESS: usize = 0x1c09_0000;
+    /// Base address of the GIC distributor.
+    pub const GICD_BASE_ADDRESS: usize = 0x2f00_0000;
+    /// Base address of the GICv3 redistributors.
+    pub const GICR_BASE_ADDRESS: usize = 0x2f10_0000;
+
+    /// An initial pagetable mapping the first 2 GiB as MMIO and the following 2 GiB as RAM.
+    #[cfg(feature = "initial-pagetable")]
+    pub const INITIAL_PAGETABLE: InitialPagetable = super::identity_map(&[(0, 2)], &[(2, 4)]);
+}
+
+/// The Raspberry Pi 3.
+///
+/// There is no initial pagetable for the Pi 3, as its peripherals are in the same 1 GiB block as
+/// RAM.
+pub mod raspberry_pi3 {
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0;
+    /// Base address of the peripherals.
+    pub const PERIPHERAL_BASE_ADDRESS: usize = 0x3f00_0000;
+    /// Base address of the PL011 UART.
+    pub const PL011_BASE_ADDRESS: usize = PERIPHERAL_BASE_ADDRESS + 0x20_1000;
+}
+
+/// The Raspberry Pi 4, with the peripherals in low peripheral mode.
+pub mod raspberry_pi4 {
+    #[cfg(feature = "initial-pagetable")]
+    use crate::InitialPagetable;
+
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0;
+    /// Base address of the peripherals.
+    pub const PERIPHERAL_BASE_ADDRESS: usize = 0xfe00_0000;
+    /// Base address of the PL011 UART.
+    pub const PL011_BASE_ADDRESS: usize = PERIPHERAL_BASE_ADDRESS + 0x20_1000;
+    /// Base address of the GIC-400 distributor.
+    pub const GICD_BASE_ADDRESS: usize = 0xff84_1000;
+    /// Base address of the GIC-400 CPU interface.
+    pub const GICC_BASE_ADDRESS: usize = 0xff84_2000;
+
+    /// An initial pagetable mapping the first 3 GiB as RAM and the following 1 GiB, which contains
+    /// the peripherals, as MMIO.
+    #[cfg(feature = "initial-pagetable")]
+    pub const INITIAL_PAGETABLE: InitialPagetable = super::identity_map(&[(3, 4)], &[(0, 3)]);
+}
+
+/// The Raspberry Pi 5.
+pub mod raspberry_pi5 {
+    #[cfg(feature = "initial-pagetable")]
+    use crate::InitialPagetable;
+
+    /// The start of RAM.
+    pub const RAM_BASE: usize = 0;
+    /// Base address of the debug PL011 UART.
+    pub const PL011_BASE_ADDRESS: usize = 0x10_7d00_1000;
+    /// Base address of the GIC-400 distributor.
+    pub const GICD_BASE_ADDRESS: usize = 0x10_7fff_9000;
+    /// Base address of the GIC-400 CPU interface.
+    pub const GICC_BASE_ADDRESS: usize = 0x10_7fff_a000;
+
+    /// An initial pagetable mapping the first 4 GiB as RAM, and the 2 GiB starting at 64 GiB which
+    /// contain the SoC peripherals as MMIO.
+    #[cfg(feature = "initial-pagetable")]
+    pub const INITIAL_PAGETABLE: InitialPagetable = super::identity_map(&[(64, 66)], &[(0, 4)]);
+}
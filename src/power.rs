@@ -0,0 +1,133 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Shutting down and rebooting the system, and powering off individual cores.
+//!
+//! These first try PSCI if the `psci` feature is enabled, using the conduit configured in the
+//! `psci` module, then if the `semihosting` feature is enabled fall back to a semihosting exit, so
+//! that they also work under emulators without PSCI. If neither works they halt the current core,
+//! waiting for interrupts with `wfi`.
+//!
+//! The semihosting call uses the `HLT` instruction, which is undefined if semihosting isn't
+//! enabled by the emulator or a debugger, and so causes a synchronous exception. The `semihosting`
+//! feature should therefore only be enabled for images which will always run with it.
+
+use crate::platform::platform;
+#[cfg(feature = "psci")]
+use crate::{
+    cache::{PowerDownScope, prepare_dcache_for_power_down},
+    psci::AutoConduit,
+    smp::disable_remote_calls,
+    timer::stop_periodic_tick,
+};
+use core::arch::asm;
+#[cfg(feature = "psci")]
+use smccc::psci::{cpu_off, system_off, system_reset};
+
+/// The semihosting operation number to exit the application.
+#[cfg(feature = "semihosting")]
+const SYS_EXIT: u32 = 0x18;
+/// The semihosting exit reason for a normal application exit.
+#[cfg(feature = "semihosting")]
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Shuts down the system.
+pub fn shutdown() -> ! {
+    #[cfg(feature = "psci")]
+    {
+        // A failure is ignored, as we fall back to other methods below.
+        let _ = system_off::<AutoConduit>();
+    }
+    #[cfg(feature = "semihosting")]
+    semihosting_exit(0);
+    halt()
+}
+
+/// Reboots the system.
+///
+/// This uses the [`Platform::reset`](crate::platform::Platform::reset) method of the registered
+/// platform, which by default tries PSCI and then semihosting if the `semihosting` feature is
+/// enabled. Semihosting has no way to reboot, so the fallback exits with a non-zero status instead.
+pub fn reboot() -> ! {
+    platform().reset()
+}
+
+/// Takes the current core offline and powers it off with PSCI `CPU_OFF`.
+///
+/// The steps must happen in this order, so that nothing can run on the core or allocate cache lines
+/// once its caches have been flushed:
+///
+/// 1. Mask all interrupts, so that no handler runs after the following steps.
+/// 2. Stop other cores queueing remote calls for this core, and run any which were already queued,
+///    so that they don't wait for it forever.
+/// 3. Stop this core's periodic tick and disable its virtual timer, leaving other cores' ticks
+///    running. Any deadlines which the application had set for this core are not migrated, so
+///    should be moved to another core before calling this.
+/// 4. Disable the data cache, clean and invalidate the levels private to the core by set/way, and
+///    take the core out of coherency, with [`prepare_dcache_for_power_down`].
+/// 5. Call `CPU_OFF`, via the conduit configured in the `psci` module.
+///
+/// Firmware is responsible for the GIC redistributor and anything else shared with other cores. If
+/// `CPU_OFF` fails, e.g. because this is the last core running a trusted OS, the core halts
+/// instead.
+///
+/// This is intended for use at EL1 or EL2, where PSCI is implemented by firmware.
+#[cfg(feature = "psci")]
+pub fn power_off_core() -> ! {
+    // SAFETY: Masking interrupts doesn't affect memory safety.
+    unsafe {
+        asm!(
+            "msr daifset, #0xf",
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    disable_remote_calls();
+    // This only affects the current core's tick and timer.
+    stop_periodic_tick();
+    // SAFETY: After this we only make the PSCI call and then halt, without sharing any data with
+    // other cores.
+    unsafe {
+        prepare_dcache_for_power_down(PowerDownScope::Core);
+    }
+    // A failure is ignored, as we halt below.
+    let _ = cpu_off::<AutoConduit>();
+    halt()
+}
+
+/// The default implementation of [`Platform::reset`](crate::platform::Platform::reset).
+pub(crate) fn default_reboot() -> ! {
+    #[cfg(feature = "psci")]
+    {
+        // A failure is ignored, as we fall back to other methods below.
+        let _ = system_reset::<AutoConduit>();
+    }
+    #[cfg(feature = "semihosting")]
+    semihosting_exit(1);
+    halt()
+}
+
+/// Exits via semihosting with the given status code, if semihosting is enabled.
+#[cfg(feature = "semihosting")]
+fn semihosting_exit(status: u64) {
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status];
+    // SAFETY: The semihosting exit call only reads the parameter block we pass.
+    unsafe {
+        asm!(
+            "hlt #0xf000",
+            inout("w0") SYS_EXIT => _,
+            in("x1") &block,
+            options(nostack, readonly),
+        );
+    }
+}
+
+/// Halts the current core forever.
+fn halt() -> ! {
+    loop {
+        // SAFETY: Waiting for an interrupt doesn't affect memory safety.
+        unsafe {
+            asm!("wfi", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Runtime selection of the SMCCC conduit used for PSCI calls.
+//!
+//! [`AutoConduit`] implements [`smccc::Call`] using whichever conduit has been configured, so it
+//! can be passed as the type parameter to [`start_core`](crate::start_core) and the `smccc::psci`
+//! functions rather than choosing between [`Hvc`] and [`Smc`] at each call site. The conduit may be
+//! configured with [`set_conduit`] or from the device tree with [`set_conduit_from_fdt`]; otherwise
+//! a default based on the current exception level is used.
+
+use crate::{
+    fdt::{FDT_BEGIN_NODE, FDT_END_NODE, FDT_NOP, FDT_PROP, align4, c_str, fdt_slice, read_be32},
+    sysreg::current_el,
+};
+use core::sync::atomic::{AtomicU8, Ordering};
+use smccc::{Call, Hvc, Smc};
+
+/// The configured conduit, or 0 if none has been configured.
+static CONDUIT: AtomicU8 = AtomicU8::new(0);
+
+/// A conduit for SMCCC calls.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Conduit {
+    /// Hypervisor Call.
+    Hvc = 1,
+    /// Secure Monitor Call.
+    Smc = 2,
+}
+
+/// Sets the conduit to use for subsequent calls via [`AutoConduit`].
+pub fn set_conduit(conduit: Conduit) {
+    CONDUIT.store(conduit as u8, Ordering::Release);
+}
+
+/// Returns the conduit which [`AutoConduit`] will use.
+///
+/// If none has been configured, this is SMC at EL2 and above, as there is no hypervisor to handle
+/// an HVC, or HVC at EL1, assuming that we are running in a VM.
+pub fn conduit() -> Conduit {
+    match CONDUIT.load(Ordering::Acquire) {
+        1 => Conduit::Hvc,
+        2 => Conduit::Smc,
+        _ if current_el() >= 2 => Conduit::Smc,
+        _ => Conduit::Hvc,
+    }
+}
+
+/// Sets the conduit from the `method` property of the `/psci` node of the given flattened device
+/// tree, and returns it.
+///
+/// Returns `None` and leaves the conduit unchanged if the device tree is invalid or has no valid
+/// PSCI `method`.
+///
+/// # Safety
+///
+/// `fdt` must point to a flattened device tree blob, which must be valid for reads of the size
+/// given in its header.
+pub unsafe fn set_conduit_from_fdt(fdt: *const u8) -> Option<Conduit> {
+    // SAFETY: Our caller promised that `fdt` points to a valid device tree blob.
+    let fdt = unsafe { fdt_slice(fdt)? };
+    let conduit = match find_psci_method(fdt)? {
+        b"hvc" => Conduit::Hvc,
+        b"smc" => Conduit::Smc,
+        _ => return None,
+    };
+    set_conduit(conduit);
+    Some(conduit)
+}
+
+/// An SMCCC conduit which uses the configured [`conduit`].
+pub struct AutoConduit;
+
+impl Call for AutoConduit {
+    fn call32(function: u32, args: [u32; 7]) -> [u32; 8] {
+        match conduit() {
+            Conduit::Hvc => Hvc::call32(function, args),
+            Conduit::Smc => Smc::call32(function, args),
+        }
+    }
+
+    fn call64(function: u32, args: [u64; 17]) -> [u64; 18] {
+        match conduit() {
+            Conduit::Hvc => Hvc::call64(function, args),
+            Conduit::Smc => Smc::call64(function, args),
+        }
+    }
+}
+
+/// Returns the value of the `method` property of the `/psci` node, without its NUL terminator.
+fn find_psci_method(fdt: &[u8]) -> Option<&[u8]> {
+    let structs = read_be32(fdt, 8)? as usize;
+    let strings = read_be32(fdt, 12)? as usize;
+    let mut offset = structs;
+    let mut depth = 0;
+    let mut in_psci = false;
+    loop {
+        let token = read_be32(fdt, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(fdt.get(offset..)?)?;
+                offset = align4(offset + name.len() + 1);
+                depth += 1;
+                in_psci = depth == 2 && (name == b"psci" || name.starts_with(b"psci@"));
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+                in_psci = false;
+            }
+            FDT_PROP => {
+                let len = read_be32(fdt, offset)? as usize;
+                let name_offset = read_be32(fdt, offset + 4)? as usize;
+                let value = fdt.get(offset + 8..offset + 8 + len)?;
+                offset = align4(offset + 8 + len);
+                if in_psci && c_str(fdt.get(strings + name_offset..)?)? == b"method" {
+                    return value.strip_suffix(b"\0").or(Some(value));
+                }
+            }
+            FDT_NOP => {}
+            _ => return None,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A PSCI proxy for images running as a hypervisor at EL2.
+//!
+//! [`PsciProxy`] handles the PSCI calls which a guest makes with `HVC`, or with `SMC` if HCR_EL2.TSC
+//! traps them to EL2. Each call is passed through a [`PsciPolicy`], which decides whether to forward
+//! it to the firmware at EL3 with an SMC, or to deny it.
+//!
+//! Some calls can't be forwarded as they are: the firmware would start a core at the guest's entry
+//! point at EL2 for `CPU_ON`, or resume at it at EL2 for `CPU_SUSPEND`. `CPU_ON` is instead passed
+//! to [`PsciPolicy::cpu_on`], which may start a host core with [`start_core`](crate::start_core)
+//! to run the guest's vCPU, and enter the guest there with [`enter_guest`]:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::psci_proxy::{PsciPolicy, PsciProxy, enter_guest};
+//!
+//! struct Policy;
+//!
+//! impl PsciPolicy for Policy {
+//!     fn cpu_on(&self, target: u64, entry_point: u64, context_id: u64) -> Result<(), Error> {
+//!         let stack = vcpu_stack(target).ok_or(Error::InvalidParameters)?;
+//!         // SAFETY: Each vCPU has its own stack, and the guest is responsible for its entry point.
+//!         unsafe {
+//!             start_core::<Smc, _, _>(target, stack, move || {
+//!                 configure_stage2();
+//!                 enter_guest(entry_point, context_id);
+//!             })
+//!         }
+//!     }
+//! }
+//!
+//! static PSCI: PsciProxy<Policy> = PsciProxy::new(Policy);
+//!
+//! extern "C" fn sync_lower(mut register_state: RegisterStateRef) {
+//!     if PSCI.handle_sync_lower(&mut register_state) {
+//!         return;
+//!     }
+//!     // Handle other exceptions.
+//! }
+//! ```
+//!
+//! Calls which the policy denies return `NOT_SUPPORTED` to the guest, as do `PSCI_FEATURES`
+//! queries for them, so the guest sees them as unimplemented.
+
+use crate::{
+    RegisterStateRef,
+    sysreg::{SCTLR_EL1_RES1, current_el, read_esr},
+};
+use core::arch::naked_asm;
+use smccc::{
+    Call, Smc,
+    psci::{
+        Error, PSCI_AFFINITY_INFO_32, PSCI_AFFINITY_INFO_64, PSCI_CPU_OFF, PSCI_CPU_ON_32,
+        PSCI_CPU_ON_64, PSCI_FEATURES, PSCI_MIGRATE_INFO_TYPE, PSCI_SYSTEM_OFF, PSCI_SYSTEM_RESET,
+        PSCI_SYSTEM_RESET2_32, PSCI_SYSTEM_RESET2_64, PSCI_VERSION,
+    },
+};
+
+/// Exception class for an HVC from AArch64.
+const ESR_EC_HVC64: u64 = 0x16;
+/// Exception class for an SMC from AArch64 trapped to EL2.
+const ESR_EC_SMC64: u64 = 0x17;
+
+/// The mask for the SMCCC function identifier bits which distinguish PSCI functions: the fast call
+/// and SMC64 bits, the owning entity and the function number.
+const PSCI_FUNCTION_MASK: u32 = 0xbfff_ffe0;
+/// The SMC32 function identifiers reserved for PSCI, with the function number cleared.
+const PSCI_FUNCTION_BASE: u32 = 0x8400_0000;
+
+/// SPSR_EL2 value to enter EL1h with all interrupts masked.
+const SPSR_EL1H_DAIF: u64 = 0x3c5;
+
+/// What [`PsciProxy`] should do with a PSCI call from a guest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PsciDecision {
+    /// Forward the call to the firmware with an SMC, and return its results to the guest.
+    Forward,
+    /// Return `NOT_SUPPORTED` to the guest without forwarding the call.
+    Deny,
+    /// Return the given value in x0 to the guest without forwarding the call.
+    Return(i64),
+}
+
+/// The policy which a [`PsciProxy`] applies to a guest's PSCI calls.
+pub trait PsciPolicy: Sync {
+    /// Handles a guest's `CPU_ON` call to start the vCPU with the given MPIDR at the given entry
+    /// point, with the context ID in x0.
+    ///
+    /// For a 32-bit call the arguments have been truncated to 32 bits. The default implementation
+    /// denies the call.
+    fn cpu_on(&self, target: u64, entry_point: u64, context_id: u64) -> Result<(), Error> {
+        _ = (target, entry_point, context_id);
+        Err(Error::Denied)
+    }
+
+    /// Decides what to do with a PSCI call from a guest, other than `CPU_ON` and `PSCI_FEATURES`.
+    ///
+    /// The default implementation forwards `PSCI_VERSION`, `CPU_OFF`, `AFFINITY_INFO`,
+    /// `MIGRATE_INFO_TYPE`, `SYSTEM_OFF`, `SYSTEM_RESET` and `SYSTEM_RESET2`, which are safe to
+    /// pass through when each vCPU runs on its own host core, and denies everything else.
+    ///
+    /// A forwarded `CPU_OFF` doesn't return if it succeeds, so the exception vector's count of
+    /// exceptions being handled stays raised on the core. This relies on the core being started
+    /// again through [`secondary_entry`](crate::secondary_entry), e.g. by
+    /// [`start_core`](crate::start_core), which resets the count; otherwise the guest's next call
+    /// on it would be reported as a double fault.
+    fn decide(&self, function: u32) -> PsciDecision {
+        match function {
+            PSCI_VERSION
+            | PSCI_CPU_OFF
+            | PSCI_AFFINITY_INFO_32
+            | PSCI_AFFINITY_INFO_64
+            | PSCI_MIGRATE_INFO_TYPE
+            | PSCI_SYSTEM_OFF
+            | PSCI_SYSTEM_RESET
+            | PSCI_SYSTEM_RESET2_32
+            | PSCI_SYSTEM_RESET2_64 => PsciDecision::Forward,
+            _ => PsciDecision::Deny,
+        }
+    }
+}
+
+/// Handles PSCI calls from guests of a hypervisor at EL2, according to the policy `P`.
+pub struct PsciProxy<P: PsciPolicy> {
+    policy: P,
+}
+
+impl<P: PsciPolicy> PsciProxy<P> {
+    /// Creates a new PSCI proxy applying the given policy.
+    pub const fn new(policy: P) -> Self {
+        Self { policy }
+    }
+
+    /// Handles a synchronous exception from a lower exception level, if it is a PSCI call made
+    /// with an `HVC`, or an `SMC` trapped to EL2.
+    ///
+    /// Returns whether it was handled, in which case the results have been written to the saved
+    /// registers, and the saved ELR moved past a trapped `SMC`. This must only be called from
+    /// [`sync_lower`](crate::ExceptionHandlers::sync_lower), and returns false if not running at
+    /// EL2.
+    pub fn handle_sync_lower(&self, register_state: &mut RegisterStateRef) -> bool {
+        if current_el() != 2 {
+            return false;
+        }
+        let trapped_smc = match (read_esr() >> 26) & 0x3f {
+            ESR_EC_HVC64 => false,
+            ESR_EC_SMC64 => true,
+            _ => return false,
+        };
+        let registers = register_state.as_ref().registers;
+        let function = registers[0] as u32;
+        if function & PSCI_FUNCTION_MASK != PSCI_FUNCTION_BASE {
+            return false;
+        }
+        let [_, arg1, arg2, arg3, ..] = registers;
+        let results = match function {
+            PSCI_CPU_ON_32 => result(self.policy.cpu_on(
+                arg1 & 0xffff_ffff,
+                arg2 & 0xffff_ffff,
+                arg3 & 0xffff_ffff,
+            )),
+            PSCI_CPU_ON_64 => result(self.policy.cpu_on(arg1, arg2, arg3)),
+            PSCI_FEATURES => match self.decision(arg1 as u32) {
+                PsciDecision::Forward => forward(function, &registers),
+                PsciDecision::Deny => result(Err(Error::NotSupported)),
+                PsciDecision::Return(_) => result(Ok(())),
+            },
+            _ => match self.decision(function) {
+                PsciDecision::Forward => forward(function, &registers),
+                PsciDecision::Deny => result(Err(Error::NotSupported)),
+                PsciDecision::Return(value) => [value as u64, 0, 0, 0],
+            },
+        };
+        // SAFETY: The caller of the PSCI function expects x0-x3 to be set to the results, and the
+        // return address of a trapped SMC to be the next instruction.
+        let state = unsafe { register_state.get_mut() };
+        state.registers[..4].copy_from_slice(&results);
+        if trapped_smc {
+            state.elr += 4;
+        }
+        true
+    }
+
+    /// Returns what to do with the given PSCI function. `CPU_ON` and `PSCI_FEATURES` are always
+    /// handled by the proxy itself, so are reported as supported.
+    fn decision(&self, function: u32) -> PsciDecision {
+        match function {
+            PSCI_CPU_ON_32 | PSCI_CPU_ON_64 => PsciDecision::Return(0),
+            PSCI_FEATURES => PsciDecision::Return(0),
+            _ => self.policy.decide(function),
+        }
+    }
+}
+
+/// Returns the results to give the guest for the given PSCI result.
+fn result(result: Result<(), Error>) -> [u64; 4] {
+    let value = match result {
+        Ok(()) => 0,
+        Err(error) => i64::from(error),
+    };
+    [value as u64, 0, 0, 0]
+}
+
+/// Forwards the given PSCI call to the firmware with an SMC, with the arguments from the guest's
+/// x1-x17, and returns the results for its x0-x3.
+fn forward(function: u32, registers: &[u64; 19]) -> [u64; 4] {
+    let mut args = [0; 17];
+    args.copy_from_slice(&registers[1..18]);
+    let results = Smc::call64(function, args);
+    [results[0], results[1], results[2], results[3]]
+}
+
+/// Enters a guest at the given entry point at EL1h on the current core, with the MMU and caches off,
+/// interrupts masked and the context ID in x0, as the firmware would for PSCI `CPU_ON`. All other
+/// general-purpose registers are zeroed, so no host state leaks to the guest.
+///
+/// This is for a [`PsciPolicy::cpu_on`] implementation which starts a host core to run the guest's
+/// vCPU, once it has configured anything the guest needs such as stage 2 translation.
+///
+/// # Panics
+///
+/// Panics if not running at EL2.
+///
+/// # Safety
+///
+/// The entry point must be valid code for the guest at EL1, and the core must be configured to
+/// run the guest safely, e.g. with stage 2 translation limiting what it can access.
+pub unsafe fn enter_guest(entry_point: u64, context_id: u64) -> ! {
+    assert_eq!(current_el(), 2);
+    // SAFETY: We are running at EL2, and our caller guarantees that the guest can safely be
+    // entered.
+    unsafe { enter_el1(entry_point, context_id) }
+}
+
+/// Enters the given entry point at EL1h from EL2 with the MMU and caches off, interrupts masked,
+/// the context ID in x0 and all other general-purpose registers zeroed.
+///
+/// # Safety
+///
+/// This must be called at EL2, and the entry point must be valid code for EL1.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_el1(entry_point: u64, context_id: u64) -> ! {
+    naked_asm!(
+        "msr elr_el2, x0",
+        "mov x9, #{spsr}",
+        "msr spsr_el2, x9",
+        "ldr x9, ={sctlr_el1}",
+        "msr sctlr_el1, x9",
+        "isb",
+        "mov x0, x1",
+        ".irp reg, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15",
+        r"mov x\reg, xzr",
+        ".endr",
+        ".irp reg, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30",
+        r"mov x\reg, xzr",
+        ".endr",
+        "eret",
+        ".ltorg",
+        spsr = const SPSR_EL1H_DAIF,
+        sctlr_el1 = const SCTLR_EL1_RES1,
+    )
+}
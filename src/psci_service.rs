@@ -0,0 +1,401 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A minimal PSCI implementation for images running as the EL3 monitor.
+//!
+//! [`PsciService`] serves PSCI 1.0 calls from lower exception levels, so that payloads which expect
+//! PSCI, such as Linux, can start and stop cores and power off or reset the system. It supports
+//! `PSCI_VERSION`, `PSCI_FEATURES`, `CPU_ON`, `CPU_OFF`, `AFFINITY_INFO`, `MIGRATE_INFO_TYPE`,
+//! `SYSTEM_OFF` and `SYSTEM_RESET`, but not `CPU_SUSPEND` or any of the optional functions.
+//!
+//! Powering cores and the system on and off is left to the platform, via the [`PsciPlatform`]
+//! trait. `CPU_ON` registers a warm boot entry for the target core with [`set_warm_boot_entry`] and
+//! then asks the platform to power it on, so when it comes out of reset it enters the caller's
+//! entry point at the caller's exception level and security state, with the MMU and caches off.
+//!
+//! The service is called from [`ExceptionHandlers::handle_smc`]:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{ExceptionHandlers, RegisterStateRef, psci_service::PsciService};
+//!
+//! static PSCI: PsciService<Board, 4, 2> = PsciService::new(Board);
+//!
+//! impl ExceptionHandlers for Exceptions {
+//!     fn handle_smc(register_state: &mut RegisterStateRef) -> bool {
+//!         PSCI.handle_smc(register_state)
+//!     }
+//! }
+//! ```
+//!
+//! Cores are assumed to be off until they either make an SMC or are started with `CPU_ON`, so a
+//! core which was started by other means should make a call such as `PSCI_VERSION` before others
+//! query it with `AFFINITY_INFO`.
+//!
+//! [`ExceptionHandlers::handle_smc`]: crate::ExceptionHandlers::handle_smc
+
+use crate::{
+    RegisterStateRef, Stack,
+    cache::{PowerDownScope, clean_invalidate_dcache_range, prepare_dcache_for_power_down},
+    reset::set_warm_boot_entry,
+    reset_exception_depth,
+    sysreg::{MPIDR_AFFINITY_MASK, SCTLR_EL1_RES1, SCTLR_EL2_RES1, read_mpidr, read_sysreg},
+};
+use core::{
+    arch::{asm, naked_asm},
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+use smccc::psci::{
+    Error, PSCI_AFFINITY_INFO_32, PSCI_AFFINITY_INFO_64, PSCI_CPU_OFF, PSCI_CPU_ON_32,
+    PSCI_CPU_ON_64, PSCI_FEATURES, PSCI_MIGRATE_INFO_TYPE, PSCI_SYSTEM_OFF, PSCI_SYSTEM_RESET,
+    PSCI_VERSION,
+};
+
+/// The PSCI version implemented, 1.0.
+const PSCI_VERSION_1_0: i64 = 0x1_0000;
+/// The `MIGRATE_INFO_TYPE` result for a Trusted OS which is not present or doesn't need migration.
+const MIGRATE_INFO_TYPE_NOT_PRESENT: i64 = 2;
+
+/// `AFFINITY_INFO` state for a core which is on, also used to track each core's state.
+const CORE_ON: u8 = 0;
+/// `AFFINITY_INFO` state for a core which is off.
+const CORE_OFF: u8 = 1;
+/// `AFFINITY_INFO` state for a core which is being turned on.
+const CORE_ON_PENDING: u8 = 2;
+
+/// Set in [`CoreEntry::mpidr`] once the entry is in use, as an MPIDR of 0 is valid.
+const MPIDR_VALID: u64 = 1 << 63;
+
+/// The EL2 field of ID_AA64PFR0_EL1, which is non-zero if EL2 is implemented.
+const ID_AA64PFR0_EL2_SHIFT: u32 = 8;
+
+/// The mode field of SPSR_EL3.
+const SPSR_M_MASK: u64 = 0xf;
+/// The D, A, I and F interrupt mask bits of SPSR_EL3.
+const SPSR_DAIF: u64 = 0xf << 6;
+
+/// The address of the [`PsciService`] which last handled a `CPU_ON` call, for the warm boot entry
+/// of the core it starts.
+static SERVICE: AtomicUsize = AtomicUsize::new(0);
+
+/// The platform-specific operations needed by [`PsciService`].
+pub trait PsciPlatform: Sync {
+    /// Powers on the core with the given MPIDR, so that it comes out of reset through the reset
+    /// vector.
+    ///
+    /// A warm boot entry has already been registered for the core, so it will enter the caller's
+    /// entry point once it is on.
+    fn power_on_core(&self, mpidr: u64) -> Result<(), Error>;
+
+    /// Powers off the current core.
+    ///
+    /// This is called with interrupts masked, after the data cache has been disabled and flushed
+    /// and the core taken out of coherency. By default it waits for interrupts forever, which
+    /// leaves the core in a low power state but doesn't power it off.
+    fn power_off_core(&self) -> ! {
+        loop {
+            // SAFETY: Waiting for an interrupt doesn't affect memory safety.
+            unsafe {
+                asm!("wfi", options(nomem, nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Powers off the system.
+    fn system_off(&self) -> !;
+
+    /// Resets the system.
+    fn system_reset(&self) -> !;
+}
+
+/// The PSCI state of a core, and where it should start when it is turned on.
+struct CoreEntry {
+    /// The MPIDR affinity fields of the core with [`MPIDR_VALID`] set, or 0 if the entry is unused.
+    mpidr: AtomicU64,
+    /// One of `CORE_ON`, `CORE_OFF` or `CORE_ON_PENDING`.
+    state: AtomicU8,
+    /// The entry point passed to `CPU_ON`.
+    entry_point: AtomicU64,
+    /// The context ID passed to `CPU_ON`.
+    context_id: AtomicU64,
+    /// The SCR_EL3 value of the caller of `CPU_ON`.
+    scr: AtomicU64,
+    /// The SPSR_EL3 value with which to enter the entry point.
+    spsr: AtomicU64,
+}
+
+impl CoreEntry {
+    const fn new() -> Self {
+        Self {
+            mpidr: AtomicU64::new(0),
+            state: AtomicU8::new(CORE_OFF),
+            entry_point: AtomicU64::new(0),
+            context_id: AtomicU64::new(0),
+            scr: AtomicU64::new(0),
+            spsr: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A PSCI implementation for up to `NUM_CORES` cores, with EL3 stacks of `STACK_PAGES` pages for
+/// cores started with `CPU_ON`.
+///
+/// Each core is given one of the `NUM_CORES` entries the first time it makes a PSCI call or is the
+/// target of `CPU_ON`, and keeps it. Once they are all taken, `CPU_ON` for any other core fails with
+/// `INVALID_PARAMETERS`.
+/// less than `NUM_CORES`.
+pub struct PsciService<P: PsciPlatform, const NUM_CORES: usize, const STACK_PAGES: usize> {
+    platform: P,
+    cores: [CoreEntry; NUM_CORES],
+    stacks: UnsafeCell<[Stack<STACK_PAGES>; NUM_CORES]>,
+}
+
+// SAFETY: Each stack is only used by the core with the corresponding index, and only while it is
+// starting after `CPU_ON` or handling exceptions at EL3. Everything else is atomic or `Sync`.
+unsafe impl<P: PsciPlatform, const NUM_CORES: usize, const STACK_PAGES: usize> Sync
+    for PsciService<P, NUM_CORES, STACK_PAGES>
+{
+}
+
+impl<P: PsciPlatform, const NUM_CORES: usize, const STACK_PAGES: usize>
+    PsciService<P, NUM_CORES, STACK_PAGES>
+{
+    /// Creates a new PSCI service using the given platform, with all cores off.
+    pub const fn new(platform: P) -> Self {
+        Self {
+            platform,
+            cores: [const { CoreEntry::new() }; NUM_CORES],
+            stacks: UnsafeCell::new([const { Stack::new() }; NUM_CORES]),
+        }
+    }
+
+    /// Handles an SMC from a lower exception level, if it is a supported PSCI function.
+    ///
+    /// Returns whether the function was handled, in which case the result has been written to the
+    /// saved x0. `CPU_OFF`, `SYSTEM_OFF` and `SYSTEM_RESET` don't return if they succeed.
+    pub fn handle_smc(&'static self, register_state: &mut RegisterStateRef) -> bool {
+        if let Some(index) = self.entry_index(read_mpidr(), true) {
+            let core = &self.cores[index];
+            // The caller must be on to have made the call.
+            core.state.store(CORE_ON, Ordering::Release);
+        }
+        let [function, arg1, arg2, arg3, ..] = register_state.registers;
+        let result = match function as u32 {
+            PSCI_VERSION => PSCI_VERSION_1_0,
+            PSCI_FEATURES => {
+                if is_supported(arg1 as u32) {
+                    0
+                } else {
+                    Error::NotSupported.into()
+                }
+            }
+            PSCI_CPU_ON_32 => {
+                self.cpu_on(arg1 & 0xffff_ffff, arg2 & 0xffff_ffff, arg3 & 0xffff_ffff)
+            }
+            PSCI_CPU_ON_64 => self.cpu_on(arg1, arg2, arg3),
+            PSCI_CPU_OFF => self.cpu_off(),
+            PSCI_AFFINITY_INFO_32 => self.affinity_info(arg1 & 0xffff_ffff, arg2 as u32),
+            PSCI_AFFINITY_INFO_64 => self.affinity_info(arg1, arg2 as u32),
+            PSCI_MIGRATE_INFO_TYPE => MIGRATE_INFO_TYPE_NOT_PRESENT,
+            PSCI_SYSTEM_OFF => self.platform.system_off(),
+            PSCI_SYSTEM_RESET => self.platform.system_reset(),
+            _ => return false,
+        };
+        // SAFETY: The caller of the SMC expects x0 to be set to the result.
+        unsafe {
+            register_state.get_mut().registers[0] = result as u64;
+        }
+        true
+    }
+
+    /// Starts the core with the given MPIDR at the given entry point, in the caller's exception
+    /// level and security state.
+    fn cpu_on(&'static self, target: u64, entry_point: u64, context_id: u64) -> i64 {
+        let target = target & MPIDR_AFFINITY_MASK;
+        let Some(index) = self.entry_index(target, true) else {
+            return Error::InvalidParameters.into();
+        };
+        let core = &self.cores[index];
+        if let Err(state) = core.state.compare_exchange(
+            CORE_OFF,
+            CORE_ON_PENDING,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            return if state == CORE_ON_PENDING {
+                Error::OnPending.into()
+            } else {
+                Error::AlreadyOn.into()
+            };
+        }
+        // SAFETY: Reading SCR_EL3 and SPSR_EL3 is safe at EL3, which we must be at to handle SMCs.
+        let (scr, spsr) = unsafe { (read_sysreg!("scr_el3"), read_sysreg!("spsr_el3")) };
+        core.entry_point.store(entry_point, Ordering::Relaxed);
+        core.context_id.store(context_id, Ordering::Relaxed);
+        core.scr.store(scr, Ordering::Relaxed);
+        core.spsr
+            .store((spsr & SPSR_M_MASK) | SPSR_DAIF, Ordering::Relaxed);
+        SERVICE.store(self as *const Self as usize, Ordering::Release);
+        let stack = self
+            .stacks
+            .get()
+            .cast::<Stack<STACK_PAGES>>()
+            .wrapping_add(index);
+        // SAFETY: The stack is reserved for the target core, which is off so not using it.
+        unsafe {
+            set_warm_boot_entry(target, stack, resume_core::<P, NUM_CORES, STACK_PAGES>);
+        }
+        match self.platform.power_on_core(target) {
+            Ok(()) => 0,
+            Err(error) => {
+                core.state.store(CORE_OFF, Ordering::Release);
+                error.into()
+            }
+        }
+    }
+
+    /// Powers off the current core.
+    fn cpu_off(&self) -> ! {
+        // SAFETY: Masking interrupts doesn't affect memory safety.
+        unsafe {
+            asm!(
+                "msr daifset, #0xf",
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        let core = self
+            .entry_index(read_mpidr(), false)
+            .map(|index| &self.cores[index]);
+        // SAFETY: After this we only publish that the core is off and call the platform to power it
+        // off, which doesn't return.
+        unsafe {
+            prepare_dcache_for_power_down(PowerDownScope::Core);
+        }
+        // Only report the core as off once it has finished with its caches, as `CPU_ON` may then
+        // start it again. With the data cache disabled the store goes straight to memory, so also
+        // discard any stale copies of it which other cores have cached.
+        if let Some(core) = core {
+            core.state.store(CORE_OFF, Ordering::Release);
+            clean_invalidate_dcache_range(core.state.as_ptr().cast(), size_of::<AtomicU8>());
+        }
+        // The SMC handler never returns, so isn't counted as being handled any more.
+        reset_exception_depth();
+        self.platform.power_off_core()
+    }
+
+    /// Returns the PSCI state of the core with the given MPIDR.
+    fn affinity_info(&self, target: u64, lowest_affinity_level: u32) -> i64 {
+        if lowest_affinity_level != 0 {
+            return Error::InvalidParameters.into();
+        }
+        match self.entry_index(target & MPIDR_AFFINITY_MASK, false) {
+            Some(index) => self.cores[index].state.load(Ordering::Acquire).into(),
+            // The core hasn't made a PSCI call or been started with `CPU_ON`, so is assumed off.
+            None => CORE_OFF.into(),
+        }
+    }
+
+    /// Returns the index of the entry for the core with the given MPIDR affinity fields, first
+    /// giving it a free entry if it doesn't have one yet and `claim` is true.
+    fn entry_index(&self, mpidr: u64, claim: bool) -> Option<usize> {
+        let entry = mpidr | MPIDR_VALID;
+        for (index, core) in self.cores.iter().enumerate() {
+            let current = match core.mpidr.load(Ordering::Acquire) {
+                0 if !claim => return None,
+                0 => {
+                    match core
+                        .mpidr
+                        .compare_exchange(0, entry, Ordering::AcqRel, Ordering::Acquire)
+                    {
+                        Ok(_) => return Some(index),
+                        Err(current) => current,
+                    }
+                }
+                current => current,
+            };
+            if current == entry {
+                return Some(index);
+            }
+        }
+        None
+    }
+}
+
+/// Returns whether the given PSCI function is supported by [`PsciService`].
+fn is_supported(function: u32) -> bool {
+    matches!(
+        function,
+        PSCI_VERSION
+            | PSCI_FEATURES
+            | PSCI_CPU_ON_32
+            | PSCI_CPU_ON_64
+            | PSCI_CPU_OFF
+            | PSCI_AFFINITY_INFO_32
+            | PSCI_AFFINITY_INFO_64
+            | PSCI_MIGRATE_INFO_TYPE
+            | PSCI_SYSTEM_OFF
+            | PSCI_SYSTEM_RESET
+    )
+}
+
+/// The warm boot entry for cores started by `CPU_ON`, which enters the entry point which was passed
+/// to it.
+extern "C" fn resume_core<P: PsciPlatform, const NUM_CORES: usize, const STACK_PAGES: usize>() -> !
+{
+    // SAFETY: `SERVICE` was set to a `&'static PsciService` with these type parameters before the
+    // warm boot entry was registered.
+    let service = unsafe {
+        &*(SERVICE.load(Ordering::Acquire) as *const PsciService<P, NUM_CORES, STACK_PAGES>)
+    };
+    let index = service
+        .entry_index(read_mpidr(), false)
+        .expect("Warm boot entry for a core which wasn't started by CPU_ON");
+    let core = &service.cores[index];
+    let entry_point = core.entry_point.load(Ordering::Relaxed);
+    let context_id = core.context_id.load(Ordering::Relaxed);
+    let scr = core.scr.load(Ordering::Relaxed);
+    let spsr = core.spsr.load(Ordering::Relaxed);
+    core.state.store(CORE_ON, Ordering::Release);
+    // SAFETY: The entry point and exception level were given by the caller of `CPU_ON`, which is
+    // responsible for them being valid.
+    unsafe { enter_lower_el(entry_point, context_id, scr, spsr) }
+}
+
+/// Enters the given entry point at a lower exception level with the MMU and caches off, with the
+/// given SCR_EL3 and SPSR_EL3 values, the context ID in x0 and all other general-purpose registers
+/// zeroed so that nothing leaks from EL3.
+///
+/// # Safety
+///
+/// The entry point must be valid code for the exception level and security state it is entered in.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_lower_el(entry_point: u64, context_id: u64, scr: u64, spsr: u64) -> ! {
+    naked_asm!(
+        "msr elr_el3, x0",
+        "msr scr_el3, x2",
+        "msr spsr_el3, x3",
+        // SCTLR_EL2 can only be written if EL2 is implemented.
+        "mrs x9, id_aa64pfr0_el1",
+        "ubfx x9, x9, #{el2_shift}, #4",
+        "cbz x9, 0f",
+        "ldr x9, ={sctlr_el2}",
+        "msr sctlr_el2, x9",
+        "0:",
+        "ldr x9, ={sctlr_el1}",
+        "msr sctlr_el1, x9",
+        "isb",
+        "mov x0, x1",
+        ".irp reg, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15",
+        r"mov x\reg, xzr",
+        ".endr",
+        ".irp reg, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30",
+        r"mov x\reg, xzr",
+        ".endr",
+        "eret",
+        ".ltorg",
+        el2_shift = const ID_AA64PFR0_EL2_SHIFT,
+        sctlr_el2 = const SCTLR_EL2_RES1,
+        sctlr_el1 = const SCTLR_EL1_RES1,
+    )
+}
@@ -0,0 +1,319 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Decoding of SErrors and RAS error records.
+
+use crate::{
+    address::VirtAddr,
+    mapping::unmap_region,
+    sysreg::{read_esr, read_sysreg, write_sysreg},
+};
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+};
+
+/// The maximum number of valid error records included in an [`SErrorReport`].
+pub const MAX_REPORTED_RECORDS: usize = 8;
+
+/// Exception class for an SError, in ESR_ELx.
+const ESR_EC_SERROR: u64 = 0x2f;
+/// Implementation defined syndrome, in ESR_ELx.ISS for an SError.
+const ESR_ISS_IDS: u64 = 1 << 24;
+/// External abort type, in ESR_ELx.ISS for an SError.
+const ESR_ISS_EA: u64 = 1 << 9;
+/// Asynchronous SError interrupt, in ESR_ELx.ISS.DFSC for an SError.
+const ESR_ISS_DFSC_ASYNC: u64 = 0b010001;
+
+/// Status register valid, in `ERR<n>STATUS`.
+const ERXSTATUS_V: u64 = 1 << 30;
+/// Address valid, in `ERR<n>STATUS`.
+const ERXSTATUS_AV: u64 = 1 << 31;
+/// Miscellaneous registers valid, in `ERR<n>STATUS`.
+const ERXSTATUS_MV: u64 = 1 << 26;
+
+/// The severity of an SError, from the AET field of the syndrome.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorSeverity {
+    /// Uncontainable (UC).
+    Uncontainable,
+    /// Unrecoverable (UEU).
+    Unrecoverable,
+    /// Restartable (UEO).
+    Restartable,
+    /// Recoverable (UER).
+    Recoverable,
+    /// Corrected (CE).
+    Corrected,
+}
+
+/// The decoded syndrome of an SError.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SErrorSyndrome {
+    /// The raw ISS value, which may be implementation defined.
+    pub iss: u32,
+    /// Whether the ISS has an implementation defined format.
+    pub implementation_defined: bool,
+    /// The severity of the error, if the architected syndrome includes it.
+    pub severity: Option<ErrorSeverity>,
+    /// The external abort type bit.
+    pub external_abort: bool,
+}
+
+impl SErrorSyndrome {
+    /// Decodes the given ESR value, returning `None` if it isn't for an SError.
+    pub fn from_esr(esr: u64) -> Option<Self> {
+        if (esr >> 26) & 0x3f != ESR_EC_SERROR {
+            return None;
+        }
+        let iss = esr & 0x1ff_ffff;
+        let implementation_defined = iss & ESR_ISS_IDS != 0;
+        let severity = if !implementation_defined && iss & 0x3f == ESR_ISS_DFSC_ASYNC {
+            match (iss >> 10) & 0b111 {
+                0b000 => Some(ErrorSeverity::Uncontainable),
+                0b001 => Some(ErrorSeverity::Unrecoverable),
+                0b010 => Some(ErrorSeverity::Restartable),
+                0b011 => Some(ErrorSeverity::Recoverable),
+                0b110 => Some(ErrorSeverity::Corrected),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        Some(Self {
+            iss: iss as u32,
+            implementation_defined,
+            severity,
+            external_abort: !implementation_defined && iss & ESR_ISS_EA != 0,
+        })
+    }
+}
+
+/// What to do about an SError, as decided by
+/// [`ExceptionHandlers::classify_serror`](crate::ExceptionHandlers::classify_serror).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SErrorAction {
+    /// The error is fatal, so panic with the report.
+    Fatal,
+    /// The error has been contained. Clear the reported error records and resume.
+    Contained,
+    /// The error is expected, e.g. due to a known device quirk. Resume without clearing the error
+    /// records.
+    Ignore,
+    /// The error is confined to a region of memory which can be given up, e.g. a buffer whose
+    /// contents are corrupt. Unmap the region, clear the reported error records and resume. If the
+    /// region can't be unmapped then the error is treated as fatal.
+    Quarantine(QuarantineRegion),
+}
+
+/// A region of virtual memory to unmap for [`SErrorAction::Quarantine`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QuarantineRegion {
+    va: VirtAddr,
+    len: usize,
+}
+
+impl QuarantineRegion {
+    /// Creates a region of the `len` bytes of virtual memory starting at `va`, to be unmapped with
+    /// [`unmap_region`], so it must meet the same requirements.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access the region after it is unmapped.
+    pub const unsafe fn new(va: VirtAddr, len: usize) -> Self {
+        Self { va, len }
+    }
+
+    /// Returns the start of the region.
+    pub const fn va(&self) -> VirtAddr {
+        self.va
+    }
+
+    /// Returns the length of the region in bytes.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the region is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A RAS error record with a valid status.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ErrorRecord {
+    /// The index of the record, as selected by ERRSELR_EL1.
+    pub index: u16,
+    /// The value of `ERR<n>STATUS`.
+    pub status: u64,
+    /// The value of `ERR<n>ADDR`, if it is valid.
+    pub address: Option<u64>,
+    /// The values of `ERR<n>MISC0` and `ERR<n>MISC1`, if they are valid.
+    pub misc: Option<[u64; 2]>,
+}
+
+impl ErrorRecord {
+    /// Reads the error record with the given index, returning `None` if its status isn't valid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the CPU doesn't implement FEAT_RAS.
+    pub fn read(index: u16) -> Option<Self> {
+        assert!(ras_implemented());
+        // SAFETY: FEAT_RAS is implemented, so the error record registers are too. Selecting and
+        // reading an error record doesn't affect memory safety.
+        unsafe {
+            select_record(index);
+            let status = read_sysreg!("s3_0_c5_c4_2");
+            if status & ERXSTATUS_V == 0 {
+                return None;
+            }
+            let address = (status & ERXSTATUS_AV != 0).then(|| read_sysreg!("s3_0_c5_c4_3"));
+            let misc = (status & ERXSTATUS_MV != 0)
+                .then(|| [read_sysreg!("s3_0_c5_c5_0"), read_sysreg!("s3_0_c5_c5_1")]);
+            Some(Self {
+                index,
+                status,
+                address,
+                misc,
+            })
+        }
+    }
+
+    /// Clears the status of this error record, so it won't be reported again.
+    pub fn clear(&self) {
+        // SAFETY: This record was read, so FEAT_RAS must be implemented. Writing back the status
+        // value clears the write-one-to-clear bits which were set.
+        unsafe {
+            select_record(self.index);
+            write_sysreg!("s3_0_c5_c4_2", self.status);
+        }
+    }
+}
+
+/// Writes the given index to ERRSELR_EL1.
+///
+/// # Safety
+///
+/// FEAT_RAS must be implemented.
+unsafe fn select_record(index: u16) {
+    // SAFETY: Our caller promised that FEAT_RAS is implemented.
+    unsafe {
+        write_sysreg!("s3_0_c5_c3_1", index);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns whether the CPU implements FEAT_RAS.
+pub fn ras_implemented() -> bool {
+    // SAFETY: Reading ID_AA64PFR0_EL1 is always safe.
+    let pfr0 = unsafe { read_sysreg!("id_aa64pfr0_el1") };
+    (pfr0 >> 28) & 0xf != 0
+}
+
+/// Returns the number of error records accessible via the system register interface, or 0 if
+/// FEAT_RAS is not implemented.
+pub fn error_record_count() -> u16 {
+    if ras_implemented() {
+        // SAFETY: FEAT_RAS is implemented, so ERRIDR_EL1 is too.
+        unsafe { read_sysreg!("s3_0_c5_c3_0") as u16 }
+    } else {
+        0
+    }
+}
+
+/// Returns an iterator over all error records with a valid status.
+pub fn error_records() -> impl Iterator<Item = ErrorRecord> {
+    (0..error_record_count()).filter_map(ErrorRecord::read)
+}
+
+/// A report about an SError, including the decoded syndrome and any valid error records.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SErrorReport {
+    /// The raw value of ESR_ELx.
+    pub esr: u64,
+    /// The decoded syndrome, if ESR_ELx is for an SError.
+    pub syndrome: Option<SErrorSyndrome>,
+    /// The value of DISR_EL1, if FEAT_RAS is implemented.
+    pub disr: Option<u64>,
+    /// The first few valid error records.
+    pub records: [Option<ErrorRecord>; MAX_REPORTED_RECORDS],
+}
+
+impl SErrorReport {
+    /// Captures a report for the SError currently being handled.
+    ///
+    /// This reads the ESR of the current exception level, so should be called from an SError
+    /// handler before anything else could cause another exception.
+    pub fn current() -> Self {
+        let esr = read_esr();
+        let disr = ras_implemented().then(|| {
+            // SAFETY: FEAT_RAS is implemented, so DISR_EL1 is too.
+            unsafe { read_sysreg!("s3_0_c12_c1_1") }
+        });
+        let mut records = [None; MAX_REPORTED_RECORDS];
+        for (slot, record) in records.iter_mut().zip(error_records()) {
+            *slot = Some(record);
+        }
+        Self {
+            esr,
+            syndrome: SErrorSyndrome::from_esr(esr),
+            disr,
+            records,
+        }
+    }
+}
+
+impl Display for SErrorReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "ESR {:#x}", self.esr)?;
+        if let Some(severity) = self.syndrome.and_then(|syndrome| syndrome.severity) {
+            write!(f, " ({severity:?})")?;
+        }
+        if let Some(disr) = self.disr {
+            write!(f, ", DISR {disr:#x}")?;
+        }
+        for record in self.records.iter().flatten() {
+            write!(f, ", ERR{}STATUS {:#x}", record.index, record.status)?;
+            if let Some(address) = record.address {
+                write!(f, " ADDR {address:#x}")?;
+            }
+            if let Some([misc0, misc1]) = record.misc {
+                write!(f, " MISC0 {misc0:#x} MISC1 {misc1:#x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Carries out the given action for the SError described by the given report, returning whether
+/// execution may resume.
+pub(crate) fn resolve_serror(report: &SErrorReport, action: SErrorAction) -> bool {
+    if report.syndrome.and_then(|syndrome| syndrome.severity) == Some(ErrorSeverity::Uncontainable)
+    {
+        return false;
+    }
+    match action {
+        SErrorAction::Fatal => false,
+        SErrorAction::Contained => {
+            for record in report.records.iter().flatten() {
+                record.clear();
+            }
+            true
+        }
+        SErrorAction::Ignore => true,
+        SErrorAction::Quarantine(region) => {
+            // SAFETY: The creator of the `QuarantineRegion` promised that nothing will access it
+            // after it is unmapped.
+            if unsafe { unmap_region(region.va, region.len) }.is_err() {
+                return false;
+            }
+            for record in report.records.iter().flatten() {
+                record.clear();
+            }
+            true
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Link-time registration of values into a slice.
+//!
+//! The `aarch64-rt` linker script keeps all input sections named `.registry.*`, sorted by name, in
+//! the `.registry` output section. The [`registry!`](crate::registry) macro places zero-sized begin
+//! and end markers around the section used for each registry's entries, so that the entries
+//! registered from any crate with [`register!`](crate::register) can be found at runtime.
+
+use core::{fmt::Debug, ops::Deref, slice};
+
+/// A slice of values collected at link time, declared with [`registry!`](crate::registry).
+pub struct Registry<T: 'static> {
+    begin: &'static [T; 0],
+    end: &'static [T; 0],
+}
+
+impl<T> Registry<T> {
+    /// Creates a registry from the given begin and end markers.
+    ///
+    /// # Safety
+    ///
+    /// `begin` and `end` must be placed by the linker immediately before and after a contiguous
+    /// array of valid values of type `T`. Use the [`registry!`](crate::registry) macro rather than
+    /// calling this directly.
+    #[doc(hidden)]
+    pub const unsafe fn new(begin: &'static [T; 0], end: &'static [T; 0]) -> Self {
+        const {
+            assert!(
+                size_of::<T>() != 0,
+                "registry entries must not be zero-sized"
+            )
+        };
+        Self { begin, end }
+    }
+
+    /// Returns all the values which have been registered, in an unspecified order.
+    pub fn entries(&self) -> &'static [T] {
+        let begin = self.begin.as_ptr();
+        let len = (self.end.as_ptr() as usize - begin as usize) / size_of::<T>();
+        // SAFETY: Our constructor's caller promised that the markers are placed around a
+        // contiguous array of valid values of type `T`, which are in a static so live forever.
+        unsafe { slice::from_raw_parts(begin, len) }
+    }
+}
+
+impl<T> Deref for Registry<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        self.entries()
+    }
+}
+
+impl<T: Debug> Debug for Registry<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.entries()).finish()
+    }
+}
+
+/// Declares a registry of values of some type, which may be added to by
+/// [`register!`](crate::register) from any crate linked into the image.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::{register, registry};
+///
+/// registry!(pub static INIT_FUNCTIONS: [fn()]);
+///
+/// register!(INIT_FUNCTIONS: fn() = init_uart);
+///
+/// fn init_all() {
+///     for init in INIT_FUNCTIONS.iter() {
+///         init();
+///     }
+/// }
+/// ```
+///
+/// The name of the registry is used to name its linker section, so it must be unique within the
+/// image. Registries are placed in read-only memory, so the element type must not have interior
+/// mutability.
+#[macro_export]
+macro_rules! registry {
+    ($(#[$attributes:meta])* $vis:vis static $name:ident: [$type:ty]) => {
+        $(#[$attributes])*
+        $vis static $name: $crate::Registry<$type> = {
+            #[used]
+            #[unsafe(link_section = concat!(".registry.", stringify!($name), ".0"))]
+            static BEGIN: [$type; 0] = [];
+            #[used]
+            #[unsafe(link_section = concat!(".registry.", stringify!($name), ".2"))]
+            static END: [$type; 0] = [];
+
+            // SAFETY: The linker script sorts registry sections by name, so the entries added by
+            // `register!` will be placed between these markers.
+            unsafe { $crate::Registry::new(&BEGIN, &END) }
+        };
+    };
+}
+
+/// Adds a value to a registry declared with [`registry!`](crate::registry).
+///
+/// The registry must be in scope under its declared name, and the type given must match its element
+/// type.
+#[macro_export]
+macro_rules! register {
+    ($registry:ident: $type:ty = $value:expr) => {
+        const _: () = {
+            // Ensure that the entry type matches the registry.
+            const _: fn() -> &'static $crate::Registry<$type> = || &$registry;
+
+            #[used]
+            #[unsafe(link_section = concat!(".registry.", stringify!($registry), ".1"))]
+            static ENTRY: $type = $value;
+        };
+    };
+}
@@ -0,0 +1,94 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Runtime relocation of position-independent images.
+//!
+//! If the image is linked as position-independent (e.g. with `-C relocation-model=pie` and
+//! `-pie`), the linker script collects its dynamic relocations in the `.rela.dyn` section. An
+//! application which is loaded or copies itself to an address other than the one it was linked at
+//! can then call [`relocate_image`] to apply them.
+
+use crate::boot_error::{BootError, boot_failed};
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The relocation type for a relative relocation, which adds the load offset to the addend.
+const R_AARCH64_RELATIVE: u64 = 1027;
+
+/// An ELF64 relocation entry with addend.
+#[repr(C)]
+struct Elf64Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+unsafe extern "C" {
+    static rela_begin: Elf64Rela;
+    static rela_end: Elf64Rela;
+}
+
+/// Whether `relocate_image` has been called.
+static RELOCATED: AtomicBool = AtomicBool::new(false);
+
+/// Applies the image's dynamic relocations, for the image linked at `link_base` but loaded at
+/// `load_base`.
+///
+/// Only relative relocations are supported, which are the only kind a static position-independent
+/// executable should have.
+///
+/// # Safety
+///
+/// This must be called at most once, before any code which depends on absolute addresses (such as
+/// function pointers, vtables or references stored in statics) is run. `load_base` and `link_base`
+/// must be the actual load and link addresses of the start of the image, and the image must be
+/// writable.
+///
+/// If there is a relocation of any type other than `R_AARCH64_RELATIVE` then the core stops with
+/// [`BootError::UnsupportedRelocation`], as panicking may depend on absolute addresses.
+pub unsafe fn relocate_image(load_base: usize, link_base: usize) {
+    let offset = load_base.wrapping_sub(link_base);
+    // The relocation section is found PC-relative, so this is its address as loaded.
+    let mut rela = addr_of!(rela_begin);
+    let end = addr_of!(rela_end);
+    let mut count = 0usize;
+    while rela < end {
+        count += 1;
+        if count.is_multiple_of(4096) {
+            crate::watchdog::kick();
+        }
+        // SAFETY: The linker script places an array of `Elf64Rela` between `rela_begin` and
+        // `rela_end`.
+        let entry = unsafe { &*rela };
+        if entry.info & 0xffff_ffff != R_AARCH64_RELATIVE {
+            boot_failed(BootError::UnsupportedRelocation, entry.info & 0xffff_ffff);
+        }
+        let place = (entry.offset as usize).wrapping_add(offset) as *mut usize;
+        // SAFETY: The relocation offset is within the image, which our caller promised is
+        // writable.
+        unsafe {
+            place.write_volatile((entry.addend as usize).wrapping_add(offset));
+        }
+        rela = rela.wrapping_add(1);
+    }
+    // SAFETY: The image now runs at the address it was loaded at, which our caller promised is
+    // mapped, so there is no longer any offset between the image's virtual and physical addresses.
+    unsafe {
+        crate::address::set_virt_offset(0);
+    }
+    RELOCATED.store(true, Ordering::Release);
+}
+
+/// Returns whether [`relocate_image`] has been called.
+pub fn is_relocated() -> bool {
+    RELOCATED.load(Ordering::Acquire)
+}
+
+/// Returns whether the image has any dynamic relocations which need to be applied if it is loaded
+/// at an address other than the one it was linked at.
+pub fn has_relocations() -> bool {
+    addr_of!(rela_begin) != addr_of!(rela_end)
+}
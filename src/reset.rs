@@ -0,0 +1,222 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Handling of cold and warm resets when running as the reset vector at EL3.
+//!
+//! When the `el3` feature is enabled, the entry point first does the minimal CPU initialisation
+//! required out of reset: it sets SCTLR_EL3 to a known little-endian state with the MMU and caches
+//! off. The SMPEN bit is then set on cores which need it for cache coherency, as for all entry
+//! points at EL3.
+//!
+//! It then checks whether the current core has a warm boot entry registered with
+//! [`set_warm_boot_entry`]. If so, this is a warm boot, e.g. a core being powered on again after a
+//! `CPU_ON` or `CPU_SUSPEND` served by this image, so it jumps to the registered resume function.
+//! Otherwise it is a cold boot, and continues through the normal entry point to `main`.
+//!
+//! The warm boot table is in the `.data` section, so it is initialised to empty by loading the
+//! image. With the `rom` feature RAM is not initialised on a cold reset, so the table is never
+//! checked and warm boot entries are not supported.
+
+use crate::entry::{init_secondary_core, reset_exception_depth};
+#[cfg(not(feature = "rom"))]
+use crate::{Stack, cache::clean_dcache_range, sysreg::MPIDR_AFFINITY_MASK};
+#[cfg(not(feature = "rom"))]
+use core::sync::atomic::Ordering;
+use core::{
+    arch::naked_asm,
+    mem::offset_of,
+    sync::atomic::{AtomicU64, AtomicUsize},
+};
+
+/// The maximum number of cores which may have a warm boot entry registered at once.
+pub const MAX_WARM_BOOT_CORES: usize = 16;
+
+/// Set in [`WarmBootEntry::mpidr`] once the entry has been claimed for a core, as an MPIDR of 0 is
+/// valid.
+const MPIDR_CLAIMED: u64 = 1 << 63;
+
+/// The value of SCTLR_EL3 set out of reset: little-endian, with the MMU, caches and alignment
+/// checking disabled.
+const SCTLR_EL3_RESET: u64 = (1 << 4)
+    | (1 << 5)
+    | (1 << 11)
+    | (1 << 16)
+    | (1 << 18)
+    | (1 << 22)
+    | (1 << 23)
+    | (1 << 28)
+    | (1 << 29);
+
+/// A registered resume path for some core.
+#[repr(C)]
+struct WarmBootEntry {
+    /// The affinity fields of the core's MPIDR with [`MPIDR_CLAIMED`] set, or 0 if the entry is
+    /// free.
+    mpidr: AtomicU64,
+    /// The resume function, or 0 if the core should cold boot.
+    resume: AtomicUsize,
+    /// The initial stack pointer for the resume function.
+    stack_end: AtomicUsize,
+}
+
+impl WarmBootEntry {
+    const fn new() -> Self {
+        Self {
+            mpidr: AtomicU64::new(0),
+            resume: AtomicUsize::new(0),
+            stack_end: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Warm boot entries, read by [`reset_init`] with the MMU and caches off.
+///
+/// This is explicitly placed in `.data` rather than `.bss`, as it must be valid before the entry
+/// point zeroes `.bss`.
+#[unsafe(link_section = ".data.warm_boot_table")]
+static WARM_BOOT_TABLE: [WarmBootEntry; MAX_WARM_BOOT_CORES] =
+    [const { WarmBootEntry::new() }; MAX_WARM_BOOT_CORES];
+
+/// Registers a function to be called when the core with the given MPIDR is next reset, instead of
+/// going through the cold boot path.
+///
+/// The resume function is called after the same initialisation as
+/// [`secondary_entry`](crate::secondary_entry), with the MMU enabled with the same configuration as
+/// the boot core, the exception vector set, and the stack pointer set to the end of the given
+/// stack. It stays registered for any subsequent warm boots until it is cleared with
+/// [`clear_warm_boot_entry`]. It may be called concurrently for different cores, but not for the
+/// same core.
+///
+/// # Safety
+///
+/// `stack` must point to a region of memory which is reserved for this core's stack whenever it
+/// warm boots, and must be mapped in the initial pagetable.
+///
+/// # Panics
+///
+/// Panics if there are already [`MAX_WARM_BOOT_CORES`] other cores with warm boot entries.
+#[cfg(not(feature = "rom"))]
+pub unsafe fn set_warm_boot_entry<const N: usize>(
+    mpidr: u64,
+    stack: *mut Stack<N>,
+    resume: extern "C" fn() -> !,
+) {
+    let mpidr = (mpidr & MPIDR_AFFINITY_MASK) | MPIDR_CLAIMED;
+    // Claim a free entry atomically, so that concurrent calls for different cores can't both
+    // take the same one.
+    let entry = WARM_BOOT_TABLE
+        .iter()
+        .find(|entry| entry.mpidr.load(Ordering::Acquire) == mpidr)
+        .or_else(|| {
+            WARM_BOOT_TABLE.iter().find(|entry| {
+                entry
+                    .mpidr
+                    .compare_exchange(0, mpidr, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            })
+        })
+        .expect("Too many warm boot entries");
+    entry.resume.store(0, Ordering::Release);
+    entry
+        .stack_end
+        .store(stack.wrapping_add(1) as usize, Ordering::Release);
+    entry
+        .resume
+        .store(resume as *const () as usize, Ordering::Release);
+    // The core will read the table with its MMU and caches off.
+    clean_dcache_range(
+        (entry as *const WarmBootEntry).cast(),
+        size_of::<WarmBootEntry>(),
+    );
+}
+
+/// Clears the warm boot entry for the core with the given MPIDR, if any, so that it will go through
+/// the cold boot path the next time it is reset.
+///
+/// This must not be called concurrently with [`set_warm_boot_entry`] for the same core.
+#[cfg(not(feature = "rom"))]
+pub fn clear_warm_boot_entry(mpidr: u64) {
+    let mpidr = (mpidr & MPIDR_AFFINITY_MASK) | MPIDR_CLAIMED;
+    for entry in &WARM_BOOT_TABLE {
+        if entry.mpidr.load(Ordering::Acquire) == mpidr {
+            entry.resume.store(0, Ordering::Release);
+            entry.mpidr.store(0, Ordering::Release);
+            clean_dcache_range(
+                (entry as *const WarmBootEntry).cast(),
+                size_of::<WarmBootEntry>(),
+            );
+        }
+    }
+}
+
+/// Does the minimal CPU initialisation required out of reset, then jumps to the registered resume
+/// function if this is a warm boot.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from the entry point, before anything else.
+///
+/// Clobbers x9-x14, and doesn't return on a warm boot.
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub(crate) unsafe extern "C" fn reset_init() {
+    naked_asm!(
+        ".macro mov_i, reg:req, imm:req",
+        r"movz \reg, :abs_g3:\imm",
+        r"movk \reg, :abs_g2_nc:\imm",
+        r"movk \reg, :abs_g1_nc:\imm",
+        r"movk \reg, :abs_g0_nc:\imm",
+        ".endm",
+        // Put SCTLR_EL3 into a known state.
+        "mov_i x9, {sctlr_el3}",
+        "msr sctlr_el3, x9",
+        "isb",
+        // Get the affinity fields of our MPIDR, as they are stored in a claimed entry.
+        "mrs x9, mpidr_el1",
+        "ubfx x10, x9, #32, #8",
+        "and x9, x9, #0xffffff",
+        "orr x9, x9, x10, lsl #32",
+        "orr x9, x9, #{mpidr_claimed}",
+        // Look for a warm boot entry for this core.
+        "adrp x10, {table}",
+        "add x10, x10, :lo12:{table}",
+        "mov x11, #{max_cores}",
+        "2:",
+        "cbz x11, 3f",
+        "sub x11, x11, #1",
+        "ldr x12, [x10, #{mpidr_offset}]",
+        "ldr x13, [x10, #{resume_offset}]",
+        "ldr x14, [x10, #{stack_end_offset}]",
+        "add x10, x10, #{entry_size}",
+        "cbz x13, 2b",
+        "cmp x12, x9",
+        "b.ne 2b",
+        // This is a warm boot, so set up the stack, initialise the core like a secondary core
+        // with the boot core's MMU configuration, and jump to the resume function.
+        "mov x19, x13",
+        "mov sp, x14",
+        "mov x4, #0",
+        "mov x5, #0",
+        "bl {init_secondary_core}",
+        "bl {reset_exception_depth}",
+        "bl {set_exception_vector}",
+        "br x19",
+        // This is a cold boot, so carry on with the normal entry point.
+        "3:",
+        "ret",
+        ".purgem mov_i",
+        sctlr_el3 = const SCTLR_EL3_RESET,
+        table = sym WARM_BOOT_TABLE,
+        max_cores = const if cfg!(feature = "rom") { 0 } else { MAX_WARM_BOOT_CORES },
+        mpidr_claimed = const MPIDR_CLAIMED,
+        mpidr_offset = const offset_of!(WarmBootEntry, mpidr),
+        resume_offset = const offset_of!(WarmBootEntry, resume),
+        stack_end_offset = const offset_of!(WarmBootEntry, stack_end),
+        entry_size = const size_of::<WarmBootEntry>(),
+        init_secondary_core = sym init_secondary_core,
+        reset_exception_depth = sym reset_exception_depth,
+        set_exception_vector = sym crate::set_exception_vector,
+    )
+}
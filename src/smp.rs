@@ -0,0 +1,707 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Synchronisation between cores while they are being brought up, and running functions on other
+//! cores once they are up.
+//!
+//! Secondary cores may run for a while with their MMU and data cache disabled, e.g. if the
+//! `initial-pagetable` feature isn't enabled. Their data accesses then bypass the caches, so they
+//! don't see data which other cores only have in their caches, and stale cached copies held by
+//! other cores may later be written back over what they wrote. Nor can they rely on the exclusive
+//! monitors, so atomic read-modify-write operations may not work.
+//!
+//! The primitives in this module therefore only use plain loads and stores, to variables which
+//! are each written by a single core and which each have a cache line to themselves. Every access
+//! cleans and invalidates the variable to the point of coherency, and cores wait with `wfe`, being
+//! woken by `sev` when a variable is written.
+//!
+//! [`EarlySpinLock`] and [`EarlyOnceCell`] also clean and invalidate the data they protect, so it
+//! may be shared between cores with and without their data caches enabled.
+//!
+//! As the variables are initialised (e.g. zeroed in `.bss`) by the boot core, possibly with its
+//! data cache enabled, `prepare` must be called on each primitive before starting any core which
+//! will use it with its data cache disabled.
+//!
+//! ```rust,ignore
+//! use aarch64_rt::smp::{BootBarrier, CoreLatch};
+//!
+//! static READY: BootBarrier<3> = BootBarrier::new();
+//! static GO: CoreLatch = CoreLatch::new();
+//!
+//! fn boot_core() {
+//!     READY.prepare();
+//!     GO.prepare();
+//!     // Start the secondary cores...
+//!     READY.wait();
+//!     // All secondary cores have reached a known point.
+//!     GO.release();
+//! }
+//!
+//! fn secondary_core(index: usize) -> ! {
+//!     READY.arrive(index);
+//!     GO.wait();
+//!     // ...
+//! }
+//! ```
+//!
+//! Once a core has its IRQ handler and the SGI for [`IpiKind::CallFunction`] set up as described
+//! in the [`gic`](crate::gic) module, it can call [`enable_remote_calls`] to let other cores run
+//! functions on it with [`run_on_core`], [`queue_on_core`] and [`run_on_all_cores`], e.g. to do
+//! cache maintenance or write system registers on every core:
+//!
+//! ```rust,ignore
+//! smp::run_on_all_cores(timer::stop_periodic_tick);
+//! ```
+//!
+//! Only one call may be queued for each core at a time, so queueing another waits until the core
+//! has taken the previous one. Cores waiting to queue a call or for one to finish run calls queued
+//! for themselves in the meantime, so cores making calls to each other don't deadlock.
+
+use crate::{
+    cache::clean_invalidate_dcache_range,
+    gic::{IpiKind, send_ipi, set_ipi_handler},
+    sysreg::{MAX_CORES, core_index, find_core_index, read_mpidr},
+};
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    hint::spin_loop,
+    mem::{MaybeUninit, transmute},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+/// A variable which has a cache line to itself, so that cleaning or invalidating it doesn't affect
+/// any other data.
+///
+/// 128 bytes is at least the size of the data cache lines of all cores we know of.
+#[repr(C, align(128))]
+struct Slot(AtomicU32);
+
+impl Slot {
+    const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    fn prepare(&self) {
+        clean_invalidate_dcache_range(self.0.as_ptr().cast(), size_of::<AtomicU32>());
+    }
+
+    /// Stores the given value, makes it visible to cores with their caches disabled, and wakes any
+    /// cores waiting for it to change.
+    fn store(&self, value: u32) {
+        self.0.store(value, Ordering::Release);
+        // This waits for the clean to complete, so the event can't be seen before the value, and
+        // later loads can't be observed before it either.
+        clean_invalidate_dcache_range(self.0.as_ptr().cast(), size_of::<AtomicU32>());
+        // SAFETY: Sending an event doesn't affect memory safety.
+        unsafe {
+            asm!("sev", options(nomem, nostack, preserves_flags));
+        }
+    }
+
+    fn load(&self) -> u32 {
+        // The value may have been written by a core with its cache disabled, so make sure we read
+        // it from memory rather than a stale cached copy.
+        clean_invalidate_dcache_range(self.0.as_ptr().cast(), size_of::<AtomicU32>());
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Waits until the value satisfies the given condition, and returns it.
+    ///
+    /// If the value is changed after it is checked but before the `wfe`, the event register will
+    /// already be set so the `wfe` won't wait.
+    fn wait_until(&self, condition: impl Fn(u32) -> bool) -> u32 {
+        loop {
+            let value = self.load();
+            if condition(value) {
+                return value;
+            }
+            wfe();
+        }
+    }
+}
+
+fn wfe() {
+    // SAFETY: Waiting for an event doesn't affect memory safety.
+    unsafe {
+        asm!("wfe", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// A one-shot latch which one core releases and any number of other cores wait for, e.g. to let
+/// secondary cores proceed once the boot core has finished initialisation.
+pub struct CoreLatch {
+    released: Slot,
+}
+
+impl CoreLatch {
+    /// Creates a new latch which hasn't been released.
+    pub const fn new() -> Self {
+        Self {
+            released: Slot::new(),
+        }
+    }
+
+    /// Makes the initial state of the latch visible to cores with their data caches disabled.
+    ///
+    /// This must be called before starting any core which will use the latch with its data cache
+    /// disabled.
+    pub fn prepare(&self) {
+        self.released.prepare();
+    }
+
+    /// Releases the latch, waking all cores waiting for it.
+    pub fn release(&self) {
+        self.released.store(1);
+    }
+
+    /// Returns whether the latch has been released.
+    pub fn is_released(&self) -> bool {
+        self.released.load() != 0
+    }
+
+    /// Waits until the latch is released.
+    pub fn wait(&self) {
+        self.released.wait_until(|released| released != 0);
+    }
+}
+
+impl Default for CoreLatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A one-shot barrier for which one core waits until each of `N` other cores has arrived, e.g. to
+/// let the boot core wait for all secondary cores to reach a known point.
+///
+/// Each participating core has an index from 0 to `N - 1`, which it passes to
+/// [`arrive`](Self::arrive).
+pub struct BootBarrier<const N: usize> {
+    arrived: [Slot; N],
+}
+
+impl<const N: usize> BootBarrier<N> {
+    /// Creates a new barrier which no cores have arrived at.
+    pub const fn new() -> Self {
+        Self {
+            arrived: [const { Slot::new() }; N],
+        }
+    }
+
+    /// Makes the initial state of the barrier visible to cores with their data caches disabled.
+    ///
+    /// This must be called before starting any core which will use the barrier with its data cache
+    /// disabled.
+    pub fn prepare(&self) {
+        for slot in &self.arrived {
+            slot.prepare();
+        }
+    }
+
+    /// Records that the core with the given index has arrived at the barrier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `N`.
+    pub fn arrive(&self, index: usize) {
+        self.arrived[index].store(1);
+    }
+
+    /// Returns whether the core with the given index has arrived at the barrier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `N`.
+    pub fn has_arrived(&self, index: usize) -> bool {
+        self.arrived[index].load() != 0
+    }
+
+    /// Waits until all `N` cores have arrived at the barrier.
+    pub fn wait(&self) {
+        for slot in &self.arrived {
+            slot.wait_until(|arrived| arrived != 0);
+        }
+    }
+}
+
+impl<const N: usize> Default for BootBarrier<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value which has cache lines to itself, so that cleaning or invalidating it doesn't affect any
+/// other data.
+#[repr(C, align(128))]
+struct Aligned<T>(UnsafeCell<T>);
+
+impl<T> Aligned<T> {
+    /// Cleans and invalidates the value to the point of coherency, so that cores with their caches
+    /// disabled see what the current core has written, and the current core's next read of it
+    /// comes from memory.
+    fn clean_invalidate(&self) {
+        clean_invalidate_dcache_range(self.0.get().cast(), size_of::<T>());
+    }
+}
+
+/// A spin lock for up to `N` cores, which works even when some of them have their data caches
+/// disabled.
+///
+/// This uses Lamport's bakery algorithm, so needs no atomic read-modify-write operations, but each
+/// core must pass its own index from 0 to `N - 1` when locking. It is much slower than a lock
+/// using exclusives, so should only be used until all cores have enabled their caches.
+///
+/// The protected value is cleaned and invalidated when locking and unlocking, so that each core
+/// sees what the previous owner wrote.
+pub struct EarlySpinLock<T, const N: usize> {
+    /// Whether each core is choosing its ticket.
+    choosing: [Slot; N],
+    /// The ticket of each core which wants the lock, or 0 if it doesn't.
+    tickets: [Slot; N],
+    value: Aligned<T>,
+}
+
+// SAFETY: The value is only accessed by the core which holds the lock.
+unsafe impl<T: Send, const N: usize> Sync for EarlySpinLock<T, N> {}
+
+impl<T, const N: usize> EarlySpinLock<T, N> {
+    /// Creates a new unlocked spin lock protecting the given value.
+    pub const fn new(value: T) -> Self {
+        Self {
+            choosing: [const { Slot::new() }; N],
+            tickets: [const { Slot::new() }; N],
+            value: Aligned(UnsafeCell::new(value)),
+        }
+    }
+
+    /// Makes the initial state of the lock and its value visible to cores with their data caches
+    /// disabled.
+    ///
+    /// This must be called before starting any core which will use the lock with its data cache
+    /// disabled.
+    pub fn prepare(&self) {
+        for slot in self.choosing.iter().chain(&self.tickets) {
+            slot.prepare();
+        }
+        self.value.clean_invalidate();
+    }
+
+    /// Waits until the lock is available, then locks it for the core with the given index.
+    ///
+    /// Deadlocks if the core already holds the lock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `N`.
+    pub fn lock(&self, index: usize) -> EarlySpinLockGuard<'_, T, N> {
+        self.choosing[index].store(1);
+        let ticket = 1 + self
+            .tickets
+            .iter()
+            .map(Slot::load)
+            .max()
+            .unwrap_or_default();
+        self.tickets[index].store(ticket);
+        self.choosing[index].store(0);
+        for other in (0..N).filter(|&other| other != index) {
+            self.choosing[other].wait_until(|choosing| choosing == 0);
+            self.tickets[other].wait_until(|other_ticket| {
+                other_ticket == 0 || (other_ticket, other) > (ticket, index)
+            });
+        }
+        self.value.clean_invalidate();
+        EarlySpinLockGuard { lock: self, index }
+    }
+}
+
+impl<T: Default, const N: usize> Default for EarlySpinLock<T, N> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// A guard for an [`EarlySpinLock`], which unlocks it when dropped.
+pub struct EarlySpinLockGuard<'a, T, const N: usize> {
+    lock: &'a EarlySpinLock<T, N>,
+    index: usize,
+}
+
+impl<T, const N: usize> Deref for EarlySpinLockGuard<'_, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold the lock, so no other core can access the value.
+        unsafe { &*self.lock.value.0.get() }
+    }
+}
+
+impl<T, const N: usize> DerefMut for EarlySpinLockGuard<'_, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We hold the lock, so no other core can access the value.
+        unsafe { &mut *self.lock.value.0.get() }
+    }
+}
+
+impl<T, const N: usize> Drop for EarlySpinLockGuard<'_, T, N> {
+    fn drop(&mut self) {
+        self.lock.value.clean_invalidate();
+        self.lock.tickets[self.index].store(0);
+    }
+}
+
+/// A cell which is initialised at most once, by one of up to `N` cores, and which works even when
+/// some of them have their data caches disabled.
+pub struct EarlyOnceCell<T, const N: usize> {
+    lock: EarlySpinLock<(), N>,
+    initialised: Slot,
+    value: Aligned<MaybeUninit<T>>,
+}
+
+// SAFETY: The value is only written once, with the lock held and before `initialised` is set, and
+// is only shared after that.
+unsafe impl<T: Send + Sync, const N: usize> Sync for EarlyOnceCell<T, N> {}
+
+impl<T, const N: usize> EarlyOnceCell<T, N> {
+    /// Creates a new uninitialised cell.
+    pub const fn new() -> Self {
+        Self {
+            lock: EarlySpinLock::new(()),
+            initialised: Slot::new(),
+            value: Aligned(UnsafeCell::new(MaybeUninit::uninit())),
+        }
+    }
+
+    /// Makes the initial state of the cell visible to cores with their data caches disabled.
+    ///
+    /// This must be called before starting any core which will use the cell with its data cache
+    /// disabled.
+    pub fn prepare(&self) {
+        self.lock.prepare();
+        self.initialised.prepare();
+    }
+
+    /// Returns the value of the cell, if it has been initialised.
+    pub fn get(&self) -> Option<&T> {
+        if self.initialised.load() == 0 {
+            return None;
+        }
+        self.value.clean_invalidate();
+        // SAFETY: The value has been initialised, and won't be written again.
+        Some(unsafe { (*self.value.0.get()).assume_init_ref() })
+    }
+
+    /// Returns the value of the cell, first initialising it with the given function on the core with
+    /// the given index if no core has done so yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than `N`.
+    pub fn get_or_init(&self, index: usize, init: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let guard = self.lock.lock(index);
+        if self.initialised.load() == 0 {
+            // SAFETY: We hold the lock and the value hasn't been initialised, so no other core is
+            // accessing it.
+            unsafe {
+                (*self.value.0.get()).write(init());
+            }
+            self.value.clean_invalidate();
+            self.initialised.store(1);
+        }
+        drop(guard);
+        self.get().unwrap()
+    }
+}
+
+impl<T, const N: usize> Default for EarlyOnceCell<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for EarlyOnceCell<T, N> {
+    fn drop(&mut self) {
+        if self.initialised.0.load(Ordering::Relaxed) != 0 {
+            // SAFETY: The value has been initialised, and we have exclusive access to it.
+            unsafe {
+                self.value.0.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+/// A function call queued for a core by [`run_on_core`], [`queue_on_core`] or
+/// [`run_on_all_cores`].
+struct CallMailbox {
+    /// Whether the core has enabled remote calls with [`enable_remote_calls`].
+    online: AtomicBool,
+    /// The MPIDR of the core, to send it IPIs.
+    mpidr: AtomicU64,
+    /// Whether a call is queued or being queued for the core. This is cleared by the core once it
+    /// has taken the call, so that another can be queued while it runs.
+    busy: AtomicBool,
+    /// The trampoline for the queued call, or 0 if no call is queued.
+    trampoline: AtomicUsize,
+    /// The argument to pass to the trampoline.
+    data: AtomicUsize,
+    /// A pointer to an `AtomicUsize` to decrement once the call has finished, or 0 if nothing is
+    /// waiting for it.
+    pending: AtomicUsize,
+}
+
+impl CallMailbox {
+    const fn new() -> Self {
+        Self {
+            online: AtomicBool::new(false),
+            mpidr: AtomicU64::new(0),
+            busy: AtomicBool::new(false),
+            trampoline: AtomicUsize::new(0),
+            data: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues a call to the given trampoline for the core, and sends it an IPI to run it.
+    ///
+    /// Returns false if the core hasn't enabled remote calls.
+    ///
+    /// # Safety
+    ///
+    /// `trampoline` must be safe to call with `data` until the call has finished, and `pending`
+    /// must be 0 or point to an `AtomicUsize` which remains valid until then.
+    unsafe fn queue(&self, trampoline: unsafe fn(usize), data: usize, pending: usize) -> bool {
+        if !self.online.load(Ordering::Acquire) {
+            return false;
+        }
+        while self
+            .busy
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // The target core may itself be waiting to queue a call for us.
+            handle_remote_calls();
+            spin_loop();
+        }
+        self.data.store(data, Ordering::Relaxed);
+        self.pending.store(pending, Ordering::Relaxed);
+        self.trampoline.store(trampoline as usize, Ordering::SeqCst);
+        // The core may have disabled remote calls since we checked, after running the calls queued
+        // for it. Publishing the call before checking again, and the core clearing `online` before
+        // taking its call, means that either we see that it is offline or it sees the call.
+        if !self.online.load(Ordering::SeqCst)
+            && self
+                .trampoline
+                .compare_exchange(trampoline as usize, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.busy.store(false, Ordering::Release);
+            return false;
+        }
+        send_ipi(self.mpidr.load(Ordering::Relaxed), IpiKind::CallFunction);
+        true
+    }
+}
+
+/// The mailbox for each core, indexed by its `core_index`.
+static MAILBOXES: [CallMailbox; MAX_CORES] = [const { CallMailbox::new() }; MAX_CORES];
+
+/// An error queueing a call for another core.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CoreOffline {
+    /// The MPIDR of the core which hasn't enabled remote calls.
+    pub mpidr: u64,
+}
+
+/// Lets other cores run functions on the current core with [`run_on_core`], [`queue_on_core`] and
+/// [`run_on_all_cores`].
+///
+/// This registers [`handle_remote_calls`] as the handler for [`IpiKind::CallFunction`]. Before
+/// calling it, the current core must have the SGI for that IPI enabled in its redistributor, and
+/// must pass IRQs to [`gic::handle_ipi`](crate::gic::handle_ipi).
+pub fn enable_remote_calls() {
+    set_ipi_handler(IpiKind::CallFunction, handle_remote_calls);
+    let mailbox = &MAILBOXES[core_index()];
+    mailbox.mpidr.store(read_mpidr(), Ordering::Relaxed);
+    mailbox.online.store(true, Ordering::Release);
+}
+
+/// Stops other cores from queueing any more calls for the current core, e.g. before it is powered
+/// down, and runs any call which was already queued.
+pub fn disable_remote_calls() {
+    MAILBOXES[core_index()]
+        .online
+        .store(false, Ordering::SeqCst);
+    handle_remote_calls();
+}
+
+/// Runs the call queued for the current core, if any.
+///
+/// This is registered as the [`IpiKind::CallFunction`] handler by [`enable_remote_calls`], so
+/// normally doesn't need to be called directly.
+pub fn handle_remote_calls() {
+    let mailbox = &MAILBOXES[core_index()];
+    let trampoline = mailbox.trampoline.swap(0, Ordering::SeqCst);
+    if trampoline == 0 {
+        return;
+    }
+    let data = mailbox.data.load(Ordering::Relaxed);
+    let pending = mailbox.pending.load(Ordering::Relaxed);
+    mailbox.busy.store(false, Ordering::Release);
+    // SAFETY: The trampoline was set by `CallMailbox::queue`, whose caller promised that it is
+    // safe to call with `data` until the call has finished.
+    unsafe {
+        let trampoline: unsafe fn(usize) = transmute(trampoline);
+        trampoline(data);
+    }
+    if pending != 0 {
+        // SAFETY: The caller of `CallMailbox::queue` promised that `pending` remains valid until
+        // the call has finished, which it now has.
+        unsafe { &*(pending as *const AtomicUsize) }.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Calls the function which `data` points to.
+///
+/// # Safety
+///
+/// `data` must be a valid pointer to an `F`.
+unsafe fn call_function<F: Fn()>(data: usize) {
+    // SAFETY: Our caller promised that `data` points to an `F`.
+    unsafe { (*(data as *const F))() }
+}
+
+/// Waits until the given counter reaches zero, running any calls queued for the current core in the
+/// meantime so that cores waiting for each other don't deadlock.
+fn wait_for_calls(pending: &AtomicUsize) {
+    while pending.load(Ordering::Acquire) != 0 {
+        handle_remote_calls();
+        spin_loop();
+    }
+}
+
+/// Runs the given function on the core with the given MPIDR, and waits for it to finish.
+///
+/// If the MPIDR is that of the current core then the function is run directly. Otherwise the core
+/// must have called [`enable_remote_calls`], and the function is run from its IRQ handler.
+pub fn run_on_core<F: Fn() + Sync>(mpidr: u64, f: F) -> Result<(), CoreOffline> {
+    if mpidr & 0xff_00ff_ffff == read_mpidr() {
+        f();
+        return Ok(());
+    }
+    let Some(index) = find_core_index(mpidr) else {
+        return Err(CoreOffline { mpidr });
+    };
+    let pending = AtomicUsize::new(1);
+    // SAFETY: `f` and `pending` remain valid until we have waited for the call to finish.
+    let queued = unsafe {
+        MAILBOXES[index].queue(
+            call_function::<F>,
+            &raw const f as usize,
+            &raw const pending as usize,
+        )
+    };
+    if !queued {
+        return Err(CoreOffline { mpidr });
+    }
+    wait_for_calls(&pending);
+    Ok(())
+}
+
+/// Queues the given function to run on the core with the given MPIDR, without waiting for it to
+/// finish.
+///
+/// The core must have called [`enable_remote_calls`], and the function is run from its IRQ
+/// handler. This waits for any call already queued for the core to be taken first.
+pub fn queue_on_core<F: Fn() + Sync>(mpidr: u64, f: &'static F) -> Result<(), CoreOffline> {
+    let Some(index) = find_core_index(mpidr) else {
+        return Err(CoreOffline { mpidr });
+    };
+    // SAFETY: `f` is valid forever, and nothing waits for the call to finish.
+    let queued = unsafe { MAILBOXES[index].queue(call_function::<F>, &raw const *f as usize, 0) };
+    if queued {
+        Ok(())
+    } else {
+        Err(CoreOffline { mpidr })
+    }
+}
+
+/// Runs the given function on the current core and every other core which has called
+/// [`enable_remote_calls`], and waits for them all to finish.
+pub fn run_on_all_cores<F: Fn() + Sync>(f: F) {
+    let current = core_index();
+    let pending = AtomicUsize::new(0);
+    for (index, mailbox) in MAILBOXES.iter().enumerate() {
+        if index == current || !mailbox.online.load(Ordering::Acquire) {
+            continue;
+        }
+        pending.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: `f` and `pending` remain valid until we have waited for all the calls to finish.
+        let queued = unsafe {
+            mailbox.queue(
+                call_function::<F>,
+                &raw const f as usize,
+                &raw const pending as usize,
+            )
+        };
+        if !queued {
+            // The core disabled remote calls since we checked.
+            pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    f();
+    wait_for_calls(&pending);
+}
+
+/// Calls the function which `data` is the address of.
+///
+/// # Safety
+///
+/// `data` must be the address of a `fn() -> !`.
+unsafe fn call_diverging_function(data: usize) {
+    // SAFETY: Our caller promised that `data` is the address of a `fn() -> !`.
+    let f: fn() -> ! = unsafe { transmute(data) };
+    f()
+}
+
+/// Calls `f` with the MPIDR of every other core which has called [`enable_remote_calls`] and not
+/// since called [`disable_remote_calls`].
+#[cfg(feature = "psci")]
+pub(crate) fn for_each_other_online_core(mut f: impl FnMut(u64)) {
+    let current = core_index();
+    for (index, mailbox) in MAILBOXES.iter().enumerate() {
+        if index != current && mailbox.online.load(Ordering::Acquire) {
+            f(mailbox.mpidr.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Runs the given function on every other core which has called [`enable_remote_calls`], and waits
+/// until they have all called [`disable_remote_calls`].
+///
+/// This is intended to take all other cores offline before handing over the system to another
+/// image, e.g. with `power::power_off_core`, which disables remote calls before powering the core
+/// off. `f` must call [`disable_remote_calls`] itself, or this will wait forever.
+pub fn stop_other_cores(f: fn() -> !) {
+    let current = core_index();
+    for (index, mailbox) in MAILBOXES.iter().enumerate() {
+        if index == current {
+            continue;
+        }
+        // SAFETY: `f` is a function pointer so is valid forever, and nothing waits for the call to
+        // finish.
+        unsafe {
+            mailbox.queue(call_diverging_function, f as usize, 0);
+        }
+    }
+    for (index, mailbox) in MAILBOXES.iter().enumerate() {
+        while index != current && mailbox.online.load(Ordering::Acquire) {
+            handle_remote_calls();
+            spin_loop();
+        }
+    }
+}
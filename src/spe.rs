@@ -0,0 +1,267 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Sampling with the Statistical Profiling Extension (SPE).
+//!
+//! [`start`] programs the profiling buffer and starts sampling at the current exception level, which
+//! must be EL1 or EL2. When the buffer fills the profiling buffer management interrupt is raised,
+//! which the application must enable in the GIC. Its INTID is platform-specific, but
+//! [`BUFFER_INTID`] is recommended by the Server Base System Architecture. The IRQ handler should
+//! call [`handle_buffer_interrupt`] when it acknowledges it, e.g.:
+//!
+//! ```rust,ignore
+//! extern "C" fn irq_current(register_state: RegisterStateRef) {
+//!     if let Some(intid) = gic::acknowledge_interrupt() {
+//!         if intid == spe::BUFFER_INTID {
+//!             if let Some(event) = spe::handle_buffer_interrupt() {
+//!                 // Process the `event.len` bytes of records at the start of the buffer.
+//!             }
+//!         }
+//!         gic::end_interrupt(intid);
+//!     }
+//! }
+//! ```
+
+use crate::sysreg::{current_el, read_sysreg, write_sysreg};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The INTID of the profiling buffer management interrupt PPI, as recommended by the Server Base
+/// System Architecture.
+pub const BUFFER_INTID: u32 = 21;
+
+/// PMSCR_EL1.E0SPE and PMSCR_EL2.E0HSPE: enables sampling at EL0.
+const PMSCR_E0SPE: u64 = 1 << 0;
+/// PMSCR_EL1.E1SPE and PMSCR_EL2.E2SPE: enables sampling at EL1 or EL2 respectively.
+const PMSCR_EXSPE: u64 = 1 << 1;
+/// PMSCR_ELx.CX: enables collection of the context ID.
+const PMSCR_CX: u64 = 1 << 3;
+/// PMSCR_ELx.PA: enables collection of physical addresses.
+const PMSCR_PA: u64 = 1 << 4;
+/// PMSCR_ELx.TS: enables collection of timestamps.
+const PMSCR_TS: u64 = 1 << 5;
+
+/// PMBLIMITR_EL1.E: enables the profiling buffer.
+const PMBLIMITR_E: u64 = 1 << 0;
+/// PMBIDR_EL1.P: the profiling buffer is owned by a higher exception level.
+const PMBIDR_P: u64 = 1 << 4;
+/// PMBSR_EL1.S: a buffer management event has been raised.
+const PMBSR_S: u64 = 1 << 17;
+/// MDCR_EL2.E2PB: the profiling buffer is owned by EL2.
+const MDCR_EL2_E2PB_MASK: u64 = 0b11 << 12;
+
+/// The alignment required for the profiling buffer base and size.
+const BUFFER_ALIGN: usize = 4096;
+
+/// Configuration for statistical profiling.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SpeConfig {
+    /// The number of operations between samples. This is rounded down to a multiple of 256, and up
+    /// to the minimum supported by the implementation.
+    pub interval: u32,
+    /// Whether to sample at EL0.
+    pub el0: bool,
+    /// Whether to sample at the current exception level.
+    pub current_el: bool,
+    /// Whether to collect timestamps.
+    pub timestamps: bool,
+    /// Whether to collect physical addresses.
+    pub physical_addresses: bool,
+}
+
+/// An error starting statistical profiling.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpeError {
+    /// The Statistical Profiling Extension isn't implemented.
+    NotImplemented,
+    /// The profiling buffer is owned by a higher exception level.
+    Prohibited,
+    /// The current exception level isn't EL1 or EL2.
+    UnsupportedExceptionLevel,
+    /// The buffer isn't aligned to 4 KiB, or its length isn't a multiple of 4 KiB.
+    Misaligned,
+}
+
+/// A profiling buffer management event, reported by the buffer management interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BufferEvent {
+    /// The value of PMBSR_EL1 describing the event.
+    pub status: u64,
+    /// The number of bytes of records written to the buffer.
+    pub len: usize,
+}
+
+impl BufferEvent {
+    /// Returns whether the event was because the buffer filled.
+    pub fn is_buffer_full(&self) -> bool {
+        // Buffer management event with the buffer filled status code.
+        (self.status >> 26) & 0x3f == 0 && self.status & 0x3f == 1
+    }
+}
+
+/// The base address of the profiling buffer set by `start`.
+static BUFFER_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the Statistical Profiling Extension is implemented.
+pub fn spe_implemented() -> bool {
+    // SAFETY: Reading ID_AA64DFR0_EL1 is always safe.
+    let dfr0 = unsafe { read_sysreg!("id_aa64dfr0_el1") };
+    (dfr0 >> 32) & 0xf != 0
+}
+
+/// Starts statistical profiling on the current core, writing records to the given buffer.
+///
+/// Only one core may be profiled at a time.
+///
+/// # Safety
+///
+/// The buffer must be valid for writes for `len` bytes, and must not be accessed by anything else
+/// until profiling is stopped with [`stop`], except to read records reported by
+/// [`handle_buffer_interrupt`].
+pub unsafe fn start(buffer: *mut u8, len: usize, config: SpeConfig) -> Result<(), SpeError> {
+    if !spe_implemented() {
+        return Err(SpeError::NotImplemented);
+    }
+    let el = current_el();
+    if el != 1 && el != 2 {
+        return Err(SpeError::UnsupportedExceptionLevel);
+    }
+    if !(buffer as usize).is_multiple_of(BUFFER_ALIGN)
+        || !len.is_multiple_of(BUFFER_ALIGN)
+        || len == 0
+    {
+        return Err(SpeError::Misaligned);
+    }
+    if el == 2 {
+        // SAFETY: Taking ownership of the profiling buffer at EL2 doesn't affect memory safety.
+        unsafe {
+            write_sysreg!("mdcr_el2", read_sysreg!("mdcr_el2") & !MDCR_EL2_E2PB_MASK);
+            asm!("isb", options(nomem, nostack, preserves_flags));
+        }
+    }
+    // SAFETY: Reading PMBIDR_EL1 is always safe.
+    let pmbidr = unsafe { read_sysreg!("s3_0_c9_c10_7") };
+    if pmbidr & PMBIDR_P != 0 {
+        return Err(SpeError::Prohibited);
+    }
+    // SAFETY: Reading PMSIDR_EL1 is always safe.
+    let pmsidr = unsafe { read_sysreg!("s3_0_c9_c9_7") };
+    let min_interval = match (pmsidr >> 8) & 0xf {
+        0 => 256,
+        2 => 512,
+        3 => 768,
+        4 => 1024,
+        5 => 1536,
+        6 => 2048,
+        7 => 3072,
+        _ => 4096,
+    };
+    let interval = u64::from(config.interval).max(min_interval) & !0xff;
+
+    let mut pmscr = 0;
+    if config.el0 {
+        pmscr |= PMSCR_E0SPE;
+    }
+    if config.current_el {
+        pmscr |= PMSCR_EXSPE;
+    }
+    if config.timestamps {
+        pmscr |= PMSCR_TS;
+    }
+    if config.physical_addresses {
+        pmscr |= PMSCR_PA;
+    }
+    pmscr |= PMSCR_CX;
+
+    // SAFETY: Our caller promised that the buffer is valid and reserved for profiling records, so
+    // having the profiling unit write to it doesn't affect memory safety.
+    unsafe {
+        BUFFER_BASE.store(buffer as usize, Ordering::Relaxed);
+        // PMBPTR_EL1
+        write_sysreg!("s3_0_c9_c10_1", buffer as u64);
+        // PMBSR_EL1
+        write_sysreg!("s3_0_c9_c10_3", 0u64);
+        // PMBLIMITR_EL1
+        write_sysreg!("s3_0_c9_c10_0", (buffer as u64 + len as u64) | PMBLIMITR_E);
+        // PMSIRR_EL1
+        write_sysreg!("s3_0_c9_c9_3", interval);
+        // PMSFCR_EL1: no filtering.
+        write_sysreg!("s3_0_c9_c9_4", 0u64);
+        // PMSICR_EL1
+        write_sysreg!("s3_0_c9_c9_2", 0u64);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+        if el == 2 {
+            // PMSCR_EL2
+            write_sysreg!("s3_4_c9_c9_0", pmscr);
+        } else {
+            // PMSCR_EL1
+            write_sysreg!("s3_0_c9_c9_0", pmscr);
+        }
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    Ok(())
+}
+
+/// Stops statistical profiling on the current core, and returns the number of bytes of records
+/// written to the buffer.
+pub fn stop() -> usize {
+    // SAFETY: Stopping profiling doesn't affect memory safety. The PSB CSYNC and DSB ensure that
+    // all records have been written to memory before the buffer is disabled.
+    unsafe {
+        if current_el() == 2 {
+            write_sysreg!("s3_4_c9_c9_0", 0u64);
+        } else {
+            write_sysreg!("s3_0_c9_c9_0", 0u64);
+        }
+        asm!(
+            "isb",
+            // PSB CSYNC
+            "hint #17",
+            "dsb nsh",
+            options(nostack, preserves_flags)
+        );
+        write_sysreg!("s3_0_c9_c10_0", 0u64);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    bytes_written()
+}
+
+/// Returns the number of bytes of records written to the buffer since it was last reset.
+fn bytes_written() -> usize {
+    // SAFETY: Reading PMBPTR_EL1 is always safe.
+    let pointer = unsafe { read_sysreg!("s3_0_c9_c10_1") } as usize;
+    pointer - BUFFER_BASE.load(Ordering::Relaxed)
+}
+
+/// Handles the profiling buffer management interrupt.
+///
+/// If a buffer management event has been raised, this returns it and then resets the buffer so
+/// that profiling continues from its start. The records in the buffer must be read before the next
+/// sample is taken, so the caller should copy or process them immediately. Returns `None` if no
+/// event has been raised.
+pub fn handle_buffer_interrupt() -> Option<BufferEvent> {
+    // SAFETY: Reading PMBSR_EL1 is always safe.
+    let status = unsafe { read_sysreg!("s3_0_c9_c10_3") };
+    if status & PMBSR_S == 0 {
+        return None;
+    }
+    // SAFETY: The PSB CSYNC and DSB ensure that all records have been written to memory.
+    unsafe {
+        asm!("hint #17", "dsb nsh", options(nostack, preserves_flags));
+    }
+    let event = BufferEvent {
+        status,
+        len: bytes_written(),
+    };
+    // SAFETY: Resetting the write pointer to the start of the buffer which was validated by `start`
+    // and clearing the event so that profiling resumes doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("s3_0_c9_c10_1", BUFFER_BASE.load(Ordering::Relaxed) as u64);
+        write_sysreg!("s3_0_c9_c10_3", 0u64);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    Some(event)
+}
@@ -0,0 +1,117 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Starting secondary cores with the spin-table enable method.
+//!
+//! Platforms without PSCI, such as the Raspberry Pi 3 and 4, hold secondary cores in a loop
+//! waiting for an entry point address to be written to a per-core release address. This is done
+//! either by the firmware, or by the entry point if the `raspberry-pi` feature is enabled.
+//!
+//! Secondary cores are released with the MMU off, so the entry point written to the release
+//! address must be identity mapped.
+
+use crate::{
+    Stack,
+    cache::{clean_dcache_range, clean_invalidate_dcache_range},
+    prepare_start_core_stack, secondary_entry,
+};
+use core::{
+    arch::{asm, naked_asm},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The spin-table release addresses used by the Raspberry Pi 3 and 4 firmware, indexed by core
+/// number.
+pub const RASPBERRY_PI_RELEASE_ADDRESSES: [usize; 4] = [0xd8, 0xe0, 0xe8, 0xf0];
+
+/// The initial stack pointer for the core currently being started, or 0 if no core is being
+/// started.
+///
+/// The spin-table protocol doesn't pass any arguments to the released core, so this is used to hand
+/// the stack pointer over to it.
+static STACK_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Releases the core waiting on the given spin-table release address.
+///
+/// This starts the core with an assembly entry point which will enable the MMU, disable trapping of
+/// floating point instructions, initialise the stack pointer to the end of the given stack, and
+/// then call the given Rust entry point closure.
+///
+/// This waits until the core has started running before returning, so only one core is started at
+/// a time.
+///
+/// The closure passed as `rust_entry` **should never return**. Because the
+/// [never type has not been stabilized](https://github.com/rust-lang/rust/issues/35121)), this
+/// cannot be enforced by the type system yet.
+///
+/// # Safety
+///
+/// `release_address` must be the spin-table release address of a core which is waiting to be
+/// released, and must be identity mapped.
+///
+/// `stack` must point to a region of memory which is reserved for this core's stack. It must remain
+/// valid as long as the core is running, and there must not be any other access to it during that
+/// time. It must be mapped both for the current core to write to it (to pass initial parameters)
+/// and in the initial page table which the core being started will used, with the same memory
+/// attributes for both.
+// TODO: change `F` generic bounds to `FnOnce() -> !` when the never type is stabilized:
+// https://github.com/rust-lang/rust/issues/35121
+pub unsafe fn start_core<F: FnOnce() + Send + 'static, const N: usize>(
+    release_address: *mut u64,
+    stack: *mut Stack<N>,
+    rust_entry: F,
+) {
+    let rust_entry = move || {
+        // Let the core which started us know that we have taken our stack pointer.
+        STACK_END.store(0, Ordering::Release);
+        rust_entry()
+    };
+    // SAFETY: Our caller promised that the stack is valid and nothing else will access it.
+    let stack_end = unsafe { prepare_start_core_stack(stack, rust_entry, 0) };
+
+    while STACK_END
+        .compare_exchange_weak(0, stack_end as usize, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {}
+    // The new core starts with its MMU off, so it may not see anything that is only in our cache.
+    clean_dcache_range(STACK_END.as_ptr().cast(), size_of::<AtomicUsize>());
+
+    // SAFETY: Our caller promised that the release address is valid and used by a waiting core.
+    unsafe {
+        release_address.write_volatile(spin_table_entry as *const () as usize as u64);
+    }
+    clean_dcache_range(release_address.cast(), size_of::<u64>());
+    // SAFETY: Sending an event doesn't affect memory safety.
+    unsafe {
+        asm!("sev", options(nomem, nostack, preserves_flags));
+    }
+
+    // Wait for the new core to take its stack pointer, so that STACK_END can be reused. It may
+    // still be running with its cache disabled, so invalidate our copy each time.
+    loop {
+        clean_invalidate_dcache_range(STACK_END.as_ptr().cast(), size_of::<AtomicUsize>());
+        if STACK_END.load(Ordering::Acquire) == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The entry point written to the release address by [`start_core`].
+///
+/// This loads the stack pointer from `STACK_END` and then jumps to [`secondary_entry`].
+///
+/// # Safety
+///
+/// This must only be jumped to by a core released by [`start_core`].
+#[unsafe(naked)]
+unsafe extern "C" fn spin_table_entry() -> ! {
+    naked_asm!(
+        "adrp x0, {stack_end}",
+        "ldr x0, [x0, :lo12:{stack_end}]",
+        "b {secondary_entry}",
+        stack_end = sym STACK_END,
+        secondary_entry = sym secondary_entry,
+    )
+}
@@ -0,0 +1,135 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Selection between the SP_EL0 and SP_ELx stack pointers at the current exception level.
+//!
+//! Each exception level from EL1 up has its own stack pointer, SP_ELx, and may also use SP_EL0,
+//! selected by PSTATE.SPSel. The entry point runs with SPSel set to 1, so the boot stack, the
+//! stacks passed to `start_core` and everything the application does before calling these
+//! functions use SP_ELx. Taking an exception always switches to SP_ELx, so the exception handlers
+//! run on whatever stack SP_ELx points to when the exception is taken, and when it is taken while
+//! using SP_EL0 the vector table's "current EL with SP0" entries are used, which call the same
+//! handlers.
+//!
+//! A kernel can therefore run its threads on SP_EL0 and its exception handlers on a dedicated
+//! SP_ELx stack, so that an exception never runs on a thread's stack:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::spsel::switch_to_sp_el0;
+//!
+//! static mut EXCEPTION_STACK: Stack<4> = Stack::new();
+//!
+//! // SAFETY: The exception stack isn't used for anything else.
+//! unsafe { switch_to_sp_el0((&raw mut EXCEPTION_STACK).wrapping_add(1).cast()) };
+//! // We are still running on the same stack, but it is now SP_EL0. Context switches can change
+//! // SP_EL0 to move between threads, while exceptions run on EXCEPTION_STACK.
+//! ```
+
+use crate::sysreg::{read_sysreg, write_sysreg};
+use core::arch::asm;
+
+/// The stack pointer selected by PSTATE.SPSel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StackPointer {
+    /// SP_EL0.
+    El0,
+    /// The stack pointer for the current exception level.
+    ElX,
+}
+
+/// Returns which stack pointer is currently selected.
+pub fn current_stack_pointer() -> StackPointer {
+    // SAFETY: Reading SPSel is always safe.
+    if unsafe { read_sysreg!("spsel") } & 1 == 0 {
+        StackPointer::El0
+    } else {
+        StackPointer::ElX
+    }
+}
+
+/// Returns the value of SP_EL0.
+///
+/// # Panics
+///
+/// Panics if SP_EL0 is the currently selected stack pointer, as its value would change as soon as
+/// this returns.
+pub fn sp_el0() -> usize {
+    assert_eq!(current_stack_pointer(), StackPointer::ElX);
+    // SAFETY: Reading SP_EL0 while it isn't selected is always safe.
+    unsafe { read_sysreg!("sp_el0") as usize }
+}
+
+/// Sets SP_EL0 to the given value, e.g. to prepare a thread's stack before returning to it from an
+/// exception.
+///
+/// # Safety
+///
+/// SP_EL0 must not be the currently selected stack pointer, and the new value must be the end of a
+/// stack which is valid for whatever will next run on SP_EL0.
+pub unsafe fn set_sp_el0(value: usize) {
+    assert_eq!(current_stack_pointer(), StackPointer::ElX);
+    // SAFETY: Our caller promised that the value is valid for whatever runs on SP_EL0 next.
+    unsafe {
+        write_sysreg!("sp_el0", value as u64);
+    }
+}
+
+/// Switches the current code from SP_ELx to SP_EL0, keeping the same stack, and points SP_ELx at
+/// the given exception stack.
+///
+/// Afterwards exceptions at the current exception level are handled on the exception stack, and
+/// whatever is running continues on its current stack through SP_EL0.
+///
+/// # Safety
+///
+/// `exception_stack_end` must be the 16-byte aligned end of a stack which is reserved for the
+/// current core's exception handlers, and is large enough for them.
+///
+/// # Panics
+///
+/// Panics if SP_EL0 is already selected.
+pub unsafe fn switch_to_sp_el0(exception_stack_end: *mut u8) {
+    assert_eq!(current_stack_pointer(), StackPointer::ElX);
+    assert!(exception_stack_end.addr().is_multiple_of(16));
+    // SAFETY: SP_EL0 is set to the current stack pointer before it is selected, so the value of
+    // `sp` is the same afterwards. Our caller promised that the exception stack is valid.
+    unsafe {
+        asm!(
+            "mov {current}, sp",
+            "msr sp_el0, {current}",
+            "mov sp, {exception_stack_end}",
+            "msr spsel, #0",
+            current = out(reg) _,
+            exception_stack_end = in(reg) exception_stack_end.addr(),
+            options(nomem, preserves_flags),
+        );
+    }
+}
+
+/// Switches the current code from SP_EL0 back to SP_ELx, keeping the same stack, and returns the
+/// previous value of SP_ELx.
+///
+/// Afterwards exceptions at the current exception level are handled on the current stack.
+///
+/// # Panics
+///
+/// Panics if SP_ELx is already selected.
+pub fn switch_to_sp_elx() -> *mut u8 {
+    assert_eq!(current_stack_pointer(), StackPointer::El0);
+    let exception_stack: *mut u8;
+    // SAFETY: SP_ELx is set to the current stack pointer after it is selected, so the value of `sp`
+    // is the same afterwards.
+    unsafe {
+        asm!(
+            "mov {current}, sp",
+            "msr spsel, #1",
+            "mov {exception_stack}, sp",
+            "mov sp, {current}",
+            current = out(reg) _,
+            exception_stack = out(reg) exception_stack,
+            options(nomem, preserves_flags),
+        );
+    }
+    exception_stack
+}
@@ -0,0 +1,155 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Helpers for accessing system registers.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Reads the system register with the given name, returning it as a `u64`.
+///
+/// This expands to an `asm!` block, so must be used inside an `unsafe` block.
+macro_rules! read_sysreg {
+    ($sysreg:expr) => {{
+        let value: u64;
+        core::arch::asm!(
+            concat!("mrs {value}, ", $sysreg),
+            options(nomem, nostack, preserves_flags),
+            value = out(reg) value,
+        );
+        value
+    }};
+}
+
+/// Writes the given `u64` value to the system register with the given name.
+///
+/// This expands to an `asm!` block, so must be used inside an `unsafe` block.
+macro_rules! write_sysreg {
+    ($sysreg:expr, $value:expr) => {
+        core::arch::asm!(
+            concat!("msr ", $sysreg, ", {value}"),
+            options(nostack, preserves_flags),
+            value = in(reg) u64::from($value),
+        )
+    };
+}
+
+pub(crate) use read_sysreg;
+pub(crate) use write_sysreg;
+
+/// Returns the current exception level.
+///
+/// If one of the `elX` features is enabled then this is assumed rather than read from `CurrentEL`.
+pub(crate) fn current_el() -> u8 {
+    if cfg!(feature = "el1") {
+        1
+    } else if cfg!(feature = "el2") {
+        2
+    } else if cfg!(feature = "el3") {
+        3
+    } else {
+        // SAFETY: Reading CurrentEL is always safe.
+        let current_el = unsafe { read_sysreg!("CurrentEL") };
+        ((current_el >> 2) & 0b11) as u8
+    }
+}
+
+/// Reads the exception syndrome register for the current exception level.
+pub(crate) fn read_esr() -> u64 {
+    // SAFETY: Reading the ESR of the current EL is always safe.
+    unsafe {
+        match current_el() {
+            1 => read_sysreg!("esr_el1"),
+            2 => read_sysreg!("esr_el2"),
+            3 => read_sysreg!("esr_el3"),
+            _ => panic!("Unexpected EL"),
+        }
+    }
+}
+
+/// Reads the fault address register for the current exception level.
+pub(crate) fn read_far() -> u64 {
+    // SAFETY: Reading the FAR of the current EL is always safe.
+    unsafe {
+        match current_el() {
+            1 => read_sysreg!("far_el1"),
+            2 => read_sysreg!("far_el2"),
+            3 => read_sysreg!("far_el3"),
+            _ => panic!("Unexpected EL"),
+        }
+    }
+}
+
+/// MMU enable bit in SCTLR_ELx.
+pub(crate) const SCTLR_M: u64 = 1 << 0;
+/// Data cache enable bit in SCTLR_ELx.
+pub(crate) const SCTLR_C: u64 = 1 << 2;
+/// The RES1 bits of SCTLR_EL1, with the MMU and caches off and little-endian.
+pub(crate) const SCTLR_EL1_RES1: u64 =
+    (1 << 11) | (1 << 20) | (1 << 22) | (1 << 23) | (1 << 28) | (1 << 29);
+/// The RES1 bits of SCTLR_EL2 when HCR_EL2.E2H is 0, with the MMU and caches off and little-endian.
+pub(crate) const SCTLR_EL2_RES1: u64 = (1 << 4)
+    | (1 << 5)
+    | (1 << 11)
+    | (1 << 16)
+    | (1 << 18)
+    | (1 << 22)
+    | (1 << 23)
+    | (1 << 28)
+    | (1 << 29);
+
+/// The affinity fields of MPIDR_EL1.
+pub(crate) const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// The number of cores which can be given an index by [`core_index`].
+pub(crate) const MAX_CORES: usize = 256;
+
+/// Set in the entries of [`CORE_MPIDRS`] which are in use, as an MPIDR of 0 is valid.
+const CORE_MPIDR_VALID: u64 = 1 << 63;
+
+/// The MPIDR affinity fields of each core which has been given an index by [`core_index`], with
+/// [`CORE_MPIDR_VALID`] set, or 0 for unused entries. Entries are never freed, so the used ones are
+/// all at the start.
+static CORE_MPIDRS: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Returns the index of the current core, which is unique to it and less than [`MAX_CORES`].
+///
+/// Each core is given the next free index the first time it calls this, so indices are only
+/// meaningful while the image is running, and not related to the core's MPIDR.
+///
+/// # Panics
+///
+/// Panics if more than [`MAX_CORES`] cores ask for an index.
+pub(crate) extern "C" fn core_index() -> usize {
+    let entry = read_mpidr() | CORE_MPIDR_VALID;
+    for (index, slot) in CORE_MPIDRS.iter().enumerate() {
+        let current = match slot.load(Ordering::Acquire) {
+            0 => match slot.compare_exchange(0, entry, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return index,
+                Err(current) => current,
+            },
+            current => current,
+        };
+        if current == entry {
+            return index;
+        }
+    }
+    panic!("More than {MAX_CORES} cores asked for a core index");
+}
+
+/// Returns the index which [`core_index`] returned for the core with the given MPIDR, or `None` if
+/// it hasn't asked for one.
+pub(crate) fn find_core_index(mpidr: u64) -> Option<usize> {
+    let entry = (mpidr & MPIDR_AFFINITY_MASK) | CORE_MPIDR_VALID;
+    CORE_MPIDRS
+        .iter()
+        .map(|slot| slot.load(Ordering::Acquire))
+        .take_while(|&current| current != 0)
+        .position(|current| current == entry)
+}
+
+/// Returns the affinity fields of the MPIDR of the current core.
+pub(crate) fn read_mpidr() -> u64 {
+    // SAFETY: Reading MPIDR_EL1 is always safe.
+    unsafe { read_sysreg!("mpidr_el1") & MPIDR_AFFINITY_MASK }
+}
@@ -0,0 +1,414 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Access to the generic timer, and a periodic tick and sleeping using the EL1 virtual timer.
+//!
+//! Each core has its own periodic tick, with its own period and handler, which is started,
+//! stopped, suspended and resumed by calling the functions here on that core.
+//!
+//! [`start_periodic_tick`] enables the virtual timer interrupt in the current core's GIC
+//! redistributor if the redistributor region has been registered with
+//! [`gic::set_redistributor_region`], otherwise the application must enable it. The tick is re-armed
+//! by [`handle_tick`], which [`gic::dispatch_irq`] calls when no other handler has been registered
+//! for [`VIRTUAL_TIMER_INTID`], e.g.:
+//!
+//! ```rust,ignore
+//! extern "C" fn irq_current(register_state: RegisterStateRef) {
+//!     if let Some(intid) = gic::acknowledge_interrupt() {
+//!         gic::dispatch_irq(intid);
+//!         gic::end_interrupt(intid);
+//!     }
+//! }
+//! ```
+//!
+//! An IRQ handler which doesn't use `dispatch_irq` should call [`handle_tick`] itself when it
+//! acknowledges [`VIRTUAL_TIMER_INTID`].
+//!
+//! [`sleep`] and [`sleep_until`] wait for interrupts with `wfi` until a deadline. If no periodic
+//! tick is running they use the virtual timer to wake up at the deadline, so its interrupt must be
+//! enabled and passed to [`handle_tick`] in the same way. Otherwise they just wait for ticks, so
+//! can only wake up at the first tick after the deadline.
+
+use crate::{
+    gic,
+    sysreg::{MAX_CORES, core_index, read_sysreg, write_sysreg},
+};
+use core::{
+    arch::asm,
+    fmt::{self, Debug, Formatter},
+    mem::transmute,
+    ops::{Add, AddAssign, Sub},
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The INTID of the EL1 virtual timer PPI, as recommended by the Server Base System Architecture.
+pub const VIRTUAL_TIMER_INTID: u32 = 27;
+
+/// Timer enable, in CNTV_CTL_EL0.
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+/// Timer condition met, in CNTV_CTL_EL0.
+const CNTV_CTL_ISTATUS: u64 = 1 << 2;
+
+/// The periodic tick state of each core, indexed by its `core_index`.
+static TICKS: [TickState; MAX_CORES] = [const { TickState::new() }; MAX_CORES];
+
+/// The state of the periodic tick on a single core.
+struct TickState {
+    /// The tick period in counter ticks, or 0 if no periodic tick has been started.
+    period: AtomicU64,
+    /// The counter value at which the next tick was due when the tick was suspended, or 0 if it
+    /// isn't suspended.
+    suspended_until: AtomicU64,
+    /// The tick handler function pointer, or null if no periodic tick has been started.
+    handler: AtomicPtr<()>,
+}
+
+impl TickState {
+    const fn new() -> Self {
+        Self {
+            period: AtomicU64::new(0),
+            suspended_until: AtomicU64::new(0),
+            handler: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Returns the tick state of the current core.
+    fn current() -> &'static Self {
+        &TICKS[core_index()]
+    }
+}
+
+/// Returns the frequency of the system counter in Hz.
+pub fn counter_frequency() -> u64 {
+    // SAFETY: Reading CNTFRQ_EL0 is always safe.
+    unsafe { read_sysreg!("cntfrq_el0") }
+}
+
+/// Returns the current value of the virtual counter.
+pub fn virtual_counter() -> u64 {
+    // SAFETY: Reading the virtual counter is always safe. The ISB ensures that it isn't read
+    // speculatively out of order.
+    unsafe {
+        asm!("isb", options(nomem, nostack, preserves_flags));
+        read_sysreg!("cntvct_el0")
+    }
+}
+
+/// Converts the given duration to a number of counter ticks, rounding down.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * u128::from(counter_frequency()) / 1_000_000_000) as u64
+}
+
+/// Converts the given number of counter ticks to a duration, rounding down.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(
+        (u128::from(ticks) * 1_000_000_000 / u128::from(counter_frequency())) as u64,
+    )
+}
+
+/// A point in time, measured by the virtual counter.
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Self {
+        Self(virtual_counter())
+    }
+
+    /// Returns the time at which the virtual counter has the given value.
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Returns the value of the virtual counter at this time.
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed since `earlier`, or zero if `earlier` is later than this.
+    pub fn duration_since(self, earlier: Self) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Returns the time elapsed since this time.
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+
+    /// Returns the time the given duration after this, or `None` if the counter would overflow.
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Self)
+    }
+}
+
+impl Debug for Instant {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Instant({})", self.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("Overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Self) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Sleeps for at least the given duration.
+///
+/// See [`sleep_until`] for details.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// Sleeps with `wfi` until the given time.
+///
+/// If no periodic tick is running, this sets the EL1 virtual timer to fire at the deadline, and
+/// disables it again afterwards. Otherwise it leaves the tick running, and may oversleep by up to
+/// a tick period. Other interrupts may wake the core earlier, in which case it goes back to sleep
+/// after they have been handled.
+///
+/// If IRQs are masked then interrupts still wake the core but aren't handled, so this may spin
+/// until the deadline rather than sleeping.
+pub fn sleep_until(deadline: Instant) {
+    let mut set_timer = false;
+    while wait_for_interrupt_if(|| {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        if !tick_running() {
+            set_virtual_timer_deadline(deadline.0);
+            set_timer = true;
+        }
+        true
+    }) {}
+    if set_timer && !tick_running() {
+        disable_virtual_timer();
+    }
+}
+
+/// Suspends the periodic tick on the current core, e.g. before idling for longer than a tick
+/// period, and returns the time at which the next tick was due.
+///
+/// The tick handler isn't called while the tick is suspended, and the virtual timer is left
+/// disabled so that the caller may set it for its own next deadline, e.g. from a timer wheel, with
+/// [`set_virtual_timer_deadline`]. [`handle_tick`] disables it again when it fires. Returns `None`
+/// if no periodic tick is running.
+pub fn suspend_tick() -> Option<Instant> {
+    if !tick_running() {
+        return None;
+    }
+    // SAFETY: Reading CNTV_CVAL_EL0 is always safe.
+    let next_tick = unsafe { read_sysreg!("cntv_cval_el0") };
+    TickState::current()
+        .suspended_until
+        .store(next_tick, Ordering::Release);
+    disable_virtual_timer();
+    Some(Instant(next_tick))
+}
+
+/// Resumes the periodic tick after it was suspended with [`suspend_tick`], and returns the number
+/// of ticks which were missed while it was suspended.
+///
+/// The tick is re-armed for the next tick time after now, keeping the same phase as before it was
+/// suspended. The tick handler isn't called for the missed ticks, so the caller should account
+/// for them itself, e.g. by advancing its timer wheel. Returns 0 if the tick wasn't suspended.
+pub fn resume_tick() -> u64 {
+    let tick = TickState::current();
+    let next_tick = tick.suspended_until.swap(0, Ordering::Acquire);
+    let period = tick.period.load(Ordering::Acquire);
+    if next_tick == 0 || period == 0 {
+        return 0;
+    }
+    let now = virtual_counter();
+    let missed = if now >= next_tick {
+        (now - next_tick) / period + 1
+    } else {
+        0
+    };
+    set_virtual_timer_deadline(next_tick + missed * period);
+    missed
+}
+
+/// Idles the current core without the periodic tick until the next interrupt, setting the virtual
+/// timer to wake it at `next_event` if given, and returns the number of ticks which were missed.
+///
+/// This is a combination of [`suspend_tick`], [`set_virtual_timer_deadline`], `wfi` and
+/// [`resume_tick`]. If no periodic tick is running then it just waits for the next interrupt or
+/// `next_event`, and returns 0.
+pub fn tickless_idle(next_event: Option<Instant>) -> u64 {
+    tickless_idle_with(next_event, wfi)
+}
+
+/// Like [`tickless_idle`], but calls `wait` to wait for the next interrupt rather than using `wfi`.
+///
+/// `wait` is called with IRQs masked, and must return once an interrupt is pending.
+pub(crate) fn tickless_idle_with(next_event: Option<Instant>, wait: impl FnOnce()) -> u64 {
+    suspend_tick();
+    wait_if(
+        || {
+            if let Some(next_event) = next_event {
+                if Instant::now() >= next_event {
+                    return false;
+                }
+                set_virtual_timer_deadline(next_event.0);
+            }
+            true
+        },
+        wait,
+    );
+    if next_event.is_some() {
+        // Stop the timer if it was set for the event but we were woken by something else. If the
+        // tick was running then resuming it will set the timer again.
+        disable_virtual_timer();
+    }
+    resume_tick()
+}
+
+/// Masks IRQs, calls `prepare`, and if it returns true waits for an interrupt, then restores the
+/// previous IRQ mask so that the interrupt is handled. Returns what `prepare` returned.
+///
+/// Masking IRQs while checking whether to sleep and going to sleep ensures that an interrupt which
+/// `prepare` expects to wake the core can't be handled in between, which would leave nothing to
+/// wake it. A pending interrupt still wakes the core from `wfi` while masked.
+fn wait_for_interrupt_if(prepare: impl FnOnce() -> bool) -> bool {
+    wait_if(prepare, wfi)
+}
+
+/// Like [`wait_for_interrupt_if`], but calls `wait` to wait for the interrupt rather than using
+/// `wfi`.
+fn wait_if(prepare: impl FnOnce() -> bool, wait: impl FnOnce()) -> bool {
+    // SAFETY: Masking IRQs doesn't affect memory safety.
+    let daif = unsafe {
+        let daif = read_sysreg!("daif");
+        asm!("msr daifset, #2", options(nomem, nostack, preserves_flags));
+        daif
+    };
+    let should_wait = prepare();
+    if should_wait {
+        wait();
+    }
+    // SAFETY: Restoring the previous interrupt masks doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("daif", daif);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+    should_wait
+}
+
+/// Waits for an interrupt.
+fn wfi() {
+    // SAFETY: Waiting for an interrupt doesn't affect memory safety.
+    unsafe {
+        asm!("wfi", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Returns whether a periodic tick is running and not suspended.
+fn tick_running() -> bool {
+    let tick = TickState::current();
+    tick.period.load(Ordering::Acquire) != 0 && tick.suspended_until.load(Ordering::Acquire) == 0
+}
+
+/// Starts the EL1 virtual timer on the current core to fire periodically, and arranges for
+/// [`handle_tick`] to call the given handler each time it does.
+///
+/// This also enables the virtual timer interrupt in the current core's GIC redistributor, if the
+/// redistributor region has been registered with [`gic::set_redistributor_region`].
+///
+/// # Panics
+///
+/// Panics if the period is shorter than one counter tick.
+pub fn start_periodic_tick(period: Duration, handler: fn()) {
+    let period = duration_to_ticks(period);
+    assert!(period > 0);
+    let tick = TickState::current();
+    tick.handler.store(handler as *mut (), Ordering::Release);
+    tick.period.store(period, Ordering::Release);
+    set_virtual_timer_deadline(virtual_counter() + period);
+    gic::enable_private_interrupt(VIRTUAL_TIMER_INTID);
+}
+
+/// Stops the periodic tick and disables the EL1 virtual timer on the current core.
+///
+/// Ticks running on other cores are unaffected.
+pub fn stop_periodic_tick() {
+    let tick = TickState::current();
+    tick.period.store(0, Ordering::Release);
+    tick.suspended_until.store(0, Ordering::Release);
+    disable_virtual_timer();
+}
+
+/// Handles the virtual timer interrupt for the periodic tick.
+///
+/// If the timer has fired, this re-arms it for the next period and calls the tick handler. If no
+/// periodic tick is running or it is suspended, e.g. because the timer was set by [`sleep_until`]
+/// or for a deadline while idle, this instead disables the timer so that its interrupt is no longer
+/// asserted. Returns whether the timer had fired.
+pub fn handle_tick() -> bool {
+    // SAFETY: Reading CNTV_CTL_EL0 is always safe.
+    let ctl = unsafe { read_sysreg!("cntv_ctl_el0") };
+    if ctl & CNTV_CTL_ISTATUS == 0 {
+        return false;
+    }
+    if !tick_running() {
+        disable_virtual_timer();
+        return true;
+    }
+    let tick = TickState::current();
+    let period = tick.period.load(Ordering::Acquire);
+
+    // Advance from the previous deadline rather than the current time to avoid drift, unless we
+    // have fallen more than a period behind.
+    // SAFETY: Reading CNTV_CVAL_EL0 is always safe.
+    let deadline = unsafe { read_sysreg!("cntv_cval_el0") } + period;
+    set_virtual_timer_deadline(deadline.max(virtual_counter() + 1));
+
+    let handler = tick.handler.load(Ordering::Acquire);
+    if !handler.is_null() {
+        // SAFETY: The tick handler is only ever set to null or a valid `fn()`.
+        let handler = unsafe { transmute::<*mut (), fn()>(handler) };
+        handler();
+    }
+    true
+}
+
+/// Sets the EL1 virtual timer to fire when the virtual counter reaches the given value, and
+/// enables it with its interrupt unmasked.
+pub fn set_virtual_timer_deadline(deadline: u64) {
+    // SAFETY: Configuring the virtual timer doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("cntv_cval_el0", deadline);
+        write_sysreg!("cntv_ctl_el0", CNTV_CTL_ENABLE);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Disables the EL1 virtual timer, so that its interrupt is no longer asserted.
+fn disable_virtual_timer() {
+    // SAFETY: Disabling the timer doesn't affect memory safety.
+    unsafe {
+        write_sysreg!("cntv_ctl_el0", 0u64);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! Boot-time configuration of the traps on floating point, SVE, SME, trace and AMU accesses in
+//! CPACR_EL1, CPTR_EL2 and CPTR_EL3.
+//!
+//! On each core, before enabling the MMU or running any Rust code, the entry points allow or trap
+//! access to each unit from the current exception level and EL0. By default only floating point and
+//! SIMD access is allowed, as the compiler may use SIMD registers for ordinary code, and the traps
+//! for the other units are left as they were. An image may register a different configuration with the
+//! [`trap_config!`](macro@crate::trap_config) macro, e.g. to use SVE:
+//!
+//! ```rust,ignore
+//! use aarch64_rt::{trap_config, traps::{Access, TrapConfig}};
+//!
+//! trap_config!(TrapConfig { sve: Access::Allow, ..TrapConfig::DEFAULT });
+//! ```
+//!
+//! Units which aren't implemented are skipped. When running at EL1, any higher exception levels
+//! must also allow access for it to work.
+
+use crate::init_steps::IdField;
+use core::arch::{global_asm, naked_asm};
+
+/// CPACR_EL1.FPEN, and CPTR_EL2.FPEN when HCR_EL2.E2H is set: don't trap FP or SIMD accesses.
+const CPACR_FPEN: u64 = 0b11 << 20;
+/// CPACR_EL1.ZEN, and CPTR_EL2.ZEN when HCR_EL2.E2H is set: don't trap SVE accesses.
+const CPACR_ZEN: u64 = 0b11 << 16;
+/// CPACR_EL1.SMEN, and CPTR_EL2.SMEN when HCR_EL2.E2H is set: don't trap SME accesses.
+const CPACR_SMEN: u64 = 0b11 << 24;
+/// CPACR_EL1.TTA, and CPTR_EL2.TTA when HCR_EL2.E2H is set: trap trace register accesses.
+const CPACR_TTA: u64 = 1 << 28;
+/// CPTR_EL2.TZ when HCR_EL2.E2H is clear: trap SVE accesses.
+const CPTR_EL2_TZ: u64 = 1 << 8;
+/// CPTR_EL2.TSM when HCR_EL2.E2H is clear: trap SME accesses.
+const CPTR_EL2_TSM: u64 = 1 << 12;
+/// CPTR_EL3.EZ: don't trap SVE accesses.
+const CPTR_EL3_EZ: u64 = 1 << 8;
+/// CPTR_EL3.ESM: don't trap SME accesses.
+const CPTR_EL3_ESM: u64 = 1 << 12;
+/// CPTR_EL2.TFP when HCR_EL2.E2H is clear, and CPTR_EL3.TFP: trap FP and SIMD accesses.
+const CPTR_TFP: u64 = 1 << 10;
+/// CPTR_EL2.TTA when HCR_EL2.E2H is clear, and CPTR_EL3.TTA: trap trace register accesses.
+const CPTR_TTA: u64 = 1 << 20;
+/// CPTR_EL2.TAM and CPTR_EL3.TAM: trap AMU register accesses.
+const CPTR_TAM: u64 = 1 << 30;
+/// The bit number of HCR_EL2.E2H.
+const HCR_E2H_BIT: u32 = 34;
+
+/// The bit number for each unit in the flags passed to `__configure_traps`.
+const UNIT_FP: u32 = 0;
+const UNIT_SVE: u32 = 1;
+const UNIT_SME: u32 = 2;
+const UNIT_TRACE: u32 = 3;
+const UNIT_AMU: u32 = 4;
+
+/// Whether to trap accesses to some unit.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Access {
+    /// Leave the traps as they were.
+    #[default]
+    Unchanged,
+    /// Trap accesses from the current exception level and EL0.
+    Trap,
+    /// Allow accesses from the current exception level and EL0.
+    Allow,
+}
+
+/// Configuration of the traps to apply at boot.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TrapConfig {
+    /// Floating point and Advanced SIMD instructions and registers.
+    pub fp: Access,
+    /// The Scalable Vector Extension. This also needs `fp` to be allowed.
+    pub sve: Access,
+    /// The Scalable Matrix Extension, including streaming SVE mode.
+    pub sme: Access,
+    /// The trace unit system registers.
+    pub trace: Access,
+    /// The Activity Monitors Unit registers. This only has an effect at EL2 and EL3, as there is no
+    /// corresponding trap in CPACR_EL1.
+    pub amu: Access,
+}
+
+impl TrapConfig {
+    /// Allows floating point and SIMD access and leaves everything else unchanged, which is what
+    /// the entry points do if no configuration is registered.
+    pub const DEFAULT: Self = Self {
+        fp: Access::Allow,
+        sve: Access::Unchanged,
+        sme: Access::Unchanged,
+        trace: Access::Unchanged,
+        amu: Access::Unchanged,
+    };
+
+    /// Allows access to all of the units.
+    pub const ALLOW_ALL: Self = Self {
+        fp: Access::Allow,
+        sve: Access::Allow,
+        sme: Access::Allow,
+        trace: Access::Allow,
+        amu: Access::Allow,
+    };
+
+    const fn units(self) -> [(u32, Access); 5] {
+        [
+            (UNIT_FP, self.fp),
+            (UNIT_SVE, self.sve),
+            (UNIT_SME, self.sme),
+            (UNIT_TRACE, self.trace),
+            (UNIT_AMU, self.amu),
+        ]
+    }
+
+    /// Returns the flags for the units whose traps should be changed.
+    #[doc(hidden)]
+    pub const fn changed_units(self) -> u64 {
+        let units = self.units();
+        let mut flags = 0;
+        let mut i = 0;
+        while i < units.len() {
+            if !matches!(units[i].1, Access::Unchanged) {
+                flags |= 1 << units[i].0;
+            }
+            i += 1;
+        }
+        flags
+    }
+
+    /// Returns the flags for the units which should be allowed.
+    #[doc(hidden)]
+    pub const fn allowed_units(self) -> u64 {
+        let units = self.units();
+        let mut flags = 0;
+        let mut i = 0;
+        while i < units.len() {
+            if matches!(units[i].1, Access::Allow) {
+                flags |= 1 << units[i].0;
+            }
+            i += 1;
+        }
+        flags
+    }
+}
+
+impl Default for TrapConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Applies a trap configuration for the current exception level.
+///
+/// Expects the flags for the units to change in x12 and for those to allow in x8, as returned by
+/// [`TrapConfig::changed_units`] and [`TrapConfig::allowed_units`].
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code, early in the boot process.
+///
+/// Clobbers x8-x12.
+#[doc(hidden)]
+#[unsafe(naked)]
+#[unsafe(link_section = ".init")]
+pub unsafe extern "C" fn __configure_traps() {
+    naked_asm!(
+        // Sets the given bits in x9 if the unit is allowed, or clears them if it is trapped.
+        r".macro enable_bits, unit:req, bits:req",
+            r"tbz x12, #\unit, 9f",
+            r"bic x9, x9, #\bits",
+            r"tbz x8, #\unit, 9f",
+            r"orr x9, x9, #\bits",
+        r"9:",
+        r".endm",
+        // Sets the given bits in x9 if the unit is trapped, or clears them if it is allowed.
+        r".macro trap_bits, unit:req, bits:req",
+            r"tbz x12, #\unit, 9f",
+            r"orr x9, x9, #\bits",
+            r"tbz x8, #\unit, 9f",
+            r"bic x9, x9, #\bits",
+        r"9:",
+        r".endm",
+
+        // Skip units which aren't implemented.
+        "mrs x9, id_aa64pfr0_el1",
+        "ubfx x11, x9, #{sve_shift}, #4",
+        "cbnz x11, 0f",
+        "bic x12, x12, #(1 << {unit_sve})",
+        "0:",
+        "ubfx x11, x9, #{amu_shift}, #4",
+        "cbnz x11, 0f",
+        "bic x12, x12, #(1 << {unit_amu})",
+        "0:",
+        "mrs x9, id_aa64pfr1_el1",
+        "ubfx x11, x9, #{sme_shift}, #4",
+        "cbnz x11, 0f",
+        "bic x12, x12, #(1 << {unit_sme})",
+        "0:",
+        "mrs x9, id_aa64dfr0_el1",
+        "ubfx x11, x9, #{trace_shift}, #4",
+        "cbnz x11, 0f",
+        "bic x12, x12, #(1 << {unit_trace})",
+        "0:",
+
+        "mrs x9, cpacr_el1",
+        "enable_bits {unit_fp}, {cpacr_fpen}",
+        "enable_bits {unit_sve}, {cpacr_zen}",
+        "enable_bits {unit_sme}, {cpacr_smen}",
+        "trap_bits {unit_trace}, {cpacr_tta}",
+        "msr cpacr_el1, x9",
+
+        "mrs x10, CurrentEL",
+        "ubfx x10, x10, #2, #2",
+        "cmp x10, #2",
+        "b.lo 2f",
+        "b.eq 0f",
+        "mrs x9, cptr_el3",
+        "trap_bits {unit_fp}, {cptr_tfp}",
+        "enable_bits {unit_sve}, {cptr_el3_ez}",
+        "enable_bits {unit_sme}, {cptr_el3_esm}",
+        "trap_bits {unit_trace}, {cptr_tta}",
+        "trap_bits {unit_amu}, {cptr_tam}",
+        "msr cptr_el3, x9",
+        "b 2f",
+        "0:",
+        // CPTR_EL2 has the same layout as CPACR_EL1 if HCR_EL2.E2H is set.
+        "mrs x9, hcr_el2",
+        "tbnz x9, #{hcr_e2h_bit}, 1f",
+        "mrs x9, cptr_el2",
+        "trap_bits {unit_fp}, {cptr_tfp}",
+        "trap_bits {unit_sve}, {cptr_el2_tz}",
+        "trap_bits {unit_sme}, {cptr_el2_tsm}",
+        "trap_bits {unit_trace}, {cptr_tta}",
+        "trap_bits {unit_amu}, {cptr_tam}",
+        "msr cptr_el2, x9",
+        "b 2f",
+        "1:",
+        "mrs x9, cptr_el2",
+        "enable_bits {unit_fp}, {cpacr_fpen}",
+        "enable_bits {unit_sve}, {cpacr_zen}",
+        "enable_bits {unit_sme}, {cpacr_smen}",
+        "trap_bits {unit_trace}, {cpacr_tta}",
+        "trap_bits {unit_amu}, {cptr_tam}",
+        "msr cptr_el2, x9",
+        "2:",
+        "isb",
+        "ret",
+
+        ".purgem enable_bits",
+        ".purgem trap_bits",
+        sve_shift = const IdField::SVE.shift(),
+        amu_shift = const IdField::AMU.shift(),
+        sme_shift = const IdField::SME.shift(),
+        trace_shift = const IdField::TRACE.shift(),
+        unit_fp = const UNIT_FP,
+        unit_sve = const UNIT_SVE,
+        unit_sme = const UNIT_SME,
+        unit_trace = const UNIT_TRACE,
+        unit_amu = const UNIT_AMU,
+        cpacr_fpen = const CPACR_FPEN,
+        cpacr_zen = const CPACR_ZEN,
+        cpacr_smen = const CPACR_SMEN,
+        cpacr_tta = const CPACR_TTA,
+        cptr_el2_tz = const CPTR_EL2_TZ,
+        cptr_el2_tsm = const CPTR_EL2_TSM,
+        cptr_el3_ez = const CPTR_EL3_EZ,
+        cptr_el3_esm = const CPTR_EL3_ESM,
+        cptr_tfp = const CPTR_TFP,
+        cptr_tta = const CPTR_TTA,
+        cptr_tam = const CPTR_TAM,
+        hcr_e2h_bit = const HCR_E2H_BIT,
+    )
+}
+
+// The default hook, which is overridden by the strong symbol defined by `trap_config!` if it is
+// used.
+global_asm!(
+    ".section .init.__aarch64_rt_configure_traps, \"ax\"",
+    ".weak __aarch64_rt_configure_traps",
+    ".type __aarch64_rt_configure_traps, %function",
+    "__aarch64_rt_configure_traps:",
+    "mov x12, #{CHANGED}",
+    "mov x8, #{ALLOWED}",
+    "b {configure_traps}",
+    CHANGED = const TrapConfig::DEFAULT.changed_units(),
+    ALLOWED = const TrapConfig::DEFAULT.allowed_units(),
+    configure_traps = sym __configure_traps,
+);
+
+/// Registers a trap configuration for the entry points to apply, in place of
+/// [`TrapConfig::DEFAULT`](crate::traps::TrapConfig::DEFAULT).
+///
+/// The value must be a [`TrapConfig`](crate::traps::TrapConfig) constant expression.
+#[macro_export]
+macro_rules! trap_config {
+    ($config:expr) => {
+        core::arch::global_asm!(
+            ".section .init.__aarch64_rt_configure_traps, \"ax\"",
+            ".global __aarch64_rt_configure_traps",
+            "__aarch64_rt_configure_traps:",
+                "mov x12, #{CHANGED}",
+                "mov x8, #{ALLOWED}",
+                "b {configure_traps}",
+            CHANGED = const $crate::traps::TrapConfig::changed_units($config),
+            ALLOWED = const $crate::traps::TrapConfig::allowed_units($config),
+            configure_traps = sym $crate::traps::__configure_traps,
+        );
+    };
+}
@@ -0,0 +1,98 @@
+// Copyright 2025 The aarch64-rt Authors.
+// This project is dual-licensed under Apache 2.0 and MIT terms.
+// See LICENSE-APACHE and LICENSE-MIT for details.
+
+//! A hook to service a watchdog during long startup operations.
+//!
+//! Boards with a watchdog which is already running when the image is entered may be reset if
+//! startup takes too long, e.g. while zeroing a large `.bss` section. A function registered with
+//! the [`watchdog_kick!`](crate::watchdog_kick) macro is called periodically during such operations
+//! to prevent this:
+//!
+//! - by the entry point for every 1 MiB of `.bss` zeroed, and
+//! - by [`relocate_image`](crate::relocate::relocate_image) for every 4096 relocations applied.
+//!
+//! Applications may also call [`kick`] during their own long operations such as copying payloads.
+//!
+//! The first calls are made before `.bss` has been zeroed, so the function must not access any
+//! statics other than those explicitly placed in `.data`.
+
+use core::arch::{global_asm, naked_asm};
+
+// A default no-op hook, which is overridden by the strong symbol defined by `watchdog_kick!` if it
+// is used.
+global_asm!(
+    ".section .text.__aarch64_rt_watchdog_kick, \"ax\"",
+    ".weak __aarch64_rt_watchdog_kick",
+    ".type __aarch64_rt_watchdog_kick, %function",
+    "__aarch64_rt_watchdog_kick:",
+    "ret",
+);
+
+unsafe extern "C" {
+    /// The watchdog hook registered with `watchdog_kick!`, or a no-op by default.
+    safe fn __aarch64_rt_watchdog_kick();
+}
+
+/// Registers a function to service the watchdog during long startup operations.
+///
+/// Example:
+///
+/// ```rust,ignore
+/// use aarch64_rt::watchdog_kick;
+///
+/// watchdog_kick!(kick_watchdog);
+/// fn kick_watchdog() {
+///     // Write to the watchdog's refresh register.
+/// }
+/// ```
+#[macro_export]
+macro_rules! watchdog_kick {
+    ($kick:path) => {
+        #[unsafe(export_name = "__aarch64_rt_watchdog_kick")]
+        extern "C" fn __watchdog_kick() {
+            // Ensure that the function provided by the application has the correct type.
+            let kick: fn() = $kick;
+            kick()
+        }
+    };
+}
+
+/// Calls the watchdog hook registered with [`watchdog_kick!`](crate::watchdog_kick), if any.
+pub fn kick() {
+    __aarch64_rt_watchdog_kick();
+}
+
+/// Calls the watchdog hook, preserving all general-purpose registers other than x16 and x17.
+///
+/// # Safety
+///
+/// This function doesn't follow the standard aarch64 calling convention. It must only be called
+/// from assembly code, with a valid stack.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn kick_preserving_registers() {
+    naked_asm!(
+        "stp x29, x30, [sp, #-176]!",
+        "stp x0, x1, [sp, #16]",
+        "stp x2, x3, [sp, #32]",
+        "stp x4, x5, [sp, #48]",
+        "stp x6, x7, [sp, #64]",
+        "stp x8, x9, [sp, #80]",
+        "stp x10, x11, [sp, #96]",
+        "stp x12, x13, [sp, #112]",
+        "stp x14, x15, [sp, #128]",
+        "str x18, [sp, #144]",
+        "bl __aarch64_rt_watchdog_kick",
+        "ldr x18, [sp, #144]",
+        "ldp x14, x15, [sp, #128]",
+        "ldp x12, x13, [sp, #112]",
+        "ldp x10, x11, [sp, #96]",
+        "ldp x8, x9, [sp, #80]",
+        "ldp x6, x7, [sp, #64]",
+        "ldp x4, x5, [sp, #48]",
+        "ldp x2, x3, [sp, #32]",
+        "ldp x0, x1, [sp, #16]",
+        "ldp x29, x30, [sp], #176",
+        "ret",
+    )
+}